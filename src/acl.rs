@@ -0,0 +1,125 @@
+// POSIX ACLs as stored in the system.posix_acl_access/system.posix_acl_default xattrs: a
+// little endian u32 version followed by (u16 tag, u16 perm, u32 id) entries
+pub const ACL_ACCESS_XATTR: &str = "system.posix_acl_access";
+pub const ACL_DEFAULT_XATTR: &str = "system.posix_acl_default";
+
+const ACL_XATTR_VERSION: u32 = 2;
+
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+
+#[derive(Debug,Clone,PartialEq)]
+struct AclEntry {
+    tag: u16,
+    perm: u16,
+    id: u32,
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct Acl {
+    entries: Vec<AclEntry>,
+}
+
+impl Acl {
+    /// Parses the xattr form of an ACL, or returns None if it's malformed
+    pub fn parse(data: &[u8]) -> Option<Acl> {
+        if data.len() < 4 || !(data.len() - 4).is_multiple_of(8) {
+            return None;
+        }
+        if u32::from_le_bytes(data[0..4].try_into().ok()?) != ACL_XATTR_VERSION {
+            return None;
+        }
+
+        let mut entries = Vec::new();
+        for chunk in data[4..].chunks(8) {
+            let entry = AclEntry{
+                tag: u16::from_le_bytes(chunk[0..2].try_into().ok()?),
+                perm: u16::from_le_bytes(chunk[2..4].try_into().ok()?),
+                id: u32::from_le_bytes(chunk[4..8].try_into().ok()?),
+            };
+            match entry.tag {
+                ACL_USER_OBJ | ACL_USER | ACL_GROUP_OBJ | ACL_GROUP | ACL_MASK | ACL_OTHER => entries.push(entry),
+                _ => return None,
+            }
+        }
+        Some(Acl{
+            entries,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = ACL_XATTR_VERSION.to_le_bytes().to_vec();
+        for e in &self.entries {
+            data.extend_from_slice(&e.tag.to_le_bytes());
+            data.extend_from_slice(&e.perm.to_le_bytes());
+            data.extend_from_slice(&e.id.to_le_bytes());
+        }
+        data
+    }
+
+    /// The owner/group/other permission bits the ACL corresponds to. With a mask entry the group
+    /// bits show the mask, as they do for chmod and stat
+    pub fn mode_bits(&self) -> u16 {
+        let group = self.perm(ACL_MASK).or(self.perm(ACL_GROUP_OBJ)).unwrap_or(0);
+        (self.perm(ACL_USER_OBJ).unwrap_or(0) << 6) | (group << 3) | self.perm(ACL_OTHER).unwrap_or(0)
+    }
+
+    /// The access ACL a new inode gets from this default ACL when created with `mode`: the
+    /// owner, mask (or owning group) and other entries are limited to what `mode` allows
+    pub fn inherit(&self, mode: u16) -> Acl {
+        let has_mask = self.perm(ACL_MASK).is_some();
+        let mut entries = self.entries.clone();
+        for e in entries.iter_mut() {
+            match e.tag {
+                ACL_USER_OBJ => e.perm &= (mode >> 6) & 0o7,
+                ACL_MASK => e.perm &= (mode >> 3) & 0o7,
+                ACL_GROUP_OBJ if !has_mask => e.perm &= (mode >> 3) & 0o7,
+                ACL_OTHER => e.perm &= mode & 0o7,
+                _ => (),
+            }
+        }
+        Acl{
+            entries,
+        }
+    }
+
+    fn perm(&self, tag: u16) -> Option<u16> {
+        self.entries.iter().find(|e| e.tag == tag).map(|e| e.perm)
+    }
+
+    /// Whether the ACL grants every bit of `want` (0o4 read, 0o2 write, 0o1 execute) to the
+    /// requester, following the POSIX.1e access check algorithm. `req_groups` holds every group
+    /// the requester is in, primary and supplementary
+    pub fn allows(&self, owner_uid: u32, owner_gid: u32, req_uid: u32, req_groups: &[u32], want: u16) -> bool {
+        let grants = |perm: u16| perm & want == want;
+        // The mask caps everything except the owner and other entries
+        let mask = self.perm(ACL_MASK).unwrap_or(0o7);
+
+        if req_uid == owner_uid {
+            return grants(self.perm(ACL_USER_OBJ).unwrap_or(0));
+        }
+        if let Some(e) = self.entries.iter().find(|e| e.tag == ACL_USER && e.id == req_uid) {
+            return grants(e.perm & mask);
+        }
+
+        let mut group_matched = false;
+        for e in &self.entries {
+            let matches = (e.tag == ACL_GROUP_OBJ && req_groups.contains(&owner_gid)) || (e.tag == ACL_GROUP && req_groups.contains(&e.id));
+            if matches {
+                if grants(e.perm & mask) {
+                    return true;
+                }
+                group_matched = true;
+            }
+        }
+        if group_matched {
+            return false;
+        }
+
+        grants(self.perm(ACL_OTHER).unwrap_or(0))
+    }
+}
@@ -0,0 +1,501 @@
+use crate::cdc;
+use crate::crypt::EncryptedBackend;
+use crate::dns::{self, DnsBackend, UpdateClient};
+use crate::http::HttpBackend;
+use crate::options::Options;
+use fuser::FileType;
+use std::fmt::Debug;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+/// Where file contents are persisted. The tree stays the working copy; a backend is given a
+/// file's contents when it's flushed, synced or closed after changing (or truncated), and read
+/// from when an inode's data hasn't been loaded yet
+pub trait StorageBackend: Debug + Send {
+    /// Reads up to `size` bytes of `ino`'s contents starting at `offset`
+    fn load(&self, ino: u64, offset: u64, size: u64) -> io::Result<Vec<u8>>;
+    /// Replaces `ino`'s contents with `data`
+    fn store(&self, ino: u64, data: &[u8]) -> io::Result<()>;
+    fn delete(&self, ino: u64) -> io::Result<()>;
+    /// Deletes the contents of every one of `inos`, in as few requests as the backend can manage
+    fn delete_many(&self, inos: &[u64]) -> io::Result<()> {
+        inos.iter().try_for_each(|ino| self.delete(*ino))
+    }
+    /// The current size of `ino`'s contents, if the backend can tell cheaply
+    fn size(&self, _ino: u64) -> io::Result<Option<u64>> {
+        Ok(None)
+    }
+    /// When `ino`'s contents last changed in the backend, if it keeps track
+    fn mtime(&self, _ino: u64) -> io::Result<Option<SystemTime>> {
+        Ok(None)
+    }
+    /// The transfer size, in bytes, the backend reads and writes most efficiently in, if it
+    /// prefers one over the filesystem's block size
+    fn io_size(&self) -> Option<u32> {
+        None
+    }
+    /// Whether anything stored survives an unmount
+    fn is_persistent(&self) -> bool {
+        true
+    }
+    /// Checks the backend can be reached right now
+    fn ping(&self) -> io::Result<()> {
+        Ok(())
+    }
+    /// Up to `count` of the children the backend lists for directory `ino`, starting `offset`
+    /// entries into the listing; fewer means the listing ends there. None if the backend keeps
+    /// no listing for `ino`, whose children are then only the tree's own
+    fn list_children(&self, _ino: u64, _offset: u64, _count: usize) -> io::Result<Option<Vec<ListedChild>>> {
+        Ok(None)
+    }
+    /// The child called `name` in the listing of directory `ino`, if it has one
+    fn find_child(&self, ino: u64, name: &str) -> io::Result<Option<ListedChild>> {
+        let mut offset = 0;
+        while let Some(page) = self.list_children(ino, offset, LIST_PAGE_SIZE)? {
+            if let Some(child) = page.iter().find(|c| c.name == name) {
+                return Ok(Some(child.clone()));
+            }
+            if page.len() < LIST_PAGE_SIZE {
+                break;
+            }
+            offset += page.len() as u64;
+        }
+        Ok(None)
+    }
+}
+
+/// How many children of a listed directory are asked for at a time
+pub const LIST_PAGE_SIZE: usize = 256;
+
+/// A child in a directory listing kept by the backend. Its contents are kept under `ino` like any
+/// other file's
+#[derive(Debug,Clone,PartialEq)]
+pub struct ListedChild {
+    pub ino: u64,
+    pub kind: FileType,
+    pub size: u64,
+    pub name: String,
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum BackendKind {
+    Memory,
+    Dns,
+    Http,
+    Dir,
+}
+
+impl FromStr for BackendKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<BackendKind, ()> {
+        match s {
+            "memory" => Ok(BackendKind::Memory),
+            "dns" => Ok(BackendKind::Dns),
+            "http" => Ok(BackendKind::Http),
+            "dir" => Ok(BackendKind::Dir),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Builds the backend chosen with --backend, encrypting through it with --encrypt-key-file
+pub fn open(options: &Options) -> Result<Box<dyn StorageBackend>, String> {
+    let backend: Box<dyn StorageBackend> = match options.backend {
+        BackendKind::Memory => Box::new(MemoryBackend),
+        BackendKind::Dir => {
+            let root = match options.backing_dir {
+                Some(ref d) => d,
+                None => return Err("--backend dir requires --backing-dir".to_string()),
+            };
+            match DirBackend::open(root) {
+                Ok(b) => Box::new(b),
+                Err(e) => return Err(format!("Could not open backing directory {}: {}", root, e)),
+            }
+        },
+        BackendKind::Dns => {
+            let (server, zone) = match (options.dns_server, options.dns_zone.as_ref()) {
+                (Some(s), Some(z)) => (s, z),
+                _ => return Err("--backend dns requires --dns-server and --dns-zone".to_string()),
+            };
+            Box::new(DnsStorage{
+                records: Box::new(UpdateClient::new(server, zone, options.dns_rrtype, options.dns_ttl)),
+            })
+        },
+        BackendKind::Http => {
+            let url = match options.http_url {
+                Some(ref u) => u,
+                None => return Err("--backend http requires --http-url".to_string()),
+            };
+            Box::new(HttpBackend::new(url)?)
+        },
+    };
+
+    let key_file = match options.encrypt_key_file {
+        Some(ref k) => k,
+        None => return Ok(backend),
+    };
+    if !backend.is_persistent() {
+        return Err("--encrypt-key-file requires a --backend that stores file contents".to_string());
+    }
+    // Both keep file contents in the clear
+    if options.wal.is_some() || options.snapshot.is_some() {
+        return Err("--encrypt-key-file can't be combined with --wal or --snapshot".to_string());
+    }
+    Ok(Box::new(EncryptedBackend::new(backend, key_file)?))
+}
+
+/// Keeps nothing outside the tree itself
+#[derive(Debug)]
+pub struct MemoryBackend;
+
+impl StorageBackend for MemoryBackend {
+    fn load(&self, _ino: u64, _offset: u64, _size: u64) -> io::Result<Vec<u8>> {
+        // Everything is already in the tree, so there's never anything to load
+        Ok(Vec::new())
+    }
+
+    fn store(&self, _ino: u64, _data: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn delete(&self, _ino: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn is_persistent(&self) -> bool {
+        false
+    }
+}
+
+/// Stores each file's contents in a host directory, one file per inode number
+#[derive(Debug)]
+pub struct DirBackend {
+    root: PathBuf,
+    // st_blksize of the backing directory's filesystem
+    blksize: u32,
+}
+
+impl DirBackend {
+    pub fn open(root: &str) -> io::Result<DirBackend> {
+        fs::create_dir_all(root)?;
+        Ok(DirBackend{
+            root: PathBuf::from(root),
+            blksize: fs::metadata(root)?.blksize() as u32,
+        })
+    }
+
+    fn path(&self, ino: u64) -> PathBuf {
+        self.root.join(ino.to_string())
+    }
+}
+
+impl StorageBackend for DirBackend {
+    fn load(&self, ino: u64, offset: u64, size: u64) -> io::Result<Vec<u8>> {
+        let mut file = File::open(self.path(ino))?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = Vec::new();
+        file.take(size).read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn store(&self, ino: u64, data: &[u8]) -> io::Result<()> {
+        // Write beside the real file and rename over it, so a crash never leaves half a file
+        let tmp = self.root.join(format!(".{}.tmp", ino));
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, self.path(ino))
+    }
+
+    fn delete(&self, ino: u64) -> io::Result<()> {
+        match fs::remove_file(self.path(ino)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn size(&self, ino: u64) -> io::Result<Option<u64>> {
+        match fs::metadata(self.path(ino)) {
+            Ok(m) => Ok(Some(m.len())),
+            // Never stored, so there's nothing newer than what the tree has
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn mtime(&self, ino: u64) -> io::Result<Option<SystemTime>> {
+        match fs::metadata(self.path(ino)) {
+            Ok(m) => Ok(Some(m.modified()?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn io_size(&self) -> Option<u32> {
+        Some(self.blksize)
+    }
+
+    fn ping(&self) -> io::Result<()> {
+        fs::read_dir(&self.root).map(|_| ())
+    }
+}
+
+/// Stores each file's contents in DNS, cut into content-defined chunks (see cdc) so an edit only
+/// rewrites the records around it. Each chunk lives under `<digest>.<inode>` in the zone, and a
+/// manifest listing the file's chunks in order lives under `<inode>`. A directory's children can be
+/// published under `<n>.list.<inode>`, LIST_RECORD_ENTRIES to a record, one per line as
+/// `<inode> <file|dir> <size> <name>`
+#[derive(Debug)]
+pub struct DnsStorage {
+    records: Box<dyn DnsBackend>,
+}
+
+/// How many children each record of a directory listing holds
+pub const LIST_RECORD_ENTRIES: u64 = 64;
+
+impl DnsStorage {
+    fn chunk_name(ino: u64, digest: u64) -> String {
+        format!("{:016x}.{}", digest, ino)
+    }
+
+    // The (digest, length) of each of a file's chunks, in order. Stored as "digest:length,..."
+    fn list_name(ino: u64, record: u64) -> String {
+        format!("{}.list.{}", record, ino)
+    }
+
+    // The children listed in one record of a directory listing
+    fn list_record(&self, ino: u64, record: u64) -> io::Result<Vec<ListedChild>> {
+        let data = self.records.read_record(&DnsStorage::list_name(ino, record))?;
+        let text = String::from_utf8_lossy(&data);
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("Malformed listing record {} of inode {}", record, ino));
+        text.lines().filter(|l| !l.is_empty()).map(|line| {
+            let mut fields = line.splitn(4, ' ');
+            let mut field = || fields.next().ok_or_else(invalid);
+            let ino = field()?.parse().map_err(|_| invalid())?;
+            let kind = match field()? {
+                "file" => FileType::RegularFile,
+                "dir" => FileType::Directory,
+                _ => return Err(invalid()),
+            };
+            let size = field()?.parse().map_err(|_| invalid())?;
+            let name = field()?.to_string();
+            Ok(ListedChild{ ino, kind, size, name })
+        }).collect()
+    }
+
+    fn manifest(&self, ino: u64) -> io::Result<Vec<(u64, u64)>> {
+        let data = self.records.read_record(&ino.to_string())?;
+        let text = String::from_utf8_lossy(&data);
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("Malformed chunk manifest for inode {}", ino));
+        text.split(',').filter(|e| !e.is_empty()).map(|entry| {
+            let (digest, len) = entry.split_once(':').ok_or_else(invalid)?;
+            Ok((u64::from_str_radix(digest, 16).map_err(|_| invalid())?, len.parse().map_err(|_| invalid())?))
+        }).collect()
+    }
+}
+
+impl StorageBackend for DnsStorage {
+    fn load(&self, ino: u64, offset: u64, size: u64) -> io::Result<Vec<u8>> {
+        let end = offset.saturating_add(size);
+        let mut data = Vec::new();
+        let mut chunk_start = 0;
+        let mut first_start = None;
+        for (digest, len) in self.manifest(ino)? {
+            let chunk_end = chunk_start + len;
+            // Only fetch the chunks the range overlaps
+            if chunk_end > offset && chunk_start < end {
+                first_start.get_or_insert(chunk_start);
+                data.extend_from_slice(&self.records.read_record(&DnsStorage::chunk_name(ino, digest))?);
+            }
+            chunk_start = chunk_end;
+        }
+        let skip = (offset - first_start.unwrap_or(offset)) as usize;
+        Ok(data.into_iter().skip(skip).take(size as usize).collect())
+    }
+
+    fn store(&self, ino: u64, data: &[u8]) -> io::Result<()> {
+        let old = self.manifest(ino)?;
+        let mut new = Vec::new();
+        for chunk in cdc::chunks(data) {
+            let digest = cdc::digest(chunk);
+            // Chunks the file already had are still there
+            if !old.iter().any(|(d, _)| *d == digest) && !new.iter().any(|(d, _)| *d == digest) {
+                self.records.write_record(&DnsStorage::chunk_name(ino, digest), chunk)?;
+            }
+            new.push((digest, chunk.len() as u64));
+        }
+
+        if new != old {
+            let manifest: Vec<String> = new.iter().map(|(d, len)| format!("{:016x}:{}", d, len)).collect();
+            self.records.write_record(&ino.to_string(), manifest.join(",").as_bytes())?;
+        }
+        // Only drop chunks once the manifest no longer points at them
+        for (digest, _) in old {
+            if !new.iter().any(|(d, _)| *d == digest) {
+                self.records.write_record(&DnsStorage::chunk_name(ino, digest), &[])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn delete(&self, ino: u64) -> io::Result<()> {
+        self.store(ino, &[])
+    }
+
+    fn delete_many(&self, inos: &[u64]) -> io::Result<()> {
+        // Every file's manifest and chunks go in the same update
+        let mut names = Vec::new();
+        for &ino in inos {
+            names.extend(self.manifest(ino)?.into_iter().map(|(digest, _)| DnsStorage::chunk_name(ino, digest)));
+            names.push(ino.to_string());
+        }
+        self.records.delete_records(&names)
+    }
+
+    fn io_size(&self) -> Option<u32> {
+        Some(dns::CHUNK_SIZE as u32)
+    }
+
+    fn list_children(&self, ino: u64, offset: u64, count: usize) -> io::Result<Option<Vec<ListedChild>>> {
+        let mut record = offset / LIST_RECORD_ENTRIES;
+        let mut skip = (offset % LIST_RECORD_ENTRIES) as usize;
+        let mut children = Vec::new();
+        while children.len() < count {
+            let entries = self.list_record(ino, record)?;
+            // Without a first record there's no listing at all
+            if entries.is_empty() && record == 0 {
+                return Ok(None);
+            }
+            let last = (entries.len() as u64) < LIST_RECORD_ENTRIES;
+            children.extend(entries.into_iter().skip(skip));
+            if last {
+                break;
+            }
+            skip = 0;
+            record += 1;
+        }
+        children.truncate(count);
+        Ok(Some(children))
+    }
+
+    fn ping(&self) -> io::Result<()> {
+        self.records.ping()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DnsStorage, ListedChild, StorageBackend, LIST_RECORD_ENTRIES};
+    use crate::dns::DnsBackend;
+    use fuser::FileType;
+    use std::collections::BTreeMap;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    // A zone held in memory, remembering the name of every record written to it
+    #[derive(Debug,Default)]
+    struct MemoryZone {
+        records: Mutex<BTreeMap<String, Vec<u8>>>,
+        writes: Arc<Mutex<Vec<String>>>,
+        // The names given to each delete_records call
+        deletes: Arc<Mutex<Vec<Vec<String>>>>,
+    }
+
+    impl DnsBackend for MemoryZone {
+        fn read_record(&self, name: &str) -> io::Result<Vec<u8>> {
+            Ok(self.records.lock().unwrap().get(name).cloned().unwrap_or_default())
+        }
+
+        fn write_record(&self, name: &str, data: &[u8]) -> io::Result<()> {
+            self.writes.lock().unwrap().push(name.to_string());
+            let mut records = self.records.lock().unwrap();
+            if data.is_empty() {
+                records.remove(name);
+            } else {
+                records.insert(name.to_string(), data.to_vec());
+            }
+            Ok(())
+        }
+
+        fn delete_records(&self, names: &[String]) -> io::Result<()> {
+            self.deletes.lock().unwrap().push(names.to_vec());
+            let mut records = self.records.lock().unwrap();
+            for name in names {
+                records.remove(name);
+            }
+            Ok(())
+        }
+
+        fn ping(&self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn an_insert_mid_file_only_rewrites_the_chunks_around_it() {
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let storage = DnsStorage{ records: Box::new(MemoryZone{ writes: writes.clone(), ..Default::default() }) };
+        // Incompressible-looking contents, so boundaries are spread the way real data's would be
+        let mut state: u64 = 1;
+        let mut data: Vec<u8> = (0..1 << 20).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        }).collect();
+        storage.store(1, &data).unwrap();
+        let first = writes.lock().unwrap().len();
+        assert!(first > 20, "a MiB went into only {} records", first);
+
+        writes.lock().unwrap().clear();
+        data.insert(data.len() / 2, b'!');
+        storage.store(1, &data).unwrap();
+        // The new chunk or two, the old ones' removal and the manifest; nothing shifted past them
+        let rewritten = writes.lock().unwrap().clone();
+        assert!(rewritten.len() <= 6, "{} of {} records rewritten: {:?}", rewritten.len(), first, rewritten);
+        assert!(rewritten.contains(&"1".to_string()));
+        assert_eq!(storage.load(1, 0, data.len() as u64).unwrap(), data);
+    }
+
+    #[test]
+    fn a_listing_spread_over_records_pages_from_any_offset() {
+        let zone = MemoryZone::default();
+        let lines: Vec<String> = (0..150).map(|i| format!("{} {} {} child {}", 100 + i, if i % 10 == 0 { "dir" } else { "file" }, i, i)).collect();
+        for (record, chunk) in lines.chunks(LIST_RECORD_ENTRIES as usize).enumerate() {
+            zone.write_record(&format!("{}.list.7", record), chunk.join("\n").as_bytes()).unwrap();
+        }
+        let storage = DnsStorage{ records: Box::new(zone) };
+
+        let page = storage.list_children(7, 60, 10).unwrap().unwrap();
+        assert_eq!(page.iter().map(|c| c.ino).collect::<Vec<_>>(), (160..170).collect::<Vec<_>>());
+        assert_eq!(page[0], ListedChild{ ino: 160, kind: FileType::Directory, size: 60, name: "child 60".to_string() });
+        assert_eq!(page[1].kind, FileType::RegularFile);
+        // The last page comes up short, and past the end there's nothing
+        assert_eq!(storage.list_children(7, 140, 64).unwrap().unwrap().len(), 10);
+        assert_eq!(storage.list_children(7, 150, 64).unwrap(), Some(Vec::new()));
+        assert_eq!(storage.find_child(7, "child 149").unwrap().map(|c| c.ino), Some(249));
+        assert_eq!(storage.find_child(7, "child 150").unwrap(), None);
+        // A directory nothing's published for has no listing
+        assert_eq!(storage.list_children(8, 0, 64).unwrap(), None);
+    }
+
+    #[test]
+    fn deleting_several_files_removes_all_their_records_in_one_update() {
+        let deletes = Arc::new(Mutex::new(Vec::new()));
+        let storage = DnsStorage{ records: Box::new(MemoryZone{ deletes: deletes.clone(), ..Default::default() }) };
+        storage.store(1, &[7; 100_000]).unwrap();
+        storage.store(2, b"small").unwrap();
+        storage.store(3, b"kept").unwrap();
+
+        storage.delete_many(&[1, 2]).unwrap();
+        let deletes = deletes.lock().unwrap();
+        assert_eq!(deletes.len(), 1);
+        assert!(deletes[0].contains(&"1".to_string()) && deletes[0].contains(&"2".to_string()));
+        assert_eq!(storage.load(1, 0, 10).unwrap(), b"");
+        assert_eq!(storage.load(2, 0, 10).unwrap(), b"");
+        assert_eq!(storage.load(3, 0, 10).unwrap(), b"kept");
+    }
+}
@@ -0,0 +1,50 @@
+use log::warn;
+use std::collections::BTreeMap;
+use std::fs;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Capability bit numbers from linux/capability.h
+pub const CAP_DAC_OVERRIDE: u32 = 1;
+pub const CAP_DAC_READ_SEARCH: u32 = 2;
+
+// Pids get reused, so a process's capabilities are only trusted for this long
+const CACHE_TTL: Duration = Duration::from_secs(1);
+
+/// The effective capabilities of requesting processes, read from /proc/<pid>/status
+#[derive(Debug,Default)]
+pub struct CapabilityCache {
+    caps: Mutex<BTreeMap<u32, (u64, Instant)>>,
+}
+
+impl CapabilityCache {
+    pub fn has(&self, pid: u32, cap: u32) -> bool {
+        self.effective(pid) & (1 << cap) != 0
+    }
+
+    fn effective(&self, pid: u32) -> u64 {
+        let mut caps = self.caps.lock().unwrap();
+        if let Some((set, read_at)) = caps.get(&pid) {
+            if read_at.elapsed() < CACHE_TTL {
+                return *set;
+            }
+        }
+        // A process we can't inspect (already gone, or no /proc) gets no capabilities
+        let set = match read_effective(pid) {
+            Some(s) => s,
+            None => {
+                warn!("Could not read the capabilities of pid {}", pid);
+                0
+            },
+        };
+        caps.retain(|_, (_, read_at)| read_at.elapsed() < CACHE_TTL);
+        caps.insert(pid, (set, Instant::now()));
+        set
+    }
+}
+
+fn read_effective(pid: u32) -> Option<u64> {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let line = status.lines().find(|l| l.starts_with("CapEff:"))?;
+    u64::from_str_radix(line["CapEff:".len()..].trim(), 16).ok()
+}
@@ -0,0 +1,66 @@
+// Content-defined chunking. Boundaries fall wherever a rolling hash of the last few dozen bytes
+// hits a pattern, so they move with the content: inserting a byte near the front of a file only
+// changes the chunk it lands in, instead of shifting every fixed-size chunk after it
+
+// No chunk is cut shorter than this, except the last
+const MIN_CHUNK: usize = 2 * 1024;
+// or longer than this, so each still fits in a single record
+const MAX_CHUNK: usize = crate::dns::CHUNK_SIZE;
+// A boundary is wherever the low 13 bits of the hash are zero, so past the minimum a chunk runs
+// about 8KiB further
+const BOUNDARY_MASK: u64 = (1 << 13) - 1;
+
+// One random-looking value per byte, fed into the hash as each byte rolls in (splitmix64)
+const GEAR: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut state: u64 = 0x9e3779b97f4a7c15;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// Splits `data` into chunks at content-defined boundaries
+pub fn chunks(data: &[u8]) -> Vec<&[u8]> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < data.len() {
+        let end = boundary(&data[start..]) + start;
+        chunks.push(&data[start..end]);
+        start = end;
+    }
+    chunks
+}
+
+// Length of the first chunk of `data`
+fn boundary(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK {
+        return data.len();
+    }
+    // Shifting left pushes older bytes out of the hash, so only the bytes just before a
+    // position decide whether it's a boundary
+    let mut hash: u64 = 0;
+    for (i, b) in data.iter().enumerate().take(MAX_CHUNK) {
+        hash = (hash << 1).wrapping_add(GEAR[*b as usize]);
+        if i + 1 >= MIN_CHUNK && hash & BOUNDARY_MASK == 0 {
+            return i + 1;
+        }
+    }
+    data.len().min(MAX_CHUNK)
+}
+
+/// A 64-bit FNV-1a digest, used to name chunks by their content
+pub fn digest(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in data {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
@@ -0,0 +1,40 @@
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+/// Where the filesystem gets the current time for timestamps
+pub trait Clock: Debug + Send {
+    fn now(&self) -> SystemTime;
+}
+
+/// The host's wall clock
+#[derive(Debug)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Starts at a fixed time and moves forward one nanosecond on every reading, so timestamps are
+/// the same from run to run but still strictly increasing
+#[derive(Debug)]
+pub struct SteppingClock {
+    // Nanoseconds since the unix epoch of the next reading
+    next: AtomicU64,
+}
+
+impl SteppingClock {
+    pub fn new(start_secs: u64) -> SteppingClock {
+        SteppingClock{
+            next: AtomicU64::new(start_secs.saturating_mul(1_000_000_000)),
+        }
+    }
+}
+
+impl Clock for SteppingClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_nanos(self.next.fetch_add(1, Ordering::Relaxed))
+    }
+}
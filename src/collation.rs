@@ -0,0 +1,106 @@
+use std::cmp::Ordering;
+use std::str::FromStr;
+
+/// How readdir orders the entries of a directory
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Collation {
+    // Plain byte order
+    Byte,
+    // Case-insensitive, falling back to byte order for names that only differ in case
+    Ci,
+    // Natural order, so runs of digits compare by value (file2 before file10)
+    Version,
+}
+
+impl FromStr for Collation {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Collation, ()> {
+        match s {
+            "byte" => Ok(Collation::Byte),
+            "ci" => Ok(Collation::Ci),
+            "version" => Ok(Collation::Version),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Collation {
+    pub fn compare(&self, a: &str, b: &str) -> Ordering {
+        match self {
+            Collation::Byte => a.cmp(b),
+            Collation::Ci => a.to_lowercase().cmp(&b.to_lowercase()).then_with(|| a.cmp(b)),
+            Collation::Version => version_compare(a, b).then_with(|| a.cmp(b)),
+        }
+    }
+}
+
+fn version_compare(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+    loop {
+        match (a.first(), b.first()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let (a_num, a_rest) = split_digits(a);
+                let (b_num, b_rest) = split_digits(b);
+                // Compare by value without parsing, so arbitrarily long runs still work
+                let a_trimmed = trim_zeros(a_num);
+                let b_trimmed = trim_zeros(b_num);
+                let ord = a_trimmed.len().cmp(&b_trimmed.len()).then_with(|| a_trimmed.cmp(b_trimmed));
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+                a = a_rest;
+                b = b_rest;
+            },
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(y);
+                }
+                a = &a[1..];
+                b = &b[1..];
+            },
+        }
+    }
+}
+
+fn split_digits(s: &[u8]) -> (&[u8], &[u8]) {
+    let end = s.iter().position(|c| !c.is_ascii_digit()).unwrap_or(s.len());
+    s.split_at(end)
+}
+
+fn trim_zeros(s: &[u8]) -> &[u8] {
+    let start = s.iter().position(|c| *c != b'0').unwrap_or(s.len());
+    &s[start..]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Collation;
+
+    const NAMES: [&str; 4] = ["file10", "File3", "file2", "file1"];
+
+    fn sorted(collation: Collation) -> Vec<&'static str> {
+        let mut names = NAMES.to_vec();
+        names.sort_by(|a, b| collation.compare(a, b));
+        names
+    }
+
+    #[test]
+    fn byte_sorts_uppercase_first_and_digits_as_characters() {
+        assert_eq!(sorted(Collation::Byte), ["File3", "file1", "file10", "file2"]);
+    }
+
+    #[test]
+    fn ci_ignores_case() {
+        assert_eq!(sorted(Collation::Ci), ["file1", "file10", "file2", "File3"]);
+    }
+
+    #[test]
+    fn version_compares_runs_of_digits_by_value() {
+        assert_eq!(sorted(Collation::Version), ["File3", "file1", "file2", "file10"]);
+        assert_eq!(Collation::Version.compare("file02", "file10"), std::cmp::Ordering::Less);
+    }
+}
@@ -0,0 +1,149 @@
+use crate::backend::{ListedChild, StorageBackend};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use fuser::FileType;
+use sha2::Sha256;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::time::SystemTime;
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+// What encryption adds to each stored file: the nonce in front and the tag behind
+pub const OVERHEAD: u64 = (NONCE_LEN + TAG_LEN) as u64;
+
+// PBKDF2 settings for turning the passphrase into a key. The salt is fixed so the same passphrase
+// gives the same key on every mount; the key never leaves the process, so it only has to slow
+// down guessing the passphrase from stolen ciphertext
+const KDF_SALT: &[u8] = b"dnsfs encryption key";
+const KDF_ROUNDS: u32 = 600_000;
+
+/// Encrypts everything stored through another backend with ChaCha20-Poly1305 (--encrypt-key-file).
+/// Each stored file is a fresh random nonce, the ciphertext and its tag. The inode number is
+/// authenticated along with the contents, so one file's contents can't be passed off as another's
+pub struct EncryptedBackend {
+    inner: Box<dyn StorageBackend>,
+    cipher: ChaCha20Poly1305,
+}
+
+impl fmt::Debug for EncryptedBackend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Keep the key out of the logs
+        f.debug_struct("EncryptedBackend").field("inner", &self.inner).finish()
+    }
+}
+
+impl EncryptedBackend {
+    pub fn new(inner: Box<dyn StorageBackend>, key_file: &str) -> Result<EncryptedBackend, String> {
+        let passphrase = match fs::read(key_file) {
+            Ok(p) => p,
+            Err(e) => return Err(format!("Could not read {}: {}", key_file, e)),
+        };
+        // A key file written with echo ends in a newline that isn't part of the passphrase
+        let passphrase = passphrase.strip_suffix(b"\n").unwrap_or(&passphrase);
+        if passphrase.is_empty() {
+            return Err(format!("{} is empty", key_file));
+        }
+
+        let mut key = [0u8; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(passphrase, KDF_SALT, KDF_ROUNDS, &mut key);
+        Ok(EncryptedBackend{
+            inner,
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+        })
+    }
+
+    pub fn encrypt(&self, ino: u64, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut nonce = [0u8; NONCE_LEN];
+        random_bytes(&mut nonce)?;
+        let aad = ino.to_le_bytes();
+        let sealed = match self.cipher.encrypt(Nonce::from_slice(&nonce), Payload{ msg: data, aad: &aad }) {
+            Ok(s) => s,
+            Err(_) => return Err(io::Error::other(format!("Could not encrypt inode {}", ino))),
+        };
+        let mut out = nonce.to_vec();
+        out.extend_from_slice(&sealed);
+        Ok(out)
+    }
+
+    pub fn decrypt(&self, ino: u64, data: &[u8]) -> io::Result<Vec<u8>> {
+        // Nothing stored yet, or deleted
+        if data.is_empty() {
+            return Ok(Vec::new());
+        }
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("Inode {} failed to decrypt: corrupt, tampered with or encrypted with another key", ino));
+        if (data.len() as u64) < OVERHEAD {
+            return Err(invalid());
+        }
+        let (nonce, sealed) = data.split_at(NONCE_LEN);
+        let aad = ino.to_le_bytes();
+        self.cipher.decrypt(Nonce::from_slice(nonce), Payload{ msg: sealed, aad: &aad }).map_err(|_| invalid())
+    }
+}
+
+impl StorageBackend for EncryptedBackend {
+    fn load(&self, ino: u64, offset: u64, size: u64) -> io::Result<Vec<u8>> {
+        // The tag covers the whole file, so all of it has to be fetched to check any of it
+        let data = self.decrypt(ino, &self.inner.load(ino, 0, u64::MAX)?)?;
+        let start = (offset as usize).min(data.len());
+        let end = (offset.saturating_add(size) as usize).min(data.len());
+        Ok(data[start..end].to_vec())
+    }
+
+    fn store(&self, ino: u64, data: &[u8]) -> io::Result<()> {
+        self.inner.store(ino, &self.encrypt(ino, data)?)
+    }
+
+    fn delete(&self, ino: u64) -> io::Result<()> {
+        self.inner.delete(ino)
+    }
+
+    fn delete_many(&self, inos: &[u64]) -> io::Result<()> {
+        self.inner.delete_many(inos)
+    }
+
+    fn size(&self, ino: u64) -> io::Result<Option<u64>> {
+        Ok(self.inner.size(ino)?.map(|s| s.saturating_sub(OVERHEAD)))
+    }
+
+    fn mtime(&self, ino: u64) -> io::Result<Option<SystemTime>> {
+        self.inner.mtime(ino)
+    }
+
+    fn io_size(&self) -> Option<u32> {
+        self.inner.io_size()
+    }
+
+    fn is_persistent(&self) -> bool {
+        self.inner.is_persistent()
+    }
+
+    fn ping(&self) -> io::Result<()> {
+        self.inner.ping()
+    }
+
+    fn list_children(&self, ino: u64, offset: u64, count: usize) -> io::Result<Option<Vec<ListedChild>>> {
+        // The sizes listed are of what's stored, nonce and tag included
+        Ok(self.inner.list_children(ino, offset, count)?.map(|page| page.into_iter().map(|c| ListedChild{
+            size: if c.kind == FileType::RegularFile { c.size.saturating_sub(OVERHEAD) } else { c.size },
+            ..c
+        }).collect()))
+    }
+}
+
+fn random_bytes(buf: &mut [u8]) -> io::Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = unsafe { libc::getrandom(buf[filled..].as_mut_ptr() as *mut libc::c_void, buf.len() - filled, 0) };
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        filled += n as usize;
+    }
+    Ok(())
+}
@@ -0,0 +1,628 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use std::fmt::Debug;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use log::warn;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::{Duration, SystemTime};
+
+/// How much of a file goes in each record. Base64 grows it by a third, which still leaves a whole
+/// record well inside the 64KiB a DNS message can carry
+pub const CHUNK_SIZE: usize = 32 * 1024;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+// Label under the zone that --from-dns looks for files beneath
+pub const FILES_LABEL: &str = "files";
+
+const TYPE_SOA: u16 = 6;
+const TYPE_AXFR: u16 = 252;
+const CLASS_IN: u16 = 1;
+const CLASS_ANY: u16 = 255;
+const OPCODE_UPDATE: u16 = 5;
+const RCODE_NXDOMAIN: u8 = 3;
+
+/// Somewhere file contents can be kept under DNS names
+pub trait DnsBackend: Debug + Send {
+    /// The contents stored under `name`, or nothing if there are no records there
+    fn read_record(&self, name: &str) -> io::Result<Vec<u8>>;
+    /// Replaces whatever is stored under `name` with `data`. Empty data removes the records
+    fn write_record(&self, name: &str, data: &[u8]) -> io::Result<()>;
+    /// Removes the records under every one of `names`
+    fn delete_records(&self, names: &[String]) -> io::Result<()> {
+        names.iter().try_for_each(|name| self.write_record(name, &[]))
+    }
+    /// Checks the server answers for the zone
+    fn ping(&self) -> io::Result<()>;
+}
+
+/// Keeps contents in a zone on an authoritative server, reading them back with ordinary queries
+/// and changing them with dynamic updates (RFC 2136). Everything goes over TCP, since records
+/// holding file contents rarely fit in a UDP reply. The server has to accept unsigned updates
+/// from this host
+#[derive(Debug)]
+pub struct UpdateClient {
+    server: SocketAddr,
+    zone: String,
+    rrtype: RecordType,
+    ttl: u32,
+    next_id: AtomicU16,
+}
+
+impl UpdateClient {
+    pub fn new(server: SocketAddr, zone: &str, rrtype: RecordType, ttl: u32) -> UpdateClient {
+        // Start message ids somewhere unpredictable so replies to an earlier run don't match
+        let seed = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.subsec_nanos());
+        UpdateClient{
+            server,
+            zone: zone.trim_end_matches('.').to_string(),
+            rrtype,
+            ttl,
+            next_id: AtomicU16::new(seed as u16),
+        }
+    }
+
+    fn fqdn(&self, name: &str) -> String {
+        format!("{}.{}", name, self.zone)
+    }
+
+    fn header(&self, flags: u16, counts: [u16; 4]) -> Vec<u8> {
+        let mut msg = Vec::with_capacity(512);
+        push_u16(&mut msg, self.next_id.fetch_add(1, Ordering::Relaxed));
+        push_u16(&mut msg, flags);
+        for count in counts {
+            push_u16(&mut msg, count);
+        }
+        msg
+    }
+
+    // Sends one message and waits for its reply, each prefixed with its length as DNS over TCP is
+    fn exchange(&self, msg: &[u8]) -> io::Result<Vec<u8>> {
+        let mut stream = TcpStream::connect_timeout(&self.server, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        let mut out = Vec::with_capacity(msg.len() + 2);
+        push_u16(&mut out, msg.len() as u16);
+        out.extend_from_slice(msg);
+        stream.write_all(&out)?;
+
+        let mut len = [0; 2];
+        stream.read_exact(&mut len)?;
+        let mut reply = vec![0; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut reply)?;
+        if reply.len() < 12 || reply[0..2] != msg[0..2] {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "DNS reply doesn't match the request"));
+        }
+        Ok(reply)
+    }
+}
+
+impl DnsBackend for UpdateClient {
+    fn read_record(&self, name: &str) -> io::Result<Vec<u8>> {
+        // Recursion desired, so pointing at a resolver rather than the primary works too
+        let mut msg = self.header(0x0100, [1, 0, 0, 0]);
+        push_name(&mut msg, &self.fqdn(name))?;
+        push_u16(&mut msg, self.rrtype.code());
+        push_u16(&mut msg, CLASS_IN);
+
+        let reply = self.exchange(&msg)?;
+        let rdatas = answers(&reply, self.rrtype.code())?;
+        if rdatas.is_empty() {
+            return Ok(Vec::new());
+        }
+        let records = rdatas.iter().map(|r| self.rrtype.presentation(r)).collect::<io::Result<Vec<String>>>()?;
+        let records: Vec<&str> = records.iter().map(|r| r.as_str()).collect();
+        self.rrtype.decode(&records).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn write_record(&self, name: &str, data: &[u8]) -> io::Result<()> {
+        let fqdn = self.fqdn(name);
+        let updates = if data.is_empty() { 1 } else { 2 };
+        let mut msg = self.header(OPCODE_UPDATE << 11, [1, 0, updates, 0]);
+
+        // Zone section
+        push_name(&mut msg, &self.zone)?;
+        push_u16(&mut msg, TYPE_SOA);
+        push_u16(&mut msg, CLASS_IN);
+
+        // Drop the whole RRset first, so the new contents don't sit beside the old
+        push_name(&mut msg, &fqdn)?;
+        push_u16(&mut msg, self.rrtype.code());
+        push_u16(&mut msg, CLASS_ANY);
+        push_u32(&mut msg, 0);
+        push_u16(&mut msg, 0);
+
+        if !data.is_empty() {
+            let rdata = self.rrtype.encode(data);
+            push_name(&mut msg, &fqdn)?;
+            push_u16(&mut msg, self.rrtype.code());
+            push_u16(&mut msg, CLASS_IN);
+            push_u32(&mut msg, self.ttl);
+            push_u16(&mut msg, rdata.len() as u16);
+            msg.extend_from_slice(&rdata);
+        }
+        if msg.len() > u16::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Record too large for a DNS message"));
+        }
+
+        let reply = self.exchange(&msg)?;
+        match reply[3] & 0x0f {
+            0 => Ok(()),
+            rcode => Err(io::Error::other(format!("DNS update of {} refused with rcode {}", fqdn, rcode))),
+        }
+    }
+
+    fn delete_records(&self, names: &[String]) -> io::Result<()> {
+        // One update dropping each RRset, split only where a message would outgrow what DNS can carry
+        // What's left of a message once the header and zone section are in
+        let room = u16::MAX as usize - (12 + self.zone.len() + 6);
+        let mut rest = names;
+        while !rest.is_empty() {
+            let mut updates = Vec::new();
+            let mut count = 0;
+            for name in rest {
+                let mut update = Vec::new();
+                push_name(&mut update, &self.fqdn(name))?;
+                push_u16(&mut update, self.rrtype.code());
+                push_u16(&mut update, CLASS_ANY);
+                push_u32(&mut update, 0);
+                push_u16(&mut update, 0);
+                if count > 0 && updates.len() + update.len() > room {
+                    break;
+                }
+                updates.extend_from_slice(&update);
+                count += 1;
+            }
+
+            let mut msg = self.header(OPCODE_UPDATE << 11, [1, 0, count as u16, 0]);
+            push_name(&mut msg, &self.zone)?;
+            push_u16(&mut msg, TYPE_SOA);
+            push_u16(&mut msg, CLASS_IN);
+            msg.extend_from_slice(&updates);
+
+            let reply = self.exchange(&msg)?;
+            if reply[3] & 0x0f != 0 {
+                return Err(io::Error::other(format!("DNS update removing {} records refused with rcode {}", count, reply[3] & 0x0f)));
+            }
+            rest = &rest[count..];
+        }
+        Ok(())
+    }
+
+    fn ping(&self) -> io::Result<()> {
+        let mut msg = self.header(0, [1, 0, 0, 0]);
+        push_name(&mut msg, &self.zone)?;
+        push_u16(&mut msg, TYPE_SOA);
+        push_u16(&mut msg, CLASS_IN);
+        let reply = self.exchange(&msg)?;
+        if answers(&reply, TYPE_SOA)?.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} isn't served by {}", self.zone, self.server)));
+        }
+        Ok(())
+    }
+}
+
+/// Which kind of DNS record a file's contents are kept in
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum RecordType {
+    // One or more character-strings holding base64, concatenated in order
+    Txt,
+    // "priority weight target", where the target is a base64 data: URI or bare base64
+    Uri,
+}
+
+impl FromStr for RecordType {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<RecordType, ()> {
+        match s.to_ascii_uppercase().as_str() {
+            "TXT" => Ok(RecordType::Txt),
+            "URI" => Ok(RecordType::Uri),
+            _ => Err(()),
+        }
+    }
+}
+
+impl RecordType {
+    fn code(&self) -> u16 {
+        match self {
+            RecordType::Txt => 16,
+            RecordType::Uri => 256,
+        }
+    }
+
+    /// The wire form of a single record holding `data`
+    fn encode(&self, data: &[u8]) -> Vec<u8> {
+        let encoded = BASE64.encode(data);
+        let mut rdata = Vec::new();
+        match self {
+            // Character-strings are at most 255 bytes, each prefixed with its length
+            RecordType::Txt => for s in encoded.as_bytes().chunks(255) {
+                rdata.push(s.len() as u8);
+                rdata.extend_from_slice(s);
+            },
+            RecordType::Uri => {
+                push_u16(&mut rdata, 10);
+                push_u16(&mut rdata, 1);
+                rdata.extend_from_slice(b"data:;base64,");
+                rdata.extend_from_slice(encoded.as_bytes());
+            },
+        }
+        rdata
+    }
+
+    // The presentation form of a record's wire rdata, as decode expects it
+    fn presentation(&self, rdata: &[u8]) -> io::Result<String> {
+        let malformed = || io::Error::new(io::ErrorKind::InvalidData, format!("Malformed {:?} record", self));
+        match self {
+            RecordType::Txt => {
+                let mut strings = Vec::new();
+                let mut pos = 0;
+                while pos < rdata.len() {
+                    let len = rdata[pos] as usize;
+                    let s = rdata.get(pos + 1..pos + 1 + len).ok_or_else(malformed)?;
+                    strings.push(quote(s));
+                    pos += 1 + len;
+                }
+                Ok(strings.join(" "))
+            },
+            RecordType::Uri => {
+                if rdata.len() < 4 {
+                    return Err(malformed());
+                }
+                let priority = u16::from_be_bytes([rdata[0], rdata[1]]);
+                let weight = u16::from_be_bytes([rdata[2], rdata[3]]);
+                Ok(format!("{} {} {}", priority, weight, quote(&rdata[4..])))
+            },
+        }
+    }
+
+    /// Decodes a file's contents from the presentation form of its records, in the order they
+    /// make up the file
+    pub fn decode(&self, records: &[&str]) -> Result<Vec<u8>, String> {
+        let mut encoded = String::new();
+        for record in records {
+            match self {
+                RecordType::Txt => {
+                    for s in character_strings(record) {
+                        encoded.push_str(&s);
+                    }
+                },
+                RecordType::Uri => encoded.push_str(&uri_payload(record)?),
+            }
+        }
+        BASE64.decode(encoded.trim()).map_err(|e| format!("Invalid base64 in {:?} record: {}", self, e))
+    }
+}
+
+// Splits TXT rdata into its character-strings. Quoted strings may contain spaces and backslash
+// escapes; anything unquoted is split on whitespace
+fn character_strings(rdata: &str) -> Vec<String> {
+    let mut strings = Vec::new();
+    let mut chars = rdata.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let mut s = String::new();
+        if c == '"' {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => if let Some(escaped) = chars.next() {
+                        s.push(escaped);
+                    },
+                    _ => s.push(c),
+                }
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                s.push(c);
+                chars.next();
+            }
+        }
+        strings.push(s);
+    }
+    strings
+}
+
+// Pulls the base64 payload out of URI rdata ("10 1 \"data:;base64,AAAA\"")
+fn uri_payload(rdata: &str) -> Result<String, String> {
+    let mut fields = character_strings(rdata).into_iter();
+    let (priority, weight, target) = match (fields.next(), fields.next(), fields.next()) {
+        (Some(p), Some(w), Some(t)) => (p, w, t),
+        _ => return Err(format!("Malformed URI record: {}", rdata)),
+    };
+    if priority.parse::<u16>().is_err() || weight.parse::<u16>().is_err() {
+        return Err(format!("Malformed URI record: {}", rdata));
+    }
+    if target.starts_with("data:") {
+        match target.split_once(',') {
+            Some((header, payload)) if header.ends_with(";base64") => Ok(payload.to_string()),
+            _ => Err(format!("URI record target isn't a base64 data: URI: {}", target)),
+        }
+    } else {
+        Ok(target)
+    }
+}
+
+/// Transfers `zone` from `server` and gathers the files published beneath `files.<zone>`: each
+/// TXT record at `<name>.files.<zone>` holds a whole file, and records at
+/// `<index>.<name>.files.<zone>` hold chunks of one, joined in index order. A file with any
+/// record that won't decode is logged and left out
+pub fn load_zone(server: SocketAddr, zone: &str) -> io::Result<BTreeMap<String, Vec<u8>>> {
+    let zone = zone.trim_end_matches('.').to_ascii_lowercase();
+    let suffix = format!(".{}.{}", FILES_LABEL, zone);
+
+    // File name -> chunk index -> contents, or None once a record of it failed to decode
+    let mut parts: BTreeMap<String, Option<BTreeMap<u64, Vec<u8>>>> = BTreeMap::new();
+    for (name, rtype, rdata) in transfer(server, &zone)? {
+        let relative = match name.to_ascii_lowercase().strip_suffix(&suffix) {
+            Some(r) if rtype == RecordType::Txt.code() => r.to_string(),
+            _ => continue,
+        };
+        let (file, index) = match relative.split_once('.') {
+            Some((index, file)) if index.parse::<u64>().is_ok() => (file.to_string(), index.parse().unwrap()),
+            _ => (relative, 0),
+        };
+        let decoded = RecordType::Txt.presentation(&rdata)
+            .map_err(|e| e.to_string())
+            .and_then(|p| RecordType::Txt.decode(&[&p]));
+        match decoded {
+            Ok(data) => if let Some(chunks) = parts.entry(file).or_insert_with(|| Some(BTreeMap::new())) {
+                chunks.insert(index, data);
+            },
+            Err(e) => {
+                warn!("Skipping {}: {}", name, e);
+                parts.insert(file, None);
+            },
+        }
+    }
+
+    Ok(parts.into_iter().filter_map(|(file, chunks)| {
+        Some((file, chunks?.into_values().flatten().collect()))
+    }).collect())
+}
+
+// Every record in `zone`, as (owner name, type, rdata), fetched with a zone transfer (AXFR)
+fn transfer(server: SocketAddr, zone: &str) -> io::Result<Vec<(String, u16, Vec<u8>)>> {
+    let mut msg = Vec::new();
+    push_u16(&mut msg, (SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.subsec_nanos())) as u16);
+    for field in [0, 1, 0, 0, 0] {
+        push_u16(&mut msg, field);
+    }
+    push_name(&mut msg, zone)?;
+    push_u16(&mut msg, TYPE_AXFR);
+    push_u16(&mut msg, CLASS_IN);
+
+    let mut stream = TcpStream::connect_timeout(&server, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut out = Vec::new();
+    push_u16(&mut out, msg.len() as u16);
+    out.extend_from_slice(&msg);
+    stream.write_all(&out)?;
+
+    // The transfer may span many messages; it's over once the zone's SOA comes round again
+    let mut records = Vec::new();
+    let mut soas = 0;
+    while soas < 2 {
+        let mut len = [0; 2];
+        stream.read_exact(&mut len)?;
+        let mut reply = vec![0; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut reply)?;
+        if reply.len() < 12 || reply[0..2] != msg[0..2] {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "DNS reply doesn't match the request"));
+        }
+        match reply[3] & 0x0f {
+            0 => (),
+            rcode => return Err(io::Error::other(format!("Zone transfer refused with rcode {}", rcode))),
+        }
+
+        let mut pos = 12;
+        for _ in 0..read_u16(&reply, 4)? {
+            pos = skip_name(&reply, pos)? + 4;
+        }
+        let answer_count = read_u16(&reply, 6)?;
+        if answer_count == 0 {
+            return Err(truncated());
+        }
+        for _ in 0..answer_count {
+            let (name, next) = read_name(&reply, pos)?;
+            let typ = read_u16(&reply, next)?;
+            let len = read_u16(&reply, next + 8)? as usize;
+            pos = next + 10;
+            let rdata = reply.get(pos..pos + len).ok_or_else(truncated)?;
+            if typ == TYPE_SOA {
+                soas += 1;
+            }
+            records.push((name, typ, rdata.to_vec()));
+            pos += len;
+        }
+    }
+    Ok(records)
+}
+
+fn quote(s: &[u8]) -> String {
+    let mut quoted = String::from("\"");
+    for c in String::from_utf8_lossy(s).chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn push_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn push_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn push_name(buf: &mut Vec<u8>, name: &str) -> io::Result<()> {
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid DNS name {}", name)));
+        }
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+    Ok(())
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Truncated DNS reply")
+}
+
+fn read_u16(buf: &[u8], pos: usize) -> io::Result<u16> {
+    match buf.get(pos..pos + 2) {
+        Some(b) => Ok(u16::from_be_bytes([b[0], b[1]])),
+        None => Err(truncated()),
+    }
+}
+
+// Steps over a possibly compressed name, returning where the data after it starts
+fn skip_name(buf: &[u8], mut pos: usize) -> io::Result<usize> {
+    loop {
+        let len = *buf.get(pos).ok_or_else(truncated)?;
+        if len & 0xc0 == 0xc0 {
+            return Ok(pos + 2);
+        }
+        if len == 0 {
+            return Ok(pos + 1);
+        }
+        pos += 1 + len as usize;
+    }
+}
+
+// Reads a possibly compressed name, returning it along with where the data after it starts
+fn read_name(buf: &[u8], mut pos: usize) -> io::Result<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Each pointer has to go backwards, which bounds how many can be followed
+    let mut limit = pos;
+    loop {
+        let len = *buf.get(pos).ok_or_else(truncated)? as usize;
+        if len & 0xc0 == 0xc0 {
+            let target = (read_u16(buf, pos)? & 0x3fff) as usize;
+            if target >= limit {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Looping name compression in DNS reply"));
+            }
+            end.get_or_insert(pos + 2);
+            limit = target;
+            pos = target;
+            continue;
+        }
+        if len == 0 {
+            return Ok((labels.join("."), end.unwrap_or(pos + 1)));
+        }
+        let label = buf.get(pos + 1..pos + 1 + len).ok_or_else(truncated)?;
+        labels.push(String::from_utf8_lossy(label).to_string());
+        pos += 1 + len;
+    }
+}
+
+// The rdata of every answer of type `rrtype` in a reply. A name that doesn't exist just has none
+fn answers(reply: &[u8], rrtype: u16) -> io::Result<Vec<Vec<u8>>> {
+    match reply[3] & 0x0f {
+        0 => (),
+        RCODE_NXDOMAIN => return Ok(Vec::new()),
+        rcode => return Err(io::Error::other(format!("DNS query failed with rcode {}", rcode))),
+    }
+    let questions = read_u16(reply, 4)?;
+    let answer_count = read_u16(reply, 6)?;
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(reply, pos)? + 4;
+    }
+    let mut rdatas = Vec::new();
+    for _ in 0..answer_count {
+        pos = skip_name(reply, pos)?;
+        let typ = read_u16(reply, pos)?;
+        let len = read_u16(reply, pos + 8)? as usize;
+        pos += 10;
+        let rdata = reply.get(pos..pos + len).ok_or_else(truncated)?;
+        if typ == rrtype {
+            rdatas.push(rdata.to_vec());
+        }
+        pos += len;
+    }
+    Ok(rdatas)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{push_u16, DnsBackend, RecordType, UpdateClient};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    // Answers one query, over TCP, with a URI record for each of `targets`
+    fn serve_uri_records(targets: &[&str]) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let targets: Vec<String> = targets.iter().map(|t| t.to_string()).collect();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut len = [0; 2];
+            stream.read_exact(&mut len).unwrap();
+            let mut query = vec![0; u16::from_be_bytes(len) as usize];
+            stream.read_exact(&mut query).unwrap();
+
+            // Same id and question, then the answers, each named by a pointer to the question
+            let mut reply = query[0..2].to_vec();
+            for field in [0x8180, 1, targets.len() as u16, 0, 0] {
+                push_u16(&mut reply, field);
+            }
+            reply.extend_from_slice(&query[12..]);
+            for target in &targets {
+                for field in [0xc00c, 256, 1, 0, 60, 4 + target.len() as u16, 10, 1] {
+                    push_u16(&mut reply, field);
+                }
+                reply.extend_from_slice(target.as_bytes());
+            }
+            let mut out = Vec::new();
+            push_u16(&mut out, reply.len() as u16);
+            out.extend_from_slice(&reply);
+            stream.write_all(&out).unwrap();
+        });
+        addr
+    }
+
+    #[test]
+    fn uri_records_decode_to_the_file_they_hold() {
+        // "contents in URI records", split over a data: URI and a bare base64 target
+        let addr = serve_uri_records(&["data:;base64,Y29udGVudHMgaW4g", "VVJJIHJlY29yZHM="]);
+        let client = UpdateClient::new(addr, "example.com.", RecordType::Uri, 60);
+        assert_eq!(client.read_record("file").unwrap(), b"contents in URI records");
+    }
+
+    #[test]
+    fn uri_records_that_arent_base64_are_refused() {
+        let addr = serve_uri_records(&["https://example.com/file"]);
+        let client = UpdateClient::new(addr, "example.com", RecordType::Uri, 60);
+        assert_eq!(client.read_record("file").unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn record_types_parse_case_insensitively_and_reject_others() {
+        assert_eq!("uri".parse(), Ok(RecordType::Uri));
+        assert_eq!("TXT".parse(), Ok(RecordType::Txt));
+        assert_eq!("CAA".parse::<RecordType>(), Err(()));
+    }
+}
@@ -0,0 +1,821 @@
+// File contents: open, read, write, fallocate, flush, release, fsync and byte-range locks, along
+// with file handles and moving contents to and from the backend
+
+use super::{TreeFilesystem, FILE_HANDLE_READ_BIT, FILE_HANDLE_WRITE_BIT, FILE_HANDLE_DIR_BIT, FILE_HANDLE_NUM_MASK, FMODE_EXEC};
+use log::{info,error,warn};
+use crate::inode::{Inode, InodeTrait};
+use crate::status::{self, is_status_inode, STATUS_FILE_INO};
+use crate::sparse::SparseData;
+use crate::wal::WalRecord;
+use std::ffi::OsStr;
+use libc::c_int;
+use libc::{EACCES, S_ISGID, ENOENT, EINVAL, EIO, ELOOP, EFBIG, EISDIR, ENOTDIR, EROFS};
+use libc::S_IXGRP;
+use libc::{EAGAIN, EBADF, F_RDLCK, F_WRLCK, F_UNLCK};
+use libc::{ENODEV, ENOSPC, EOPNOTSUPP, FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE};
+use libc::{EMFILE, ENFILE};
+use libc::{W_OK, R_OK, X_OK, O_RDONLY, O_WRONLY, O_RDWR, O_ACCMODE, O_DIRECTORY};
+use std::time::SystemTime;
+use fuser::consts::FOPEN_DIRECT_IO;
+use fuser::{FileType, Request, ReplyData, ReplyEmpty, ReplyOpen, ReplyWrite, ReplyLock};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+impl TreeFilesystem {
+    // Why `pid` can't open another handle right now, if it can't: EMFILE once it has
+    // --max-open-files open, ENFILE once handle numbers have run out
+    pub(super) fn handle_limit_error(&self, pid: u32) -> Option<c_int> {
+        if self.next_file_handle > FILE_HANDLE_NUM_MASK {
+            return Some(ENFILE);
+        }
+        match self.options.max_open_files {
+            Some(max) if self.file_handles.values().filter(|(_, p)| *p == pid).count() >= max => Some(EMFILE),
+            _ => None,
+        }
+    }
+
+    pub(super) fn allocate_file_handle(&mut self, ino: u64, pid: u32, can_read: bool, can_write: bool) -> Result<u64, c_int> {
+        if let Some(e) = self.handle_limit_error(pid) {
+            warn!("allocate_file_handle: pid {} can't open inode {}: {}", pid, ino, if e == EMFILE { "too many handles" } else { "out of handle numbers" });
+            return Err(e);
+        }
+        let fh_num = self.next_file_handle;
+        self.next_file_handle += 1;
+        info!("allocate_file_handle: ino={}, pid={}, fh_num={}", ino, pid, fh_num);
+
+        let mut fh = fh_num;
+        self.file_handles.insert(fh_num, (ino, pid));
+
+        if can_read {
+            fh |= FILE_HANDLE_READ_BIT;
+        }
+        if can_write {
+            fh |= FILE_HANDLE_WRITE_BIT;
+        }
+        Ok(fh)
+    }
+
+    pub(super) fn release_file_handle(&mut self, fh: u64) {
+        let fh_num = fh & FILE_HANDLE_NUM_MASK;
+        self.dir_listings.remove(&fh_num);
+        match self.file_handles.remove(&fh_num) {
+            Some((ino, _)) => info!("release_file_handle: ino={}, fh_num={}", ino, fh_num),
+            None => warn!("release_file_handle: fh_num={} isn't open", fh_num),
+        }
+    }
+
+    // The inode `fh` was opened on, if it's open
+    pub(super) fn file_handle_inode(&self, fh: u64) -> Option<u64> {
+        self.file_handles.get(&(fh & FILE_HANDLE_NUM_MASK)).map(|(ino, _)| *ino)
+    }
+
+    // Whether `fh` can be written through to `ino`: a file handle opened for writing on that
+    // same inode
+    pub(super) fn check_write_handle(&self, ino: u64, fh: u64) -> Result<(), c_int> {
+        if (fh & FILE_HANDLE_DIR_BIT) != 0 || self.file_handle_inode(fh) != Some(ino) {
+            return Err(EBADF);
+        }
+        if (fh & FILE_HANDLE_WRITE_BIT) == 0 {
+            return Err(EACCES);
+        }
+        Ok(())
+    }
+
+    // How many handles are open on `ino`
+    pub(super) fn open_handles(&self, ino: u64) -> u64 {
+        self.file_handles.values().filter(|(i, _)| *i == ino).count() as u64
+    }
+
+    // Fetches a file's contents from the backend if they haven't been yet
+    pub(super) fn ensure_loaded(&mut self, ino: u64) -> Result<(), c_int> {
+        let size = match self.get_inode(ino) {
+            Some(Inode::FileInode(ref a)) if !a.loaded => a.attrs.size,
+            _ => return Ok(()),
+        };
+
+        self.metrics.backend_fetches.fetch_add(1, Ordering::Relaxed);
+        let data = match self.backend.load(ino, 0, size) {
+            Ok(d) => d,
+            Err(e) => {
+                error!("Failed to load inode {} from the backend: {}", ino, e);
+                self.metrics.backend_fetch_failures.fetch_add(1, Ordering::Relaxed);
+                self.metrics.backend_error(format!("loading inode {}: {}", ino, e));
+                return Err(EIO);
+            },
+        };
+        if let Some(Inode::FileInode(ref mut a)) = self.get_inode_mut(ino) {
+            a.data = Arc::new(data.into());
+            a.loaded = true;
+        }
+        self.note_backing_mtime(ino);
+        Ok(())
+    }
+
+    // Picks up a size change made to the backend behind our back (--stat-refresh). The cached
+    // contents no longer match, so they're dropped and fetched again on the next access
+    pub(super) fn refresh_size(&mut self, ino: u64) {
+        // Contents still waiting to reach the backend are newer than whatever size it reports
+        if !matches!(self.get_inode(ino), Some(Inode::FileInode(_))) || self.dirty.contains(&ino) {
+            return;
+        }
+        let size = match self.backend.size(ino) {
+            Ok(Some(s)) => s,
+            Ok(None) => return,
+            Err(e) => {
+                warn!("Failed to get the size of inode {} from the backend, using the cached size: {}", ino, e);
+                return;
+            },
+        };
+
+        if self.get_inode(ino).is_some_and(|a| a.attrs().size != size) {
+            info!("Inode {} changed size in the backend: {} -> {}", ino, self.get_inode(ino).unwrap().attrs().size, size);
+            self.invalidate_data(ino, size, None);
+        }
+    }
+
+    // Picks up any change made to a file's backing copy since we last loaded or stored it
+    // (--watch-backing). A modified file takes its size and mtime from the backend and is fetched
+    // again on the next read; a file whose backing copy was deleted is removed from the tree
+    pub(super) fn revalidate(&mut self, ino: u64) {
+        let (parent, name) = match self.get_inode(ino) {
+            Some(Inode::FileInode(ref a)) => (a.parent, a.name.clone()),
+            _ => return,
+        };
+        // Nothing in the backend is newer than contents it hasn't been given yet
+        if self.dirty.contains(&ino) {
+            return;
+        }
+        let mtime = match self.backend.mtime(ino) {
+            Ok(m) => m,
+            Err(e) => {
+                warn!("Failed to check inode {} in the backend: {}", ino, e);
+                return;
+            },
+        };
+
+        match mtime {
+            None => {
+                // Files that were never stored have no backing copy to lose
+                if self.backing_mtimes.remove(&ino).is_some() {
+                    info!("Inode {} was deleted from the backend", ino);
+                    if let Err(e) = self.do_unlink(parent, OsStr::new(&name)) {
+                        warn!("Failed to remove inode {} after it was deleted from the backend: errno {}", ino, e);
+                    }
+                }
+            },
+            Some(m) if self.backing_mtimes.get(&ino) != Some(&m) => {
+                let size = match self.backend.size(ino) {
+                    Ok(Some(s)) => s,
+                    Ok(None) => 0,
+                    Err(e) => {
+                        warn!("Failed to get the size of inode {} from the backend: {}", ino, e);
+                        return;
+                    },
+                };
+                info!("Inode {} was modified in the backend", ino);
+                self.backing_mtimes.insert(ino, m);
+                self.invalidate_data(ino, size, Some(m));
+            },
+            Some(_) => (),
+        }
+    }
+
+    // Drops a file's cached contents so they're fetched again, taking on the backend's size (and
+    // mtime, if given)
+    pub(super) fn invalidate_data(&mut self, ino: u64, size: u64, mtime: Option<SystemTime>) {
+        let block_size = self.block_size as u64;
+        let now = self.clock.now();
+        if let Some(Inode::FileInode(ref mut a)) = Arc::make_mut(&mut self.tree).get_mut(&ino) {
+            self.metrics.bytes.fetch_add(size, Ordering::Relaxed);
+            self.metrics.bytes.fetch_sub(a.attrs.size, Ordering::Relaxed);
+            a.attrs.size = size;
+            a.attrs.blocks = size.div_ceil(block_size);
+            if let Some(m) = mtime {
+                a.attrs.mtime = m;
+                a.attrs.ctime = now;
+            }
+            a.data = Arc::new(SparseData::default());
+            a.loaded = false;
+        }
+    }
+
+    // Remembers the backend's current mtime for a file we just loaded or stored, so our own
+    // stores aren't mistaken for outside changes
+    pub(super) fn note_backing_mtime(&mut self, ino: u64) {
+        if !self.options.watch_backing {
+            return;
+        }
+        match self.backend.mtime(ino) {
+            Ok(Some(m)) => {
+                self.backing_mtimes.insert(ino, m);
+            },
+            _ => {
+                self.backing_mtimes.remove(&ino);
+            },
+        }
+    }
+
+    // Writes a file's current contents through to the backend
+    pub(super) fn store_data(&mut self, ino: u64) -> Result<(), c_int> {
+        // Nothing to write to a backend that doesn't keep anything, and filling in the holes of a
+        // large sparse file just to throw it away would be expensive
+        if !self.backend.is_persistent() {
+            return Ok(());
+        }
+        let data = match self.get_inode(ino) {
+            Some(Inode::FileInode(ref a)) => a.data.to_vec(),
+            _ => return Ok(()),
+        };
+
+        self.metrics.backend_stores.fetch_add(1, Ordering::Relaxed);
+        if let Err(e) = self.backend.store(ino, &data) {
+            error!("Failed to store inode {} to the backend: {}", ino, e);
+            self.metrics.backend_store_failures.fetch_add(1, Ordering::Relaxed);
+            self.metrics.backend_error(format!("storing inode {}: {}", ino, e));
+            self.dirty.insert(ino);
+            return Err(EIO);
+        }
+        self.dirty.remove(&ino);
+        self.note_backing_mtime(ino);
+        Ok(())
+    }
+
+    // Notes that a file's contents have changed since the backend last took them. They're stored
+    // on flush, fsync or release, so a run of writes reaches the backend once instead of
+    // once per write
+    fn mark_dirty(&mut self, ino: u64) {
+        if self.backend.is_persistent() {
+            self.dirty.insert(ino);
+        }
+    }
+
+    // Stores a file's contents if the backend doesn't have the latest of them
+    pub(super) fn flush_data(&mut self, ino: u64) -> Result<(), c_int> {
+        if !self.dirty.contains(&ino) {
+            return Ok(());
+        }
+        self.store_data(ino)
+    }
+
+    // Stores every file the backend doesn't have the latest contents of, returning how many
+    // it still doesn't
+    pub(super) fn flush_all(&mut self) -> usize {
+        let dirty: Vec<u64> = self.dirty.iter().copied().collect();
+        for ino in dirty {
+            let _ = self.store_data(ino);
+        }
+        self.dirty.len()
+    }
+
+    pub(super) fn do_write(&mut self, inode: u64, offset: u64, data: &[u8]) -> Result<(), c_int> {
+        // Writing into contents we haven't fetched would throw the rest of them away
+        self.ensure_loaded(inode)?;
+        let block_size = self.block_size as u64;
+        let now = self.clock.now();
+        // Work on the inode in place. Cloning it copied the whole file on every write, and the
+        // copy's exact-fit capacity meant every append reallocated as well
+        let ino_data = match self.get_inode_mut(inode) {
+            Some(a) => a,
+            None => return Err(ENOENT),
+        };
+        let old_bytes = TreeFilesystem::file_bytes(ino_data);
+
+        ino_data.write_data(data, offset as usize);
+        let new_length = ino_data.data().len();
+        let allocated = ino_data.data().allocated();
+
+        let mut attrs = ino_data.attrs().clone();
+        // A write changes the contents, not when they were last read
+        attrs.mtime = now;
+        attrs.ctime = now;
+        // An overwrite inside the file leaves both of these as they were, unless it fills in a
+        // hole, which then takes up blocks
+        attrs.size = new_length;
+        attrs.blocks = allocated.div_ceil(block_size);
+        ino_data.set_attrs(attrs);
+        let new_bytes = TreeFilesystem::file_bytes(ino_data);
+
+        self.metrics.bytes.fetch_add(new_bytes, Ordering::Relaxed);
+        self.metrics.bytes.fetch_sub(old_bytes, Ordering::Relaxed);
+        self.mark_dirty(inode);
+        Ok(())
+    }
+
+    pub(super) fn do_fallocate(&mut self, inode: u64, offset: u64, length: u64, mode: i32) -> Result<(), c_int> {
+        self.ensure_loaded(inode)?;
+        let block_size = self.block_size as u64;
+        let now = self.clock.now();
+        let a = match self.get_inode_mut(inode) {
+            Some(Inode::FileInode(ref mut a)) => a,
+            Some(_) => return Err(ENODEV),
+            None => return Err(ENOENT),
+        };
+        let old_bytes = a.attrs.size;
+
+        let data = Arc::make_mut(&mut a.data);
+        if mode & FALLOC_FL_PUNCH_HOLE != 0 {
+            data.punch_hole(offset, length);
+        } else if mode & FALLOC_FL_KEEP_SIZE != 0 {
+            // Only the part of the range inside the file gets allocated
+            let end = offset.saturating_add(length).min(data.len());
+            if end > offset {
+                data.allocate(offset, end - offset);
+            }
+        } else {
+            data.allocate(offset, length);
+        }
+
+        a.attrs.size = a.data.len();
+        a.attrs.blocks = a.data.allocated().div_ceil(block_size);
+        a.attrs.mtime = now;
+        a.attrs.ctime = now;
+        let new_bytes = a.attrs.size;
+
+        self.metrics.bytes.fetch_add(new_bytes, Ordering::Relaxed);
+        self.metrics.bytes.fetch_sub(old_bytes, Ordering::Relaxed);
+        self.mark_dirty(inode);
+        Ok(())
+    }
+
+    // The FOPEN_* flags open and create hand back with a new file handle
+    pub(super) fn open_flags(&self) -> u32 {
+        if self.options.direct_io {
+            FOPEN_DIRECT_IO
+        } else {
+            0
+        }
+    }
+
+    // Whether I/O on `len` bytes at `offset` runs into a byte-range lock held by another owner
+    // on a file with mandatory locking (setgid set, group execute clear)
+    pub(super) fn mandatory_lock_conflict(&self, ino: u64, owner: Option<u64>, offset: u64, len: u64, write: bool) -> bool {
+        let perm = match self.get_inode(ino) {
+            Some(a) => a.attrs().perm as u32,
+            None => return false,
+        };
+        if perm & S_ISGID == 0 || perm & S_IXGRP != 0 || len == 0 {
+            return false;
+        }
+        let typ = if write { F_WRLCK } else { F_RDLCK };
+        self.locks.conflict(ino, owner, offset, offset.saturating_add(len - 1), typ).is_some()
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(super) fn handle_read(&mut self, req: &Request, ino: u64, fh: u64, offset: i64, size: u32, flags: i32, lock_owner: Option<u64>, reply: ReplyData) {
+        let _timer = self.metrics.time("read", ino);
+        info!("read(ino={}, fh={}, offset={}, size={}, flags={})", ino, fh, offset, size, flags);
+        if (fh & FILE_HANDLE_DIR_BIT) != 0 {
+            reply.error(EISDIR);
+            return;
+        }
+        if ino == STATUS_FILE_INO {
+            let status = status::render(self.options.backend, self.backend.as_ref(), &self.metrics);
+            let start = (offset as usize).min(status.len());
+            let end = start.saturating_add(size as usize).min(status.len());
+            reply.data(&status.as_bytes()[start..end]);
+            return;
+        }
+        if self.options.deny_symlinks {
+            if let Some(Inode::LinkInode(_)) = self.get_inode(ino) {
+                reply.error(ELOOP);
+                return;
+            }
+        }
+
+        // Checked against the link target, for a symlink
+        let attrs = match self.get_inode(ino) {
+            Some(a) => match self.resolve_symlink(a) {
+                Ok(t) => *t.unwrap_or(a).attrs(),
+                Err(e) => {
+                    reply.error(e);
+                    return;
+                },
+            },
+            None => {
+                reply.error(ENOENT);
+                return;
+            },
+        };
+
+        // Directories (or links to them) can be opened, but have no data to read
+        if attrs.kind == FileType::Directory {
+            reply.error(EISDIR);
+            return;
+        }
+
+        if self.mandatory_lock_conflict(attrs.ino, lock_owner, offset as u64, size as u64, false) {
+            reply.error(EAGAIN);
+            return;
+        }
+
+        if !self.can_read(&attrs, req.uid(), req.gid(), req.pid()) {
+            reply.error(EACCES);
+            info!("Can't read");
+            return;
+        }
+
+        match self.do_read(ino, offset as u64, size as u64) {
+            Ok(buf) => reply.data(&buf),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    // The bytes of `ino` (the link target, for a symlink) in [offset, offset + size), clipped to
+    // the file's size. Contents that haven't been fetched from the backend yet are fetched first,
+    // so this works on a file that was never opened
+    pub(super) fn do_read(&mut self, ino: u64, offset: u64, size: u64) -> Result<Vec<u8>, c_int> {
+        let data_ino = match self.get_inode(ino) {
+            Some(a) => self.resolve_symlink(a)?.map(|t| t.inode_num()).unwrap_or(ino),
+            None => return Err(ENOENT),
+        };
+        if self.options.watch_backing {
+            self.revalidate(data_ino);
+        }
+        self.ensure_loaded(data_ino)?;
+
+        let a = match self.get_inode(data_ino) {
+            Some(Inode::FileInode(ref a)) => a,
+            Some(Inode::DirectoryInode(_)) => return Err(EISDIR),
+            // A link whose target is gone
+            Some(Inode::LinkInode(_)) | None => return Err(ENOENT),
+        };
+        // Reading at or past the end returns nothing, which is how a direct IO reader finds EOF.
+        // Holes in sparse files read back as zeros, and so does anything between the end of the
+        // data and attrs.size if the two ever disagree
+        let end = offset.saturating_add(size).min(a.attrs.size);
+        let len = end.saturating_sub(offset);
+        let mut buf = a.data.read(offset, len);
+        buf.resize(len as usize, 0);
+        Ok(buf)
+    }
+
+    // The inode open would give a handle to. The kernel resolves links before opening, so getting
+    // one here means the caller asked to open the link itself (e.g. O_NOFOLLOW)
+    pub(super) fn open_target(&self, ino: u64) -> Result<Inode, c_int> {
+        match self.get_inode(ino) {
+            Some(Inode::LinkInode(_)) => Err(ELOOP),
+            Some(inode) => Ok(inode.clone()),
+            None => Err(ENOENT),
+        }
+    }
+
+    pub(super) fn handle_open(&mut self, req: &Request, inode: u64, flags: i32, reply: ReplyOpen) {
+        let _timer = self.metrics.time("open", inode);
+        info!("Open started");
+        // Only the access mode decides what the handle allows. The rest of the flags the kernel
+        // passes along (O_CLOEXEC, O_NONBLOCK, O_LARGEFILE, O_NOCTTY and so on) don't change how
+        // a regular file behaves, so they're ignored; FIFOs and sockets never get here, since the
+        // kernel opens those itself
+        let acc = flags & O_ACCMODE;
+        let mut mode: c_int;
+
+        let (read_allowed, write_allowed, exec_allowed) = match acc {
+            O_RDONLY => {
+                let r = true;
+                mode = R_OK;
+                // This is undefined behavior; so we bail
+                if flags & libc::O_TRUNC != 0 {
+                    reply.error(EACCES);
+                    return;
+                }
+                if flags & FMODE_EXEC != 0{
+                    mode = X_OK;
+                }
+                (r, false, false)
+            },
+            O_WRONLY => {
+                mode = W_OK;
+                (false, true, false)
+            },
+            O_RDWR => {
+                mode = R_OK | W_OK;
+                (true, true, false)
+            },
+            _ => {
+                reply.error(EINVAL);
+                return;
+            }
+        };
+
+        info!("open(inode={}, flags={}, mode={}, acc={})", inode, flags, mode, acc);
+        if let Some(attrs) = self.status_attrs(inode) {
+            if write_allowed {
+                reply.error(if attrs.kind == FileType::Directory { EISDIR } else { EACCES });
+                return;
+            }
+            let mut fh = match self.allocate_file_handle(inode, req.pid(), true, false) {
+                Ok(f) => f,
+                Err(e) => {
+                    reply.error(e);
+                    return;
+                },
+            };
+            if attrs.kind == FileType::Directory {
+                fh |= FILE_HANDLE_DIR_BIT;
+            }
+            // Bypass the page cache, since the reported size of 0 is never the real one
+            reply.opened(fh, FOPEN_DIRECT_IO);
+            return;
+        }
+        if self.is_read_only() && write_allowed {
+            reply.error(EROFS);
+            return;
+        }
+
+        let ino_data = match self.open_target(inode) {
+            Ok(a) => a,
+            Err(e) => {
+                reply.error(e);
+                return;
+            },
+        };
+
+        // Directories can only be opened for reading
+        if write_allowed && ino_data.attrs().kind == FileType::Directory {
+            reply.error(EISDIR);
+            return;
+        }
+
+        if flags & O_DIRECTORY != 0 && ino_data.attrs().kind != FileType::Directory {
+            reply.error(ENOTDIR);
+            return;
+        }
+
+        let mut perms_match = true;
+        if read_allowed {
+            if !self.can_read(ino_data.attrs(), req.uid(), req.gid(), req.pid()) {
+                perms_match = false;
+            }
+        }
+
+        if write_allowed {
+            if !self.can_write(ino_data.attrs(), req.uid(), req.gid(), req.pid()) {
+                perms_match = false;
+            }
+        }
+
+        if exec_allowed {
+            if !self.can_execute(ino_data.attrs(), req.uid(), req.gid(), req.pid()) {
+                perms_match = false;
+            }
+        }
+        
+        if perms_match {
+            // Before truncating, so an open that can't get a handle changes nothing
+            if let Some(e) = self.handle_limit_error(req.pid()) {
+                reply.error(e);
+                return;
+            }
+            if write_allowed && flags & libc::O_TRUNC != 0 && ino_data.attrs().kind == FileType::RegularFile {
+                if let Err(e) = self.truncate_on_open(inode) {
+                    reply.error(e);
+                    return;
+                }
+            }
+            let open_flags = self.open_flags();
+            let mut fh = match self.allocate_file_handle(inode, req.pid(), read_allowed, write_allowed) {
+                Ok(f) => f,
+                Err(e) => {
+                    reply.error(e);
+                    return;
+                },
+            };
+            if ino_data.attrs().kind == FileType::Directory {
+                fh |= FILE_HANDLE_DIR_BIT;
+            }
+            reply.opened(fh, open_flags);
+            return;
+        }
+        reply.error(EACCES);
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(super) fn handle_write(&mut self, _req: &Request, inode: u64, fh: u64, offset: i64, data: &[u8], _write_flags: u32,flags: i32, lock_owner: Option<u64>, reply: ReplyWrite) {
+        let _timer = self.metrics.time("write", inode);
+        info!("write(inode={}, fh={}, offset={}, len(data)={}, flags={})", inode, fh, offset, data.len(), flags);
+        if self.is_read_only() {
+            reply.error(EROFS);
+            return;
+        }
+        if let Err(e) = self.check_write_handle(inode, fh) {
+            reply.error(e);
+            return;
+        }
+
+        match self.get_inode(inode) {
+            // Symlinks are never opened for writing, so a handle can't be pointing at one
+            Some(Inode::LinkInode(_)) => {
+                reply.error(EINVAL);
+                return;
+            },
+            Some(Inode::DirectoryInode(_)) => {
+                reply.error(EISDIR);
+                return;
+            },
+            Some(_) => (),
+            None => {
+                reply.error(ENOENT);
+                return;
+            },
+        };
+
+        if self.exceeds_max_file_size(offset as u64 + data.len() as u64) {
+            reply.error(EFBIG);
+            return;
+        }
+
+        if self.mandatory_lock_conflict(inode, lock_owner, offset as u64, data.len() as u64, true) {
+            reply.error(EAGAIN);
+            return;
+        }
+
+        if let Err(e) = self.log_mutation(&WalRecord::Write{ino: inode, offset: offset as u64, data: data.to_vec()}) {
+            reply.error(e);
+            return;
+        }
+        if let Err(e) = self.do_write(inode, offset as u64, data) {
+            reply.error(e);
+            return;
+        }
+
+        reply.written(data.len() as u32);
+        return;
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(super) fn handle_fallocate(&mut self, _req: &Request<'_>, inode: u64, fh: u64, offset: i64, length: i64, mode: i32, reply: ReplyEmpty) {
+        let _timer = self.metrics.time("fallocate", inode);
+        info!("fallocate(inode={}, fh={}, offset={}, length={}, mode={})", inode, fh, offset, length, mode);
+        if offset < 0 || length <= 0 {
+            reply.error(EINVAL);
+            return;
+        }
+        // Preallocating, with or without growing the file, and punching holes are all we do
+        if mode != 0 && mode != FALLOC_FL_KEEP_SIZE && mode != FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE {
+            reply.error(EOPNOTSUPP);
+            return;
+        }
+        if self.is_read_only() {
+            reply.error(EROFS);
+            return;
+        }
+        // Like write, this needs a handle opened for writing on this very inode
+        if (fh & FILE_HANDLE_DIR_BIT) != 0 || (fh & FILE_HANDLE_WRITE_BIT) == 0 || self.file_handle_inode(fh) != Some(inode) {
+            reply.error(EBADF);
+            return;
+        }
+
+        match self.get_inode(inode) {
+            Some(Inode::FileInode(_)) => (),
+            Some(Inode::DirectoryInode(_)) => {
+                reply.error(EISDIR);
+                return;
+            },
+            Some(Inode::LinkInode(_)) => {
+                reply.error(ENODEV);
+                return;
+            },
+            None => {
+                reply.error(EBADF);
+                return;
+            },
+        };
+
+        let end = match (offset as u64).checked_add(length as u64) {
+            Some(e) => e,
+            None => {
+                reply.error(EFBIG);
+                return;
+            },
+        };
+        if mode == 0 && self.exceeds_max_file_size(end) {
+            reply.error(EFBIG);
+            return;
+        }
+        // Preallocated space is real memory, so don't promise more than the host has free
+        if mode & FALLOC_FL_PUNCH_HOLE == 0 && length as u64 > TreeFilesystem::free_bytes() {
+            reply.error(ENOSPC);
+            return;
+        }
+
+        if let Err(e) = self.log_mutation(&WalRecord::Fallocate{ino: inode, offset: offset as u64, length: length as u64, mode}) {
+            reply.error(e);
+            return;
+        }
+        if let Err(e) = self.do_fallocate(inode, offset as u64, length as u64, mode) {
+            reply.error(e);
+            return;
+        }
+        reply.ok();
+    }
+
+    // Called on every close() of a descriptor, so possibly several times per open; the handle
+    // itself stays open until release
+    pub(super) fn handle_flush(&mut self, _req: &Request<'_>, inode: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        let _timer = self.metrics.time("flush", inode);
+        info!("flush(inode={}, fh={}, lock_owner={})", inode, fh, lock_owner);
+        if self.get_inode(inode).is_none() && !is_status_inode(inode) {
+            reply.error(EBADF);
+            return;
+        }
+        // close() drops the POSIX locks the closing process holds on the file, and reports
+        // whether what it wrote reached the backend
+        self.locks.release_owner(inode, lock_owner);
+        match self.flush_data(inode) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(super) fn handle_release(&mut self, _req: &Request<'_>, inode: u64, fh: u64, flags: i32, lock_owner: Option<u64>, flush: bool, reply: ReplyEmpty) {
+        let _timer = self.metrics.time("release", inode);
+        info!("release(inode={}, fh={}, flags={}, flush={})", inode, fh, flags, flush);
+        self.release_file_handle(fh);
+        if let Some(owner) = lock_owner {
+            self.locks.release_owner(inode, owner);
+        }
+        // A file unlinked (or renamed over) while open goes once its last handle does. Its name
+        // is already gone, so there's nobody left to report a failure to
+        let unlinked = match self.get_inode(inode) {
+            Some(Inode::FileInode(ref a)) => a.attrs.nlink == 0,
+            _ => false,
+        };
+        if unlinked && self.open_handles(inode) == 0 {
+            let _ = self.remove_file(inode);
+        } else {
+            // Writes through a mapping can come after the last flush
+            let _ = self.flush_data(inode);
+        }
+        reply.ok();
+    }
+
+    pub(super) fn handle_fsync(&mut self, _req: &Request, inode: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        let _timer = self.metrics.time("fsync", inode);
+        info!("fsync(inode={}, fh={}, datasync={})", inode, fh, datasync);
+        // The file may have been removed out from under the handle
+        if self.get_inode(inode).is_none() {
+            reply.error(EBADF);
+            return;
+        }
+        if self.options.strict_fsync && !self.has_persistent_backend() {
+            // Don't let applications think their data is durable when it only lives in memory
+            reply.error(EINVAL);
+            return;
+        }
+        if let Err(e) = self.flush_data(inode) {
+            reply.error(e);
+            return;
+        }
+        if let Some(ref wal) = self.wal {
+            if let Err(e) = wal.sync() {
+                error!("Failed to sync the write-ahead log: {}", e);
+                reply.error(EIO);
+                return;
+            }
+        }
+        reply.ok();
+    }
+
+    pub(super) fn handle_fsyncdir(&mut self, _req: &Request, inode: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        let _timer = self.metrics.time("fsyncdir", inode);
+        info!("fsyncdir(inode={}, fh={}, datasync={})", inode, fh, datasync);
+        if self.options.strict_fsync && !self.has_persistent_backend() {
+            reply.error(EINVAL);
+            return;
+        }
+        reply.ok();
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(super) fn handle_getlk(&mut self, _req: &Request, inode: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: i32, pid: u32, reply: ReplyLock) {
+        let _timer = self.metrics.time("getlk", inode);
+        info!("getlk(inode={}, fh={}, lock_owner={}, start={}, end={}, typ={}, pid={})", inode, fh, lock_owner, start, end, typ, pid);
+        match self.locks.conflict(inode, Some(lock_owner), start, end, typ) {
+            Some(l) => reply.locked(l.start, l.end, l.typ, l.pid),
+            None => reply.locked(start, end, F_UNLCK, pid),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(super) fn handle_setlk(&mut self, _req: &Request, inode: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: i32, pid: u32, sleep: bool, reply: ReplyEmpty) {
+        let _timer = self.metrics.time("setlk", inode);
+        info!("setlk(inode={}, fh={}, lock_owner={}, start={}, end={}, typ={}, pid={}, sleep={})", inode, fh, lock_owner, start, end, typ, pid, sleep);
+        if self.get_inode(inode).is_none() {
+            reply.error(ENOENT);
+            return;
+        }
+        if typ != F_UNLCK && typ != F_RDLCK && typ != F_WRLCK {
+            reply.error(EINVAL);
+            return;
+        }
+        // Requests are handled one at a time, so waiting here would stop the holder from ever
+        // unlocking; F_SETLKW fails the same way F_SETLK does
+        if typ != F_UNLCK && self.locks.conflict(inode, Some(lock_owner), start, end, typ).is_some() {
+            reply.error(EAGAIN);
+            return;
+        }
+        self.locks.set(inode, lock_owner, start, end, typ, pid);
+        reply.ok();
+    }
+}
@@ -0,0 +1,426 @@
+// Attributes: getattr, setattr, lookup, access and statfs, along with the generated entries
+// under /.dnsfs
+
+use super::{TreeFilesystem, NAME_MAX};
+use log::info;
+use crate::inode::{Inode, InodeTrait};
+use crate::status::{is_status_inode, STATUS_DIR_INO, STATUS_DIR_NAME, STATUS_FILE_INO, STATUS_FILE_NAME};
+use crate::wal::WalRecord;
+use std::ffi::OsStr;
+use libc::c_int;
+use libc::{EPERM, EACCES, S_ISGID, ENOENT, EINVAL, EFBIG, EISDIR, ENOTDIR, EROFS, ENAMETOOLONG};
+use libc::{W_OK, R_OK, X_OK};
+use std::time::{SystemTime, Duration};
+use fuser::{TimeOrNow, FileAttr, FileType, Request, ReplyAttr, ReplyEntry, ReplyEmpty, ReplyStatfs};
+use std::sync::atomic::Ordering;
+
+impl TreeFilesystem {
+    #[allow(clippy::too_many_arguments)]
+    pub fn do_setattr(&mut self, inode: u64, mode: Option<u16>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, atime: Option<SystemTime>, mtime: Option<SystemTime>, ctime: Option<SystemTime>, crtime: Option<SystemTime>) -> Result<FileAttr, c_int> {
+       if size.is_some() {
+           self.ensure_loaded(inode)?;
+       }
+       let block_size = self.block_size as u64;
+       // Mutate in place so a metadata-only change doesn't copy the whole file body
+       let ino_data = match self.get_inode_mut(inode) {
+           Some(a) => a,
+           None => return Err(ENOENT),
+       };
+       let old_bytes = TreeFilesystem::file_bytes(ino_data);
+
+       let mut attrs = ino_data.attrs().clone();
+       if let Some(m) = mode {
+           // The kernel passes the file type bits along with the permissions
+           attrs.perm = m & 0o7777;
+       }
+       if let Some(u) = uid {
+           attrs.uid = u;
+       }
+       if let Some(g) = gid {
+           attrs.gid = g;
+       }
+       if let Some(s) = size {
+           // Only files have contents to resize
+           if let Inode::DirectoryInode(_) | Inode::LinkInode(_) = ino_data {
+               return Err(if attrs.kind == FileType::Directory { EISDIR } else { EINVAL });
+           }
+           // Keep the data buffer exactly `size` bytes long so later reads and writes line up
+           ino_data.truncate_data(s as usize);
+           attrs.size = s;
+           // Growing leaves a hole, which takes up no blocks
+           attrs.blocks = ino_data.data().allocated().div_ceil(block_size);
+       }
+       if let Some(a) = atime {
+           attrs.atime = a;
+       }
+       if let Some(t) = mtime {
+           attrs.mtime = t;
+       }
+       if let Some(t) = ctime {
+           attrs.ctime = t;
+       }
+       if let Some(t) = crtime {
+           attrs.crtime = t;
+       }
+
+       ino_data.set_attrs(attrs.clone());
+       let new_bytes = TreeFilesystem::file_bytes(ino_data);
+       // This bypasses set_inode, so keep the usage gauge in step by hand
+       self.metrics.bytes.fetch_add(new_bytes, Ordering::Relaxed);
+       self.metrics.bytes.fetch_sub(old_bytes, Ordering::Relaxed);
+       if size.is_some() {
+           self.store_data(inode)?;
+       }
+       Ok(attrs)
+    }
+
+    // Empties a file being opened with O_TRUNC, through the log like any other setattr
+    pub(super) fn truncate_on_open(&mut self, ino: u64) -> Result<FileAttr, c_int> {
+        let now = self.clock.now();
+        let record = WalRecord::SetAttr{
+            ino,
+            mode: None,
+            uid: None,
+            gid: None,
+            size: Some(0),
+            atime: None,
+            mtime: Some(now),
+            ctime: Some(now),
+            crtime: None,
+        };
+        self.log_mutation(&record)?;
+        self.do_setattr(ino, None, None, None, Some(0), None, Some(now), Some(now), None)
+    }
+
+    pub(super) fn touch_ctime(&mut self, ino: u64) {
+        let now = self.clock.now();
+        match self.get_inode_mut(ino) {
+            Some(Inode::FileInode(ref mut a)) => a.attrs.ctime = now,
+            Some(Inode::DirectoryInode(ref mut b)) => b.attrs.ctime = now,
+            Some(Inode::LinkInode(ref mut c)) => c.attrs.ctime = now,
+            None => (),
+        }
+    }
+
+    pub(super) fn negative_entry_attrs(&self) -> FileAttr {
+        FileAttr{
+            ino: 0,
+            size: 0,
+            blocks: 0,
+            atime: SystemTime::UNIX_EPOCH,
+            mtime: SystemTime::UNIX_EPOCH,
+            ctime: SystemTime::UNIX_EPOCH,
+            crtime: SystemTime::UNIX_EPOCH,
+            kind: FileType::RegularFile,
+            perm: 0,
+            nlink: 0,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            flags: 0,
+            blksize: self.block_size,
+        }
+    }
+
+    // Attributes of /.dnsfs and /.dnsfs/backend, which live outside the tree
+    pub(super) fn status_attrs(&self, ino: u64) -> Option<FileAttr> {
+        let kind = match ino {
+            STATUS_DIR_INO => FileType::Directory,
+            STATUS_FILE_INO => FileType::RegularFile,
+            _ => return None,
+        };
+        let now = self.clock.now();
+        Some(FileAttr{
+            ino,
+            // Generated on every read, so there's no size to give up front
+            size: 0,
+            blocks: 0,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            crtime: now,
+            kind,
+            perm: if kind == FileType::Directory { 0o555 } else { 0o444 },
+            nlink: if kind == FileType::Directory { 2 } else { 1 },
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            flags: 0,
+            blksize: self.block_size,
+        })
+    }
+
+    // Whether `name` in `parent` is /.dnsfs or something in it
+    pub(super) fn is_status_name(parent: u64, name: &OsStr) -> bool {
+        (parent == 1 && name == STATUS_DIR_NAME) || parent == STATUS_DIR_INO
+    }
+
+    pub(super) fn handle_getattr(&mut self, __req: &Request, ino: u64, reply: ReplyAttr) {
+        let _timer = self.metrics.time("getattr", ino);
+        info!("getattr(ino={})", ino);
+        if let Some(attrs) = self.status_attrs(ino) {
+            reply.attr(&Duration::new(0, 0), &attrs);
+            return;
+        }
+        match self.do_getattr(ino) {
+            Ok(attrs) => reply.attr(&self.attr_ttl(), &attrs),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    // How long the kernel may cache attributes and entries. Nothing, with --watch-backing, since
+    // the backing directory can change behind the kernel's back
+    pub(super) fn attr_ttl(&self) -> Duration {
+        if self.options.watch_backing { Duration::new(0, 0) } else { Duration::from_secs(1) }
+    }
+
+    // The attributes of `ino`, after picking up changes made to its backing copy
+    // (--watch-backing, --stat-refresh)
+    pub(super) fn do_getattr(&mut self, ino: u64) -> Result<FileAttr, c_int> {
+        if self.options.watch_backing {
+            self.revalidate(ino);
+        }
+        if self.options.stat_refresh {
+            self.refresh_size(ino);
+        }
+        // Symlinks included: the kernel stats a link it found through lookup or readdir before
+        // reading it
+        self.get_inode(ino).map(|a| *a.attrs()).ok_or(ENOENT)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(super) fn handle_setattr(&mut self, req: &Request, inode: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, atime: Option<TimeOrNow>, mtime: Option<TimeOrNow>, ctime: Option<SystemTime>, fh: Option<u64>, crtime: Option<SystemTime>, _chgtime: Option<SystemTime>, _bkuptime: Option<SystemTime>, _flags: Option<u32>, reply: ReplyAttr) {
+       let _timer = self.metrics.time("setattr", inode);
+       info!("setattr(inode={:?}, mode={:?}, uid={:?}, gid={:?}, size={:?}, atime={:?}, mtime={:?}, ctime={:?}, crtime={:?}, fh={:?})", inode, mode, uid, gid, size, atime, mtime, ctime, crtime, fh);
+       if self.is_read_only() {
+           reply.error(EROFS);
+           return;
+       }
+       // The status entries are generated, so there's nothing to change
+       if is_status_inode(inode) {
+           reply.error(EPERM);
+           return;
+       }
+       // Only the attributes are needed here; don't clone the file body just to check them
+       let cur_attrs = match self.get_inode(inode) {
+           Some(a) => *a.attrs(),
+           None => {
+               reply.error(ENOENT);
+               return;
+           },
+       };
+
+       // Check that we can write to the file
+       if !self.can_write(&cur_attrs, req.uid(), req.gid(), req.pid()) {
+           reply.error(EPERM);
+           return;
+       }
+
+       if let Some(s) = size {
+           if cur_attrs.kind == FileType::Directory {
+               reply.error(EISDIR);
+               return;
+           }
+           if s > cur_attrs.size && self.exceeds_max_file_size(s) {
+               reply.error(EFBIG);
+               return;
+           }
+       }
+
+       // Setting the change or birth time outright is reserved for the owner
+       if (ctime.is_some() || crtime.is_some()) && req.uid() != 0 && req.uid() != cur_attrs.uid {
+           reply.error(EPERM);
+           return;
+       }
+
+       // Only root or a member of the file's group may set its setgid bit; anyone else loses it
+       let new_gid = gid.unwrap_or(cur_attrs.gid);
+       let mode = mode.map(|m| {
+           if req.uid() != 0 && !self.in_group(new_gid, req.gid(), req.pid()) { m & !S_ISGID } else { m }
+       });
+
+       // Resolve "now" up front so a replay of the log sets the same times
+       let resolve_time = |t: Option<TimeOrNow>| match t {
+           Some(TimeOrNow::Now) => Some(self.clock.now()),
+           Some(TimeOrNow::SpecificTime(time)) => Some(time),
+           None => None,
+       };
+       let atime = resolve_time(atime);
+       let mtime = resolve_time(mtime);
+       // Any attribute change is a status change, unless the caller says when it was
+       let ctime = Some(ctime.unwrap_or_else(|| self.clock.now()));
+       let record = WalRecord::SetAttr{
+           ino: inode,
+           mode: mode.map(|m| m as u16),
+           uid,
+           gid,
+           size,
+           atime,
+           mtime,
+           ctime,
+           crtime,
+       };
+       if let Err(e) = self.log_mutation(&record) {
+           reply.error(e);
+           return;
+       }
+
+       match self.do_setattr(inode, mode.map(|m| m as u16), uid, gid, size, atime, mtime, ctime, crtime) {
+           Ok(attrs) => reply.attr(&Duration::new(0, 0), &attrs),
+           Err(e) => reply.error(e),
+       }
+    }
+
+    // The entry called `name` in directory `parent`, including "." and "..", which aren't kept in
+    // contents. The root is its own parent
+    pub(super) fn lookup_entry(&self, parent: u64, name: &OsStr) -> Option<&Inode> {
+        if name == "." || name == ".." {
+            let ino = if name == "." || parent == 1 {
+                parent
+            } else {
+                self.get_inode(parent)?.parent()
+            };
+            return self.get_inode(ino);
+        }
+        self.get_child(parent, name)
+    }
+
+    pub(super) fn handle_lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let _timer = self.metrics.time("lookup", parent);
+        info!("lookup(parent={}, name={})", parent, name.to_string_lossy());
+        // No entry can have a name this long, so it isn't worth looking for
+        if name.len() > NAME_MAX {
+            reply.error(ENAMETOOLONG);
+            return;
+        }
+        // A name --name-encoding reject refuses can't exist
+        let name = match self.options.name_encoding.encode(name) {
+            Some(n) => n,
+            None => {
+                reply.error(ENOENT);
+                return;
+            },
+        };
+        let name = OsStr::new(&name);
+        if TreeFilesystem::is_status_name(parent, name) {
+            let ino = if parent == STATUS_DIR_INO { STATUS_FILE_INO } else { STATUS_DIR_INO };
+            match self.status_attrs(ino) {
+                Some(ref attrs) if parent == 1 || name == STATUS_FILE_NAME => reply.entry(&Duration::from_secs(1), attrs, 0),
+                _ => reply.error(ENOENT),
+            }
+            return;
+        }
+
+        let parent_ino = match self.get_inode(parent) {
+            Some(a) => match a {
+                Inode::DirectoryInode(ref c) => Inode::DirectoryInode(c.clone()),
+                // Only directories have children to look up
+                Inode::FileInode(_) | Inode::LinkInode(_) => {
+                    reply.error(ENOTDIR);
+                    return;
+                },
+            },
+            None => {
+                info!("Could not find parent during lookup");
+                reply.error(ENOENT);
+                return;
+            },
+        };
+
+        // Finding a name in a directory takes search permission on it
+        let attrs = parent_ino.attrs();
+        if !self.can_execute(attrs, req.uid(), req.gid(), req.pid()) {
+            reply.error(EACCES);
+            return;
+        }
+
+        if name == "." || name == ".." {
+            match self.lookup_entry(parent_ino.inode_num(), name) {
+                Some(a) => reply.entry(&Duration::from_secs(1), a.attrs(), 0),
+                None => reply.error(ENOENT),
+            }
+            return;
+        }
+
+        if self.options.watch_backing {
+            if let Some(child_ino) = self.get_child(parent, name).map(|c| c.inode_num()) {
+                self.revalidate(child_ino);
+            }
+        }
+
+        // Matches any of a hard linked file's names
+        if let Some(child) = self.get_child(parent, name) {
+            reply.entry(&self.attr_ttl(), child.attrs(), 0);
+            return;
+        }
+        match self.find_listed_child(parent, name) {
+            Ok(Some(child_ino)) => {
+                reply.entry(&self.attr_ttl(), self.get_inode(child_ino).unwrap().attrs(), 0);
+                return;
+            },
+            Ok(None) => (),
+            Err(e) => {
+                reply.error(e);
+                return;
+            },
+        }
+
+        if self.options.negative_ttl_ms > 0 {
+            // An entry with inode 0 tells the kernel to cache the miss for the given ttl
+            let ttl = Duration::from_millis(self.options.negative_ttl_ms);
+            reply.entry(&ttl, &self.negative_entry_attrs(), 0);
+            return;
+        }
+        reply.error(ENOENT);
+    }
+
+    pub(super) fn handle_access(&mut self, req: &Request, inode: u64, mask: i32, reply: ReplyEmpty) {
+        let _timer = self.metrics.time("access", inode);
+        info!("access(inode={}, mask={})", inode, mask);
+        if is_status_inode(inode) {
+            if mask & W_OK != 0 {
+                reply.error(EACCES);
+            } else {
+                reply.ok();
+            }
+            return;
+        }
+        let attrs = match self.get_inode(inode) {
+            Some(a) => *a.attrs(),
+            None => {
+                reply.error(ENOENT);
+                return;
+            },
+        };
+        // F_OK (no bits) only asks whether the inode exists
+        if mask & W_OK != 0 && self.is_read_only() {
+            reply.error(EROFS);
+            return;
+        }
+        let permitted = (mask & R_OK == 0 || self.can_read(&attrs, req.uid(), req.gid(), req.pid())) &&
+            (mask & W_OK == 0 || self.can_write(&attrs, req.uid(), req.gid(), req.pid())) &&
+            (mask & X_OK == 0 || self.can_execute(&attrs, req.uid(), req.gid(), req.pid()));
+        if permitted {
+            reply.ok();
+        } else {
+            reply.error(EACCES);
+        }
+    }
+
+    pub(super) fn handle_statfs(&mut self, _req: &Request, ino: u64, reply: ReplyStatfs) {
+        let _timer = self.metrics.time("statfs", ino);
+        info!("statfs(inode={})", ino);
+        let block_size = self.block_size as u64;
+        let used: u64 = self.tree.values().filter_map(|i| match i {
+            Inode::FileInode(ref a) => Some(a.attrs.blocks),
+            _ => None,
+        }).sum();
+
+        let free = TreeFilesystem::free_bytes() / block_size;
+
+        let files = self.tree.len() as u64;
+        // Inode numbers are never reused, so only those not yet handed out are free
+        let ffree = (u32::MAX as u64).saturating_sub(self.cur_inode);
+        reply.statfs(used + free, free, free, files, ffree, self.block_size, NAME_MAX as u32, self.block_size);
+    }
+}
@@ -0,0 +1,901 @@
+// The filesystem itself. The tree and the helpers everything shares live here, and each group
+// of FUSE operations lives in its own module as `handle_*` methods the Filesystem impl below
+// forwards to
+
+mod data;
+mod metadata;
+mod namespace;
+mod permissions;
+mod xattr;
+#[cfg(test)]
+mod tests;
+
+use log::{info,error,warn};
+use crate::{dns, metrics, snapshot};
+use crate::backend::{self, BackendKind, ListedChild, MemoryBackend, StorageBackend};
+use crate::caps::CapabilityCache;
+use crate::groups::GroupCache;
+use crate::clock::{Clock, SteppingClock, SystemClock};
+use crate::inode::{Inode, DirectoryInode, FileInode, LinkInode, InodeTrait};
+use crate::linkcache::LinkCache;
+use crate::lock::LockTable;
+use crate::metrics::Metrics;
+use crate::options::Options;
+use crate::snapshot::{Snapshot, SnapshotWriter};
+use crate::wal::{Wal, WalRecord};
+use std::path::Path;
+use std::ffi::OsStr;
+use libc::c_int;
+use libc::{EINVAL, EIO, ELOOP, ENOENT};
+use std::time::{SystemTime, Duration};
+use fuser::consts::{FUSE_ATOMIC_O_TRUNC, FUSE_DONT_MASK, FUSE_POSIX_LOCKS};
+use fuser::{TimeOrNow, FileAttr, FileType, Filesystem, Request, ReplyAttr, ReplyData, ReplyEntry, ReplyDirectory, ReplyEmpty, ReplyOpen, ReplyWrite, ReplyCreate, ReplyStatfs, ReplyXattr, ReplyIoctl, ReplyLock, KernelConfig};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+const FILE_HANDLE_READ_BIT: u64 = 1 << 63;
+const FILE_HANDLE_WRITE_BIT: u64 = 1 << 62;
+// Set on handles to directories, which can be listed but not read or written
+const FILE_HANDLE_DIR_BIT: u64 = 1 << 61;
+// The bits of a handle below the flags, which number it
+const FILE_HANDLE_NUM_MASK: u64 = FILE_HANDLE_DIR_BIT - 1;
+
+const FMODE_EXEC: i32 = 0x20;
+
+// Longest chain of symlinks followed before giving up with ELOOP, as with Linux's MAXSYMLINKS
+const MAX_SYMLINK_HOPS: usize = 40;
+
+// Longest name, in bytes, statfs advertises and new entries may have
+const NAME_MAX: usize = 255;
+
+#[derive(Debug)]
+pub struct TreeFilesystem {
+    // Shared with the snapshot writer, which is why changes go through tree_mut and xattrs_mut
+    tree: Arc<BTreeMap<u64, Inode>>,
+    cur_inode: u64,
+    block_size: u32,
+    // The inode each open handle (by number, without the flag bits) was opened on, and the pid
+    // that opened it
+    file_handles: BTreeMap<u64, (u64, u32)>,
+    next_file_handle: u64,
+    mountpoint: String,
+    options: Options,
+    wal: Option<Wal>,
+    metrics: Arc<Metrics>,
+    xattrs: Arc<BTreeMap<u64, BTreeMap<String, Vec<u8>>>>,
+    backend: Box<dyn StorageBackend>,
+    snapshots: Option<SnapshotWriter>,
+    clock: Box<dyn Clock>,
+    // Backend mtime of each file as of our last load or store of it (--watch-backing)
+    backing_mtimes: BTreeMap<u64, SystemTime>,
+    // Files whose latest contents the backend doesn't have: written since they were last
+    // flushed, or refused by the backend. Stored on flush, fsync and release, and at unmount
+    dirty: BTreeSet<u64>,
+    locks: LockTable,
+    link_cache: LinkCache,
+    capabilities: CapabilityCache,
+    groups: GroupCache,
+    // readdir's view of each open directory handle (by number) whose directory the backend
+    // lists, or None once readdir has found the backend doesn't list it
+    dir_listings: BTreeMap<u64, Option<DirListing>>,
+    // Children from backend listings that lookup has brought into the tree. They stay here once
+    // removed, so the listing doesn't bring them back
+    listed: BTreeSet<u64>,
+    // Files removed while a remove-tree is under way, whose contents it deletes from the backend
+    // all at once when it's done
+    deferred_deletes: Option<Vec<u64>>,
+}
+
+// A directory listed by the backend, as one handle is reading it: the tree's own entries as of
+// the first readdir, then the listing, fetched a page at a time. Listed children already in the
+// tree by then are among the tree's entries, so the listing skips them
+#[derive(Debug)]
+struct DirListing {
+    entries: Vec<(u64, FileType, String)>,
+    in_tree: BTreeSet<u64>,
+    // Where in the listing `page` starts
+    page_start: u64,
+    page: Vec<ListedChild>,
+}
+
+impl TreeFilesystem {
+    pub fn new(contents: &BTreeMap<String, String>, options: &Options) -> TreeFilesystem {
+        let tree = Arc::new(BTreeMap::new());
+        let file_handles = BTreeMap::new();
+        let mut fs = TreeFilesystem{
+            tree,
+            cur_inode: 0,
+            block_size: 512,
+            file_handles,
+            next_file_handle: 1,
+            mountpoint: options.mountpoint.clone(),
+            options: options.clone(),
+            wal: None,
+            metrics: Arc::new(Metrics::new(options.slow_op_ms)),
+            xattrs: Arc::new(BTreeMap::new()),
+            backend: Box::new(MemoryBackend),
+            snapshots: None,
+            clock: match options.fixed_clock {
+                Some(start) => Box::new(SteppingClock::new(start)),
+                None => Box::new(SystemClock),
+            },
+            backing_mtimes: BTreeMap::new(),
+            dirty: BTreeSet::new(),
+            locks: LockTable::default(),
+            link_cache: LinkCache::default(),
+            capabilities: CapabilityCache::default(),
+            groups: GroupCache::default(),
+            dir_listings: BTreeMap::new(),
+            listed: BTreeSet::new(),
+            deferred_deletes: None,
+        };
+
+        let root_mode = options.root_mode.unwrap_or(options.default_dir_mode);
+        let _ = fs.create_inode("/".to_string(), FileType::Directory, root_mode, 0, options.uid, options.gid, 0, "".to_string());
+
+        for (name, data) in contents {
+            let _ = fs.create_inode(name.clone(), FileType::RegularFile, options.default_file_mode, data.to_string().len() as u64, options.uid, options.gid, 1, data.to_string());
+        }
+        fs
+    }
+
+    /// Builds the tree from the files published in a zone (see dns::load_zone), all directly
+    /// under the root
+    fn from_dns(zone: &str, server: SocketAddr, options: &Options) -> Result<TreeFilesystem, String> {
+        let files = match dns::load_zone(server, zone) {
+            Ok(f) => f,
+            Err(e) => return Err(format!("Could not transfer {} from {}: {}", zone, server, e)),
+        };
+        let mut fs = TreeFilesystem::new(&BTreeMap::new(), options);
+        let block_size = fs.block_size as u64;
+        for (name, contents) in files {
+            if name.contains('/') {
+                warn!("Skipping {}: not a valid file name", name);
+                continue;
+            }
+            let ino = match fs.create_inode(format!("/{}", name), FileType::RegularFile, options.default_file_mode, 0, options.uid, options.gid, 1, String::new()) {
+                Ok(a) => a.inode_num(),
+                Err(e) => return Err(format!("Could not create {}: errno {}", name, e)),
+            };
+            if let Some(Inode::FileInode(ref mut a)) = Arc::make_mut(&mut fs.tree).get_mut(&ino) {
+                a.attrs.size = contents.len() as u64;
+                a.attrs.blocks = a.attrs.size.div_ceil(block_size);
+                a.data = Arc::new(contents.into());
+                // This bypasses set_inode, so keep the usage gauge in step by hand
+                fs.metrics.bytes.fetch_add(a.attrs.size, Ordering::Relaxed);
+            }
+        }
+        Ok(fs)
+    }
+
+    // The inode a symlink ends up at after following every link in the chain. Ok(None) means
+    // `inode` isn't a symlink; a chain that loops back on itself, or runs longer than the kernel
+    // would follow, fails with ELOOP
+    fn resolve_symlink(&self, inode: &Inode) -> Result<Option<&Inode>, c_int> {
+        let mut target = match inode.target() {
+            Some(a) => a.clone(),
+            None => return Ok(None), // If the current inode's target is None, then this is not a
+                                     // symlink, bro
+        };
+
+        let link = inode.inode_num();
+        if let Some(resolved) = self.link_cache.get(link) {
+            self.metrics.symlink_cache_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(self.get_inode(resolved));
+        }
+
+        let mut cur_ino = link;
+        let mut visited = HashSet::new();
+        visited.insert(link);
+
+        while target != 0 {
+            if !visited.insert(target) || visited.len() > MAX_SYMLINK_HOPS {
+                return Err(ELOOP);
+            }
+            cur_ino = match self.get_inode(target) {
+                Some(a) => {
+                    self.metrics.symlink_steps.fetch_add(1, Ordering::Relaxed);
+                    target = match a.target() {
+                        Some(b) => b.clone(),
+                        None => 0,
+                    };
+                    a.inode_num()
+                },
+                None => break,
+            };
+        }
+
+        self.link_cache.insert(link, cur_ino);
+        Ok(self.get_inode(cur_ino))
+    }
+
+    fn create_inode(&mut self, path: String, ino_type: FileType, mode: u16, size: u64, uid: u32, gid: u32, parent: u64, data: String) -> Result<&Inode, c_int> {
+        let curtime = self.clock.now();
+        self.cur_inode += 1;
+        let attr = FileAttr{
+            ino: self.cur_inode,
+            size,
+            blocks: size.div_ceil(self.block_size as u64),
+            atime: curtime,
+            mtime: curtime,
+            ctime: curtime,
+            crtime: curtime,
+            kind: ino_type,
+            perm: mode,
+            // A directory is also linked from its own "."
+            nlink: if ino_type == FileType::Directory { 2 } else { 1 },
+            uid,
+            gid,
+            rdev: 0,
+            flags: 0,
+            blksize: if ino_type == FileType::RegularFile { self.file_blksize() } else { self.block_size },
+        };
+
+        let mut name = path.clone();
+        if path != "/" {
+            name = Path::new(&path).file_name().unwrap().to_str().unwrap().to_string();
+        }
+        let inode: Inode = match ino_type {
+            // FIFOs and sockets are just names; the kernel handles their I/O itself
+            FileType::RegularFile | FileType::NamedPipe | FileType::Socket => 
+                Inode::FileInode(FileInode{
+                    inode_num: self.cur_inode,
+                    attrs: attr,
+                    path: path.clone(),
+                    data: Arc::new(data.into_bytes().into()),
+                    loaded: true,
+                    num_links: attr.nlink,
+                    parent,
+                    name,
+                    links: Vec::new(),
+                }),
+            FileType::Directory =>
+                Inode::DirectoryInode(DirectoryInode{
+                    inode_num: self.cur_inode,
+                    attrs: attr,
+                    path: path.clone(),
+                    contents: Vec::new(),
+                    num_links: attr.nlink,
+                    parent,
+                    name,
+                }),
+            // Symlinks go through create_symlink, and device nodes aren't supported
+            _ => return Err(EINVAL),
+        };
+
+        // Update the contents of the parent here!
+        if self.cur_inode != 1 {
+            let mut parent_inode = match self.get_inode(parent) {
+                Some(a) => a.clone(),
+                None => return Err(ENOENT),
+            };
+            let mut pcontents = parent_inode.contents().clone();
+            pcontents.push(self.cur_inode);
+            parent_inode.set_contents(pcontents);
+            self.set_inode(parent_inode.inode_num(), parent_inode);
+        }
+
+        self.set_inode(self.cur_inode, inode);
+        Ok(self.get_inode(self.cur_inode).unwrap())
+    }
+
+    fn create_symlink(&mut self, path: String, mode: u16, size: u64, uid: u32, gid: u32, parent: u64, target: u64, target_path: String) -> &Inode {
+        let curtime = self.clock.now();
+        self.cur_inode += 1;
+        let attr = FileAttr{
+            ino: self.cur_inode,
+            size,
+            blocks: size.div_ceil(self.block_size as u64),
+            atime: curtime,
+            mtime: curtime,
+            ctime: curtime,
+            crtime: curtime,
+            kind: FileType::Symlink,
+            perm: mode,
+            nlink: 1,
+            uid,
+            gid,
+            rdev: 0,
+            flags: 0,
+            blksize: self.block_size,
+        };
+
+        let mut name = path.clone();
+        if path != "/" {
+            name = Path::new(&path).file_name().unwrap().to_str().unwrap().to_string();
+        }
+
+        let inode = Inode::LinkInode(LinkInode{
+            inode_num: self.cur_inode,
+            attrs: attr,
+            path: path.clone(),
+            target,
+            num_links: attr.nlink,
+            parent,
+            name,
+            target_path,
+        });
+
+        // Update the contents of the parent here!
+        if self.cur_inode != 1 {
+            let mut parent_inode = self.get_inode(parent).unwrap().clone();
+            let mut pcontents = parent_inode.contents().clone();
+            pcontents.push(self.cur_inode);
+            parent_inode.set_contents(pcontents);
+            self.set_inode(parent_inode.inode_num(), parent_inode);
+        }
+
+        self.set_inode(self.cur_inode, inode);
+        self.get_inode(self.cur_inode).unwrap()
+    }
+
+    fn remove_inode(&mut self, ino: u64) {
+        info!("remove_inode(ino={})",ino);
+        self.locks.remove(ino);
+        self.dirty.remove(&ino);
+        self.link_cache.clear();
+        if let Some(old) = self.tree_mut().remove(&ino) {
+            self.metrics.bytes.fetch_sub(TreeFilesystem::file_bytes(&old), Ordering::Relaxed);
+        }
+        self.metrics.inodes.store(self.tree.len() as u64, Ordering::Relaxed);
+    }
+
+    fn set_inode(&mut self, ino: u64, inode_data: Inode) {
+        // Every change to the tree funnels through here, so keep the usage gauges current
+        self.metrics.bytes.fetch_add(TreeFilesystem::file_bytes(&inode_data), Ordering::Relaxed);
+        if let Some(old) = self.tree_mut().insert(ino, inode_data) {
+            self.metrics.bytes.fetch_sub(TreeFilesystem::file_bytes(&old), Ordering::Relaxed);
+        }
+        self.metrics.inodes.store(self.tree.len() as u64, Ordering::Relaxed);
+    }
+
+    fn file_bytes(inode: &Inode) -> u64 {
+        match inode {
+            Inode::FileInode(ref a) => a.attrs.size,
+            _ => 0,
+        }
+    }
+
+    fn get_inode(&self, ino: u64) -> Option<&Inode> {
+        self.tree.get(&ino)
+    }
+
+    // For changing an inode in place instead of cloning it and handing it back to set_inode.
+    // That skips the usage gauge, so callers that change a file's size adjust metrics.bytes
+    // themselves
+    fn get_inode_mut(&mut self, ino: u64) -> Option<&mut Inode> {
+        self.tree_mut().get_mut(&ino)
+    }
+
+    // The tree for changing. If the snapshot writer is reading it right now, this is where it
+    // gets copied
+    fn tree_mut(&mut self) -> &mut BTreeMap<u64, Inode> {
+        Arc::make_mut(&mut self.tree)
+    }
+
+    fn xattrs_mut(&mut self) -> &mut BTreeMap<u64, BTreeMap<String, Vec<u8>>> {
+        Arc::make_mut(&mut self.xattrs)
+    }
+
+    // Every inode under (and including) `root`, depth first
+    fn walk(&self, root: u64) -> Walk<'_> {
+        Walk{
+            fs: self,
+            stack: vec![root],
+        }
+    }
+
+    // Metadata (no contents) for everything under `root`, as exported by DNSFS_IOC_EXPORT_JSON
+    fn export_json(&self, root: u64) -> Value {
+        let entries: Vec<Value> = self.walk(root).map(|inode| {
+            let attrs = inode.attrs();
+            let mut entry = json!({
+                "ino": inode.inode_num(),
+                "path": inode.path(),
+                "kind": kind_name(attrs.kind),
+                "size": attrs.size,
+                "mode": attrs.perm & 0o7777,
+                "uid": attrs.uid,
+                "gid": attrs.gid,
+                "nlink": attrs.nlink,
+            });
+            if let Inode::LinkInode(ref c) = inode {
+                entry["target"] = json!(c.target_path);
+            }
+            entry
+        }).collect();
+        Value::Array(entries)
+    }
+
+    // The entry called `name` in directory `parent`
+    fn get_child(&self, parent: u64, name: &OsStr) -> Option<&Inode> {
+        let contents = match self.get_inode(parent) {
+            Some(Inode::DirectoryInode(ref b)) => &b.contents,
+            _ => return None,
+        };
+        let name = name.to_string_lossy();
+        contents.iter().filter_map(|ino| self.get_inode(*ino)).find(|c| c.names_in(parent).iter().any(|n| **n == name))
+    }
+
+    fn get_inode_by_path(&self, path: String) -> Option<&Inode> {
+        for (_ino_num, ino_data) in self.tree.iter() {
+            if path == *ino_data.path() {
+                return Some(ino_data);
+            }
+        }
+        return None;
+    }
+
+    fn log_mutation(&mut self, record: &WalRecord) -> Result<(), c_int> {
+        // Any change could retarget or break a cached symlink chain
+        self.link_cache.clear();
+
+        if let Some(ref mut wal) = self.wal {
+            if let Err(e) = wal.append(record) {
+                // If it can't be logged it can't be recovered, so don't apply it
+                error!("Failed to append to the write-ahead log: {}", e);
+                return Err(EIO);
+            }
+        }
+        Ok(())
+    }
+
+    // Replays the write-ahead log at `path` over the seeded tree, then keeps appending to it.
+    // The first `skip` records are already part of the tree (from a snapshot) and aren't replayed
+    fn open_wal(&mut self, path: &str, skip: u64) -> std::io::Result<()> {
+        let records = Wal::read_records(path)?;
+        info!("Replaying {} write-ahead log records from {}", records.len().saturating_sub(skip as usize), path);
+        for record in records.iter().skip(skip as usize) {
+            if let Err(e) = self.apply(record) {
+                warn!("Failed to replay {:?}: errno {}", record, e);
+            }
+        }
+        // Nothing replayed will be closed, so store it now
+        if self.flush_all() > 0 {
+            warn!("{} replayed files didn't reach the backend, retrying at unmount", self.dirty.len());
+        }
+        self.wal = Some(Wal::open(path, records.len() as u64)?);
+        Ok(())
+    }
+
+    // Lets the periodic snapshot writer see the tree as this operation left it
+    fn publish_snapshot(&self) {
+        if let Some(ref writer) = self.snapshots {
+            let wal_records = self.wal.as_ref().map_or(0, |w| w.records());
+            writer.publish(&self.tree, &self.xattrs, self.cur_inode, wal_records);
+        }
+    }
+
+    // Writes the whole tree to the snapshot at `path`, keeping --keep-snapshots older generations,
+    // then empties the write-ahead log, which the snapshot now covers.
+    // The snapshot claims none of the log's records, so a crash before the log is emptied replays
+    // them again rather than skipping ones appended after the next mount
+    fn save_to(&mut self, path: &str) -> std::io::Result<()> {
+        let snapshot = Snapshot::capture(&self.tree, self.cur_inode, 0, &self.xattrs);
+        snapshot.write_generation(path, self.options.keep_snapshots)?;
+        if let Some(ref mut wal) = self.wal {
+            wal.truncate()?;
+            info!("Emptied the write-ahead log");
+        }
+        Ok(())
+    }
+
+    // Replaces the tree with the snapshot at `path`, returning how many write-ahead log records
+    // it already includes
+    fn load_from(&mut self, path: &str) -> std::io::Result<u64> {
+        let snapshot = Snapshot::read(path)?;
+        let cur_inode = snapshot.cur_inode();
+        let wal_records = snapshot.wal_records();
+        let (tree, xattrs) = snapshot.into_tree()?;
+        info!("Loaded {} inodes from snapshot {}", tree.len(), path);
+
+        self.metrics.bytes.store(tree.values().map(TreeFilesystem::file_bytes).sum(), Ordering::Relaxed);
+        self.metrics.inodes.store(tree.len() as u64, Ordering::Relaxed);
+        self.tree = Arc::new(tree);
+        self.xattrs = Arc::new(xattrs);
+        self.cur_inode = cur_inode;
+        Ok(wal_records)
+    }
+
+    fn apply(&mut self, record: &WalRecord) -> Result<(), c_int> {
+        match record {
+            WalRecord::Create { ino, parent, name, mode, uid, gid } => {
+                // Hand out the same inode number as the original call did
+                self.cur_inode = ino - 1;
+                self.do_create(*parent, OsStr::new(name), *mode, *uid, *gid).map(|_| ())
+            },
+            WalRecord::Mkdir { ino, parent, name, mode, uid, gid } => {
+                self.cur_inode = ino - 1;
+                self.do_mkdir(*parent, OsStr::new(name), *mode, *uid, *gid).map(|_| ())
+            },
+            WalRecord::Symlink { ino, parent, name, target, uid, gid } => {
+                self.cur_inode = ino - 1;
+                self.do_symlink(*parent, OsStr::new(name), Path::new(target), *uid, *gid).map(|_| ())
+            },
+            WalRecord::Write { ino, offset, data } => self.do_write(*ino, *offset, data),
+            WalRecord::Fallocate { ino, offset, length, mode } => self.do_fallocate(*ino, *offset, *length, *mode),
+            WalRecord::Link { ino, new_parent, new_name } => self.do_link(*ino, *new_parent, OsStr::new(new_name)).map(|_| ()),
+            WalRecord::Unlink { parent, name } => self.do_unlink(*parent, OsStr::new(name)),
+            WalRecord::Rmdir { parent, name } => self.do_rmdir(*parent, OsStr::new(name)),
+            WalRecord::RemoveTree { ino } => self.do_remove_tree(*ino).map(|_| ()),
+            WalRecord::Rename { parent, name, new_parent, new_name } =>
+                self.do_rename(*parent, OsStr::new(name), *new_parent, OsStr::new(new_name)),
+            WalRecord::Exchange { parent, name, new_parent, new_name } =>
+                self.do_exchange(*parent, OsStr::new(name), *new_parent, OsStr::new(new_name)),
+            WalRecord::SetAttr { ino, mode, uid, gid, size, atime, mtime, ctime, crtime } =>
+                self.do_setattr(*ino, *mode, *uid, *gid, *size, *atime, *mtime, *ctime, *crtime).map(|_| ()),
+        }
+    }
+
+    // The blksize stat advertises for regular files: whatever transfer size the backend prefers,
+    // or the filesystem's block size if it has no preference
+    fn file_blksize(&self) -> u32 {
+        self.backend.io_size().unwrap_or(self.block_size)
+    }
+
+    fn exceeds_max_file_size(&self, size: u64) -> bool {
+        match self.options.max_file_size {
+            Some(max) => size > max,
+            None => false,
+        }
+    }
+
+    // Everything lives in memory, so the free space is whatever RAM the host has left
+    fn free_bytes() -> u64 {
+        let mut info: libc::sysinfo = unsafe { std::mem::zeroed() };
+        if unsafe { libc::sysinfo(&mut info) } == 0 {
+            info.freeram as u64 * info.mem_unit as u64
+        } else {
+            0
+        }
+    }
+
+    // Whether `ino` is the directory `dir` or somewhere beneath it
+    fn is_within(&self, ino: u64, dir: u64) -> bool {
+        let mut cur = ino;
+        // Bounded by the tree's size in case a parent pointer ever loops
+        for _ in 0..=self.tree.len() {
+            if cur == dir {
+                return true;
+            }
+            cur = match self.get_inode(cur) {
+                Some(a) if cur != 1 => a.parent(),
+                _ => return false,
+            };
+        }
+        false
+    }
+
+    // Whether `path` is one of the --protect paths or lives underneath one
+    fn is_protected(&self, path: &str) -> bool {
+        self.options.protect.iter().any(|p| {
+            let p = p.trim_end_matches('/');
+            path == p || path.starts_with(&format!("{}/", p))
+        })
+    }
+
+    // A --mount-snapshot mount shows a point-in-time tree that must not change
+    fn is_read_only(&self) -> bool {
+        self.options.mount_snapshot.is_some()
+    }
+
+    fn has_persistent_backend(&self) -> bool {
+        self.wal.is_some() || self.backend.is_persistent()
+    }
+}
+
+struct Walk<'a> {
+    fs: &'a TreeFilesystem,
+    stack: Vec<u64>,
+}
+
+impl<'a> Iterator for Walk<'a> {
+    type Item = &'a Inode;
+
+    fn next(&mut self) -> Option<&'a Inode> {
+        while let Some(ino) = self.stack.pop() {
+            if let Some(inode) = self.fs.get_inode(ino) {
+                if let Inode::DirectoryInode(ref d) = inode {
+                    // Reversed so children come out in the order they're listed
+                    self.stack.extend(d.contents.iter().rev());
+                }
+                return Some(inode);
+            }
+        }
+        None
+    }
+}
+
+fn kind_name(kind: FileType) -> &'static str {
+    match kind {
+        FileType::RegularFile => "file",
+        FileType::Directory => "directory",
+        FileType::Symlink => "symlink",
+        FileType::NamedPipe => "fifo",
+        FileType::Socket => "socket",
+        FileType::CharDevice => "char_device",
+        FileType::BlockDevice => "block_device",
+    }
+}
+impl Filesystem for TreeFilesystem {
+    fn init(&mut self, _req: &Request, config: &mut KernelConfig) -> Result<(), c_int> {
+        // Without this the kernel keeps byte-range locks to itself and never calls getlk/setlk
+        if let Err(e) = config.add_capabilities(FUSE_POSIX_LOCKS) {
+            warn!("Kernel doesn't support POSIX locks over FUSE (unsupported capabilities {:#x})", e);
+        }
+        // Otherwise the kernel truncates with a setattr of its own and drops O_TRUNC before open
+        // or create sees it
+        if let Err(e) = config.add_capabilities(FUSE_ATOMIC_O_TRUNC) {
+            warn!("Kernel doesn't pass O_TRUNC along over FUSE (unsupported capabilities {:#x})", e);
+        }
+        // The kernel otherwise applies the umask itself, before a default ACL can take its place
+        if self.options.acl {
+            if let Err(e) = config.add_capabilities(FUSE_DONT_MASK) {
+                warn!("Kernel always applies the umask over FUSE, so default ACLs can't replace it (unsupported capabilities {:#x})", e);
+            }
+        }
+        Ok(())
+    }
+
+    fn destroy(&mut self) {
+        info!("destroy()");
+        let bytes: u64 = self.tree.values().map(TreeFilesystem::file_bytes).sum();
+        info!("Unmounting {}: {} inodes, {} bytes", self.mountpoint, self.tree.len(), bytes);
+
+        // Last chance for contents the backend doesn't have yet, or refused earlier
+        if !self.dirty.is_empty() {
+            info!("Storing {} files the backend doesn't have the latest contents of", self.dirty.len());
+            if self.flush_all() > 0 {
+                error!("{} files never reached the backend and are lost: {:?}", self.dirty.len(), self.dirty);
+            }
+        }
+
+        // A periodic snapshot still being written would land on top of the final one
+        if let Some(writer) = self.snapshots.take() {
+            writer.finish();
+        }
+        if let Some(path) = self.options.snapshot.clone() {
+            match self.save_to(&path) {
+                Ok(()) => info!("Wrote snapshot to {} on unmount", path),
+                Err(e) => error!("Failed to write snapshot to {} on unmount: {}", path, e),
+            }
+        }
+    }
+
+    fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
+        self.handle_getattr(req, ino, reply);
+        self.publish_snapshot();
+    }
+
+    fn readdir(&mut self, req: &Request, ino: u64, fh: u64, offset: i64, reply: ReplyDirectory) {
+        self.handle_readdir(req, ino, fh, offset, reply);
+        self.publish_snapshot();
+    }
+
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        self.handle_lookup(req, parent, name, reply);
+        self.publish_snapshot();
+    }
+
+    fn read(&mut self, req: &Request, ino: u64, fh: u64, offset: i64, size: u32, flags: i32, lock_owner: Option<u64>, reply: ReplyData) {
+        self.handle_read(req, ino, fh, offset, size, flags, lock_owner, reply);
+        self.publish_snapshot();
+    }
+
+    fn open(&mut self, req: &Request, inode: u64, flags: i32, reply: ReplyOpen) {
+        self.handle_open(req, inode, flags, reply);
+        self.publish_snapshot();
+    }
+
+    fn write(&mut self, req: &Request, inode: u64, fh: u64, offset: i64, data: &[u8], write_flags: u32, flags: i32, lock_owner: Option<u64>, reply: ReplyWrite) {
+        self.handle_write(req, inode, fh, offset, data, write_flags, flags, lock_owner, reply);
+        self.publish_snapshot();
+    }
+
+    fn fsync(&mut self, req: &Request, inode: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        self.handle_fsync(req, inode, fh, datasync, reply);
+        self.publish_snapshot();
+    }
+
+    fn fsyncdir(&mut self, req: &Request, inode: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        self.handle_fsyncdir(req, inode, fh, datasync, reply);
+        self.publish_snapshot();
+    }
+
+    fn flush(&mut self, req: &Request<'_>, inode: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        self.handle_flush(req, inode, fh, lock_owner, reply);
+        self.publish_snapshot();
+    }
+
+    fn release(&mut self, req: &Request<'_>, inode: u64, fh: u64, flags: i32, lock_owner: Option<u64>, flush: bool, reply: ReplyEmpty) {
+        self.handle_release(req, inode, fh, flags, lock_owner, flush, reply);
+        self.publish_snapshot();
+    }
+
+    fn getlk(&mut self, req: &Request, inode: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: i32, pid: u32, reply: ReplyLock) {
+        self.handle_getlk(req, inode, fh, lock_owner, start, end, typ, pid, reply);
+        self.publish_snapshot();
+    }
+
+    fn setlk(&mut self, req: &Request, inode: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: i32, pid: u32, sleep: bool, reply: ReplyEmpty) {
+        self.handle_setlk(req, inode, fh, lock_owner, start, end, typ, pid, sleep, reply);
+        self.publish_snapshot();
+    }
+
+    fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.handle_unlink(req, parent, name, reply);
+        self.publish_snapshot();
+    }
+
+    fn create(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, umask: u32, flags: i32, reply: ReplyCreate) {
+        self.handle_create(req, parent, name, mode, umask, flags, reply);
+        self.publish_snapshot();
+    }
+
+    fn mknod(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, umask: u32, rdev: u32, reply: ReplyEntry) {
+        self.handle_mknod(req, parent, name, mode, umask, rdev, reply);
+        self.publish_snapshot();
+    }
+
+    fn rename(&mut self, req: &Request, parent: u64, name: &OsStr, new_parent: u64, new_name: &OsStr, flags: u32, reply: ReplyEmpty) {
+        self.handle_rename(req, parent, name, new_parent, new_name, flags, reply);
+        self.publish_snapshot();
+    }
+
+    fn setattr(&mut self, req: &Request, inode: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, atime: Option<TimeOrNow>, mtime: Option<TimeOrNow>, ctime: Option<SystemTime>, fh: Option<u64>, crtime: Option<SystemTime>, chgtime: Option<SystemTime>, bkuptime: Option<SystemTime>, flags: Option<u32>, reply: ReplyAttr) {
+        self.handle_setattr(req, inode, mode, uid, gid, size, atime, mtime, ctime, fh, crtime, chgtime, bkuptime, flags, reply);
+        self.publish_snapshot();
+    }
+
+    fn symlink(&mut self, req: &Request, parent: u64, link_name: &OsStr, target: &Path, reply: ReplyEntry) {
+        self.handle_symlink(req, parent, link_name, target, reply);
+        self.publish_snapshot();
+    }
+
+    fn getxattr(&mut self, req: &Request, inode: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        self.handle_getxattr(req, inode, name, size, reply);
+        self.publish_snapshot();
+    }
+
+    fn listxattr(&mut self, req: &Request, inode: u64, size: u32, reply: ReplyXattr) {
+        self.handle_listxattr(req, inode, size, reply);
+        self.publish_snapshot();
+    }
+
+    fn setxattr(&mut self, req: &Request, inode: u64, name: &OsStr, value: &[u8], flags: i32, position: u32, reply: ReplyEmpty) {
+        self.handle_setxattr(req, inode, name, value, flags, position, reply);
+        self.publish_snapshot();
+    }
+
+    fn removexattr(&mut self, req: &Request, inode: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.handle_removexattr(req, inode, name, reply);
+        self.publish_snapshot();
+    }
+
+    fn ioctl(&mut self, req: &Request, inode: u64, fh: u64, flags: u32, cmd: u32, in_data: &[u8], out_size: u32, reply: ReplyIoctl) {
+        self.handle_ioctl(req, inode, fh, flags, cmd, in_data, out_size, reply);
+        self.publish_snapshot();
+    }
+
+    fn readlink(&mut self, req: &Request, inode: u64, reply: ReplyData) {
+        self.handle_readlink(req, inode, reply);
+        self.publish_snapshot();
+    }
+
+    fn link(&mut self, req: &Request, inode: u64, new_parent: u64, new_name: &OsStr, reply: ReplyEntry) {
+        self.handle_link(req, inode, new_parent, new_name, reply);
+        self.publish_snapshot();
+    }
+
+    fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.handle_rmdir(req, parent, name, reply);
+        self.publish_snapshot();
+    }
+
+    fn mkdir(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, umask: u32, reply: ReplyEntry) {
+        self.handle_mkdir(req, parent, name, mode, umask, reply);
+        self.publish_snapshot();
+    }
+
+    fn access(&mut self, req: &Request, inode: u64, mask: i32, reply: ReplyEmpty) {
+        self.handle_access(req, inode, mask, reply);
+        self.publish_snapshot();
+    }
+
+    fn opendir(&mut self, req: &Request, inode: u64, flags: i32, reply: ReplyOpen) {
+        self.handle_opendir(req, inode, flags, reply);
+        self.publish_snapshot();
+    }
+
+    fn releasedir(&mut self, req: &Request<'_>, inode: u64, fh: u64, flags: i32, reply: ReplyEmpty) {
+        self.handle_releasedir(req, inode, fh, flags, reply);
+        self.publish_snapshot();
+    }
+
+    fn statfs(&mut self, req: &Request, ino: u64, reply: ReplyStatfs) {
+        self.handle_statfs(req, ino, reply);
+        self.publish_snapshot();
+    }
+
+    fn fallocate(&mut self, req: &Request<'_>, inode: u64, fh: u64, offset: i64, length: i64, mode: i32, reply: ReplyEmpty) {
+        self.handle_fallocate(req, inode, fh, offset, length, mode, reply);
+        self.publish_snapshot();
+    }
+}
+
+// Builds a tree for one mount as its options describe: backend, snapshots, write-ahead log and
+// metrics
+pub fn build_filesystem(data: &BTreeMap<String, String>, options: &Options) -> Result<TreeFilesystem, String> {
+    let mut fs = if options.from_dns {
+        match (options.dns_server, options.dns_zone.as_ref()) {
+            (Some(server), Some(zone)) => TreeFilesystem::from_dns(zone, server, options)?,
+            _ => return Err("--from-dns requires --dns-server and --dns-zone".to_string()),
+        }
+    } else {
+        TreeFilesystem::new(data, options)
+    };
+    fs.backend = backend::open(options)?;
+
+    if options.watch_backing && options.backend != BackendKind::Dir {
+        return Err("--watch-backing requires --backend dir".to_string());
+    }
+
+    if let Some(ref path) = options.mount_snapshot {
+        if options.wal.is_some() || options.snapshot.is_some() {
+            return Err("--mount-snapshot can't be combined with --wal or --snapshot".to_string());
+        }
+        if let Err(e) = fs.load_from(path) {
+            return Err(format!("Could not load snapshot {}: {}", path, e));
+        }
+    }
+
+    let mut wal_skip = 0;
+    if let Some(ref path) = options.snapshot {
+        if let Some(ref name) = options.restore_snapshot {
+            // Replaying the log would just redo the changes being rolled back
+            if options.wal.is_some() {
+                return Err("--restore-snapshot can't be combined with --wal".to_string());
+            }
+            let generation = snapshot::generation_path(path, name);
+            if let Err(e) = fs.load_from(&generation.to_string_lossy()) {
+                return Err(format!("Could not load snapshot {}: {}", generation.display(), e));
+            }
+        } else if Path::new(path).exists() {
+            match fs.load_from(path) {
+                Ok(n) => wal_skip = n,
+                Err(e) => return Err(format!("Could not load snapshot {}: {}", path, e)),
+            }
+        }
+        if let Some(secs) = options.snapshot_interval {
+            fs.snapshots = Some(SnapshotWriter::spawn(path.clone(), Duration::from_secs(secs), options.keep_snapshots));
+        }
+    } else if options.snapshot_interval.is_some() || options.keep_snapshots > 0 || options.restore_snapshot.is_some() {
+        return Err("--snapshot-interval, --keep-snapshots and --restore-snapshot require --snapshot".to_string());
+    }
+
+    if let Some(ref path) = options.wal {
+        if let Err(e) = fs.open_wal(path, wal_skip) {
+            return Err(format!("Could not open write-ahead log {}: {}", path, e));
+        }
+    }
+
+    // The seed tree and anything loaded from a snapshot were stamped before the backend was known
+    let blksize = fs.file_blksize();
+    for inode in fs.tree_mut().values_mut() {
+        if let Inode::FileInode(ref mut a) = inode {
+            if a.attrs.kind == FileType::RegularFile {
+                a.attrs.blksize = blksize;
+            }
+        }
+    }
+
+    if let Some(addr) = options.metrics_addr {
+        if let Err(e) = metrics::serve(addr, fs.metrics.clone()) {
+            return Err(format!("Could not serve metrics on {}: {}", addr, e));
+        }
+    }
+    Ok(fs)
+}
@@ -0,0 +1,1788 @@
+// The directory tree: create, mknod, mkdir, symlink, link, unlink, rmdir, rename, opendir,
+// readdir, releasedir, readlink and the tree-wide ioctls
+
+use super::{DirListing, TreeFilesystem, FILE_HANDLE_DIR_BIT, FILE_HANDLE_NUM_MASK, NAME_MAX};
+use log::{info,error};
+use crate::acl::{ACL_ACCESS_XATTR, ACL_DEFAULT_XATTR};
+use crate::backend::{ListedChild, LIST_PAGE_SIZE};
+use crate::inode::{DirectoryInode, FileInode, Inode, InodeTrait};
+use crate::ioctl::{DNSFS_IOC_EXPORT_JSON, DNSFS_IOC_REMOVE_TREE};
+use crate::status::{STATUS_DIR_INO, STATUS_DIR_NAME, STATUS_FILE_INO, STATUS_FILE_NAME};
+use crate::wal::WalRecord;
+use std::path::Path;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use libc::c_int;
+use libc::{EPERM, EACCES, EBADF, S_ISGID, ENOENT, ENOSYS, EINVAL, EEXIST, EIO, ELOOP, EISDIR, ENOTDIR, EROFS, E2BIG, ENOTTY, ENOTEMPTY, ENAMETOOLONG, EMLINK};
+use libc::{S_IFMT, S_IFREG, S_IFIFO, S_IFSOCK};
+use libc::{O_RDONLY, O_WRONLY, O_RDWR, O_ACCMODE, RENAME_EXCHANGE, RENAME_NOREPLACE};
+use std::time::Duration;
+use fuser::{FileAttr, FileType, Request, ReplyData, ReplyEntry, ReplyDirectory, ReplyEmpty, ReplyCreate, ReplyIoctl, ReplyOpen};
+use std::sync::atomic::Ordering;
+
+impl TreeFilesystem {
+    pub(super) fn do_create(&mut self, parent: u64, name: &OsStr, mode: u16, uid: u32, gid: u32) -> Result<Inode, c_int> {
+        // The new file's path is its parent's with its name on the end, so it stays right at any
+        // depth as long as the parent's is
+        let parent_path = match self.get_inode(parent) {
+            Some(Inode::DirectoryInode(ref b)) => b.path.clone(),
+            Some(_) => return Err(ENOTDIR),
+            None => return Err(ENOENT),
+        };
+        let tmp_target_path = Path::new(&parent_path).join(name);
+        let target_path = tmp_target_path.to_string_lossy();
+
+        // mknod passes the file type in the mode
+        let kind = match mode as u32 & S_IFMT {
+            S_IFIFO => FileType::NamedPipe,
+            S_IFSOCK => FileType::Socket,
+            _ => FileType::RegularFile,
+        };
+
+        // create_inode adds the new inode to the parent's contents
+        let target_ino = self.create_inode(target_path.to_string(), kind, mode, 0, uid, gid, parent, "".to_string())?.clone();
+
+        // Update parent mtime and atime
+        let now = self.clock.now();
+        if let Some(Inode::DirectoryInode(ref mut b)) = self.get_inode_mut(parent) {
+            b.attrs.mtime = now;
+            b.attrs.atime = now;
+        }
+
+        Ok(target_ino)
+    }
+
+    pub(super) fn do_mkdir(&mut self, parent: u64, name: &OsStr, mode: u16, uid: u32, gid: u32) -> Result<Inode, c_int> {
+        let parent_path = match self.get_inode(parent) {
+            Some(Inode::DirectoryInode(ref b)) => b.path.clone(),
+            Some(_) => return Err(ENOTDIR),
+            None => return Err(ENOENT),
+        };
+        let tmp_target_path = Path::new(&parent_path).join(name);
+        let target_path = tmp_target_path.to_string_lossy();
+
+        // create_inode adds the new directory to the parent's contents
+        let ino = self.create_inode(target_path.to_string(), FileType::Directory, mode & 0o7777, 0, uid, gid, parent, "".to_string())?.inode_num();
+
+        let now = self.clock.now();
+        // The new directory's ".." links back to the parent
+        if let Some(Inode::DirectoryInode(ref mut b)) = self.get_inode_mut(parent) {
+            b.attrs.nlink += 1;
+            b.attrs.mtime = now;
+            b.attrs.atime = now;
+            b.attrs.ctime = now;
+        }
+        Ok(self.get_inode(ino).unwrap().clone())
+    }
+
+    pub(super) fn do_symlink(&mut self, parent: u64, link_name: &OsStr, target: &Path, uid: u32, gid: u32) -> Result<Inode, c_int> {
+        let parent_path = match self.get_inode(parent) {
+            Some(a) => a.path().clone(),
+            None => return Err(ENOENT),
+        };
+        let path = Path::new(&parent_path).join(link_name);
+        // TODO: This will need to be modified so that we can get the full path when we
+        // have directories working
+        let target_path = Path::new(&"/".to_string()).join(target);
+
+        if let Some(target_ino) = self.get_inode_by_path(target_path.to_string_lossy().to_string()) {
+            // This gets the target path based upon the mountpoint of the filesystem; this
+            // allows tools like ls and readlink and such to actually find the target,
+            // since the paths in this filesystem are based upon "/"
+            //
+            // NOTE: Maybe we need to change the root inode to something other than "/"? 
+            // Doubt this will work but idk
+            let canonical_target = Path::new(&self.mountpoint)
+                .join(
+                    Path::new(target_ino.path())
+                        .strip_prefix("/")
+                        .unwrap()
+            );
+            let target_ino_num = target_ino.inode_num();
+
+            let link = self.create_symlink(path.to_string_lossy().to_string(), 
+                                            0o777, 
+                                            target.to_string_lossy().len() as u64, 
+                                            uid, 
+                                            gid, 
+                                            parent, 
+                                            target_ino_num,
+                                            canonical_target.to_string_lossy().to_string(),
+            );
+            Ok(link.clone())
+        } else {
+            let link = self.create_symlink(path.to_string_lossy().to_string(), 
+                                            0o777, 
+                                            target.to_string_lossy().len() as u64, 
+                                            uid, 
+                                            gid, 
+                                            parent, 
+                                            0,
+                                            target.to_string_lossy().to_string(),
+            );
+            Ok(link.clone())
+        }
+    }
+
+    pub(super) fn do_link(&mut self, ino: u64, new_parent: u64, new_name: &OsStr) -> Result<FileAttr, c_int> {
+        let now = self.clock.now();
+        let attrs = match self.get_inode_mut(ino) {
+            Some(Inode::FileInode(ref mut a)) => {
+                a.links.push((new_parent, new_name.to_string_lossy().to_string()));
+                a.attrs.nlink += 1;
+                a.num_links = a.attrs.nlink;
+                a.attrs.ctime = now;
+                a.attrs
+            },
+            Some(_) => return Err(EPERM),
+            None => return Err(ENOENT),
+        };
+        match self.get_inode_mut(new_parent) {
+            Some(Inode::DirectoryInode(ref mut b)) => {
+                b.contents.push(ino);
+                b.attrs.mtime = now;
+                b.attrs.ctime = now;
+            },
+            _ => return Err(ENOTDIR),
+        }
+        Ok(attrs)
+    }
+
+    // Drops one name of a file that has others, leaving the inode itself in place. If the name
+    // going away is the file's first one, the next link takes over as its parent, name and path
+    pub(super) fn drop_link(&mut self, ino: u64, parent: u64, name: &str) {
+        let now = self.clock.now();
+        let promoted = match self.get_inode_mut(ino) {
+            Some(Inode::FileInode(ref mut a)) => {
+                if a.parent == parent && a.name == name {
+                    let (p, n) = a.links.remove(0);
+                    a.parent = p;
+                    a.name = n;
+                    true
+                } else {
+                    if let Some(index) = a.links.iter().position(|(p, n)| *p == parent && n == name) {
+                        a.links.remove(index);
+                    }
+                    false
+                }
+            },
+            _ => return,
+        };
+        let new_path = if promoted {
+            let (p, n) = match self.get_inode(ino) {
+                Some(a) => (a.parent(), a.name().clone()),
+                None => return,
+            };
+            self.get_inode(p).map(|d| Path::new(d.path()).join(&n).to_string_lossy().to_string())
+        } else {
+            None
+        };
+        if let Some(Inode::FileInode(ref mut a)) = self.get_inode_mut(ino) {
+            if let Some(path) = new_path {
+                a.path = path;
+            }
+            a.attrs.nlink -= 1;
+            a.num_links = a.attrs.nlink;
+            a.attrs.ctime = now;
+        }
+        if let Some(Inode::DirectoryInode(ref mut b)) = self.get_inode_mut(parent) {
+            if let Some(index) = b.contents.iter().position(|x| *x == ino) {
+                b.contents.remove(index);
+            }
+        }
+        self.link_cache.clear();
+    }
+
+    pub(super) fn do_unlink(&mut self, parent: u64, name: &OsStr) -> Result<(), c_int> {
+        if self.get_inode(parent).is_none() {
+            return Err(ENOENT);
+        }
+        let (ino, nlink) = match self.get_child(parent, name) {
+            // Directories go through rmdir; removing one here would orphan its children
+            Some(Inode::DirectoryInode(_)) => return Err(EISDIR),
+            Some(a) => (a.inode_num(), a.attrs().nlink),
+            None => return Err(ENOENT),
+        };
+        let now = self.clock.now();
+        if nlink > 1 {
+            // Other names still refer to the file, so only this one goes
+            self.drop_link(ino, parent, &name.to_string_lossy());
+        } else {
+            self.remove_file(ino)?;
+        }
+
+        // Update the parent in place. drop_link has already taken its entry out of the contents.
+        // The parent's nlink stays as it is: only a subdirectory's ".." counts towards it, and
+        // rmdir is what takes that away
+        if let Some(Inode::DirectoryInode(ref mut b)) = self.get_inode_mut(parent) {
+            if nlink <= 1 {
+                if let Some(index) = b.contents.iter().position(|x| *x == ino) {
+                    b.contents.remove(index);
+                }
+            }
+            b.attrs.mtime = now;
+            b.attrs.atime = now;
+        }
+        Ok(())
+    }
+
+    // Removes a file whose last name has just gone. A file that's still open stays in the tree,
+    // unnamed and with no links, so its handles keep working; release finishes removing it once
+    // the last of them is closed
+    pub(super) fn remove_file(&mut self, ino: u64) -> Result<(), c_int> {
+        if self.open_handles(ino) > 0 {
+            let now = self.clock.now();
+            if let Some(Inode::FileInode(ref mut a)) = self.get_inode_mut(ino) {
+                a.attrs.nlink = 0;
+                a.num_links = 0;
+                a.attrs.ctime = now;
+            }
+            self.link_cache.clear();
+            return Ok(());
+        }
+        if let Some(ref mut pending) = self.deferred_deletes {
+            pending.push(ino);
+        } else {
+            self.delete_contents(&[ino])?;
+        }
+        // Remove the target inode, along with its extended attributes
+        self.remove_inode(ino);
+        self.xattrs_mut().remove(&ino);
+        Ok(())
+    }
+
+    // Deletes the contents of removed files from the backend in one request
+    fn delete_contents(&mut self, inos: &[u64]) -> Result<(), c_int> {
+        if inos.is_empty() {
+            return Ok(());
+        }
+        self.metrics.backend_stores.fetch_add(1, Ordering::Relaxed);
+        if let Err(e) = self.backend.delete_many(inos) {
+            error!("Failed to delete inodes {:?} from the backend: {}", inos, e);
+            self.metrics.backend_store_failures.fetch_add(1, Ordering::Relaxed);
+            self.metrics.backend_error(format!("deleting inodes {:?}: {}", inos, e));
+            return Err(EIO);
+        }
+        Ok(())
+    }
+
+    pub(super) fn do_rmdir(&mut self, parent: u64, name: &OsStr) -> Result<(), c_int> {
+        let ino = match self.get_child(parent, name) {
+            Some(Inode::DirectoryInode(ref b)) if !b.contents.is_empty() => return Err(ENOTEMPTY),
+            Some(Inode::DirectoryInode(ref b)) => b.inode_num,
+            Some(_) => return Err(ENOTDIR),
+            None => return Err(ENOENT),
+        };
+        self.remove_inode(ino);
+        self.xattrs_mut().remove(&ino);
+
+        let now = self.clock.now();
+        if let Some(Inode::DirectoryInode(ref mut b)) = self.get_inode_mut(parent) {
+            b.contents.retain(|x| *x != ino);
+            b.attrs.mtime = now;
+            b.attrs.ctime = now;
+            // The removed directory's ".." no longer refers to the parent
+            if b.attrs.nlink > 2 {
+                b.attrs.nlink -= 1;
+            }
+        }
+        Ok(())
+    }
+
+    // Unlinks and removes everything beneath `dir`, deepest first, leaving it empty, then deletes
+    // the removed files' contents from the backend in one go. Returns how many entries went
+    pub(super) fn do_remove_tree(&mut self, dir: u64) -> Result<u64, c_int> {
+        self.deferred_deletes = Some(Vec::new());
+        let removed = self.remove_tree(dir);
+        let pending = self.deferred_deletes.take().unwrap_or_default();
+        // Whatever was removed before a failure is gone from the tree, so its contents go too
+        self.delete_contents(&pending)?;
+        removed
+    }
+
+    fn remove_tree(&mut self, dir: u64) -> Result<u64, c_int> {
+        let contents = match self.get_inode(dir) {
+            Some(Inode::DirectoryInode(ref b)) => b.contents.clone(),
+            Some(_) => return Err(ENOTDIR),
+            None => return Err(ENOENT),
+        };
+        let mut children = Vec::new();
+        for ino in contents {
+            if children.iter().any(|(i, _, _)| *i == ino) {
+                continue;
+            }
+            if let Some(child) = self.get_inode(ino) {
+                let is_dir = child.attrs().kind == FileType::Directory;
+                for name in child.names_in(dir) {
+                    children.push((ino, name.clone(), is_dir));
+                }
+            }
+        }
+
+        let mut removed = 0;
+        for (ino, name, is_dir) in children {
+            if is_dir {
+                removed += self.remove_tree(ino)?;
+                self.do_rmdir(dir, OsStr::new(&name))?;
+            } else {
+                self.do_unlink(dir, OsStr::new(&name))?;
+            }
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    pub(super) fn do_rename(&mut self, parent: u64, name: &OsStr, new_parent: u64, new_name: &OsStr) -> Result<(), c_int> {
+        let mut source_ino = match self.get_child(parent, name) {
+            Some(a) => a.clone(),
+            None => return Err(ENOENT),
+        };
+        // A directory moved into its own subtree would be cut off from the root, and its
+        // descendants' parents would loop
+        if source_ino.attrs().kind == FileType::Directory && self.is_within(new_parent, source_ino.inode_num()) {
+            return Err(EINVAL);
+        }
+        let replaced_ino = match self.get_child(new_parent, new_name) {
+            // Two names for the same file: there's nothing to do
+            Some(a) if a.inode_num() == source_ino.inode_num() => return Ok(()),
+            // Replacing a directory would orphan anything still in it
+            Some(Inode::DirectoryInode(ref b)) if !b.contents.is_empty() => return Err(ENOTEMPTY),
+            Some(a) => Some(a.clone()),
+            None => None,
+        };
+        // A replaced file with other names only loses this one
+        if let Some(ref replaced) = replaced_ino {
+            if replaced.attrs().kind != FileType::Directory && replaced.attrs().nlink > 1 {
+                self.drop_link(replaced.inode_num(), new_parent, &new_name.to_string_lossy());
+            }
+        }
+
+        let mut parent_inode = match self.get_inode(parent) {
+            Some(a) => a.clone(),
+            None => return Err(ENOENT),
+        };
+        let mut new_parent_inode = match self.get_inode(new_parent) {
+            Some(a) => a.clone(),
+            None => return Err(ENOENT),
+        };
+
+        let tmp_target_path = Path::new(&new_parent_inode.path()).join(new_name);
+        let target_path = tmp_target_path.to_string_lossy();
+
+        //update the mtime/atime of the old and new parents
+        let now = self.clock.now();
+        let mut source_attrs = source_ino.attrs().clone();
+        let mut parent_attrs = parent_inode.attrs().clone();
+        let mut new_parent_attrs = new_parent_inode.attrs().clone();
+
+        source_attrs.mtime = now;
+        source_attrs.atime = now;
+        if let Some(ref replaced) = replaced_ino {
+            // Atomic saves (write a temp file, rename it over the original) expect the original's
+            // ownership and permissions to survive
+            if self.options.rename_preserve_mode {
+                source_attrs.perm = replaced.attrs().perm;
+                source_attrs.uid = replaced.attrs().uid;
+                source_attrs.gid = replaced.attrs().gid;
+            }
+        }
+        parent_attrs.mtime = now;
+        parent_attrs.atime = now;
+        new_parent_attrs.mtime = now;
+        new_parent_attrs.atime = now;
+        // As with rmdir, a replaced directory's ".." no longer refers to its parent
+        if let Some(Inode::DirectoryInode(_)) = replaced_ino {
+            if parent == new_parent {
+                parent_attrs.nlink -= 1;
+            } else {
+                new_parent_attrs.nlink -= 1;
+            }
+        }
+        // A directory moving to another parent takes its ".." link along with it
+        if source_attrs.kind == FileType::Directory && parent != new_parent {
+            parent_attrs.nlink -= 1;
+            new_parent_attrs.nlink += 1;
+        }
+
+        source_ino.set_attrs(source_attrs);
+        parent_inode.set_attrs(parent_attrs);
+        new_parent_inode.set_attrs(new_parent_attrs);
+
+        let source_ino_num = source_ino.inode_num();
+        // Drop the inode being replaced from the tree; its parent's entry goes below
+        let replaced_ino_num = replaced_ino.as_ref()
+            .filter(|r| r.attrs().kind == FileType::Directory || r.attrs().nlink <= 1)
+            .map(|r| r.inode_num());
+        if let Some(replaced) = replaced_ino_num {
+            // A replaced file goes just as it would on unlink, which waits for it to be closed
+            if let Some(Inode::FileInode(_)) = replaced_ino {
+                self.remove_file(replaced)?;
+            } else {
+                self.remove_inode(replaced);
+                self.xattrs_mut().remove(&replaced);
+            }
+        }
+
+        if parent == new_parent {
+            // Renaming within one directory: work on a single copy of it so no update is lost.
+            // The source keeps its slot and only the replaced entry goes away
+            let mut contents = parent_inode.contents().clone();
+            if let Some(replaced) = replaced_ino_num {
+                contents.retain(|value| *value != replaced);
+            }
+            parent_inode.set_contents(contents);
+            self.set_inode(parent, parent_inode);
+        } else {
+            //Remove from the parent_inode's contents. A file linked there under other names keeps
+            //its other entries
+            let mut parent_contents = parent_inode.contents().clone();
+            if let Some(index) = parent_contents.iter().position(|value| *value == source_ino_num) {
+                parent_contents.remove(index);
+            }
+
+            // Swap the replaced inode for the source in the new parent
+            let mut new_parent_contents = new_parent_inode.contents().clone();
+            if let Some(replaced) = replaced_ino_num {
+                new_parent_contents.retain(|value| *value != replaced);
+            }
+            new_parent_contents.push(source_ino_num);
+
+            parent_inode.set_contents(parent_contents);
+            new_parent_inode.set_contents(new_parent_contents);
+
+            //Update the parents'inodes
+            self.set_inode(parent, parent_inode);
+            self.set_inode(new_parent, new_parent_inode);
+        }
+
+        let is_first_name = source_ino.parent() == parent && *source_ino.name() == name.to_string_lossy();
+        if let (false, Inode::FileInode(ref mut a)) = (is_first_name, &mut source_ino) {
+            // One of the file's other hard links is moving
+            if let Some(link) = a.links.iter_mut().find(|(p, n)| *p == parent && *n == name.to_string_lossy()) {
+                *link = (new_parent, new_name.to_string_lossy().to_string());
+            }
+        } else {
+            // Change the source_ino's parent
+            source_ino.set_parent(new_parent);
+            // Update the path and name in the inode
+            source_ino.set_path(target_path.to_string());
+            source_ino.set_name(new_name.to_string_lossy().to_string());
+        }
+
+        // Insert the updated inode
+        let source_is_dir = source_ino.attrs().kind == FileType::Directory;
+        self.set_inode(source_ino_num, source_ino);
+        if source_is_dir {
+            self.update_descendant_paths(source_ino_num);
+        }
+
+        Ok(())
+    }
+
+    // Swaps two existing entries (RENAME_EXCHANGE): each inode takes the other's name and parent,
+    // and neither is removed
+    pub(super) fn do_exchange(&mut self, parent: u64, name: &OsStr, new_parent: u64, new_name: &OsStr) -> Result<(), c_int> {
+        let (a, a_is_dir) = match self.get_child(parent, name) {
+            Some(i) => (i.inode_num(), i.attrs().kind == FileType::Directory),
+            None => return Err(ENOENT),
+        };
+        let (b, b_is_dir) = match self.get_child(new_parent, new_name) {
+            Some(i) => (i.inode_num(), i.attrs().kind == FileType::Directory),
+            None => return Err(ENOENT),
+        };
+        // Two names for the same file: swapping them changes nothing
+        if a == b {
+            return Ok(());
+        }
+        // A directory can't end up inside itself
+        if (a_is_dir && self.is_within(new_parent, a)) || (b_is_dir && self.is_within(parent, b)) {
+            return Err(EINVAL);
+        }
+
+        self.rename_entry(a, parent, name, new_parent, new_name);
+        self.rename_entry(b, new_parent, new_name, parent, name);
+        if a_is_dir {
+            self.update_descendant_paths(a);
+        }
+        if b_is_dir {
+            self.update_descendant_paths(b);
+        }
+
+        let now = self.clock.now();
+        if parent != new_parent {
+            // Each parent's entry now refers to the other inode. A directory's ".." moves with it
+            if let Some(Inode::DirectoryInode(ref mut d)) = self.get_inode_mut(parent) {
+                if let Some(index) = d.contents.iter().position(|x| *x == a) {
+                    d.contents[index] = b;
+                }
+                if a_is_dir {
+                    d.attrs.nlink -= 1;
+                }
+                if b_is_dir {
+                    d.attrs.nlink += 1;
+                }
+                d.num_links = d.attrs.nlink;
+            }
+            if let Some(Inode::DirectoryInode(ref mut d)) = self.get_inode_mut(new_parent) {
+                if let Some(index) = d.contents.iter().position(|x| *x == b) {
+                    d.contents[index] = a;
+                }
+                if b_is_dir {
+                    d.attrs.nlink -= 1;
+                }
+                if a_is_dir {
+                    d.attrs.nlink += 1;
+                }
+                d.num_links = d.attrs.nlink;
+            }
+        }
+        for ino in [parent, new_parent] {
+            if let Some(Inode::DirectoryInode(ref mut d)) = self.get_inode_mut(ino) {
+                d.attrs.mtime = now;
+                d.attrs.ctime = now;
+            }
+        }
+        self.touch_ctime(a);
+        self.touch_ctime(b);
+        self.link_cache.clear();
+        Ok(())
+    }
+
+    // Rebuilds the path of everything beneath a directory that has just moved. Parent pointers are
+    // inode numbers, which a move doesn't change, so only the paths are stale
+    fn update_descendant_paths(&mut self, dir: u64) {
+        let inos: Vec<u64> = self.walk(dir).map(|i| i.inode_num()).filter(|i| *i != dir).collect();
+        // walk visits each directory before its children, so a parent's path is always fixed up
+        // before its children's are built from it
+        for ino in inos {
+            let new_path = match self.get_inode(ino) {
+                Some(a) => match self.get_inode(a.parent()) {
+                    Some(p) => Path::new(p.path()).join(a.name()).to_string_lossy().to_string(),
+                    None => continue,
+                },
+                None => continue,
+            };
+            if let Some(a) = self.get_inode_mut(ino) {
+                a.set_path(new_path);
+            }
+        }
+    }
+
+    // Moves the name `name` in `parent` that `ino` goes by to `new_name` in `new_parent`. For a
+    // file with several names, only that one changes
+    fn rename_entry(&mut self, ino: u64, parent: u64, name: &OsStr, new_parent: u64, new_name: &OsStr) {
+        let name = name.to_string_lossy().to_string();
+        let new_name = new_name.to_string_lossy().to_string();
+        let new_path = match self.get_inode(new_parent) {
+            Some(d) => Path::new(d.path()).join(&new_name).to_string_lossy().to_string(),
+            None => return,
+        };
+        let inode = match self.get_inode_mut(ino) {
+            Some(i) => i,
+            None => return,
+        };
+        if let Inode::FileInode(ref mut f) = inode {
+            if !(f.parent == parent && f.name == name) {
+                if let Some(link) = f.links.iter_mut().find(|(p, n)| *p == parent && *n == name) {
+                    *link = (new_parent, new_name);
+                }
+                return;
+            }
+        }
+        inode.set_parent(new_parent);
+        inode.set_path(new_path);
+        inode.set_name(new_name);
+    }
+
+    // Whether `name` can be given to a new entry: it can't be empty, "." or "..", contain a "/",
+    // or be longer than statfs says names can be
+    pub(super) fn validate_name(name: &OsStr) -> Result<(), c_int> {
+        if name.is_empty() || name == "." || name == ".." || name.as_bytes().contains(&b'/') {
+            return Err(EINVAL);
+        }
+        if name.len() > NAME_MAX {
+            return Err(ENAMETOOLONG);
+        }
+        Ok(())
+    }
+
+    // Only RENAME_NOREPLACE and RENAME_EXCHANGE are supported, and not together; anything else is
+    // rejected rather than silently treated as a plain rename
+    pub(super) fn validate_rename_flags(flags: u32) -> Result<(), c_int> {
+        if flags & !(RENAME_NOREPLACE | RENAME_EXCHANGE) != 0 || flags == RENAME_NOREPLACE | RENAME_EXCHANGE {
+            return Err(EINVAL);
+        }
+        Ok(())
+    }
+
+    // The error for creating, linking or renaming `name` in `parent`, if that would touch /.dnsfs
+    pub(super) fn status_name_error(parent: u64, name: &OsStr) -> Option<c_int> {
+        match parent {
+            STATUS_DIR_INO => Some(EACCES),
+            1 if name == STATUS_DIR_NAME => Some(EEXIST),
+            _ => None,
+        }
+    }
+
+    pub(super) fn handle_opendir(&mut self, req: &Request, inode: u64, flags: i32, reply: ReplyOpen) {
+        let _timer = self.metrics.time("opendir", inode);
+        info!("opendir(inode={}, flags={})", inode, flags);
+        let attrs = match self.status_attrs(inode).or_else(|| self.get_inode(inode).map(|a| *a.attrs())) {
+            Some(a) => a,
+            None => {
+                reply.error(ENOENT);
+                return;
+            },
+        };
+        if attrs.kind != FileType::Directory {
+            reply.error(ENOTDIR);
+            return;
+        }
+        if !self.can_execute(&attrs, req.uid(), req.gid(), req.pid()) {
+            reply.error(EACCES);
+            return;
+        }
+        let fh = match self.allocate_file_handle(inode, req.pid(), true, false) {
+            Ok(f) => f | FILE_HANDLE_DIR_BIT,
+            Err(e) => {
+                reply.error(e);
+                return;
+            },
+        };
+        reply.opened(fh, 0);
+    }
+
+    pub(super) fn handle_releasedir(&mut self, _req: &Request, inode: u64, fh: u64, flags: i32, reply: ReplyEmpty) {
+        let _timer = self.metrics.time("releasedir", inode);
+        info!("releasedir(inode={}, fh={}, flags={})", inode, fh, flags);
+        if (fh & FILE_HANDLE_DIR_BIT) == 0 || self.file_handle_inode(fh) != Some(inode) {
+            reply.error(EBADF);
+            return;
+        }
+        self.release_file_handle(fh);
+        reply.ok();
+    }
+
+    pub(super) fn handle_readdir(&mut self, req: &Request, ino: u64, fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        let _timer = self.metrics.time("readdir", ino);
+        info!("readdir(ino={}, fh={}, offset={})", ino, fh, offset);
+        // Only a handle from opendir can be listed through
+        if (fh & FILE_HANDLE_DIR_BIT) == 0 || self.file_handle_inode(fh) != Some(ino) {
+            reply.error(EBADF);
+            return;
+        }
+        // The directory may have been removed, or had its mode changed, since it was opened
+        let attrs = match self.status_attrs(ino).or_else(|| self.get_inode(ino).map(|a| *a.attrs())) {
+            Some(a) => a,
+            None => {
+                reply.error(ENOENT);
+                return;
+            },
+        };
+        if !self.can_execute(&attrs, req.uid(), req.gid(), req.pid()) {
+            reply.error(EACCES);
+            return;
+        }
+        if ino == STATUS_DIR_INO {
+            let entries = [(STATUS_DIR_INO, FileType::Directory, "."), (1, FileType::Directory, ".."), (STATUS_FILE_INO, FileType::RegularFile, STATUS_FILE_NAME)];
+            for (idx, (entry_ino, kind, name)) in entries.iter().enumerate().skip(offset as usize) {
+                if reply.add(*entry_ino, (idx as i64) + 1, *kind, name) {
+                    break;
+                }
+            }
+            reply.ok();
+            return;
+        }
+        let dir_inode = match self.get_inode(ino) {
+            Some(i) => i,
+            None => {
+                reply.error(ENOENT);
+                return;
+            },
+        };
+        // The root is its own parent
+        let parent = if ino == 1 { ino } else { dir_inode.parent() };
+        let mut entries: Vec<(u64, FileType, String)> = vec![
+            (dir_inode.inode_num(), FileType::Directory, ".".to_string()),
+            (parent, FileType::Directory, "..".to_string()),
+        ];
+        let mut seen = Vec::new();
+        for cur_ino in dir_inode.contents() {
+            // A file hard linked more than once into this directory appears once per name
+            if seen.contains(cur_ino) {
+                continue;
+            }
+            seen.push(*cur_ino);
+            let ino_data = match self.get_inode(*cur_ino) {
+                Some(a) => a,
+                None => {
+                    // The directory lists an inode that's gone; the tree is inconsistent
+                    error!("Directory {} lists missing inode {}", ino, cur_ino);
+                    reply.error(EIO);
+                    return;
+                },
+            };
+            // The same inode number lookup and getattr report, from the same attrs
+            for name in ino_data.names_in(ino) {
+                entries.push((ino_data.attrs().ino, ino_data.attrs().kind, name.clone()));
+            }
+        }
+        if ino == 1 {
+            entries.push((STATUS_DIR_INO, FileType::Directory, STATUS_DIR_NAME.to_string()));
+        }
+        // . and .. always come first
+        let collation = self.options.collation;
+        entries[2..].sort_by(|a, b| collation.compare(&a.2, &b.2));
+
+        // Whether the backend lists more children is settled once per pass through the directory
+        let fh_num = fh & FILE_HANDLE_NUM_MASK;
+        if offset == 0 || !self.dir_listings.contains_key(&fh_num) {
+            let listing = match self.fetch_listing(ino, 0) {
+                Ok(page) => page.map(|page| DirListing{ entries: entries.clone(), in_tree: self.listed.clone(), page_start: 0, page }),
+                Err(e) => {
+                    reply.error(e);
+                    return;
+                },
+            };
+            self.dir_listings.insert(fh_num, listing);
+        }
+        if let Some(listing) = self.dir_listings.get_mut(&fh_num).and_then(Option::take) {
+            self.add_listed_entries(ino, fh_num, offset as u64, listing, reply);
+            return;
+        }
+
+        // Each entry's offset is the index of the one after it, so the kernel can pick up where
+        // a full buffer cut us off by passing that offset back in
+        for (idx, (entry_ino, kind, name)) in entries.iter().enumerate().skip(offset as usize) {
+            info!("\tkey={}, inode={}, offset={}", name, entry_ino, idx + 1);
+            if reply.add(*entry_ino, (idx as i64) + 1, *kind, self.options.name_encoding.decode(name)) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    // readdir of a directory the backend lists: the tree's entries, then the listing's from the
+    // page it's at, fetching the next page when that one runs out. Only the one page is held
+    fn add_listed_entries(&mut self, ino: u64, fh_num: u64, offset: u64, mut listing: DirListing, mut reply: ReplyDirectory) {
+        for (idx, (entry_ino, kind, name)) in listing.entries.iter().enumerate().skip(offset as usize) {
+            if reply.add(*entry_ino, (idx as i64) + 1, *kind, self.options.name_encoding.decode(name)) {
+                self.dir_listings.insert(fh_num, Some(listing));
+                reply.ok();
+                return;
+            }
+        }
+
+        // The listing's entries are numbered on from the tree's
+        let tree_count = listing.entries.len() as u64;
+        let mut pos = offset.saturating_sub(tree_count);
+        loop {
+            let page_end = listing.page_start + listing.page.len() as u64;
+            if pos < listing.page_start || pos >= page_end {
+                // A short page was the listing's last
+                if pos == page_end && listing.page.len() < LIST_PAGE_SIZE {
+                    break;
+                }
+                match self.fetch_listing(ino, pos) {
+                    Ok(Some(page)) if !page.is_empty() => {
+                        listing.page_start = pos;
+                        listing.page = page;
+                    },
+                    Ok(_) => break,
+                    Err(e) => {
+                        self.dir_listings.insert(fh_num, Some(listing));
+                        reply.error(e);
+                        return;
+                    },
+                }
+            }
+            let child = &listing.page[(pos - listing.page_start) as usize];
+            pos += 1;
+            if listing.in_tree.contains(&child.ino) {
+                continue;
+            }
+            if reply.add(child.ino, (tree_count + pos) as i64, child.kind, self.options.name_encoding.decode(&child.name)) {
+                break;
+            }
+        }
+        self.dir_listings.insert(fh_num, Some(listing));
+        reply.ok();
+    }
+
+    // The page of the backend's listing of directory `ino` starting `offset` children in, or
+    // None if the backend doesn't list it
+    fn fetch_listing(&self, ino: u64, offset: u64) -> Result<Option<Vec<ListedChild>>, c_int> {
+        match self.backend.list_children(ino, offset, LIST_PAGE_SIZE) {
+            Ok(page) => {
+                if page.is_some() {
+                    self.metrics.backend_fetches.fetch_add(1, Ordering::Relaxed);
+                }
+                Ok(page)
+            },
+            Err(e) => {
+                error!("Failed to list directory {} from the backend: {}", ino, e);
+                self.metrics.backend_fetch_failures.fetch_add(1, Ordering::Relaxed);
+                self.metrics.backend_error(format!("listing directory {}: {}", ino, e));
+                Err(EIO)
+            },
+        }
+    }
+
+    // The child called `name` that the backend lists in directory `parent`, brought into the
+    // tree. Nothing if the listing has no such child, or lookup already brought it in: then it's
+    // either found in the tree, or has been removed or renamed since
+    pub(super) fn find_listed_child(&mut self, parent: u64, name: &OsStr) -> Result<Option<u64>, c_int> {
+        let child = match self.backend.find_child(parent, &name.to_string_lossy()) {
+            Ok(Some(c)) if !self.listed.contains(&c.ino) => c,
+            Ok(_) => return Ok(None),
+            Err(e) => {
+                error!("Failed to look up {} in directory {} from the backend: {}", name.to_string_lossy(), parent, e);
+                self.metrics.backend_fetch_failures.fetch_add(1, Ordering::Relaxed);
+                self.metrics.backend_error(format!("listing directory {}: {}", parent, e));
+                return Err(EIO);
+            },
+        };
+        self.metrics.backend_fetches.fetch_add(1, Ordering::Relaxed);
+        if self.get_inode(child.ino).is_some() {
+            error!("Directory {} lists {} as inode {}, which the tree already has", parent, child.name, child.ino);
+            return Err(EIO);
+        }
+        let path = match self.get_inode(parent) {
+            Some(p) => Path::new(p.path()).join(&child.name).to_string_lossy().to_string(),
+            None => return Err(ENOENT),
+        };
+
+        let now = self.clock.now();
+        let is_dir = child.kind == FileType::Directory;
+        let attrs = FileAttr{
+            ino: child.ino,
+            size: child.size,
+            blocks: child.size.div_ceil(self.block_size as u64),
+            atime: now,
+            mtime: now,
+            ctime: now,
+            crtime: now,
+            kind: child.kind,
+            perm: if is_dir { self.options.default_dir_mode } else { self.options.default_file_mode },
+            nlink: if is_dir { 2 } else { 1 },
+            uid: self.options.uid,
+            gid: self.options.gid,
+            rdev: 0,
+            flags: 0,
+            blksize: if is_dir { self.block_size } else { self.file_blksize() },
+        };
+        let inode = if is_dir {
+            Inode::DirectoryInode(DirectoryInode{
+                inode_num: child.ino,
+                attrs,
+                path,
+                contents: Vec::new(),
+                num_links: attrs.nlink,
+                parent,
+                name: child.name,
+            })
+        } else {
+            // Its contents stay in the backend until something reads them
+            Inode::FileInode(FileInode{
+                inode_num: child.ino,
+                attrs,
+                path,
+                data: Default::default(),
+                loaded: false,
+                num_links: attrs.nlink,
+                parent,
+                name: child.name,
+                links: Vec::new(),
+            })
+        };
+        if let Some(Inode::DirectoryInode(ref mut p)) = self.get_inode_mut(parent) {
+            p.contents.push(child.ino);
+            if is_dir {
+                p.attrs.nlink += 1;
+            }
+        }
+        self.set_inode(child.ino, inode);
+        // New inodes are numbered past every listed one
+        self.cur_inode = self.cur_inode.max(child.ino);
+        self.listed.insert(child.ino);
+        Ok(Some(child.ino))
+    }
+
+    pub(super) fn handle_readlink(&mut self, req: &Request, inode: u64, reply: ReplyData) {
+        let _timer = self.metrics.time("readlink", inode);
+        info!("readlink(inode={})", inode);
+        // The kernel follows a link by reading it, so refusing here stops it being followed
+        if self.options.deny_symlinks {
+            reply.error(ELOOP);
+            return;
+        }
+
+        let link_inode = match self.get_inode(inode) {
+            Some(a @ Inode::LinkInode(_)) => a,
+            Some(_) => {
+                reply.error(EINVAL);
+                return;
+            },
+            None => {
+                reply.error(ENOENT);
+                return;
+            },
+        };
+        let resolved = match self.resolve_symlink(link_inode) {
+            Ok(r) => r,
+            Err(e) => {
+                reply.error(e);
+                return;
+            },
+        };
+        if let Some(target_ino) = resolved {
+            if self.can_read(target_ino.attrs(), req.uid(), req.gid(), req.pid()) {
+                if let Some(symlink_data) = link_inode.get_symlink_data() {
+                    reply.data(symlink_data.as_bytes());
+                    return;
+                } else {
+                    reply.data(b"");
+                    return;
+                }
+            } else {
+                info!("Can't read");
+                reply.error(EACCES);
+                return;
+            }
+        }
+
+        reply.error(ENOSYS);
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(super) fn handle_create(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, umask: u32, flags: i32, reply: ReplyCreate) {
+        let _timer = self.metrics.time("create", parent);
+        info!("create(parent={}, name={}, mode={}, umask={} flags={})", parent, name.to_string_lossy(), mode, umask, flags);
+        let name = match self.options.name_encoding.encode(name) {
+            Some(n) => n,
+            None => {
+                reply.error(EINVAL);
+                return;
+            },
+        };
+        let name = OsStr::new(&name);
+        if let Err(e) = TreeFilesystem::validate_name(name) {
+            reply.error(e);
+            return;
+        }
+        if self.is_read_only() {
+            reply.error(EROFS);
+            return;
+        }
+        if let Some(e) = TreeFilesystem::status_name_error(parent, name) {
+            reply.error(e);
+            return;
+        }
+        // The kernel has already walked the path down to `parent`, however deep it is, so the new
+        // file only ever goes directly into it
+        let parent_inode = match self.get_inode(parent) {
+            Some(a @ Inode::DirectoryInode(_)) => a.clone(),
+            Some(_) => {
+                reply.error(ENOTDIR);
+                return;
+            },
+            // The parent can vanish between the kernel's lookup and this create
+            None => {
+                reply.error(ENOENT);
+                return;
+            },
+        };
+
+        // Check flags for read/write (idk why yet)
+        let (read, write) = match flags & O_ACCMODE {
+            O_RDONLY => (true, false),
+            O_WRONLY => (false, true),
+            O_RDWR => (true, true),
+            _ => {
+                reply.error(EINVAL);
+                return;
+            }
+        };
+
+        // Check up front, so a create that can't get a handle doesn't leave a file behind
+        if let Some(e) = self.handle_limit_error(req.pid()) {
+            reply.error(e);
+            return;
+        }
+
+        // Without O_EXCL an existing file is just opened, as open() would
+        let existing = self.get_child(parent, name).map(|a| *a.attrs());
+        if let Some(attrs) = existing {
+            if flags & libc::O_EXCL != 0 {
+                reply.error(EEXIST);
+                return;
+            }
+            if attrs.kind == FileType::Directory {
+                reply.error(EISDIR);
+                return;
+            }
+            if attrs.kind == FileType::Symlink {
+                reply.error(EEXIST);
+                return;
+            }
+            if (read && !self.can_read(&attrs, req.uid(), req.gid(), req.pid())) ||
+                (write && !self.can_write(&attrs, req.uid(), req.gid(), req.pid())) {
+                reply.error(EACCES);
+                return;
+            }
+            let mut attrs = attrs;
+            if write && flags & libc::O_TRUNC != 0 {
+                attrs = match self.truncate_on_open(attrs.ino) {
+                    Ok(a) => a,
+                    Err(e) => {
+                        reply.error(e);
+                        return;
+                    },
+                };
+            }
+            let fh = match self.allocate_file_handle(attrs.ino, req.pid(), read, write) {
+                Ok(f) => f,
+                Err(e) => {
+                    reply.error(e);
+                    return;
+                },
+            };
+            reply.created(&Duration::new(0, 0), &attrs, 0, fh, self.open_flags());
+            return;
+        }
+
+        if self.can_write(parent_inode.attrs(), req.uid(), req.gid(), req.pid()) {
+            let (mode, inherited_acl) = self.creation_mode(parent, mode, umask);
+            let record = WalRecord::Create{
+                ino: self.cur_inode + 1,
+                parent,
+                name: name.to_string_lossy().to_string(),
+                mode: mode.try_into().unwrap(),
+                uid: req.uid(),
+                gid: req.gid(),
+            };
+            if let Err(e) = self.log_mutation(&record) {
+                reply.error(e);
+                return;
+            }
+            let target_ino = match self.do_create(parent, name, mode.try_into().unwrap(), req.uid(), req.gid()) {
+                Ok(a) => a,
+                Err(e) => {
+                    reply.error(e);
+                    return;
+                },
+            };
+
+            if let Some(acl) = inherited_acl {
+                self.xattrs_mut().entry(target_ino.inode_num()).or_default().insert(ACL_ACCESS_XATTR.to_string(), acl);
+            }
+
+            let fh = match self.allocate_file_handle(target_ino.inode_num(), req.pid(), read, write) {
+                Ok(f) => f,
+                Err(e) => {
+                    reply.error(e);
+                    return;
+                },
+            };
+
+            // Inode numbers are never reused, so the generation is always 0
+            reply.created(
+                &Duration::new(0,0), 
+                target_ino.attrs(), 
+                0, 
+                fh.clone(), 
+                self.open_flags(),
+            );
+            return;
+        } else {
+            reply.error(EACCES);
+            return;
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(super) fn handle_mknod(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, umask: u32, rdev: u32, reply: ReplyEntry) {
+        let _timer = self.metrics.time("mknod", parent);
+        info!("mknod(parent={}, name={}, mode={:o}, umask={:o}, rdev={})", parent, name.to_string_lossy(), mode, umask, rdev);
+        let name = match self.options.name_encoding.encode(name) {
+            Some(n) => n,
+            None => {
+                reply.error(EINVAL);
+                return;
+            },
+        };
+        let name = OsStr::new(&name);
+        if let Err(e) = TreeFilesystem::validate_name(name) {
+            reply.error(e);
+            return;
+        }
+        if self.is_read_only() {
+            reply.error(EROFS);
+            return;
+        }
+        if let Some(e) = TreeFilesystem::status_name_error(parent, name) {
+            reply.error(e);
+            return;
+        }
+        // Device nodes aren't supported
+        match mode & S_IFMT {
+            S_IFREG | S_IFIFO | S_IFSOCK => (),
+            _ => {
+                reply.error(EPERM);
+                return;
+            },
+        }
+
+        let parent_inode = match self.get_inode(parent) {
+            Some(a @ Inode::DirectoryInode(_)) => a.clone(),
+            Some(_) => {
+                reply.error(ENOTDIR);
+                return;
+            },
+            None => {
+                reply.error(ENOENT);
+                return;
+            },
+        };
+        // Any name the parent has counts, including a hard link's other names
+        if self.get_child(parent, name).is_some() {
+            reply.error(EEXIST);
+            return;
+        }
+        if !self.can_write(parent_inode.attrs(), req.uid(), req.gid(), req.pid()) {
+            reply.error(EACCES);
+            return;
+        }
+
+        let (mode, inherited_acl) = self.creation_mode(parent, mode, umask);
+        let record = WalRecord::Create{
+            ino: self.cur_inode + 1,
+            parent,
+            name: name.to_string_lossy().to_string(),
+            mode: mode as u16,
+            uid: req.uid(),
+            gid: req.gid(),
+        };
+        if let Err(e) = self.log_mutation(&record) {
+            reply.error(e);
+            return;
+        }
+        let target_ino = match self.do_create(parent, name, mode as u16, req.uid(), req.gid()) {
+            Ok(a) => a,
+            Err(e) => {
+                reply.error(e);
+                return;
+            },
+        };
+        if let Some(acl) = inherited_acl {
+            self.xattrs_mut().entry(target_ino.inode_num()).or_default().insert(ACL_ACCESS_XATTR.to_string(), acl);
+        }
+
+        reply.entry(&Duration::new(0, 0), target_ino.attrs(), 0);
+    }
+
+    pub(super) fn handle_mkdir(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, umask: u32, reply: ReplyEntry) {
+        let _timer = self.metrics.time("mkdir", parent);
+        info!("mkdir(parent={}, name={}, mode={:o}, umask={:o})", parent, name.to_string_lossy(), mode, umask);
+        let name = match self.options.name_encoding.encode(name) {
+            Some(n) => n,
+            None => {
+                reply.error(EINVAL);
+                return;
+            },
+        };
+        let name = OsStr::new(&name);
+        if let Err(e) = TreeFilesystem::validate_name(name) {
+            reply.error(e);
+            return;
+        }
+        if self.is_read_only() {
+            reply.error(EROFS);
+            return;
+        }
+        if let Some(e) = TreeFilesystem::status_name_error(parent, name) {
+            reply.error(e);
+            return;
+        }
+        let parent_attrs = match self.get_inode(parent) {
+            Some(Inode::DirectoryInode(ref b)) => b.attrs,
+            Some(_) => {
+                reply.error(ENOTDIR);
+                return;
+            },
+            None => {
+                reply.error(ENOENT);
+                return;
+            },
+        };
+        if self.get_child(parent, name).is_some() {
+            reply.error(EEXIST);
+            return;
+        }
+        if !self.can_write(&parent_attrs, req.uid(), req.gid(), req.pid()) {
+            reply.error(EACCES);
+            return;
+        }
+
+        let (mut mode, inherited_acl) = self.creation_mode(parent, mode, umask);
+        // In a setgid directory new subdirectories take its group, and the setgid bit with it so
+        // the same goes for everything beneath them
+        let mut gid = req.gid();
+        if parent_attrs.perm & S_ISGID as u16 != 0 {
+            gid = parent_attrs.gid;
+            mode |= S_ISGID;
+        }
+        let record = WalRecord::Mkdir{
+            ino: self.cur_inode + 1,
+            parent,
+            name: name.to_string_lossy().to_string(),
+            mode: (mode & 0o7777) as u16,
+            uid: req.uid(),
+            gid,
+        };
+        if let Err(e) = self.log_mutation(&record) {
+            reply.error(e);
+            return;
+        }
+        let target_ino = match self.do_mkdir(parent, name, (mode & 0o7777) as u16, req.uid(), gid) {
+            Ok(a) => a,
+            Err(e) => {
+                reply.error(e);
+                return;
+            },
+        };
+
+        // A subdirectory takes the parent's default ACL both as its access ACL and as its own
+        // default, so it carries on to everything created beneath it
+        if let Some(acl) = inherited_acl {
+            let default_acl = self.xattrs.get(&parent).and_then(|attrs| attrs.get(ACL_DEFAULT_XATTR)).cloned();
+            let attrs = self.xattrs_mut().entry(target_ino.inode_num()).or_default();
+            attrs.insert(ACL_ACCESS_XATTR.to_string(), acl);
+            if let Some(default_acl) = default_acl {
+                attrs.insert(ACL_DEFAULT_XATTR.to_string(), default_acl);
+            }
+        }
+
+        reply.entry(&Duration::new(0, 0), target_ino.attrs(), 0);
+    }
+
+    pub(super) fn handle_symlink(&mut self, req: &Request, parent: u64, link_name: &OsStr, target: &Path, reply: ReplyEntry) {
+        let _timer = self.metrics.time("symlink", parent);
+        info!("symlink(parent={}, link_name={}, target={})", parent, link_name.to_string_lossy(), target.to_string_lossy());
+        let link_name = match self.options.name_encoding.encode(link_name) {
+            Some(n) => n,
+            None => {
+                reply.error(EINVAL);
+                return;
+            },
+        };
+        let link_name = OsStr::new(&link_name);
+        if let Err(e) = TreeFilesystem::validate_name(link_name) {
+            reply.error(e);
+            return;
+        }
+        if self.is_read_only() {
+            reply.error(EROFS);
+            return;
+        }
+        if let Some(e) = TreeFilesystem::status_name_error(parent, link_name) {
+            reply.error(e);
+            return;
+        }
+        if self.options.deny_symlinks {
+            reply.error(EPERM);
+            return;
+        }
+        if let Some(parent_ino) = self.get_inode(parent) {
+            if self.can_write(parent_ino.attrs(), req.uid(), req.gid(), req.pid()) {
+                let record = WalRecord::Symlink{
+                    ino: self.cur_inode + 1,
+                    parent,
+                    name: link_name.to_string_lossy().to_string(),
+                    target: target.to_string_lossy().to_string(),
+                    uid: req.uid(),
+                    gid: req.gid(),
+                };
+                if let Err(e) = self.log_mutation(&record) {
+                    reply.error(e);
+                    return;
+                }
+                match self.do_symlink(parent, link_name, target, req.uid(), req.gid()) {
+                    Ok(link) => reply.entry(&Duration::new(0, 0), link.attrs(), 0),
+                    Err(e) => reply.error(e),
+                }
+                return;
+            } else {
+                reply.error(EACCES);
+                return;
+            }
+        } else {
+            info!("Could not find parent of symlink");
+            reply.error(EPERM);
+            return;
+        }
+    }
+
+    pub(super) fn handle_link(&mut self, req: &Request, inode: u64, new_parent: u64, new_name: &OsStr, reply: ReplyEntry) {
+        let _timer = self.metrics.time("link", inode);
+        info!("link(inode={}, new_parent={}, new_name={})", inode, new_parent, new_name.to_string_lossy());
+        let new_name = match self.options.name_encoding.encode(new_name) {
+            Some(n) => n,
+            None => {
+                reply.error(EINVAL);
+                return;
+            },
+        };
+        let new_name = OsStr::new(&new_name);
+        if let Err(e) = TreeFilesystem::validate_name(new_name) {
+            reply.error(e);
+            return;
+        }
+        if self.is_read_only() {
+            reply.error(EROFS);
+            return;
+        }
+        if let Some(e) = TreeFilesystem::status_name_error(new_parent, new_name) {
+            reply.error(e);
+            return;
+        }
+        // Only regular files (and FIFOs and sockets) can take more than one name
+        match self.get_inode(inode) {
+            Some(Inode::FileInode(ref a)) if a.attrs.nlink >= self.options.max_links => {
+                reply.error(EMLINK);
+                return;
+            },
+            Some(Inode::FileInode(_)) => (),
+            Some(_) => {
+                reply.error(EPERM);
+                return;
+            },
+            None => {
+                reply.error(ENOENT);
+                return;
+            },
+        }
+        let new_parent_inode = match self.get_inode(new_parent) {
+            Some(Inode::DirectoryInode(ref b)) => Inode::DirectoryInode(b.clone()),
+            Some(_) => {
+                reply.error(ENOTDIR);
+                return;
+            },
+            None => {
+                reply.error(ENOENT);
+                return;
+            },
+        };
+        if self.get_child(new_parent, new_name).is_some() {
+            reply.error(EEXIST);
+            return;
+        }
+        if !self.can_write(new_parent_inode.attrs(), req.uid(), req.gid(), req.pid()) {
+            reply.error(EACCES);
+            return;
+        }
+
+        let record = WalRecord::Link{
+            ino: inode,
+            new_parent,
+            new_name: new_name.to_string_lossy().to_string(),
+        };
+        if let Err(e) = self.log_mutation(&record) {
+            reply.error(e);
+            return;
+        }
+        match self.do_link(inode, new_parent, new_name) {
+            Ok(attrs) => reply.entry(&Duration::from_secs(1), &attrs, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    pub(super) fn handle_unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let _timer = self.metrics.time("unlink", parent);
+        info!("unlink(parent={}, name={:?})", parent, name);
+        // A name --name-encoding reject refuses can't exist
+        let name = match self.options.name_encoding.encode(name) {
+            Some(n) => n,
+            None => {
+                reply.error(ENOENT);
+                return;
+            },
+        };
+        let name = OsStr::new(&name);
+        if self.is_read_only() {
+            reply.error(EROFS);
+            return;
+        }
+        // Only the parent's attributes and path are needed; don't clone its contents list
+        let (parent_attrs, parent_path) = match self.get_inode(parent) {
+            Some(Inode::DirectoryInode(ref b)) => (b.attrs, b.path.clone()),
+            Some(_) => {
+                reply.error(ENOTDIR);
+                return;
+            },
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        match self.get_child(parent, name) {
+            Some(Inode::DirectoryInode(_)) => {
+                reply.error(EISDIR);
+                return;
+            },
+            Some(_) => (),
+            None => {
+                reply.error(ENOENT);
+                return;
+            },
+        }
+
+        let target_path = Path::new(&parent_path).join(name);
+        if self.is_protected(&target_path.to_string_lossy()) {
+            reply.error(EPERM);
+            return;
+        }
+
+        if self.can_write(&parent_attrs, req.uid(), req.gid(), req.pid()) {
+            if let Err(e) = self.log_mutation(&WalRecord::Unlink{parent, name: name.to_string_lossy().to_string()}) {
+                reply.error(e);
+                return;
+            }
+            if let Err(e) = self.do_unlink(parent, name) {
+                reply.error(e);
+                return;
+            }
+            reply.ok();
+            return;
+        } else {
+            reply.error(EACCES);
+            return;
+        }
+    }
+
+    pub(super) fn handle_rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let _timer = self.metrics.time("rmdir", parent);
+        info!("rmdir(parent={}, name={})", parent, name.to_string_lossy());
+        // A name --name-encoding reject refuses can't exist
+        let name = match self.options.name_encoding.encode(name) {
+            Some(n) => n,
+            None => {
+                reply.error(ENOENT);
+                return;
+            },
+        };
+        let name = OsStr::new(&name);
+        if self.is_read_only() {
+            reply.error(EROFS);
+            return;
+        }
+        let parent_attrs = match self.get_inode(parent) {
+            Some(Inode::DirectoryInode(ref b)) => b.attrs,
+            Some(_) => {
+                reply.error(ENOTDIR);
+                return;
+            },
+            None => {
+                reply.error(ENOENT);
+                return;
+            },
+        };
+
+        let target_path = Path::new(self.get_inode(parent).unwrap().path()).join(name);
+        if self.is_protected(&target_path.to_string_lossy()) {
+            reply.error(EPERM);
+            return;
+        }
+        if !self.can_write(&parent_attrs, req.uid(), req.gid(), req.pid()) {
+            reply.error(EACCES);
+            return;
+        }
+
+        // contents only ever holds real children; "." and ".." are made up by readdir, so an
+        // empty directory really is one with nothing in contents
+        match self.get_child(parent, name) {
+            Some(Inode::DirectoryInode(ref b)) if !b.contents.is_empty() => {
+                reply.error(ENOTEMPTY);
+                return;
+            },
+            Some(Inode::DirectoryInode(_)) => (),
+            Some(_) => {
+                reply.error(ENOTDIR);
+                return;
+            },
+            None => {
+                reply.error(ENOENT);
+                return;
+            },
+        }
+
+        if let Err(e) = self.log_mutation(&WalRecord::Rmdir{parent, name: name.to_string_lossy().to_string()}) {
+            reply.error(e);
+            return;
+        }
+        match self.do_rmdir(parent, name) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(super) fn handle_rename(&mut self, req: &Request, parent: u64, name: &OsStr, new_parent: u64, new_name: &OsStr, flags: u32, reply: ReplyEmpty) {
+        let _timer = self.metrics.time("rename", parent);
+        info!("rename(parent={}, name={}, new_parent={}, new_name={}, flags={})", parent, name.to_string_lossy(), new_parent, new_name.to_string_lossy(), flags);
+        if let Err(e) = TreeFilesystem::validate_rename_flags(flags) {
+            reply.error(e);
+            return;
+        }
+        // A name --name-encoding reject refuses can't exist
+        let name = match self.options.name_encoding.encode(name) {
+            Some(n) => n,
+            None => {
+                reply.error(ENOENT);
+                return;
+            },
+        };
+        let name = OsStr::new(&name);
+        let new_name = match self.options.name_encoding.encode(new_name) {
+            Some(n) => n,
+            None => {
+                reply.error(EINVAL);
+                return;
+            },
+        };
+        let new_name = OsStr::new(&new_name);
+        if let Err(e) = TreeFilesystem::validate_name(new_name) {
+            reply.error(e);
+            return;
+        }
+        if self.is_read_only() {
+            reply.error(EROFS);
+            return;
+        }
+        if let Some(e) = TreeFilesystem::status_name_error(parent, name).or(TreeFilesystem::status_name_error(new_parent, new_name)) {
+            reply.error(e);
+            return;
+        }
+        // Renaming something onto itself is a successful no-op
+        if parent == new_parent && name == new_name {
+            reply.ok();
+            return;
+        }
+        //let parent_path = self.get_path_by_inode(parent);
+        //let target_path = Path::new(parent_path).join(name.to_str().unwrap())
+        //    .into_os_string()
+        //    .into_string()
+        //    .unwrap();
+        //let source_path = Path::new(parent_path).join(name.to_str().unwrap())
+        //    .into_os_string()
+        //    .into_string()
+        //    .unwrap();
+        let parent_inode = match self.get_inode(parent) {
+            Some(Inode::DirectoryInode(ref b)) => Inode::DirectoryInode(b.clone()),
+            Some(_) => {
+                reply.error(ENOTDIR);
+                return;
+            },
+            None => {
+                reply.error(ENOENT);
+                return;
+            },
+        };
+        let new_parent_inode = match self.get_inode(new_parent) {
+            Some(Inode::DirectoryInode(ref b)) => Inode::DirectoryInode(b.clone()),
+            Some(_) => {
+                reply.error(ENOTDIR);
+                return;
+            },
+            None => {
+                reply.error(ENOENT);
+                return;
+            },
+        };
+
+        let tmp_source_path = Path::new(&parent_inode.path()).join(name);
+        let source_path = tmp_source_path.to_string_lossy();
+        let tmp_target_path = Path::new(&new_parent_inode.path()).join(new_name);
+        let target_path = tmp_target_path.to_string_lossy();
+
+        let source_ino = match self.get_child(parent, name) {
+            Some(a) => a.clone(),
+            None => {
+                reply.error(ENOENT);
+                return;
+            },
+        };
+
+        if flags & RENAME_EXCHANGE != 0 {
+            // Both sides have to exist, and both of them move
+            let target_ino = match self.get_child(new_parent, new_name) {
+                Some(a) => a.clone(),
+                None => {
+                    reply.error(ENOENT);
+                    return;
+                },
+            };
+            // Neither directory can end up inside itself
+            let source_loops = source_ino.attrs().kind == FileType::Directory && self.is_within(new_parent, source_ino.inode_num());
+            let target_loops = target_ino.attrs().kind == FileType::Directory && self.is_within(parent, target_ino.inode_num());
+            if source_ino.inode_num() != target_ino.inode_num() && (source_loops || target_loops) {
+                reply.error(EINVAL);
+                return;
+            }
+            if self.is_protected(&source_path) || self.is_protected(&target_path) {
+                reply.error(EPERM);
+                return;
+            }
+            if !self.can_write(parent_inode.attrs(), req.uid(), req.gid(), req.pid()) ||
+                !self.can_execute(parent_inode.attrs(), req.uid(), req.gid(), req.pid()) ||
+                !self.can_write(new_parent_inode.attrs(), req.uid(), req.gid(), req.pid()) ||
+                !self.can_execute(new_parent_inode.attrs(), req.uid(), req.gid(), req.pid()) {
+                reply.error(EACCES);
+                return;
+            }
+            let record = WalRecord::Exchange{
+                parent,
+                name: name.to_string_lossy().to_string(),
+                new_parent,
+                new_name: new_name.to_string_lossy().to_string(),
+            };
+            if let Err(e) = self.log_mutation(&record) {
+                reply.error(e);
+                return;
+            }
+            match self.do_exchange(parent, name, new_parent, new_name) {
+                Ok(()) => reply.ok(),
+                Err(e) => reply.error(e),
+            }
+            return;
+        }
+
+        // An existing file at the target gets replaced by the source, and so does an empty
+        // directory when the source is a directory too
+        if let Some(a) = self.get_child(new_parent, new_name) {
+            if flags & RENAME_NOREPLACE != 0 {
+                reply.error(EEXIST);
+                return;
+            }
+            let target_is_dir = a.attrs().kind == FileType::Directory;
+            let source_is_dir = source_ino.attrs().kind == FileType::Directory;
+            if a.inode_num() != source_ino.inode_num() {
+                if target_is_dir && !source_is_dir {
+                    reply.error(EISDIR);
+                    return;
+                }
+                if source_is_dir && !target_is_dir {
+                    reply.error(ENOTDIR);
+                    return;
+                }
+                if target_is_dir && !a.contents().is_empty() {
+                    reply.error(ENOTEMPTY);
+                    return;
+                }
+            }
+        }
+
+        // A directory can't be moved into itself or anything beneath it
+        if source_ino.attrs().kind == FileType::Directory && self.is_within(new_parent, source_ino.inode_num()) {
+            reply.error(EINVAL);
+            return;
+        }
+
+        // Protected paths can't be moved away or replaced, not even by root
+        let replaces_protected = self.get_child(new_parent, new_name).is_some() && self.is_protected(&target_path);
+        if self.is_protected(&source_path) || replaces_protected {
+            reply.error(EPERM);
+            return;
+        }
+
+        // Renaming only changes the parents, so it takes write and search permission on both of
+        // them, and nothing on the source itself
+        if !self.can_write(parent_inode.attrs(), req.uid(), req.gid(), req.pid()) ||
+            !self.can_execute(parent_inode.attrs(), req.uid(), req.gid(), req.pid()) ||
+            !self.can_write(new_parent_inode.attrs(), req.uid(), req.gid(), req.pid()) ||
+            !self.can_execute(new_parent_inode.attrs(), req.uid(), req.gid(), req.pid()) {
+            reply.error(EACCES);
+            return;
+        }
+
+        let record = WalRecord::Rename{
+            parent,
+            name: name.to_string_lossy().to_string(),
+            new_parent,
+            new_name: new_name.to_string_lossy().to_string(),
+        };
+        if let Err(e) = self.log_mutation(&record) {
+            reply.error(e);
+            return;
+        }
+        if let Err(e) = self.do_rename(parent, name, new_parent, new_name) {
+            reply.error(e);
+            return;
+        }
+
+        reply.ok();
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(super) fn handle_ioctl(&mut self, req: &Request, inode: u64, fh: u64, flags: u32, cmd: u32, in_data: &[u8], out_size: u32, reply: ReplyIoctl) {
+        let _timer = self.metrics.time("ioctl", inode);
+        info!("ioctl(inode={}, fh={}, flags={}, cmd={:#x}, len(in_data)={}, out_size={})", inode, fh, flags, cmd, in_data.len(), out_size);
+        let attrs = match self.get_inode(inode) {
+            Some(a) => *a.attrs(),
+            None => {
+                reply.error(ENOENT);
+                return;
+            },
+        };
+
+        match cmd {
+            DNSFS_IOC_EXPORT_JSON => {
+                if !self.can_read(&attrs, req.uid(), req.gid(), req.pid()) {
+                    reply.error(EACCES);
+                    return;
+                }
+                let data = self.export_json(inode).to_string();
+                // Too big for one reply; export a subdirectory instead
+                if data.len() > out_size as usize {
+                    reply.error(E2BIG);
+                    return;
+                }
+                reply.ioctl(0, data.as_bytes());
+            },
+            DNSFS_IOC_REMOVE_TREE => {
+                if self.is_read_only() {
+                    reply.error(EROFS);
+                    return;
+                }
+                if attrs.kind != FileType::Directory {
+                    reply.error(ENOTDIR);
+                    return;
+                }
+                // Check the whole subtree up front so a refusal doesn't leave it half removed
+                for inode in self.walk(inode).skip(1) {
+                    if self.is_protected(inode.path()) {
+                        reply.error(EPERM);
+                        return;
+                    }
+                }
+                for inode in self.walk(inode) {
+                    let a = inode.attrs();
+                    if a.kind == FileType::Directory && !(self.can_write(a, req.uid(), req.gid(), req.pid()) &&
+                        self.can_execute(a, req.uid(), req.gid(), req.pid())) {
+                        reply.error(EACCES);
+                        return;
+                    }
+                }
+                if let Err(e) = self.log_mutation(&WalRecord::RemoveTree{ino: inode}) {
+                    reply.error(e);
+                    return;
+                }
+                match self.do_remove_tree(inode) {
+                    Ok(removed) => reply.ioctl(0, &removed.to_ne_bytes()),
+                    Err(e) => reply.error(e),
+                }
+            },
+            _ => reply.error(ENOTTY),
+        }
+    }
+}
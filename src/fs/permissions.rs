@@ -0,0 +1,120 @@
+// Permission checks: mode bits, ACLs (--acl) and capabilities (--honor-capabilities)
+
+use super::TreeFilesystem;
+use crate::caps::{CAP_DAC_OVERRIDE, CAP_DAC_READ_SEARCH};
+use crate::acl::{Acl, ACL_ACCESS_XATTR, ACL_DEFAULT_XATTR};
+use crate::inode::Inode;
+use fuser::FileAttr;
+
+impl TreeFilesystem {
+    // The mode a new inode in `parent` is created with, and the access ACL it inherits. Per
+    // POSIX a default ACL on the parent takes the place of the umask; without one the umask applies
+    pub(super) fn creation_mode(&self, parent: u64, mode: u32, umask: u32) -> (u32, Option<Vec<u8>>) {
+        if self.options.acl {
+            if let Some(default_acl) = self.xattrs.get(&parent).and_then(|attrs| attrs.get(ACL_DEFAULT_XATTR)).and_then(|a| Acl::parse(a)) {
+                let perm = mode & 0o777 & default_acl.mode_bits() as u32;
+                let inherited = default_acl.inherit(perm as u16);
+                return ((mode & !0o777) | perm, Some(inherited.to_bytes()));
+            }
+        }
+        (mode & !umask, None)
+    }
+
+    // With --acl, an access ACL on the inode decides instead of the mode bits
+    pub(super) fn acl_permits(&self, attrs: &FileAttr, req_uid: u32, req_gid: u32, req_pid: u32, want: u16) -> Option<bool> {
+        if !self.options.acl {
+            return None;
+        }
+        let acl = Acl::parse(self.xattrs.get(&attrs.ino)?.get(ACL_ACCESS_XATTR)?)?;
+        let mut req_groups = self.groups.supplementary(req_pid);
+        req_groups.push(req_gid);
+        Some(acl.allows(attrs.uid, attrs.gid, req_uid, &req_groups, want))
+    }
+
+    // Whether the requester is in group `gid`, as its primary group or a supplementary one. The
+    // supplementary groups are only looked up when the primary group doesn't already match
+    pub(super) fn in_group(&self, gid: u32, req_gid: u32, req_pid: u32) -> bool {
+        req_gid == gid || self.groups.contains(req_pid, gid)
+    }
+
+    // Whether the requesting process's capabilities let it skip the permission check for `want`
+    // (--honor-capabilities): CAP_DAC_OVERRIDE allows anything, CAP_DAC_READ_SEARCH allows reading
+    // and searching directories
+    pub(super) fn capability_permits(&self, ino: u64, req_pid: u32, want: u16) -> bool {
+        if !self.options.honor_capabilities {
+            return false;
+        }
+        if self.capabilities.has(req_pid, CAP_DAC_OVERRIDE) {
+            return true;
+        }
+        let is_dir = matches!(self.get_inode(ino), Some(Inode::DirectoryInode(_)));
+        let read_search = if is_dir { 0o4 | 0o1 } else { 0o4 };
+        want & !read_search == 0 && self.capabilities.has(req_pid, CAP_DAC_READ_SEARCH)
+    }
+
+    pub(super) fn can_read(&self, attrs: &FileAttr, req_uid: u32, req_gid: u32, req_pid: u32) -> bool {
+        if self.capability_permits(attrs.ino, req_pid, 0o4) {
+            return true;
+        }
+        if let Some(allowed) = self.acl_permits(attrs, req_uid, req_gid, req_pid, 0o4) {
+            return allowed || req_uid == 0;
+        }
+
+        let is_owner = req_uid == attrs.uid;
+        let is_in_grp = || self.in_group(attrs.gid, req_gid, req_pid);
+
+        // Check octal permissions
+        let can_owner_read = attrs.perm & 0o400 != 0;
+        let can_grp_read = attrs.perm & 0o040 != 0;
+        let can_other_read = attrs.perm & 0o004 != 0;
+
+        if req_uid == 0 || (can_owner_read && is_owner) || can_other_read || (can_grp_read && is_in_grp()) {
+            return true;
+        }
+        false
+    }
+
+    pub(super) fn can_write(&self, attrs: &FileAttr, req_uid: u32, req_gid: u32, req_pid: u32) -> bool {
+        if self.capability_permits(attrs.ino, req_pid, 0o2) {
+            return true;
+        }
+        if let Some(allowed) = self.acl_permits(attrs, req_uid, req_gid, req_pid, 0o2) {
+            return allowed || req_uid == 0;
+        }
+
+        let is_owner = req_uid == attrs.uid;
+        let is_in_grp = || self.in_group(attrs.gid, req_gid, req_pid);
+
+        // Check octal permissions
+        let can_owner_write = attrs.perm & 0o200 != 0;
+        let can_grp_write = attrs.perm & 0o020 != 0;
+        let can_other_write = attrs.perm & 0o002 != 0;
+
+        if req_uid == 0 || (can_owner_write && is_owner) || can_other_write || (can_grp_write && is_in_grp()) {
+            return true;
+        }
+        false
+    }
+
+    pub(super) fn can_execute(&self, attrs: &FileAttr, req_uid: u32, req_gid: u32, req_pid: u32) -> bool {
+        if self.capability_permits(attrs.ino, req_pid, 0o1) {
+            return true;
+        }
+        if let Some(allowed) = self.acl_permits(attrs, req_uid, req_gid, req_pid, 0o1) {
+            return allowed || req_uid == 0;
+        }
+
+        let is_owner = req_uid == attrs.uid;
+        let is_in_grp = || self.in_group(attrs.gid, req_gid, req_pid);
+
+        // Check octal permissions
+        let can_owner_exec = attrs.perm & 0o100 != 0;
+        let can_grp_exec = attrs.perm & 0o010 != 0;
+        let can_other_exec = attrs.perm & 0o001 != 0;
+
+        if req_uid == 0 || (can_owner_exec && is_owner) || can_other_exec || (can_grp_exec && is_in_grp()) {
+            return true;
+        }
+        false
+    }
+}
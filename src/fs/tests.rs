@@ -0,0 +1,2100 @@
+// Tests against the tree itself, through the same do_* operations the handlers and the
+// write-ahead log replay use, and against a tree mounted with FUSE for what only the handlers
+// check. The mounted tests skip themselves where nothing can be mounted
+
+use super::{build_filesystem, TreeFilesystem};
+use crate::backend::{ListedChild, StorageBackend, LIST_PAGE_SIZE};
+use crate::caps::{CAP_DAC_OVERRIDE, CAP_DAC_READ_SEARCH};
+use crate::inode::{Inode, InodeTrait};
+use crate::metrics::Metrics;
+use crate::options::Options;
+use crate::snapshot::Snapshot;
+use crate::wal::WalRecord;
+use fuser::{BackgroundSession, FileType, MountOption};
+use libc::{EACCES, EAGAIN, EFBIG, EINVAL, EIO, ELOOP, EMLINK, ENOSPC, EPERM, FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE};
+use libc::{RENAME_EXCHANGE, RENAME_NOREPLACE, RENAME_WHITEOUT};
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{DirEntryExt, FileExt, FileTypeExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Barrier, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+fn options(args: &[&str]) -> Options {
+    let args = args.iter().map(|a| a.to_string()).chain(Some("/mnt/dnsfs-test".to_string()));
+    Options::parse(args).unwrap()
+}
+
+fn empty_fs(args: &[&str]) -> TreeFilesystem {
+    TreeFilesystem::new(&BTreeMap::new(), &options(args))
+}
+
+// A tree mounted on a fresh temporary directory, unmounted when dropped
+struct Mounted {
+    session: Option<BackgroundSession>,
+    path: PathBuf,
+}
+
+impl Mounted {
+    fn join(&self, name: &str) -> PathBuf {
+        self.path.join(name)
+    }
+}
+
+impl Drop for Mounted {
+    fn drop(&mut self) {
+        if let Some(session) = self.session.take() {
+            // Joining waits for destroy(), but re-panics if the filesystem thread did
+            if std::thread::panicking() {
+                drop(session);
+            } else {
+                session.join();
+            }
+        }
+        let _ = fs::remove_dir(&self.path);
+    }
+}
+
+// Mounts `fs` the way the self-test does, or returns None (and the test passes vacuously) when
+// FUSE isn't usable here
+fn mount(mut fs: TreeFilesystem) -> Option<Mounted> {
+    static MOUNTS: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!("dnsfs-test-{}-{}", std::process::id(), MOUNTS.fetch_add(1, Ordering::Relaxed)));
+    fs::create_dir_all(&path).unwrap();
+    fs.mountpoint = path.to_string_lossy().to_string();
+    fs.options.mountpoint = fs.mountpoint.clone();
+
+    let mut mount_options = vec![MountOption::FSName("jakefs".to_string())];
+    // Lets as_user's unprivileged child in
+    if is_root() {
+        mount_options.push(MountOption::AllowOther);
+    }
+    match fuser::spawn_mount2(fs, &path, &mount_options) {
+        Ok(session) => Some(Mounted{ session: Some(session), path }),
+        Err(e) => {
+            eprintln!("Skipping: could not mount {}: {}", path.display(), e);
+            let _ = fs::remove_dir(&path);
+            None
+        },
+    }
+}
+
+fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+// The unprivileged user as_user runs as
+const NOBODY: u32 = 65534;
+
+// Runs `op` in a child process as NOBODY and returns the errno it failed with, or 0 if it
+// succeeded. Root passes every permission check, so this is the only way to see them fail; None
+// (skip the test) when not running as root
+fn as_user(op: impl FnOnce() -> io::Result<()>) -> Option<i32> {
+    as_user_with_caps(&[], op)
+}
+
+// as_user, with the child keeping the capabilities numbered in `caps` as NOBODY
+fn as_user_with_caps(caps: &[u32], op: impl FnOnce() -> io::Result<()>) -> Option<i32> {
+    if !is_root() {
+        eprintln!("Skipping: switching users needs root");
+        return None;
+    }
+    let mask = caps.iter().fold(0u32, |m, cap| m | 1 << cap);
+    match unsafe { libc::fork() } {
+        0 => {
+            let code = unsafe {
+                // Changing uid clears the effective set, and without KEEPCAPS the permitted one too
+                if libc::prctl(libc::PR_SET_KEEPCAPS, 1) != 0 {
+                    libc::_exit(255);
+                }
+                if libc::setgroups(0, std::ptr::null()) != 0 || libc::setgid(NOBODY) != 0 || libc::setuid(NOBODY) != 0 {
+                    libc::_exit(255);
+                }
+                // struct __user_cap_header_struct and two __user_cap_data_struct, version 3
+                let header = [0x20080522u32, 0];
+                let data = [mask, mask, 0, 0, 0, 0];
+                if libc::syscall(libc::SYS_capset, header.as_ptr(), data.as_ptr()) != 0 {
+                    libc::_exit(255);
+                }
+                match op() {
+                    Ok(()) => 0,
+                    Err(e) => e.raw_os_error().unwrap_or(254),
+                }
+            };
+            unsafe { libc::_exit(code) }
+        },
+        -1 => panic!("fork: {}", io::Error::last_os_error()),
+        pid => {
+            let mut status = 0;
+            unsafe { libc::waitpid(pid, &mut status, 0) };
+            assert!(libc::WIFEXITED(status), "child as uid {} died: status {}", NOBODY, status);
+            Some(libc::WEXITSTATUS(status))
+        },
+    }
+}
+
+fn chmod(path: &Path, mode: u32) {
+    fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+}
+
+fn mkdir(fs: &mut TreeFilesystem, parent: u64, name: &str) -> u64 {
+    fs.do_mkdir(parent, OsStr::new(name), 0o755, 0, 0).unwrap().inode_num()
+}
+
+fn create(fs: &mut TreeFilesystem, parent: u64, name: &str) -> u64 {
+    fs.do_create(parent, OsStr::new(name), 0o644, 0, 0).unwrap().inode_num()
+}
+
+#[test]
+fn rename_moves_directory_with_children_between_parents() {
+    let mut fs = empty_fs(&[]);
+    let a = mkdir(&mut fs, 1, "a");
+    let b = mkdir(&mut fs, 1, "b");
+    let dir = mkdir(&mut fs, a, "dir");
+    let file = create(&mut fs, dir, "file");
+    let sub = mkdir(&mut fs, dir, "sub");
+    let nested = create(&mut fs, sub, "nested");
+
+    fs.do_rename(a, OsStr::new("dir"), b, OsStr::new("dir")).unwrap();
+
+    assert!(fs.get_child(a, OsStr::new("dir")).is_none());
+    assert_eq!(fs.get_child(b, OsStr::new("dir")).unwrap().inode_num(), dir);
+    assert_eq!(fs.get_inode(dir).unwrap().parent(), b);
+    for child in [file, sub] {
+        assert_eq!(fs.get_inode(child).unwrap().parent(), dir);
+    }
+    assert_eq!(fs.lookup_entry(sub, OsStr::new("..")).unwrap().inode_num(), dir);
+    assert_eq!(fs.lookup_entry(dir, OsStr::new("..")).unwrap().inode_num(), b);
+    assert_eq!(fs.get_inode(sub).unwrap().path(), "/b/dir/sub");
+    assert_eq!(fs.get_inode(nested).unwrap().path(), "/b/dir/sub/nested");
+}
+
+#[test]
+fn rename_refuses_to_move_directory_into_its_own_subtree() {
+    let mut fs = empty_fs(&[]);
+    let a = mkdir(&mut fs, 1, "a");
+    let sub = mkdir(&mut fs, a, "sub");
+
+    assert_eq!(fs.do_rename(1, OsStr::new("a"), sub, OsStr::new("a")), Err(EINVAL));
+    assert_eq!(fs.do_rename(1, OsStr::new("a"), a, OsStr::new("a")), Err(EINVAL));
+    assert_eq!(fs.get_inode(a).unwrap().parent(), 1);
+    assert_eq!(fs.get_inode(sub).unwrap().parent(), a);
+}
+
+fn nlink(fs: &TreeFilesystem, ino: u64) -> u32 {
+    fs.get_inode(ino).unwrap().attrs().nlink
+}
+
+#[test]
+fn rename_moves_directory_link_count_to_new_parent() {
+    let mut fs = empty_fs(&[]);
+    let a = mkdir(&mut fs, 1, "a");
+    let b = mkdir(&mut fs, 1, "b");
+    mkdir(&mut fs, a, "dir");
+    mkdir(&mut fs, a, "other");
+    mkdir(&mut fs, b, "other");
+    assert_eq!((nlink(&fs, a), nlink(&fs, b)), (4, 3));
+
+    fs.do_rename(a, OsStr::new("dir"), b, OsStr::new("dir")).unwrap();
+    assert_eq!((nlink(&fs, a), nlink(&fs, b)), (3, 4));
+
+    // Replacing an empty directory: the new parent loses the replaced one's ".." and gains the
+    // moved one's
+    fs.do_rename(a, OsStr::new("other"), b, OsStr::new("other")).unwrap();
+    assert_eq!((nlink(&fs, a), nlink(&fs, b)), (2, 4));
+
+    // Files don't count towards either parent
+    create(&mut fs, a, "file");
+    fs.do_rename(a, OsStr::new("file"), b, OsStr::new("file")).unwrap();
+    assert_eq!((nlink(&fs, a), nlink(&fs, b)), (2, 4));
+}
+
+#[test]
+fn rename_over_a_file_keeps_its_mode_under_rename_preserve_mode() {
+    for (args, expected) in [(&["--rename-preserve-mode"][..], 0o600), (&[][..], 0o644)] {
+        let mut fs = empty_fs(args);
+        let original = create(&mut fs, 1, "original");
+        fs.do_setattr(original, Some(0o600), None, None, None, None, None, None, None).unwrap();
+        fs.do_write(original, 0, b"old").unwrap();
+        let temp = create(&mut fs, 1, "original.tmp");
+        fs.do_write(temp, 0, b"new contents").unwrap();
+
+        fs.do_rename(1, OsStr::new("original.tmp"), 1, OsStr::new("original")).unwrap();
+
+        let renamed = fs.get_child(1, OsStr::new("original")).unwrap();
+        assert_eq!(renamed.inode_num(), temp);
+        assert_eq!(renamed.attrs().perm & 0o7777, expected);
+        assert!(fs.get_inode(original).is_none());
+    }
+}
+
+#[test]
+fn rename_needs_write_and_search_on_parents_but_nothing_on_the_source() {
+    let Some(mnt) = mount(empty_fs(&[])) else { return };
+    let open = mnt.join("open");
+    let unsearchable = mnt.join("unsearchable");
+    fs::create_dir(&open).unwrap();
+    fs::create_dir(&unsearchable).unwrap();
+    fs::write(open.join("secret"), b"x").unwrap();
+    fs::write(unsearchable.join("file"), b"x").unwrap();
+    chmod(&open, 0o777);
+    chmod(&unsearchable, 0o666);
+    chmod(&open.join("secret"), 0o000);
+
+    // An unreadable file can still be renamed by whoever can write its directory
+    let (from, to) = (open.join("secret"), open.join("renamed"));
+    assert_eq!(as_user(|| fs::rename(&from, &to)), Some(0));
+    assert!(open.join("renamed").exists());
+
+    // Writable but not searchable isn't enough
+    let (from, to) = (unsearchable.join("file"), open.join("file"));
+    assert_eq!(as_user(|| fs::rename(&from, &to)), Some(EACCES));
+}
+
+fn contents(fs: &TreeFilesystem, ino: u64) -> Vec<u8> {
+    fs.get_inode(ino).unwrap().data().to_vec()
+}
+
+#[test]
+fn fallocate_punches_a_hole_that_reads_back_as_zeros() {
+    let mut fs = empty_fs(&[]);
+    let file = create(&mut fs, 1, "file");
+    fs.do_write(file, 0, b"0123456789").unwrap();
+
+    fs.do_fallocate(file, 3, 4, FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE).unwrap();
+
+    assert_eq!(contents(&fs, file), b"012\0\0\0\x00789");
+    assert_eq!(fs.get_inode(file).unwrap().attrs().size, 10);
+}
+
+// fallocate(2) on a mounted file, as the errno it fails with or 0
+fn fallocate(path: &Path, mode: i32, offset: i64, length: i64) -> i32 {
+    let file = fs::OpenOptions::new().write(true).open(path).unwrap();
+    if unsafe { libc::fallocate(file.as_raw_fd(), mode, offset, length) } == 0 {
+        0
+    } else {
+        io::Error::last_os_error().raw_os_error().unwrap()
+    }
+}
+
+#[test]
+fn fallocate_refuses_more_than_max_file_size_or_free_memory() {
+    let Some(mnt) = mount(empty_fs(&["--max-file-size", "1024"])) else { return };
+    let file = mnt.join("file");
+    fs::write(&file, b"0123456789").unwrap();
+
+    assert_eq!(fallocate(&file, 0, 0, 1024), 0);
+    assert_eq!(fs::metadata(&file).unwrap().len(), 1024);
+    assert_eq!(fallocate(&file, 0, 512, 1024), EFBIG);
+    assert_eq!(fs::metadata(&file).unwrap().len(), 1024);
+
+    let Some(mnt) = mount(empty_fs(&[])) else { return };
+    let file = mnt.join("file");
+    fs::write(&file, b"0123456789").unwrap();
+    assert_eq!(fallocate(&file, 0, 0, 1 << 62), ENOSPC);
+    assert_eq!(fallocate(&file, FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE, 2, 4), 0);
+    assert_eq!(fs::read(&file).unwrap(), b"01\0\0\0\x006789");
+}
+
+#[test]
+fn link_fails_with_emlink_past_max_links() {
+    let Some(mnt) = mount(empty_fs(&["--max-links", "2"])) else { return };
+    let file = mnt.join("file");
+    fs::write(&file, b"x").unwrap();
+
+    fs::hard_link(&file, mnt.join("second")).unwrap();
+    assert_eq!(fs::metadata(&file).unwrap().nlink(), 2);
+
+    let err = fs::hard_link(&file, mnt.join("third")).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(EMLINK));
+    assert!(!mnt.join("third").exists());
+    assert_eq!(fs::metadata(&file).unwrap().nlink(), 2);
+}
+
+// Waits up to a few seconds for the snapshot at `path` to show a file called `name` with
+// `contents`
+fn snapshot_shows(path: &Path, name: &str, contents: &[u8]) -> bool {
+    for _ in 0..50 {
+        if let Ok(snapshot) = Snapshot::read(&path.to_string_lossy()) {
+            let (tree, _) = snapshot.into_tree().unwrap();
+            let found = tree.values().any(|inode| match inode {
+                Inode::FileInode(ref a) => a.name == name && a.data.to_vec() == contents,
+                _ => false,
+            });
+            if found {
+                return true;
+            }
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    false
+}
+
+#[test]
+fn snapshot_interval_writes_snapshots_while_mounted() {
+    let snapshot = std::env::temp_dir().join(format!("dnsfs-test-{}-snapshot.json", std::process::id()));
+    let _ = fs::remove_file(&snapshot);
+    let fs = build_filesystem(&BTreeMap::new(), &options(&["--snapshot", &snapshot.to_string_lossy(), "--snapshot-interval", "1"])).unwrap();
+    let Some(mnt) = mount(fs) else { return };
+
+    fs::write(mnt.join("first"), b"one").unwrap();
+    assert!(snapshot_shows(&snapshot, "first", b"one"));
+
+    // Nothing happens after this write, so only the timer can get it into a snapshot
+    fs::write(mnt.join("second"), b"two").unwrap();
+    assert!(snapshot_shows(&snapshot, "second", b"two"));
+
+    drop(mnt);
+    let _ = fs::remove_file(&snapshot);
+}
+
+// Serves fixed contents, counting how often it's asked for them and noting which inodes it's
+// told to delete. It can't load or size anything else, and with `fail_stores` nothing new ever
+// reaches it
+#[derive(Debug)]
+struct MockBackend {
+    files: BTreeMap<u64, Vec<u8>>,
+    loads: Arc<AtomicUsize>,
+    deleted: Arc<Mutex<Vec<u64>>>,
+    // How many calls to delete_many there were
+    delete_batches: Arc<AtomicUsize>,
+    fail_stores: bool,
+}
+
+impl StorageBackend for MockBackend {
+    fn load(&self, ino: u64, offset: u64, size: u64) -> io::Result<Vec<u8>> {
+        self.loads.fetch_add(1, Ordering::Relaxed);
+        let data = self.files.get(&ino).ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        Ok(data.iter().skip(offset as usize).take(size as usize).copied().collect())
+    }
+
+    fn store(&self, _ino: u64, _data: &[u8]) -> io::Result<()> {
+        if self.fail_stores {
+            return Err(io::Error::other("backend unavailable"));
+        }
+        Ok(())
+    }
+
+    fn delete(&self, ino: u64) -> io::Result<()> {
+        self.deleted.lock().unwrap().push(ino);
+        Ok(())
+    }
+
+    fn delete_many(&self, inos: &[u64]) -> io::Result<()> {
+        self.delete_batches.fetch_add(1, Ordering::Relaxed);
+        self.deleted.lock().unwrap().extend_from_slice(inos);
+        Ok(())
+    }
+
+    fn size(&self, ino: u64) -> io::Result<Option<u64>> {
+        let data = self.files.get(&ino).ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        Ok(Some(data.len() as u64))
+    }
+}
+
+#[test]
+fn read_fetches_contents_never_loaded_from_the_backend() {
+    let mut fs = empty_fs(&[]);
+    let cold = create(&mut fs, 1, "cold");
+    let empty = create(&mut fs, 1, "empty");
+    let loads = Arc::new(AtomicUsize::new(0));
+    fs.backend = Box::new(MockBackend{ files: BTreeMap::from([(cold, b"from the backend".to_vec())]), loads: loads.clone(), deleted: Arc::default(), delete_batches: Arc::default(), fail_stores: false });
+    // Which is how a file whose contents are only in the backend is left
+    fs.invalidate_data(cold, 16, None);
+
+    // No open first
+    assert_eq!(fs.do_read(cold, 0, 100).unwrap(), b"from the backend");
+    assert_eq!(fs.do_read(cold, 5, 3).unwrap(), b"the");
+    assert_eq!(loads.load(Ordering::Relaxed), 1);
+
+    // A file that's really empty has nothing to fetch
+    assert_eq!(fs.do_read(empty, 0, 100).unwrap(), b"");
+    assert_eq!(loads.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn overlapping_writes_from_two_threads_never_tear() {
+    let mut fs = empty_fs(&[]);
+    let file = create(&mut fs, 1, "file");
+    let fs = Arc::new(Mutex::new(fs));
+    let first = vec![b'a'; 64 * 1024];
+    let second = vec![b'b'; 64 * 1024];
+
+    for _ in 0..20 {
+        fs.lock().unwrap().do_setattr(file, None, None, None, Some(0), None, None, None, None).unwrap();
+        let start = Arc::new(Barrier::new(2));
+        let writers: Vec<_> = [(0, first.clone()), (1024, second.clone())].into_iter().map(|(offset, data)| {
+            let fs = fs.clone();
+            let start = start.clone();
+            std::thread::spawn(move || {
+                start.wait();
+                fs.lock().unwrap().apply(&WalRecord::Write{ ino: file, offset, data }).unwrap();
+            })
+        }).collect();
+        for w in writers {
+            w.join().unwrap();
+        }
+
+        // Whichever write went second wins the overlap, all of it
+        let data = contents(&fs.lock().unwrap(), file);
+        let first_then_second = [&first[..1024], &second[..]].concat();
+        let second_then_first = [&first[..], &second[64 * 1024 - 1024..]].concat();
+        assert!(data == first_then_second || data == second_then_first, "torn write: {:?}", String::from_utf8_lossy(&data));
+    }
+}
+
+#[test]
+fn readdir_lists_directories_larger_than_one_reply_buffer() {
+    let mut fs = empty_fs(&[]);
+    let dir = mkdir(&mut fs, 1, "dir");
+    for n in 0..5000 {
+        create(&mut fs, dir, &format!("file-{:04}", n));
+    }
+    let Some(mnt) = mount(fs) else { return };
+
+    let mut names: Vec<String> = fs::read_dir(mnt.join("dir")).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().to_string()).collect();
+    names.sort();
+    let expected: Vec<String> = (0..5000).map(|n| format!("file-{:04}", n)).collect();
+    assert_eq!(names, expected);
+}
+
+#[test]
+fn write_past_the_end_zero_fills_the_gap_and_overwrites_in_place() {
+    let mut fs = empty_fs(&[]);
+    let file = create(&mut fs, 1, "file");
+    fs.do_write(file, 0, b"small").unwrap();
+
+    fs.do_write(file, 1 << 20, b"far").unwrap();
+    let data = contents(&fs, file);
+    assert_eq!(data.len(), (1 << 20) + 3);
+    assert_eq!(&data[..5], b"small");
+    assert!(data[5..1 << 20].iter().all(|b| *b == 0));
+    assert_eq!(&data[1 << 20..], b"far");
+    assert_eq!(fs.get_inode(file).unwrap().attrs().size, (1 << 20) + 3);
+
+    // The contents aren't shared, so they're changed where they are rather than copied
+    let before = match fs.get_inode(file) {
+        Some(Inode::FileInode(ref a)) => Arc::as_ptr(&a.data),
+        _ => unreachable!(),
+    };
+    fs.do_write(file, 1, b"MAL").unwrap();
+    match fs.get_inode(file) {
+        Some(Inode::FileInode(ref a)) => assert_eq!(Arc::as_ptr(&a.data), before),
+        _ => unreachable!(),
+    }
+    assert_eq!(&contents(&fs, file)[..5], b"sMALl");
+}
+
+#[test]
+fn getattr_reports_the_size_of_a_write_the_backend_hasnt_taken_yet() {
+    let mut fs = empty_fs(&["--stat-refresh"]);
+    let file = create(&mut fs, 1, "file");
+    fs.backend = Box::new(MockBackend{ files: BTreeMap::new(), loads: Arc::default(), deleted: Arc::default(), delete_batches: Arc::default(), fail_stores: true });
+
+    // The write stays in the tree to be retried at unmount, while the backend still says 0
+    fs.do_write(file, 0, &[b'x'; 30]).unwrap();
+    assert_eq!(fs.flush_data(file), Err(EIO));
+    assert_eq!(fs.do_getattr(file).unwrap().size, 30);
+    assert_eq!(fs.do_read(file, 0, 100).unwrap(), vec![b'x'; 30]);
+}
+
+#[test]
+fn rename_leaves_no_stale_names_or_paths_behind() {
+    let mut fs = empty_fs(&[]);
+    let a = mkdir(&mut fs, 1, "a");
+    let b = mkdir(&mut fs, 1, "b");
+    let file = create(&mut fs, a, "file");
+    let linked = create(&mut fs, a, "linked");
+    fs.do_link(linked, a, OsStr::new("alias")).unwrap();
+    let dir = mkdir(&mut fs, a, "dir");
+    let nested = create(&mut fs, dir, "nested");
+
+    fs.do_rename(a, OsStr::new("file"), b, OsStr::new("moved")).unwrap();
+    assert!(fs.get_child(a, OsStr::new("file")).is_none());
+    assert!(fs.get_inode_by_path("/a/file".to_string()).is_none());
+    assert_eq!(fs.get_child(b, OsStr::new("moved")).unwrap().inode_num(), file);
+    assert_eq!(fs.get_inode_by_path("/b/moved".to_string()).unwrap().inode_num(), file);
+    assert!(!fs.get_inode(a).unwrap().contents().contains(&file));
+
+    // Moving one of a hard linked file's names leaves the others where they were
+    fs.do_rename(a, OsStr::new("alias"), b, OsStr::new("alias")).unwrap();
+    assert!(fs.get_child(a, OsStr::new("alias")).is_none());
+    assert_eq!(fs.get_child(b, OsStr::new("alias")).unwrap().inode_num(), linked);
+    assert_eq!(fs.get_child(a, OsStr::new("linked")).unwrap().inode_num(), linked);
+    assert_eq!(fs.get_inode(linked).unwrap().attrs().nlink, 2);
+
+    // Everything under a moved directory is found by its new path only
+    fs.do_rename(a, OsStr::new("dir"), b, OsStr::new("dir")).unwrap();
+    assert!(fs.get_inode_by_path("/a/dir/nested".to_string()).is_none());
+    assert_eq!(fs.get_inode_by_path("/b/dir/nested".to_string()).unwrap().inode_num(), nested);
+    assert_eq!(fs.get_child(dir, OsStr::new("nested")).unwrap().inode_num(), nested);
+}
+
+#[test]
+fn an_empty_seed_mounts_as_a_usable_empty_root() {
+    let fs = empty_fs(&[]);
+    assert_eq!(fs.tree.len(), 1);
+    let Some(mnt) = mount(fs) else { return };
+
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    let path = std::ffi::CString::new(mnt.path.to_string_lossy().as_bytes()).unwrap();
+    assert_eq!(unsafe { libc::statvfs(path.as_ptr(), &mut stat) }, 0);
+    assert_eq!(stat.f_files, 1);
+
+    // Only the generated /.dnsfs, which isn't part of the tree
+    let names: Vec<String> = fs::read_dir(&mnt.path).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().to_string()).collect();
+    assert_eq!(names, vec![".dnsfs"]);
+
+    fs::write(mnt.join("file"), b"contents").unwrap();
+    assert_eq!(fs::read(mnt.join("file")).unwrap(), b"contents");
+    let names: Vec<String> = fs::read_dir(&mnt.path).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().to_string()).collect();
+    assert_eq!(names, vec![".dnsfs", "file"]);
+}
+
+#[test]
+fn large_file_reads_back_in_fuse_sized_chunks() {
+    let mut fs = empty_fs(&[]);
+    let file = create(&mut fs, 1, "file");
+    // Not a multiple of the chunk size, and never repeating on a chunk boundary
+    let data: Vec<u8> = (0..5 * 1024 * 1024 + 123).map(|i: u64| (i * 7 % 251) as u8).collect();
+    for (n, piece) in data.chunks(100_000).enumerate() {
+        fs.do_write(file, n as u64 * 100_000, piece).unwrap();
+    }
+
+    let chunk = 128 * 1024;
+    let mut read = Vec::new();
+    let mut offset = 0;
+    loop {
+        let buf = fs.do_read(file, offset, chunk).unwrap();
+        if buf.is_empty() {
+            break;
+        }
+        offset += buf.len() as u64;
+        read.extend_from_slice(&buf);
+    }
+    assert!(read == data, "read back {} bytes, wrote {}", read.len(), data.len());
+}
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("dnsfs-test-{}-{}", std::process::id(), name))
+}
+
+#[test]
+fn strict_fsync_fails_without_a_persistent_backend() {
+    let backing = temp_path("strict-fsync-backing");
+    let mounts = [
+        (vec!["--strict-fsync"], EINVAL),
+        (vec!["--strict-fsync", "--backend", "dir", "--backing-dir", backing.to_str().unwrap()], 0),
+        (vec![], 0),
+    ];
+    for (args, expected) in mounts {
+        let fs = build_filesystem(&BTreeMap::new(), &options(&args)).unwrap();
+        let Some(mnt) = mount(fs) else { return };
+        let mut file = fs::File::create(mnt.join("file")).unwrap();
+        io::Write::write_all(&mut file, b"data").unwrap();
+
+        let errno = |r: io::Result<()>| r.err().map_or(0, |e| e.raw_os_error().unwrap());
+        assert_eq!(errno(file.sync_all()), expected, "fsync with {:?}", args);
+        assert_eq!(errno(fs::File::open(&mnt.path).unwrap().sync_all()), expected, "fsyncdir with {:?}", args);
+    }
+    let _ = fs::remove_dir_all(&backing);
+}
+
+#[test]
+fn write_inside_a_truncate_extended_file_keeps_its_size_and_zeros() {
+    let mut fs = empty_fs(&[]);
+    let file = create(&mut fs, 1, "file");
+    fs.do_write(file, 0, b"hi").unwrap();
+
+    fs.do_setattr(file, None, None, None, Some(10), None, None, None, None).unwrap();
+    fs.do_write(file, 5, b"abc").unwrap();
+
+    assert_eq!(contents(&fs, file), b"hi\0\0\0abc\0\0");
+    assert_eq!(fs.get_inode(file).unwrap().attrs().size, 10);
+    assert_eq!(fs.do_read(file, 0, 100).unwrap(), b"hi\0\0\0abc\0\0");
+}
+
+// How many times `op` has run, from the metrics endpoint's rendering. An operation is counted
+// just after it replies, so this gives the one that answered the last call a moment to finish
+fn op_count(metrics: &Metrics, op: &str) -> u64 {
+    std::thread::sleep(Duration::from_millis(50));
+    let prefix = format!("dnsfs_{}_total ", op);
+    metrics.render().lines().find_map(|l| l.strip_prefix(&prefix)).map_or(0, |n| n.parse().unwrap())
+}
+
+#[test]
+fn missing_names_are_cached_for_the_negative_ttl() {
+    let fs = empty_fs(&["--negative-ttl-ms", "500"]);
+    let metrics = fs.metrics.clone();
+    let Some(mnt) = mount(fs) else { return };
+    let missing = mnt.join("missing");
+
+    assert_eq!(fs::metadata(&missing).unwrap_err().raw_os_error(), Some(libc::ENOENT));
+    let lookups = op_count(&metrics, "lookup");
+    for _ in 0..5 {
+        assert!(fs::metadata(&missing).is_err());
+    }
+    assert_eq!(op_count(&metrics, "lookup"), lookups);
+
+    // Once the TTL runs out the kernel asks again
+    std::thread::sleep(Duration::from_millis(700));
+    assert!(fs::metadata(&missing).is_err());
+    assert_eq!(op_count(&metrics, "lookup"), lookups + 1);
+
+    // Creating the name replaces the cached miss
+    fs::write(&missing, b"x").unwrap();
+    assert!(fs::metadata(&missing).is_ok());
+}
+
+#[test]
+fn missing_names_are_looked_up_every_time_without_a_negative_ttl() {
+    let fs = empty_fs(&[]);
+    let metrics = fs.metrics.clone();
+    let Some(mnt) = mount(fs) else { return };
+
+    let lookups = op_count(&metrics, "lookup");
+    for _ in 0..3 {
+        assert!(fs::metadata(mnt.join("missing")).is_err());
+    }
+    assert_eq!(op_count(&metrics, "lookup"), lookups + 3);
+}
+
+// Whether `path` names a file in `fs` holding `contents`
+fn holds(fs: &mut TreeFilesystem, path: &str, contents: &[u8]) -> bool {
+    match fs.get_inode_by_path(path.to_string()).map(|i| i.inode_num()) {
+        Some(ino) => fs.do_read(ino, 0, 1 << 20).is_ok_and(|data| data == contents),
+        None => false,
+    }
+}
+
+#[test]
+fn wal_replays_mutations_lost_to_a_crash() {
+    let wal = temp_path("crash.wal");
+    let _ = fs::remove_file(&wal);
+    let args = ["--wal", wal.to_str().unwrap()];
+    let fs = build_filesystem(&BTreeMap::new(), &options(&args)).unwrap();
+    let Some(mnt) = mount(fs) else { return };
+    fs::create_dir(mnt.join("dir")).unwrap();
+    fs::write(mnt.join("dir/kept"), b"kept").unwrap();
+    fs::write(mnt.join("dir/moved"), b"moved").unwrap();
+    fs::rename(mnt.join("dir/moved"), mnt.join("renamed")).unwrap();
+    fs::write(mnt.join("gone"), b"gone").unwrap();
+    fs::remove_file(mnt.join("gone")).unwrap();
+    chmod(&mnt.join("renamed"), 0o600);
+    // Without --snapshot nothing is flushed at unmount, so this loses as much as a crash would
+    drop(mnt);
+
+    let mut fs = build_filesystem(&BTreeMap::new(), &options(&args)).unwrap();
+    assert!(holds(&mut fs, "/dir/kept", b"kept"));
+    assert!(holds(&mut fs, "/renamed", b"moved"));
+    assert!(fs.get_inode_by_path("/dir/moved".to_string()).is_none());
+    assert!(fs.get_inode_by_path("/gone".to_string()).is_none());
+    assert_eq!(fs.get_inode_by_path("/renamed".to_string()).unwrap().attrs().perm, 0o600);
+    let _ = fs::remove_file(&wal);
+}
+
+#[test]
+fn wal_is_emptied_once_the_snapshot_on_unmount_covers_it() {
+    let wal = temp_path("flushed.wal");
+    let snapshot = temp_path("flushed.json");
+    let _ = fs::remove_file(&wal);
+    let _ = fs::remove_file(&snapshot);
+    let args = ["--wal", wal.to_str().unwrap(), "--snapshot", snapshot.to_str().unwrap()];
+    let fs = build_filesystem(&BTreeMap::new(), &options(&args)).unwrap();
+    let Some(mnt) = mount(fs) else { return };
+    fs::write(mnt.join("file"), b"first").unwrap();
+    drop(mnt);
+    assert_eq!(fs::metadata(&wal).unwrap().len(), 0);
+
+    // A crash while mounted again recovers the snapshot plus everything logged since, and
+    // nothing from before it twice
+    let fs = build_filesystem(&BTreeMap::new(), &options(&args)).unwrap();
+    let Some(mnt) = mount(fs) else { return };
+    fs::write(mnt.join("second"), b"second").unwrap();
+    let mut recovered = build_filesystem(&BTreeMap::new(), &options(&args)).unwrap();
+    assert!(holds(&mut recovered, "/file", b"first"));
+    assert!(holds(&mut recovered, "/second", b"second"));
+    drop(mnt);
+    let _ = fs::remove_file(&wal);
+    let _ = fs::remove_file(&snapshot);
+}
+
+#[test]
+fn rename_rejects_unknown_and_conflicting_flags() {
+    assert_eq!(TreeFilesystem::validate_rename_flags(1 << 10), Err(EINVAL));
+    assert_eq!(TreeFilesystem::validate_rename_flags(RENAME_WHITEOUT), Err(EINVAL));
+    assert_eq!(TreeFilesystem::validate_rename_flags(RENAME_NOREPLACE | RENAME_EXCHANGE), Err(EINVAL));
+    for flags in [0, RENAME_NOREPLACE, RENAME_EXCHANGE] {
+        assert_eq!(TreeFilesystem::validate_rename_flags(flags), Ok(()));
+    }
+
+    // The kernel turns away the others itself, but passes RENAME_WHITEOUT through (for root)
+    if !is_root() {
+        return;
+    }
+    let Some(mnt) = mount(empty_fs(&[])) else { return };
+    fs::write(mnt.join("file"), b"").unwrap();
+    let path = |name: &str| std::ffi::CString::new(mnt.join(name).to_string_lossy().as_bytes()).unwrap();
+    let (from, to) = (path("file"), path("moved"));
+    let r = unsafe { libc::renameat2(libc::AT_FDCWD, from.as_ptr(), libc::AT_FDCWD, to.as_ptr(), RENAME_WHITEOUT) };
+    assert_eq!(r, -1);
+    assert_eq!(io::Error::last_os_error().raw_os_error(), Some(EINVAL));
+    assert!(mnt.join("file").exists());
+    assert!(!mnt.join("moved").exists());
+}
+
+#[test]
+fn opening_a_symlink_itself_fails_with_eloop() {
+    let mut fs = empty_fs(&[]);
+    let file = create(&mut fs, 1, "file");
+    let link = fs.do_symlink(1, OsStr::new("link"), Path::new("file"), 0, 0).unwrap().inode_num();
+    assert_eq!(fs.open_target(link).err(), Some(ELOOP));
+    assert_eq!(fs.open_target(file).unwrap().inode_num(), file);
+
+    let Some(mnt) = mount(fs) else { return };
+    let nofollow = fs::OpenOptions::new().read(true).custom_flags(libc::O_NOFOLLOW).open(mnt.join("link"));
+    assert_eq!(nofollow.unwrap_err().raw_os_error(), Some(ELOOP));
+    // Without O_NOFOLLOW the kernel opens the target instead
+    std::os::unix::fs::symlink("file", mnt.join("followed")).unwrap();
+    fs::File::open(mnt.join("followed")).unwrap();
+}
+
+// The metrics endpoint's response to GET /metrics on `addr`
+fn scrape(addr: &str) -> String {
+    let mut stream = std::net::TcpStream::connect(addr).unwrap();
+    io::Write::write_all(&mut stream, b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut response = String::new();
+    io::Read::read_to_string(&mut stream, &mut response).unwrap();
+    response
+}
+
+#[test]
+fn metrics_endpoint_counts_reads() {
+    // A port nothing else is listening on
+    let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+    let mut fs = build_filesystem(&BTreeMap::new(), &options(&["--metrics-addr", &addr])).unwrap();
+    // Written before mounting, so none of them are in the page cache and each read reaches us
+    for name in ["a", "b", "c"] {
+        let file = create(&mut fs, 1, name);
+        fs.do_write(file, 0, name.as_bytes()).unwrap();
+    }
+    let Some(mnt) = mount(fs) else { return };
+    for name in ["a", "b", "c"] {
+        assert_eq!(fs::read(mnt.join(name)).unwrap(), name.as_bytes());
+    }
+    std::thread::sleep(Duration::from_millis(50));
+
+    let response = scrape(&addr);
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.contains("\ndnsfs_read_total 3\n"), "{}", response);
+    assert!(response.contains("\ndnsfs_inodes 4\n"), "{}", response);
+}
+
+fn cpath(path: &Path) -> std::ffi::CString {
+    std::ffi::CString::new(path.to_string_lossy().as_bytes()).unwrap()
+}
+
+#[test]
+fn xattrs_report_their_size_then_fill_a_big_enough_buffer() {
+    let Some(mnt) = mount(empty_fs(&[])) else { return };
+    fs::write(mnt.join("file"), b"").unwrap();
+    let path = cpath(&mnt.join("file"));
+    let name = std::ffi::CString::new("user.color").unwrap();
+    let value = b"blue";
+    assert_eq!(unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), value.as_ptr() as *const _, value.len(), 0) }, 0);
+
+    // The value: probe, fetch, then too small a buffer
+    let mut buf = [0u8; 64];
+    let size = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) };
+    assert_eq!(size, value.len() as isize);
+    let n = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), buf.as_mut_ptr() as *mut _, size as usize) };
+    assert_eq!(&buf[..n as usize], value);
+    assert_eq!(unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), buf.as_mut_ptr() as *mut _, 2) }, -1);
+    assert_eq!(io::Error::last_os_error().raw_os_error(), Some(libc::ERANGE));
+
+    // The name list, the same way
+    let names = b"user.color\0";
+    let size = unsafe { libc::listxattr(path.as_ptr(), std::ptr::null_mut(), 0) };
+    assert_eq!(size, names.len() as isize);
+    let n = unsafe { libc::listxattr(path.as_ptr(), buf.as_mut_ptr() as *mut _, size as usize) };
+    assert_eq!(&buf[..n as usize], names);
+    assert_eq!(unsafe { libc::listxattr(path.as_ptr(), buf.as_mut_ptr() as *mut _, 4) }, -1);
+    assert_eq!(io::Error::last_os_error().raw_os_error(), Some(libc::ERANGE));
+}
+
+#[test]
+fn protected_paths_cant_be_removed_or_renamed_even_by_root() {
+    let mut fs = empty_fs(&["--protect", "/system"]);
+    let system = mkdir(&mut fs, 1, "system");
+    create(&mut fs, system, "file");
+    let other = mkdir(&mut fs, 1, "other");
+    create(&mut fs, other, "file");
+    let Some(mnt) = mount(fs) else { return };
+
+    let errno = |r: io::Result<()>| r.err().map_or(0, |e| e.raw_os_error().unwrap());
+    assert_eq!(errno(fs::remove_dir(mnt.join("system"))), EPERM);
+    assert_eq!(errno(fs::remove_file(mnt.join("system/file"))), EPERM);
+    assert_eq!(errno(fs::rename(mnt.join("system/file"), mnt.join("file"))), EPERM);
+    assert_eq!(errno(fs::rename(mnt.join("system"), mnt.join("moved"))), EPERM);
+    assert!(mnt.join("system/file").exists());
+
+    fs::remove_file(mnt.join("other/file")).unwrap();
+    fs::remove_dir(mnt.join("other")).unwrap();
+    assert!(!mnt.join("other").exists());
+}
+
+#[test]
+fn growing_a_file_past_max_file_size_fails_with_efbig() {
+    let Some(mnt) = mount(empty_fs(&["--max-file-size", "1024"])) else { return };
+    let path = mnt.join("file");
+    let mut file = fs::OpenOptions::new().create(true).truncate(true).read(true).write(true).open(&path).unwrap();
+    io::Write::write_all(&mut file, &[b'x'; 1000]).unwrap();
+    file.sync_all().unwrap();
+
+    let errno = |r: io::Result<()>| r.err().map_or(0, |e| e.raw_os_error().unwrap());
+    assert_eq!(errno(io::Write::write_all(&mut file, &[b'x'; 1000])), EFBIG);
+    assert_eq!(errno(file.set_len(2000)), EFBIG);
+
+    // Copies between files go through write, so the limit holds for them too
+    let source = mnt.join("source");
+    fs::write(&source, [b'y'; 1000]).unwrap();
+    let source = fs::File::open(&source).unwrap();
+    let mut offset_out = 1000i64;
+    let copied = unsafe { libc::copy_file_range(source.as_raw_fd(), std::ptr::null_mut(), file.as_raw_fd(), &mut offset_out, 1000, 0) };
+    assert_eq!(copied, -1);
+    assert_eq!(io::Error::last_os_error().raw_os_error(), Some(EFBIG));
+
+    assert_eq!(fs::metadata(&path).unwrap().len(), 1000);
+}
+
+#[test]
+fn chmod_of_a_large_file_leaves_its_contents_where_they_are() {
+    let mut fs = empty_fs(&[]);
+    let file = create(&mut fs, 1, "file");
+    let body: Vec<u8> = (0..4 << 20).map(|i| i as u8).collect();
+    fs.do_write(file, 0, &body).unwrap();
+    let data = |fs: &TreeFilesystem| match fs.get_inode(file) {
+        Some(Inode::FileInode(ref a)) => (Arc::as_ptr(&a.data), Arc::strong_count(&a.data)),
+        _ => unreachable!(),
+    };
+    let before = data(&fs);
+    assert_eq!(before.1, 1);
+
+    // Copying the body would leave it somewhere else, or still shared with the copy
+    let attrs = fs.do_setattr(file, Some(0o600), None, None, None, None, None, None, None).unwrap();
+    assert_eq!(attrs.perm, 0o600);
+    assert_eq!(data(&fs), before);
+    assert_eq!(fs.get_inode(file).unwrap().attrs().perm, 0o600);
+    assert!(contents(&fs, file) == body);
+}
+
+#[test]
+fn a_symlink_isnt_a_hard_link_to_its_target() {
+    let mut fs = empty_fs(&[]);
+    let file = create(&mut fs, 1, "file");
+    fs.do_symlink(1, OsStr::new("link"), Path::new("file"), 0, 0).unwrap();
+    assert_eq!(nlink(&fs, file), 1);
+
+    fs.do_unlink(1, OsStr::new("file")).unwrap();
+    assert!(fs.get_inode(file).is_none());
+    assert!(fs.get_child(1, OsStr::new("link")).is_some());
+}
+
+// The xattr form of an ACL made of (tag, perm, id) entries
+fn acl_xattr(entries: &[(u16, u16, u32)]) -> Vec<u8> {
+    let mut acl = 2u32.to_le_bytes().to_vec();
+    for (tag, perm, id) in entries {
+        acl.extend_from_slice(&tag.to_le_bytes());
+        acl.extend_from_slice(&perm.to_le_bytes());
+        acl.extend_from_slice(&id.to_le_bytes());
+    }
+    acl
+}
+
+// An access ACL for an 0o600 file that also gives `uid` read and write
+fn acl_granting(uid: u32) -> Vec<u8> {
+    acl_xattr(&[(0x01, 6, u32::MAX), (0x02, 6, uid), (0x04, 0, u32::MAX), (0x10, 6, u32::MAX), (0x20, 0, u32::MAX)])
+}
+
+#[test]
+fn an_acl_lets_a_named_user_write_a_file_its_mode_doesnt() {
+    let mut fs = empty_fs(&["--acl"]);
+    create(&mut fs, 1, "file");
+    let Some(mnt) = mount(fs) else { return };
+    let path = cpath(&mnt.join("file"));
+    let name = std::ffi::CString::new("system.posix_acl_access").unwrap();
+    let set_acl = |uid: u32| {
+        let acl = acl_granting(uid);
+        assert_eq!(unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), acl.as_ptr() as *const _, acl.len(), 0) }, 0, "{}", io::Error::last_os_error());
+    };
+    let write = || fs::OpenOptions::new().write(true).open(mnt.join("file")).map(|_| ());
+    chmod(&mnt.join("file"), 0o600);
+
+    set_acl(NOBODY - 1);
+    let Some(errno) = as_user(write) else { return };
+    assert_eq!(errno, EACCES);
+
+    set_acl(NOBODY);
+    assert_eq!(as_user(write), Some(0));
+}
+
+#[test]
+fn rename_within_a_directory_swaps_the_old_name_for_the_new() {
+    let mut fs = empty_fs(&[]);
+    let dir = mkdir(&mut fs, 1, "dir");
+    let file = create(&mut fs, dir, "old");
+    let other = create(&mut fs, dir, "other");
+
+    fs.do_rename(dir, OsStr::new("old"), dir, OsStr::new("new")).unwrap();
+    assert!(fs.get_child(dir, OsStr::new("old")).is_none());
+    assert_eq!(fs.get_child(dir, OsStr::new("new")).unwrap().inode_num(), file);
+    assert_eq!(fs.get_inode_by_path("/dir/new".to_string()).unwrap().inode_num(), file);
+    let mut contents = fs.get_inode(dir).unwrap().contents().clone();
+    contents.sort();
+    assert_eq!(contents, vec![file, other]);
+    assert_eq!(nlink(&fs, dir), 2);
+}
+
+#[test]
+fn readdir_orders_entries_by_the_collation() {
+    let collations = [
+        ("byte", ["File3", "file1", "file10", "file2"]),
+        ("ci", ["file1", "file10", "file2", "File3"]),
+        ("version", ["File3", "file1", "file2", "file10"]),
+    ];
+    for (collation, expected) in collations {
+        let mut fs = empty_fs(&["--collation", collation]);
+        let dir = mkdir(&mut fs, 1, "dir");
+        for name in ["file10", "File3", "file2", "file1"] {
+            create(&mut fs, dir, name);
+        }
+        let Some(mnt) = mount(fs) else { return };
+        let names: Vec<String> = fs::read_dir(mnt.join("dir")).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().to_string()).collect();
+        assert_eq!(names, expected, "--collation {}", collation);
+    }
+}
+
+#[test]
+fn read_misses_load_from_the_backend_and_unlinks_delete_from_it() {
+    let mut fs = empty_fs(&[]);
+    let file = create(&mut fs, 1, "file");
+    let kept = create(&mut fs, 1, "kept");
+    let loads = Arc::new(AtomicUsize::new(0));
+    let deleted = Arc::new(Mutex::new(Vec::new()));
+    let files = BTreeMap::from([(file, b"stored".to_vec()), (kept, b"kept".to_vec())]);
+    fs.backend = Box::new(MockBackend{ files, loads: loads.clone(), deleted: deleted.clone(), delete_batches: Arc::default(), fail_stores: false });
+    fs.invalidate_data(file, 6, None);
+
+    assert_eq!(fs.do_read(file, 0, 100).unwrap(), b"stored");
+    assert_eq!(loads.load(Ordering::Relaxed), 1);
+
+    fs.do_unlink(1, OsStr::new("file")).unwrap();
+    assert_eq!(*deleted.lock().unwrap(), vec![file]);
+}
+
+#[test]
+fn o_directory_opens_only_directories() {
+    let Some(mnt) = mount(empty_fs(&[])) else { return };
+    fs::write(mnt.join("file"), b"x").unwrap();
+    fs::create_dir(mnt.join("dir")).unwrap();
+    let open_dir = |name: &str| fs::OpenOptions::new().read(true).custom_flags(libc::O_DIRECTORY).open(mnt.join(name));
+
+    assert_eq!(open_dir("file").unwrap_err().raw_os_error(), Some(libc::ENOTDIR));
+    open_dir("dir").unwrap();
+
+    // Without it a directory still opens, for fstat, but can't be read like a file
+    let mut dir = fs::File::open(mnt.join("dir")).unwrap();
+    assert!(dir.metadata().unwrap().is_dir());
+    let mut buf = [0u8; 16];
+    assert_eq!(io::Read::read(&mut dir, &mut buf).unwrap_err().raw_os_error(), Some(libc::EISDIR));
+}
+
+#[test]
+fn many_small_appends_stay_fast_and_land_in_order() {
+    let mut fs = empty_fs(&[]);
+    let file = create(&mut fs, 1, "file");
+    let mut expected = Vec::new();
+    let start = std::time::Instant::now();
+    for n in 0..10_000u32 {
+        let chunk = format!("{:05}\n", n);
+        let offset = expected.len() as u64;
+        fs.do_write(file, offset, chunk.as_bytes()).unwrap();
+        expected.extend_from_slice(chunk.as_bytes());
+    }
+    // Copying the whole file on every append takes far longer than this
+    assert!(start.elapsed() < Duration::from_secs(2), "10,000 appends took {:?}", start.elapsed());
+    assert!(contents(&fs, file) == expected);
+    assert_eq!(fs.get_inode(file).unwrap().attrs().size, 60_000);
+}
+
+#[test]
+fn a_default_acl_replaces_the_umask_for_new_entries() {
+    let mut fs = empty_fs(&["--acl"]);
+    let dir = mkdir(&mut fs, 1, "dir");
+    let plain = mkdir(&mut fs, 1, "plain");
+    // rwx for the owner and group, r-x for everyone else
+    let default_acl = acl_xattr(&[(0x01, 7, u32::MAX), (0x04, 7, u32::MAX), (0x20, 5, u32::MAX)]);
+    fs.xattrs_mut().entry(dir).or_default().insert("system.posix_acl_default".to_string(), default_acl);
+
+    let (mode, acl) = fs.creation_mode(dir, libc::S_IFREG | 0o666, 0o077);
+    assert_eq!(mode, libc::S_IFREG | 0o664);
+    let acl = crate::acl::Acl::parse(&acl.unwrap()).unwrap();
+    assert_eq!(acl.mode_bits(), 0o664);
+
+    // Without one the umask still applies
+    assert_eq!(fs.creation_mode(plain, libc::S_IFREG | 0o666, 0o077), (libc::S_IFREG | 0o600, None));
+
+    // The same through the kernel, whatever the umask here
+    let Some(mnt) = mount(fs) else { return };
+    fs::write(mnt.join("dir/file"), b"").unwrap();
+    fs::create_dir(mnt.join("dir/sub")).unwrap();
+    assert_eq!(fs::metadata(mnt.join("dir/file")).unwrap().mode() & 0o777, 0o664);
+    assert_eq!(fs::metadata(mnt.join("dir/sub")).unwrap().mode() & 0o777, 0o775);
+}
+
+#[test]
+fn lookup_finds_fifos_and_sockets_with_their_kind() {
+    let mut fs = empty_fs(&[]);
+    let dir = mkdir(&mut fs, 1, "dir");
+    fs.do_create(dir, OsStr::new("fifo"), (libc::S_IFIFO | 0o644) as u16, 0, 0).unwrap();
+    fs.do_create(dir, OsStr::new("socket"), (libc::S_IFSOCK | 0o644) as u16, 0, 0).unwrap();
+    assert_eq!(fs.lookup_entry(dir, OsStr::new("fifo")).unwrap().attrs().kind, FileType::NamedPipe);
+    assert_eq!(fs.lookup_entry(dir, OsStr::new("socket")).unwrap().attrs().kind, FileType::Socket);
+
+    let Some(mnt) = mount(fs) else { return };
+    assert!(fs::metadata(mnt.join("dir/fifo")).unwrap().file_type().is_fifo());
+    let made = cpath(&mnt.join("dir/made"));
+    assert_eq!(unsafe { libc::mkfifo(made.as_ptr(), 0o644) }, 0);
+    assert!(fs::metadata(mnt.join("dir/made")).unwrap().file_type().is_fifo());
+
+    // A hard link's second name is taken just as much as its first
+    fs::hard_link(mnt.join("dir/made"), mnt.join("dir/alias")).unwrap();
+    let alias = cpath(&mnt.join("dir/alias"));
+    assert_eq!(unsafe { libc::mkfifo(alias.as_ptr(), 0o644) }, -1);
+    assert_eq!(io::Error::last_os_error().raw_os_error(), Some(libc::EEXIST));
+}
+
+#[test]
+fn export_json_lists_each_inodes_metadata() {
+    let Some(mnt) = mount(empty_fs(&[])) else { return };
+    fs::create_dir(mnt.join("dir")).unwrap();
+    fs::write(mnt.join("dir/file"), b"twelve bytes").unwrap();
+    chmod(&mnt.join("dir/file"), 0o640);
+    std::os::unix::fs::symlink("file", mnt.join("dir/link")).unwrap();
+
+    let dir = fs::File::open(mnt.join("dir")).unwrap();
+    let mut buf = vec![0u8; 0x3fff];
+    let r = unsafe { libc::ioctl(dir.as_raw_fd(), crate::ioctl::DNSFS_IOC_EXPORT_JSON as _, buf.as_mut_ptr()) };
+    assert_eq!(r, 0, "{}", io::Error::last_os_error());
+    let end = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    let entries: serde_json::Value = serde_json::from_slice(&buf[..end]).unwrap();
+    let entry = |path: &str| entries.as_array().unwrap().iter().find(|e| e["path"] == path).cloned().unwrap_or_else(|| panic!("no {} in {}", path, entries));
+
+    let file = entry("/dir/file");
+    assert_eq!(file["kind"], "file");
+    assert_eq!(file["size"], 12);
+    assert_eq!(file["mode"], 0o640);
+    assert_eq!(file["nlink"], 1);
+    assert_eq!(entry("/dir")["kind"], "directory");
+    assert_eq!(entry("/dir/link")["target"], "file");
+    // Only what's under the directory the ioctl was made on
+    assert_eq!(entries.as_array().unwrap().len(), 3);
+}
+
+#[test]
+fn setattr_sets_the_birth_and_change_times_it_is_given() {
+    let mut fs = empty_fs(&[]);
+    let file = create(&mut fs, 1, "file");
+    let born = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+    let changed = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+
+    fs.do_setattr(file, None, None, None, None, None, None, Some(changed), Some(born)).unwrap();
+    let attrs = fs.do_getattr(file).unwrap();
+    assert_eq!(attrs.crtime, born);
+    assert_eq!(attrs.ctime, changed);
+
+    // Leaving them out leaves them alone
+    fs.do_setattr(file, Some(0o600), None, None, None, None, None, None, None).unwrap();
+    assert_eq!(fs.do_getattr(file).unwrap().crtime, born);
+}
+
+#[test]
+fn stat_refresh_reports_a_size_that_changed_in_the_backend() {
+    for (args, expected) in [(vec!["--stat-refresh"], 20), (vec![], 3)] {
+        let mut fs = empty_fs(&args);
+        let file = create(&mut fs, 1, "file");
+        let missing = create(&mut fs, 1, "missing");
+        fs.do_write(file, 0, b"abc").unwrap();
+        fs.do_write(missing, 0, b"abcd").unwrap();
+        // The record grew upstream, and the other can't be sized at all
+        let files = BTreeMap::from([(file, vec![b'x'; 20])]);
+        fs.backend = Box::new(MockBackend{ files, loads: Arc::default(), deleted: Arc::default(), delete_batches: Arc::default(), fail_stores: false });
+
+        assert_eq!(fs.do_getattr(file).unwrap().size, expected, "{:?}", args);
+        assert_eq!(fs.do_getattr(missing).unwrap().size, 4, "{:?}", args);
+    }
+}
+
+#[test]
+fn renaming_something_onto_itself_succeeds_and_changes_nothing() {
+    let Some(mnt) = mount(empty_fs(&[])) else { return };
+    fs::create_dir(mnt.join("a")).unwrap();
+    fs::write(mnt.join("a/b"), b"b").unwrap();
+    let before = fs::metadata(mnt.join("a/b")).unwrap();
+
+    fs::rename(mnt.join("a/b"), mnt.join("a/b")).unwrap();
+    fs::rename(mnt.join("a"), mnt.join("a")).unwrap();
+    let after = fs::metadata(mnt.join("a/b")).unwrap();
+    assert_eq!(after.ino(), before.ino());
+    assert_eq!(after.ctime_nsec(), before.ctime_nsec());
+    assert_eq!(fs::read(mnt.join("a/b")).unwrap(), b"b");
+    let names: Vec<String> = fs::read_dir(mnt.join("a")).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().to_string()).collect();
+    assert_eq!(names, vec!["b"]);
+}
+
+// Everything logged at warn or above since the first call, for tests that check a warning went
+// out. A process only gets one logger, so it's installed once and shared
+fn warnings() -> &'static Mutex<Vec<String>> {
+    struct WarningLog(Mutex<Vec<String>>);
+    impl log::Log for WarningLog {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            metadata.level() <= log::Level::Warn
+        }
+        fn log(&self, record: &log::Record) {
+            if self.enabled(record.metadata()) {
+                self.0.lock().unwrap().push(record.args().to_string());
+            }
+        }
+        fn flush(&self) {}
+    }
+    static LOG: WarningLog = WarningLog(Mutex::new(Vec::new()));
+    static INSTALL: std::sync::Once = std::sync::Once::new();
+    INSTALL.call_once(|| {
+        log::set_logger(&LOG).unwrap();
+        log::set_max_level(log::LevelFilter::Warn);
+    });
+    &LOG.0
+}
+
+// Takes `delay` over every load
+#[derive(Debug)]
+struct SlowBackend {
+    delay: Duration,
+}
+
+impl StorageBackend for SlowBackend {
+    fn load(&self, _ino: u64, _offset: u64, size: u64) -> io::Result<Vec<u8>> {
+        std::thread::sleep(self.delay);
+        Ok(vec![b's'; size as usize])
+    }
+
+    fn store(&self, _ino: u64, _data: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn delete(&self, _ino: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn size(&self, _ino: u64) -> io::Result<Option<u64>> {
+        Ok(None)
+    }
+}
+
+#[test]
+fn a_read_slower_than_slow_op_ms_logs_a_warning() {
+    let warnings = warnings();
+    let mut fs = empty_fs(&["--slow-op-ms", "100"]);
+    let slow = create(&mut fs, 1, "slow");
+    fs.backend = Box::new(SlowBackend{ delay: Duration::from_millis(200) });
+    fs.invalidate_data(slow, 4, None);
+    let Some(mnt) = mount(fs) else { return };
+
+    assert_eq!(fs::read(mnt.join("slow")).unwrap(), b"ssss");
+    std::thread::sleep(Duration::from_millis(50));
+    let expected = format!("Slow operation: read(ino={})", slow);
+    let logged = warnings.lock().unwrap();
+    assert!(logged.iter().any(|w| w.starts_with(&expected)), "no {:?} in {:?}", expected, logged);
+}
+
+#[test]
+fn a_cloned_file_shares_its_contents_until_one_copy_is_written() {
+    let mut fs = empty_fs(&[]);
+    let file = create(&mut fs, 1, "file");
+    fs.do_write(file, 0, &vec![b'a'; 4 << 20]).unwrap();
+    let original = fs.get_inode(file).unwrap().clone();
+    let mut copy = original.clone();
+    let data = |inode: &Inode| match inode {
+        Inode::FileInode(ref a) => a.data.clone(),
+        _ => unreachable!(),
+    };
+    assert!(Arc::ptr_eq(&data(&original), &data(&copy)));
+
+    copy.write_data(b"b", 0);
+    assert!(!Arc::ptr_eq(&data(&original), &data(&copy)));
+    assert_eq!(original.data().read(0, 2), b"aa");
+    assert_eq!(copy.data().read(0, 2), b"ba");
+    assert_eq!(copy.data().len(), 4 << 20);
+}
+
+#[test]
+fn rmdir_needs_an_empty_directory_and_dot_entries_dont_count() {
+    let mut fs = empty_fs(&[]);
+    let empty = mkdir(&mut fs, 1, "empty");
+    let full = mkdir(&mut fs, 1, "full");
+    create(&mut fs, full, "file");
+    assert!(fs.get_inode(empty).unwrap().contents().is_empty());
+    let Some(mnt) = mount(fs) else { return };
+
+    // readdir makes up "." and ".." on the fly
+    assert_eq!(fs::read_dir(mnt.join("empty")).unwrap().count(), 0);
+    fs::remove_dir(mnt.join("empty")).unwrap();
+    assert!(!mnt.join("empty").exists());
+    assert_eq!(fs::remove_dir(mnt.join("full")).unwrap_err().raw_os_error(), Some(libc::ENOTEMPTY));
+    assert!(mnt.join("full/file").exists());
+}
+
+// Always the same time
+#[derive(Debug)]
+struct FixedClock(SystemTime);
+
+impl crate::clock::Clock for FixedClock {
+    fn now(&self) -> SystemTime {
+        self.0
+    }
+}
+
+#[test]
+fn timestamps_come_from_the_filesystems_clock() {
+    let at = UNIX_EPOCH + Duration::new(1_234_567_890, 123);
+    let mut fs = empty_fs(&[]);
+    fs.clock = Box::new(FixedClock(at));
+    let file = create(&mut fs, 1, "file");
+    fs.do_write(file, 0, b"x").unwrap();
+    let attrs = fs.do_getattr(file).unwrap();
+    assert_eq!((attrs.crtime, attrs.mtime, attrs.ctime, attrs.atime), (at, at, at, at));
+
+    // --fixed-clock starts where it's told and ticks a nanosecond per reading
+    let mut fs = empty_fs(&["--fixed-clock", "1000"]);
+    let first = create(&mut fs, 1, "first");
+    let second = create(&mut fs, 1, "second");
+    let (first, second) = (fs.do_getattr(first).unwrap().crtime, fs.do_getattr(second).unwrap().crtime);
+    assert!(first >= UNIX_EPOCH + Duration::from_secs(1000));
+    assert!(first < UNIX_EPOCH + Duration::from_secs(1001));
+    assert!(second > first);
+}
+
+// Whether the kernel will map `file` shared, which it refuses for a handle opened for direct I/O
+fn maps_shared(file: &fs::File) -> bool {
+    let addr = unsafe { libc::mmap(std::ptr::null_mut(), 4096, libc::PROT_READ, libc::MAP_SHARED, file.as_raw_fd(), 0) };
+    if addr == libc::MAP_FAILED {
+        return false;
+    }
+    unsafe { libc::munmap(addr, 4096) };
+    true
+}
+
+#[test]
+fn create_hands_back_the_same_open_flags_and_access_as_open() {
+    for (args, direct) in [(vec!["--direct-io"], true), (vec![], false)] {
+        let Some(mnt) = mount(empty_fs(&args)) else { return };
+        // The handle create makes is used for both reading and writing
+        let mut file = fs::OpenOptions::new().create_new(true).read(true).write(true).open(mnt.join("file")).unwrap();
+        io::Write::write_all(&mut file, b"created").unwrap();
+        io::Seek::seek(&mut file, io::SeekFrom::Start(0)).unwrap();
+        let mut read = String::new();
+        io::Read::read_to_string(&mut file, &mut read).unwrap();
+        assert_eq!(read, "created");
+        assert_eq!(maps_shared(&file), !direct, "{:?}", args);
+    }
+}
+
+#[test]
+fn watch_backing_picks_up_changes_made_to_the_backing_directory() {
+    let backing = temp_path("watch-backing");
+    let _ = fs::remove_dir_all(&backing);
+    let args = ["--backend", "dir", "--backing-dir", backing.to_str().unwrap(), "--watch-backing"];
+    let fs = build_filesystem(&BTreeMap::new(), &options(&args)).unwrap();
+    let Some(mnt) = mount(fs) else { return };
+    fs::write(mnt.join("changed"), b"old").unwrap();
+    fs::write(mnt.join("deleted"), b"doomed").unwrap();
+    assert_eq!(fs::read(mnt.join("changed")).unwrap(), b"old");
+    let backing_file = |name: &str| backing.join(fs::metadata(mnt.join(name)).unwrap().ino().to_string());
+
+    fs::write(backing_file("changed"), b"new and longer").unwrap();
+    assert_eq!(fs::metadata(mnt.join("changed")).unwrap().len(), 14);
+    assert_eq!(fs::read(mnt.join("changed")).unwrap(), b"new and longer");
+
+    fs::remove_file(backing_file("deleted")).unwrap();
+    assert_eq!(fs::read(mnt.join("deleted")).unwrap_err().raw_os_error(), Some(libc::ENOENT));
+    drop(mnt);
+    let _ = fs::remove_dir_all(&backing);
+}
+
+#[test]
+fn io_overlapping_another_owners_lock_on_a_mandatory_locking_file_fails_with_eagain() {
+    // Lock owners only come along with direct I/O
+    let Some(mnt) = mount(empty_fs(&["--direct-io"])) else { return };
+    let path = mnt.join("file");
+    fs::write(&path, vec![b'x'; 200]).unwrap();
+    // setgid without group execute marks the file for mandatory locking
+    chmod(&path, 0o2666);
+    assert_eq!(fs::metadata(&path).unwrap().mode() & 0o7777, 0o2666);
+
+    let file = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    lock.l_type = libc::F_WRLCK as i16;
+    lock.l_whence = libc::SEEK_SET as i16;
+    lock.l_start = 0;
+    lock.l_len = 100;
+    assert_eq!(unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETLK, &lock) }, 0);
+
+    // A forked child is another lock owner
+    let other_owner = |offset: u64, write: bool| as_user(|| {
+        let file = fs::OpenOptions::new().read(true).write(true).open(&path)?;
+        if write { file.write_at(b"y", offset).map(drop) } else { file.read_at(&mut [0; 1], offset).map(drop) }
+    });
+    let Some(code) = other_owner(50, true) else { return };
+    assert_eq!(code, EAGAIN);
+    assert_eq!(other_owner(50, false), Some(EAGAIN));
+    // Outside the locked range, and for the lock's own owner, I/O goes ahead
+    assert_eq!(other_owner(150, true), Some(0));
+    file.write_at(b"z", 50).unwrap();
+
+    lock.l_type = libc::F_UNLCK as i16;
+    assert_eq!(unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETLK, &lock) }, 0);
+    assert_eq!(other_owner(50, true), Some(0));
+    let body = fs::read(&path).unwrap();
+    assert_eq!((body[50], body[150]), (b'y', b'y'));
+    drop(file);
+}
+
+#[test]
+fn resolving_a_symlink_again_comes_from_the_cache_until_the_tree_changes() {
+    let mut fs = empty_fs(&[]);
+    let file = create(&mut fs, 1, "file");
+    fs.do_write(file, 0, b"target").unwrap();
+    fs.do_symlink(1, OsStr::new("inner"), Path::new("file"), 0, 0).unwrap();
+    let outer = fs.do_symlink(1, OsStr::new("outer"), Path::new("inner"), 0, 0).unwrap().inode_num();
+    let steps = |fs: &TreeFilesystem| fs.metrics.symlink_steps.load(Ordering::Relaxed);
+    let hits = |fs: &TreeFilesystem| fs.metrics.symlink_cache_hits.load(Ordering::Relaxed);
+
+    assert_eq!(fs.do_read(outer, 0, 6).unwrap(), b"target");
+    let walked = steps(&fs);
+    assert!(walked >= 2, "resolving outer -> inner -> file took {} steps", walked);
+    assert_eq!(fs.do_read(outer, 0, 6).unwrap(), b"target");
+    assert_eq!(steps(&fs), walked);
+    assert_eq!(hits(&fs), 1);
+
+    // Removing anything could break the chain, so the next read walks it again
+    create(&mut fs, 1, "other");
+    fs.do_unlink(1, OsStr::new("other")).unwrap();
+    assert_eq!(fs.do_read(outer, 0, 6).unwrap(), b"target");
+    assert_eq!(steps(&fs), walked * 2);
+    assert_eq!(hits(&fs), 1);
+}
+
+#[test]
+fn create_under_a_parent_that_doesnt_exist_fails_with_enoent() {
+    let mut fs = empty_fs(&[]);
+    let gone = mkdir(&mut fs, 1, "gone");
+    fs.do_rmdir(1, OsStr::new("gone")).unwrap();
+    for parent in [gone, 9999] {
+        assert_eq!(fs.do_create(parent, OsStr::new("file"), 0o644, 0, 0).err(), Some(libc::ENOENT));
+    }
+}
+
+#[test]
+fn lookup_of_dot_and_dot_dot_finds_the_directory_and_its_parent() {
+    // Path walks resolve these in the kernel, so only the tree can be asked directly
+    let mut fs = empty_fs(&[]);
+    let dir = mkdir(&mut fs, 1, "dir");
+    let sub = mkdir(&mut fs, dir, "sub");
+    let lookup = |parent: u64, name: &str| fs.lookup_entry(parent, OsStr::new(name)).map(|i| i.inode_num());
+    assert_eq!(lookup(sub, "."), Some(sub));
+    assert_eq!(lookup(sub, ".."), Some(dir));
+    assert_eq!(lookup(dir, ".."), Some(1));
+    // The root is its own parent
+    assert_eq!(lookup(1, ".."), Some(1));
+    assert_eq!(lookup(9999, "."), None);
+}
+
+#[test]
+fn a_write_far_past_the_end_leaves_a_hole_that_takes_no_memory() {
+    let mut fs = empty_fs(&[]);
+    let file = create(&mut fs, 1, "file");
+    fs.do_write(file, 1_000_000_000, b"tail").unwrap();
+
+    assert_eq!(fs.do_getattr(file).unwrap().size, 1_000_000_004);
+    let allocated = fs.get_inode(file).unwrap().data().allocated();
+    assert!(allocated < 1 << 20, "{} bytes allocated for a 4 byte write", allocated);
+
+    assert_eq!(fs.do_read(file, 0, 4096).unwrap(), vec![0; 4096]);
+    assert_eq!(fs.do_read(file, 999_999_998, 6).unwrap(), b"\0\0tail");
+}
+
+#[test]
+fn a_process_with_cap_dac_override_passes_permission_checks_under_honor_capabilities() {
+    for (args, expected) in [(&["--honor-capabilities"][..], 0), (&[][..], EACCES)] {
+        let Some(mnt) = mount(empty_fs(args)) else { return };
+        let path = mnt.join("locked");
+        fs::write(&path, b"secret").unwrap();
+        chmod(&path, 0o000);
+        let read = || fs::read(&path).map(drop);
+
+        let Some(code) = as_user_with_caps(&[CAP_DAC_OVERRIDE], read) else { return };
+        assert_eq!(code, expected, "with CAP_DAC_OVERRIDE and {:?}", args);
+        assert_eq!(as_user_with_caps(&[CAP_DAC_READ_SEARCH], read), Some(expected), "with CAP_DAC_READ_SEARCH and {:?}", args);
+        // Read-search doesn't stretch to writing
+        assert_eq!(as_user_with_caps(&[CAP_DAC_READ_SEARCH], || fs::write(&path, b"x")), Some(EACCES));
+        assert_eq!(as_user(read), Some(EACCES));
+    }
+}
+
+#[test]
+fn unlink_of_a_directory_fails_with_eisdir_and_leaves_it_whole() {
+    // The kernel refuses this itself, so only the tree can be asked directly
+    let mut fs = empty_fs(&[]);
+    let dir = mkdir(&mut fs, 1, "dir");
+    let child = create(&mut fs, dir, "child");
+    fs.do_write(child, 0, b"kept").unwrap();
+
+    assert_eq!(fs.do_unlink(1, OsStr::new("dir")), Err(libc::EISDIR));
+    assert_eq!(fs.lookup_entry(1, OsStr::new("dir")).map(|i| i.inode_num()), Some(dir));
+    assert_eq!(fs.lookup_entry(dir, OsStr::new("child")).map(|i| i.inode_num()), Some(child));
+    assert_eq!(contents(&fs, child), b"kept");
+    assert_eq!(nlink(&fs, 1), 3);
+}
+
+#[test]
+fn keep_snapshots_prunes_all_but_the_newest_generations() {
+    let dir = temp_path("keep-snapshots");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let snapshot = dir.join("tree.json");
+    let path = snapshot.to_str().unwrap();
+
+    let mut fs = empty_fs(&["--snapshot", path, "--keep-snapshots", "2"]);
+    for name in ["first", "second", "third"] {
+        create(&mut fs, 1, name);
+        fs.save_to(path).unwrap();
+        // Generations are named by the millisecond they were taken in
+        std::thread::sleep(Duration::from_millis(5));
+    }
+
+    let mut generations: Vec<String> = fs::read_dir(&dir).unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+        .filter(|n| n != "tree.json")
+        .collect();
+    generations.sort();
+    assert_eq!(generations.len(), 2, "{:?}", generations);
+    assert!(snapshot_shows(&snapshot, "third", b""));
+
+    // The older one kept is the second save, which --restore-snapshot mounts by name
+    let restored = build_filesystem(&BTreeMap::new(), &options(&["--snapshot", path, "--restore-snapshot", &generations[0]])).unwrap();
+    assert!(restored.lookup_entry(1, OsStr::new("second")).is_some());
+    assert!(restored.lookup_entry(1, OsStr::new("third")).is_none());
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn files_advertise_the_backends_io_size_as_their_blksize() {
+    // Nothing is stored until a write is flushed, so no server needs to be listening
+    let args = ["--backend", "dns", "--dns-server", "127.0.0.1:53", "--dns-zone", "files.example"];
+    let mut dns = build_filesystem(&BTreeMap::new(), &options(&args)).unwrap();
+    let file = create(&mut dns, 1, "file");
+    assert_eq!(dns.do_getattr(file).unwrap().blksize, crate::dns::CHUNK_SIZE as u32);
+    // Directories have no contents to transfer, so they keep the filesystem's
+    let dir = mkdir(&mut dns, 1, "dir");
+    assert_eq!(dns.do_getattr(dir).unwrap().blksize, dns.block_size);
+
+    let mut memory = empty_fs(&[]);
+    let file = create(&mut memory, 1, "file");
+    assert_eq!(memory.do_getattr(file).unwrap().blksize, memory.block_size);
+}
+
+#[test]
+fn a_directory_opens_for_fstat_but_cant_be_read_as_a_file() {
+    let Some(mnt) = mount(empty_fs(&[])) else { return };
+    fs::create_dir(mnt.join("dir")).unwrap();
+    let dir = fs::File::open(mnt.join("dir")).unwrap();
+    let meta = dir.metadata().unwrap();
+    assert!(meta.is_dir());
+    assert_eq!(meta.ino(), fs::metadata(mnt.join("dir")).unwrap().ino());
+    assert_eq!(dir.read_at(&mut [0; 16], 0).unwrap_err().raw_os_error(), Some(libc::EISDIR));
+    assert_eq!(dir.write_at(b"x", 0).unwrap_err().raw_os_error(), Some(libc::EBADF));
+}
+
+#[test]
+fn loaded_files_and_directories_take_the_default_modes() {
+    let seed = BTreeMap::from([("/zone".to_string(), "served".to_string())]);
+    let fs = TreeFilesystem::new(&seed, &options(&["--default-file-mode", "0640", "--default-dir-mode", "0750"]));
+    let file = fs.lookup_entry(1, OsStr::new("zone")).unwrap();
+    assert_eq!(file.attrs().perm, 0o640);
+    assert_eq!(fs.get_inode(1).unwrap().attrs().perm, 0o750);
+
+    for bad in ["0999", "17777", "rw-r-----"] {
+        let args = ["--default-file-mode", bad, "/mnt/dnsfs-test"].map(String::from);
+        assert!(Options::parse(args.into_iter()).is_err(), "{} was accepted", bad);
+    }
+}
+
+#[test]
+fn create_without_o_excl_truncates_an_existing_file_under_o_trunc() {
+    let fs = empty_fs(&[]);
+    let metrics = fs.metrics.clone();
+    let Some(mnt) = mount(fs) else { return };
+    let path = mnt.join("file");
+    fs::write(&path, b"hello").unwrap();
+
+    let setattrs = op_count(&metrics, "setattr");
+    let file = fs::OpenOptions::new().write(true).create(true).truncate(true).open(&path).unwrap();
+    assert_eq!(file.metadata().unwrap().len(), 0);
+    drop(file);
+    assert_eq!(fs::read(&path).unwrap(), b"");
+    // The open truncated it, not a separate setattr from the kernel
+    assert_eq!(op_count(&metrics, "setattr"), setattrs);
+}
+
+#[test]
+fn remove_tree_ioctl_empties_a_directory_in_one_call() {
+    let mut fs = empty_fs(&[]);
+    let deleted = Arc::new(Mutex::new(Vec::new()));
+    let delete_batches = Arc::new(AtomicUsize::new(0));
+    fs.backend = Box::new(MockBackend{ files: BTreeMap::new(), loads: Arc::default(), deleted: deleted.clone(), delete_batches: delete_batches.clone(), fail_stores: false });
+    let Some(mnt) = mount(fs) else { return };
+    fs::create_dir_all(mnt.join("top/one/two")).unwrap();
+    for file in ["top/f1", "top/one/f2", "top/one/two/f3"] {
+        fs::write(mnt.join(file), file).unwrap();
+    }
+    fs::write(mnt.join("beside"), b"kept").unwrap();
+
+    let top = fs::File::open(mnt.join("top")).unwrap();
+    let mut removed = 0u64;
+    let r = unsafe { libc::ioctl(top.as_raw_fd(), crate::ioctl::DNSFS_IOC_REMOVE_TREE as _, &mut removed) };
+    assert_eq!(r, 0, "{}", io::Error::last_os_error());
+    drop(top);
+
+    // Two directories and three files, with the directory it was issued on left behind empty
+    assert_eq!(removed, 5);
+    assert_eq!(fs::read_dir(mnt.join("top")).unwrap().count(), 0);
+    assert_eq!(fs::metadata(mnt.join("top")).unwrap().nlink(), 2);
+    assert!(!mnt.join("top/one/two/f3").exists());
+    assert_eq!(fs::read(mnt.join("beside")).unwrap(), b"kept");
+    // All three files' contents went from the backend in a single batch
+    assert_eq!(delete_batches.load(Ordering::Relaxed), 1);
+    assert_eq!(deleted.lock().unwrap().len(), 3);
+}
+
+#[test]
+fn truncate_shrinks_or_zero_extends_the_contents() {
+    let mut fs = empty_fs(&[]);
+    let file = create(&mut fs, 1, "file");
+    fs.do_write(file, 0, &[b'x'; 1000]).unwrap();
+    let attrs = fs.do_setattr(file, None, None, None, Some(3), None, None, None, None).unwrap();
+    assert_eq!((attrs.size, attrs.blocks), (3, 1));
+    assert_eq!(fs.do_read(file, 0, 1000).unwrap(), b"xxx");
+
+    let Some(mnt) = mount(fs) else { return };
+    let path = mnt.join("file");
+    fs::write(&path, b"hello").unwrap();
+    let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+    file.set_len(2).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), b"he");
+    file.set_len(4).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), b"he\0\0");
+}
+
+#[test]
+fn deny_symlinks_refuses_new_links_and_following_old_ones() {
+    let mut fs = empty_fs(&["--deny-symlinks"]);
+    let file = create(&mut fs, 1, "file");
+    fs.do_write(file, 0, b"target").unwrap();
+    fs.do_symlink(1, OsStr::new("link"), Path::new("file"), 0, 0).unwrap();
+    let Some(mnt) = mount(fs) else { return };
+
+    assert_eq!(std::os::unix::fs::symlink("file", mnt.join("new")).unwrap_err().raw_os_error(), Some(EPERM));
+    assert!(!mnt.join("new").exists());
+    assert_eq!(fs::read(mnt.join("link")).unwrap_err().raw_os_error(), Some(ELOOP));
+    assert_eq!(fs::read_link(mnt.join("link")).unwrap_err().raw_os_error(), Some(ELOOP));
+    // The link itself is still there to see, just not to follow
+    assert!(fs::symlink_metadata(mnt.join("link")).unwrap().file_type().is_symlink());
+    assert_eq!(fs::read(mnt.join("file")).unwrap(), b"target");
+}
+
+#[test]
+fn opening_with_o_trunc_empties_the_file() {
+    let fs = empty_fs(&[]);
+    let metrics = fs.metrics.clone();
+    let Some(mnt) = mount(fs) else { return };
+    let path = mnt.join("file");
+    fs::write(&path, b"some contents").unwrap();
+
+    let setattrs = op_count(&metrics, "setattr");
+    let file = fs::OpenOptions::new().write(true).truncate(true).open(&path).unwrap();
+    assert_eq!(file.metadata().unwrap().len(), 0);
+    drop(file);
+    assert_eq!(fs::read(&path).unwrap(), b"");
+    // open did the truncating itself
+    assert_eq!(op_count(&metrics, "setattr"), setattrs);
+
+    // Reading can't truncate
+    fs::write(&path, b"kept").unwrap();
+    let read_only = fs::OpenOptions::new().read(true).custom_flags(libc::O_TRUNC).open(&path);
+    assert_eq!(read_only.unwrap_err().raw_os_error(), Some(EACCES));
+    assert_eq!(fs::read(&path).unwrap(), b"kept");
+}
+
+#[test]
+fn mkdir_in_a_setgid_directory_inherits_its_group_and_setgid_bit() {
+    let Some(mnt) = mount(empty_fs(&[])) else { return };
+    let parent = mnt.join("shared");
+    fs::create_dir(&parent).unwrap();
+    std::os::unix::fs::chown(&parent, None, Some(4321)).unwrap();
+    chmod(&parent, 0o2777);
+
+    // The umask is the whole process's, so set it only in the child making the directory
+    let made = as_user(|| {
+        unsafe { libc::umask(0o027) };
+        fs::create_dir(parent.join("child"))
+    });
+    let Some(code) = made else { return };
+    assert_eq!(code, 0);
+
+    let child = fs::metadata(parent.join("child")).unwrap();
+    assert_eq!((child.uid(), child.gid()), (NOBODY, 4321));
+    assert_eq!(child.mode() & 0o7777, 0o2750);
+}
+
+#[test]
+fn backend_status_file_reports_failed_stores() {
+    let mut fs = empty_fs(&[]);
+    let file = create(&mut fs, 1, "file");
+    fs.backend = Box::new(MockBackend{ files: BTreeMap::new(), loads: Arc::default(), deleted: Arc::default(), delete_batches: Arc::default(), fail_stores: true });
+    for _ in 0..3 {
+        fs.do_write(file, 0, b"lost").unwrap();
+        assert_eq!(fs.flush_data(file), Err(EIO));
+    }
+    let Some(mnt) = mount(fs) else { return };
+
+    let status = fs::read_to_string(mnt.join(".dnsfs/backend")).unwrap();
+    assert!(status.contains("reachable: yes\n"), "{}", status);
+    let last_error = status.lines().find(|l| l.starts_with("last_error: ")).unwrap();
+    assert!(last_error.contains("backend unavailable"), "{}", status);
+    assert!(status.contains("stores: 3 (3 failed, 0.0% succeeded)\n"), "{}", status);
+
+    // ls -l asks for the ACLs of everything it lists, which the status entries simply don't have
+    for entry in [".dnsfs", ".dnsfs/backend"] {
+        let path = cpath(&mnt.join(entry));
+        let r = unsafe { libc::lgetxattr(path.as_ptr(), c"system.posix_acl_access".as_ptr(), std::ptr::null_mut(), 0) };
+        assert_eq!((r, io::Error::last_os_error().raw_os_error()), (-1, Some(libc::ENODATA)), "{}", entry);
+        assert_eq!(unsafe { libc::llistxattr(path.as_ptr(), std::ptr::null_mut(), 0) }, 0, "{}", entry);
+    }
+}
+
+#[test]
+fn open_ignores_flags_that_arent_an_access_mode() {
+    let Some(mnt) = mount(empty_fs(&[])) else { return };
+    let path = mnt.join("file");
+    fs::write(&path, b"contents").unwrap();
+    let flags = libc::O_CLOEXEC | libc::O_NONBLOCK | libc::O_NOCTTY | libc::O_LARGEFILE;
+
+    let file = fs::OpenOptions::new().read(true).custom_flags(flags).open(&path).unwrap();
+    assert_eq!(io::read_to_string(&file).unwrap(), "contents");
+    let file = fs::OpenOptions::new().write(true).custom_flags(flags).open(&path).unwrap();
+    file.write_at(b"C", 0).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), b"Contents");
+}
+
+#[test]
+fn create_places_files_two_and_three_levels_deep() {
+    let mut fs = empty_fs(&[]);
+    let a = mkdir(&mut fs, 1, "a");
+    let b = mkdir(&mut fs, a, "b");
+    let two = create(&mut fs, a, "two");
+    let three = create(&mut fs, b, "three");
+    assert_eq!(fs.get_inode(two).unwrap().path(), "/a/two");
+    assert_eq!(fs.get_inode(three).unwrap().path(), "/a/b/three");
+    assert_eq!(fs.get_inode_by_path("/a/b/three".to_string()).map(|i| i.inode_num()), Some(three));
+    assert_eq!(fs.lookup_entry(b, OsStr::new("three")).map(|i| i.inode_num()), Some(three));
+
+    let Some(mnt) = mount(fs) else { return };
+    fs::write(mnt.join("a/b/c"), b"deep").unwrap();
+    assert_eq!(fs::read(mnt.join("a/b/c")).unwrap(), b"deep");
+    assert_eq!(fs::read_dir(mnt.join("a/b")).unwrap().count(), 2);
+    // Without the directories in between there's nowhere to put it
+    assert_eq!(fs::write(mnt.join("x/y/z"), b"").unwrap_err().raw_os_error(), Some(libc::ENOENT));
+}
+
+#[test]
+fn rename_exchange_swaps_two_entries_contents_and_all() {
+    let mut fs = empty_fs(&[]);
+    let dir = mkdir(&mut fs, 1, "dir");
+    let a = create(&mut fs, 1, "a");
+    let b = create(&mut fs, dir, "b");
+    fs.do_write(a, 0, b"first").unwrap();
+    fs.do_write(b, 0, b"second").unwrap();
+    fs.do_exchange(1, OsStr::new("a"), dir, OsStr::new("b")).unwrap();
+    assert_eq!(fs.lookup_entry(1, OsStr::new("a")).map(|i| i.inode_num()), Some(b));
+    assert_eq!(fs.lookup_entry(dir, OsStr::new("b")).map(|i| i.inode_num()), Some(a));
+    assert_eq!(fs.get_inode(a).unwrap().path(), "/dir/b");
+    assert_eq!(fs.get_inode(b).unwrap().path(), "/a");
+    assert_eq!(fs.do_exchange(1, OsStr::new("a"), 1, OsStr::new("missing")), Err(libc::ENOENT));
+
+    let Some(mnt) = mount(fs) else { return };
+    let exchange = |from: &str, to: &str| {
+        let (from, to) = (cpath(&mnt.join(from)), cpath(&mnt.join(to)));
+        let r = unsafe { libc::renameat2(libc::AT_FDCWD, from.as_ptr(), libc::AT_FDCWD, to.as_ptr(), RENAME_EXCHANGE) };
+        if r == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+    };
+    exchange("a", "dir/b").unwrap();
+    assert_eq!(fs::read(mnt.join("a")).unwrap(), b"first");
+    assert_eq!(fs::read(mnt.join("dir/b")).unwrap(), b"second");
+    assert_eq!(exchange("a", "missing").unwrap_err().raw_os_error(), Some(libc::ENOENT));
+    assert_eq!(fs::read(mnt.join("a")).unwrap(), b"first");
+}
+
+#[test]
+fn a_symlink_cycle_fails_with_eloop_instead_of_hanging() {
+    let mut fs = empty_fs(&[]);
+    create(&mut fs, 1, "file");
+    let a = fs.do_symlink(1, OsStr::new("a"), Path::new("file"), 0, 0).unwrap().inode_num();
+    let b = fs.do_symlink(1, OsStr::new("b"), Path::new("a"), 0, 0).unwrap().inode_num();
+    // Links are made to something that exists, so close the loop by hand: a -> b -> a
+    fs.get_inode_mut(a).unwrap().set_link_target(b);
+
+    assert_eq!(fs.do_read(a, 0, 10), Err(ELOOP));
+    assert_eq!(fs.do_read(b, 0, 10), Err(ELOOP));
+
+    let Some(mnt) = mount(fs) else { return };
+    assert_eq!(fs::read_link(mnt.join("a")).unwrap_err().raw_os_error(), Some(ELOOP));
+    assert_eq!(fs::read(mnt.join("b")).unwrap_err().raw_os_error(), Some(ELOOP));
+}
+
+#[test]
+fn root_is_uid_zero_whatever_its_group() {
+    let mut fs = empty_fs(&[]);
+    let file = create(&mut fs, 1, "file");
+    fs.do_setattr(file, Some(0o600), Some(1000), Some(1000), None, None, None, None, None).unwrap();
+    let a = *fs.get_inode(file).unwrap().attrs();
+    let pid = std::process::id();
+
+    assert!(fs.can_write(&a, 0, 1234, pid));
+    assert!(fs.can_read(&a, 0, 1234, pid));
+    // gid 0 alone makes nobody root
+    assert!(!fs.can_write(&a, 2000, 0, pid));
+    assert!(fs.can_write(&a, 1000, 1234, pid));
+}
+
+#[test]
+fn a_write_handle_only_writes_the_inode_it_was_opened_on() {
+    let mut fs = empty_fs(&[]);
+    let a = create(&mut fs, 1, "a");
+    let b = create(&mut fs, 1, "b");
+    let pid = std::process::id();
+    let write_a = fs.allocate_file_handle(a, pid, false, true).unwrap();
+    let read_a = fs.allocate_file_handle(a, pid, true, false).unwrap();
+
+    assert_eq!(fs.check_write_handle(a, write_a), Ok(()));
+    assert_eq!(fs.check_write_handle(b, write_a), Err(libc::EBADF));
+    assert_eq!(fs.check_write_handle(a, read_a), Err(EACCES));
+    fs.release_file_handle(write_a);
+    assert_eq!(fs.check_write_handle(a, write_a), Err(libc::EBADF));
+}
+
+#[test]
+fn listing_a_directory_needs_search_permission_on_it() {
+    let Some(mnt) = mount(empty_fs(&[])) else { return };
+    let private = mnt.join("private");
+    fs::create_dir(&private).unwrap();
+    fs::write(private.join("file"), b"").unwrap();
+    chmod(&private, 0o700);
+    let list = |dir: &Path| fs::read_dir(dir).and_then(|mut entries| entries.try_for_each(|e| e.map(drop)));
+
+    let Some(code) = as_user(|| list(&private)) else { return };
+    assert_eq!(code, EACCES);
+    chmod(&private, 0o701);
+    assert_eq!(as_user(|| list(&private)), Some(0));
+    // Root lists it whatever the mode
+    chmod(&private, 0o700);
+    list(&private).unwrap();
+}
+
+#[test]
+fn encrypted_contents_read_back_and_tampering_fails_with_eio() {
+    let backing = temp_path("encrypted");
+    let _ = fs::remove_dir_all(&backing);
+    let key = temp_path("encrypted.key");
+    fs::write(&key, b"correct horse battery staple\n").unwrap();
+    let args = ["--backend", "dir", "--backing-dir", backing.to_str().unwrap(), "--encrypt-key-file", key.to_str().unwrap()];
+    let mut fs = build_filesystem(&BTreeMap::new(), &options(&args)).unwrap();
+    let file = create(&mut fs, 1, "secret");
+    fs.do_write(file, 0, b"attack at dawn").unwrap();
+    fs.flush_data(file).unwrap();
+
+    // Only ciphertext reaches the backing directory, but sizes are the plaintext's
+    let stored = backing.join(file.to_string());
+    let ciphertext = fs::read(&stored).unwrap();
+    assert!(!ciphertext.windows(6).any(|w| w == b"attack"));
+    assert_eq!(fs.backend.size(file).unwrap(), Some(14));
+    fs.invalidate_data(file, 14, None);
+    assert_eq!(fs.do_read(file, 0, 100).unwrap(), b"attack at dawn");
+
+    let mut tampered = ciphertext.clone();
+    let last = tampered.len() - 1;
+    tampered[last] ^= 1;
+    fs::write(&stored, &tampered).unwrap();
+    fs.invalidate_data(file, 14, None);
+    assert_eq!(fs.do_read(file, 0, 100), Err(EIO));
+
+    let _ = fs::remove_dir_all(&backing);
+    let _ = fs::remove_file(&key);
+}
+
+#[test]
+fn unlinking_files_leaves_their_directorys_nlink_alone() {
+    let mut fs = empty_fs(&[]);
+    let dir = mkdir(&mut fs, 1, "dir");
+    create(&mut fs, dir, "one");
+    let two = create(&mut fs, dir, "two");
+    fs.do_symlink(dir, OsStr::new("link"), Path::new("dir/two"), 0, 0).unwrap();
+    fs.do_link(two, dir, OsStr::new("alias")).unwrap();
+    assert_eq!(nlink(&fs, dir), 2);
+
+    for name in ["one", "link", "alias", "two"] {
+        fs.do_unlink(dir, OsStr::new(name)).unwrap();
+        assert_eq!(nlink(&fs, dir), 2, "after unlinking {}", name);
+    }
+    assert_eq!(nlink(&fs, 1), 3);
+}
+
+#[test]
+fn root_takes_its_mode_and_owner_from_the_options() {
+    let mut fs = empty_fs(&["--root-mode", "0700", "--uid", "0", "--gid", "0"]);
+    let root = fs.do_getattr(1).unwrap();
+    assert_eq!((root.perm, root.uid, root.gid), (0o700, 0, 0));
+    assert!(Options::parse(["--root-mode", "1777x", "/mnt/dnsfs-test"].map(String::from).into_iter()).is_err());
+
+    let Some(mnt) = mount(fs) else { return };
+    let meta = fs::metadata(&mnt.path).unwrap();
+    assert_eq!((meta.mode() & 0o7777, meta.uid()), (0o700, 0));
+    // Which keeps everyone else out
+    assert_eq!(as_user(|| fs::read_dir(&mnt.path).map(drop)), Some(EACCES));
+}
+
+#[test]
+fn an_overwrite_within_the_file_leaves_its_size_and_blocks_alone() {
+    let mut fs = empty_fs(&[]);
+    let file = create(&mut fs, 1, "file");
+    fs.do_write(file, 0, b"0123456789").unwrap();
+    let before = fs.do_getattr(file).unwrap();
+
+    std::thread::sleep(Duration::from_millis(10));
+    fs.do_write(file, 0, b"abc").unwrap();
+    let after = fs.do_getattr(file).unwrap();
+    assert_eq!((after.size, after.blocks), (before.size, before.blocks));
+    assert_eq!(contents(&fs, file), b"abc3456789");
+    assert!(after.mtime > before.mtime);
+    assert!(after.ctime > before.ctime);
+}
+
+#[test]
+fn readdir_lookup_and_getattr_agree_on_every_inode_number() {
+    let Some(mnt) = mount(empty_fs(&[])) else { return };
+    fs::create_dir(mnt.join("dir")).unwrap();
+    fs::write(mnt.join("file"), b"x").unwrap();
+    fs::hard_link(mnt.join("file"), mnt.join("alias")).unwrap();
+    std::os::unix::fs::symlink("file", mnt.join("link")).unwrap();
+    std::os::unix::fs::symlink("missing", mnt.join("dangling")).unwrap();
+
+    let mut seen = 0;
+    for entry in fs::read_dir(&mnt.path).unwrap() {
+        let entry = entry.unwrap();
+        // symlink_metadata looks the name up and stats what it finds, without following links
+        let stat = fs::symlink_metadata(entry.path()).unwrap();
+        assert_eq!(entry.ino(), stat.ino(), "{:?}", entry.file_name());
+        seen += 1;
+    }
+    assert!(seen >= 5);
+    assert_eq!(fs::metadata(mnt.join("alias")).unwrap().ino(), fs::metadata(mnt.join("file")).unwrap().ino());
+}
+
+#[test]
+fn validate_name_rejects_names_no_entry_can_have() {
+    let long = "n".repeat(256);
+    assert_eq!(TreeFilesystem::validate_name(OsStr::new(&long)), Err(libc::ENAMETOOLONG));
+    assert_eq!(TreeFilesystem::validate_name(OsStr::new(&long[..255])), Ok(()));
+    for bad in ["", ".", "..", "a/b", "/"] {
+        assert_eq!(TreeFilesystem::validate_name(OsStr::new(bad)), Err(EINVAL), "{:?}", bad);
+    }
+    assert_eq!(TreeFilesystem::validate_name(OsStr::new("...")), Ok(()));
+}
+
+#[test]
+fn opening_past_max_open_files_fails_with_emfile_instead_of_panicking() {
+    let Some(mnt) = mount(empty_fs(&["--max-open-files", "3"])) else { return };
+    fs::write(mnt.join("file"), b"contents").unwrap();
+    let mut handles: Vec<_> = (0..3).map(|_| fs::File::open(mnt.join("file")).unwrap()).collect();
+
+    assert_eq!(fs::File::open(mnt.join("file")).unwrap_err().raw_os_error(), Some(libc::EMFILE));
+    assert_eq!(fs::File::create(mnt.join("other")).unwrap_err().raw_os_error(), Some(libc::EMFILE));
+    assert_eq!(fs::read_dir(&mnt.path).err().and_then(|e| e.raw_os_error()), Some(libc::EMFILE));
+
+    // The kernel sends release after close returns, so give it a moment to free the slot
+    handles.pop();
+    let reopened = (0..50).find_map(|_| {
+        fs::File::open(mnt.join("file")).ok().or_else(|| { std::thread::sleep(Duration::from_millis(10)); None })
+    });
+    assert!(reopened.is_some(), "closing a handle didn't free its slot");
+    assert_eq!(fs::read(mnt.join("file")).unwrap_err().raw_os_error(), Some(libc::EMFILE));
+    drop(handles);
+}
+
+#[test]
+fn reads_past_the_data_but_within_the_size_come_back_as_zeros() {
+    let mut fs = empty_fs(&[]);
+    let file = create(&mut fs, 1, "file");
+    fs.do_write(file, 0, b"0123456789").unwrap();
+    fs.do_setattr(file, None, None, None, Some(100), None, None, None, None).unwrap();
+
+    let read = fs.do_read(file, 5, 50).unwrap();
+    assert_eq!(read.len(), 50);
+    assert_eq!(&read[..5], b"56789");
+    assert!(read[5..].iter().all(|&b| b == 0));
+    // Up to the size and no further
+    assert_eq!(fs.do_read(file, 90, 50).unwrap(), vec![0; 10]);
+}
+
+#[test]
+fn truncating_a_directory_or_symlink_inode_leaves_it_alone() {
+    let mut fs = empty_fs(&[]);
+    let dir = mkdir(&mut fs, 1, "dir");
+    let file = create(&mut fs, dir, "file");
+    let link = fs.do_symlink(1, OsStr::new("link"), Path::new("dir/file"), 0, 0).unwrap().inode_num();
+    for ino in [dir, link] {
+        let before = fs.get_inode(ino).unwrap().clone();
+        fs.get_inode_mut(ino).unwrap().truncate_data(10);
+        assert_eq!(*fs.get_inode(ino).unwrap(), before);
+    }
+    assert_eq!(fs.get_inode(dir).unwrap().contents(), &vec![file]);
+}
+
+// Lists `count` files in directory `dir`, numbered from `first_ino`, each holding its own name.
+// Notes the size of every page it's asked for
+#[derive(Debug)]
+struct ListingBackend {
+    dir: u64,
+    first_ino: u64,
+    count: u64,
+    pages: Arc<Mutex<Vec<usize>>>,
+}
+
+impl ListingBackend {
+    fn child(&self, i: u64) -> ListedChild {
+        let name = format!("entry-{:05}", i);
+        ListedChild{ ino: self.first_ino + i, kind: FileType::RegularFile, size: name.len() as u64, name }
+    }
+}
+
+impl StorageBackend for ListingBackend {
+    fn load(&self, ino: u64, offset: u64, size: u64) -> io::Result<Vec<u8>> {
+        let name = self.child(ino - self.first_ino).name;
+        Ok(name.into_bytes().into_iter().skip(offset as usize).take(size as usize).collect())
+    }
+
+    fn store(&self, _ino: u64, _data: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn delete(&self, _ino: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn list_children(&self, ino: u64, offset: u64, count: usize) -> io::Result<Option<Vec<ListedChild>>> {
+        if ino != self.dir {
+            return Ok(None);
+        }
+        let page: Vec<_> = (offset..self.count.min(offset + count as u64)).map(|i| self.child(i)).collect();
+        self.pages.lock().unwrap().push(page.len());
+        Ok(Some(page))
+    }
+}
+
+#[test]
+fn readdir_pages_through_a_listed_directory_without_fetching_it_whole() {
+    let mut fs = empty_fs(&[]);
+    let dir = mkdir(&mut fs, 1, "big");
+    let local = create(&mut fs, dir, "local");
+    let pages = Arc::new(Mutex::new(Vec::new()));
+    fs.backend = Box::new(ListingBackend{ dir, first_ino: 1_000_000, count: 10_000, pages: pages.clone() });
+    let Some(mnt) = mount(fs) else { return };
+    let big = mnt.join("big");
+
+    let names = |pages: &Mutex<Vec<usize>>| {
+        pages.lock().unwrap().clear();
+        let mut names: Vec<_> = fs::read_dir(&big).unwrap().map(|e| {
+            let e = e.unwrap();
+            (e.file_name().into_string().unwrap(), e.ino())
+        }).collect();
+        names.sort();
+        names
+    };
+    let listed = names(&pages);
+    assert_eq!(listed.len(), 10_001);
+    assert_eq!(listed[0], ("entry-00000".to_string(), 1_000_000));
+    assert_eq!(listed[9_999], ("entry-09999".to_string(), 1_009_999));
+    assert_eq!(listed[10_000], ("local".to_string(), local));
+    // A page at a time, each asked for no more than once, and never the whole listing
+    let fetched = pages.lock().unwrap().clone();
+    assert!(fetched.iter().all(|&p| p <= LIST_PAGE_SIZE), "{:?}", fetched);
+    assert!(fetched.len() > 10_000 / LIST_PAGE_SIZE && fetched.len() <= 10_000 / LIST_PAGE_SIZE + 2, "{} pages fetched for 10,000 entries", fetched.len());
+
+    // A listed file can be looked up and read, and then shows up once, not twice
+    let entry = big.join("entry-01234");
+    assert_eq!(fs::metadata(&entry).unwrap().ino(), 1_001_234);
+    assert_eq!(fs::read_to_string(&entry).unwrap(), "entry-01234");
+    assert_eq!(names(&pages), listed);
+
+    // Removing it takes it out of the listing too
+    fs::remove_file(&entry).unwrap();
+    assert!(!entry.exists());
+    let after = names(&pages);
+    assert_eq!(after.len(), 10_000);
+    assert!(!after.iter().any(|(n, _)| n == "entry-01234"));
+}
+
+#[test]
+fn a_run_of_writes_reaches_the_backend_once_on_fsync_or_close() {
+    let backing = temp_path("write-run-backing");
+    let _ = fs::remove_dir_all(&backing);
+    let mut fs = empty_fs(&[]);
+    fs.backend = Box::new(crate::backend::DirBackend::open(backing.to_str().unwrap()).unwrap());
+    let metrics = fs.metrics.clone();
+    let Some(mnt) = mount(fs) else { return };
+    let stores = || metrics.backend_stores.load(Ordering::Relaxed);
+
+    let mut file = fs::File::create(mnt.join("file")).unwrap();
+    let block = vec![b'x'; 64 << 10];
+    for _ in 0..64 {
+        io::Write::write_all(&mut file, &block).unwrap();
+    }
+    let copy = backing.join(file.metadata().unwrap().ino().to_string());
+    let backing_size = || fs::metadata(&copy).ok().map(|m| m.len());
+    assert_eq!(stores(), 0);
+    file.sync_all().unwrap();
+    assert_eq!((stores(), backing_size()), (1, Some(4 << 20)));
+
+    io::Write::write_all(&mut file, &block).unwrap();
+    assert_eq!(stores(), 1);
+    drop(file);
+    assert_eq!((stores(), backing_size()), (2, Some((4 << 20) + (64 << 10))));
+    // Closing again with nothing new written leaves the backend alone
+    drop(fs::File::open(mnt.join("file")).unwrap());
+    assert_eq!(stores(), 2);
+    drop(mnt);
+    let _ = fs::remove_dir_all(&backing);
+}
@@ -0,0 +1,156 @@
+// Extended attributes
+
+use super::TreeFilesystem;
+use log::info;
+use crate::acl::{Acl, ACL_ACCESS_XATTR, ACL_DEFAULT_XATTR};
+use crate::inode::InodeTrait;
+use crate::status::is_status_inode;
+use std::ffi::OsStr;
+use libc::c_int;
+use libc::{EPERM, EACCES, ENOENT, EINVAL, EEXIST, ENODATA, ERANGE, EROFS};
+use libc::{XATTR_CREATE, XATTR_REPLACE};
+use fuser::{FileAttr, Request, ReplyEmpty, ReplyXattr};
+
+impl TreeFilesystem {
+    // Callers first ask with size 0 to learn how big a buffer they need, then ask again with
+    // a buffer that should fit
+    pub(super) fn reply_xattr(value: &[u8], size: u32, reply: ReplyXattr) {
+        if size == 0 {
+            reply.size(value.len() as u32);
+        } else if (size as usize) < value.len() {
+            reply.error(ERANGE);
+        } else {
+            reply.data(value);
+        }
+    }
+
+    // ACLs (like the mode they stand in for) can only be changed by the owner; anything else
+    // needs write permission on the inode
+    pub(super) fn can_change_xattr(&self, attrs: &FileAttr, name: &str, req: &Request) -> Result<(), c_int> {
+        if name == ACL_ACCESS_XATTR || name == ACL_DEFAULT_XATTR {
+            if req.uid() != 0 && req.uid() != attrs.uid {
+                return Err(EPERM);
+            }
+        } else if !self.can_write(attrs, req.uid(), req.gid(), req.pid()) {
+            return Err(EACCES);
+        }
+        Ok(())
+    }
+
+    pub(super) fn handle_getxattr(&mut self, _req: &Request, inode: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let _timer = self.metrics.time("getxattr", inode);
+        info!("getxattr(inode={}, name={}, size={})", inode, name.to_string_lossy(), size);
+        // The status entries exist, they just never have any
+        if self.get_inode(inode).is_none() && !is_status_inode(inode) {
+            reply.error(ENOENT);
+            return;
+        }
+
+        let value = match self.xattrs.get(&inode).and_then(|attrs| attrs.get(name.to_string_lossy().as_ref())) {
+            Some(v) => v,
+            None => {
+                reply.error(ENODATA);
+                return;
+            },
+        };
+        TreeFilesystem::reply_xattr(value, size, reply);
+    }
+
+    pub(super) fn handle_listxattr(&mut self, _req: &Request, inode: u64, size: u32, reply: ReplyXattr) {
+        let _timer = self.metrics.time("listxattr", inode);
+        info!("listxattr(inode={}, size={})", inode, size);
+        if self.get_inode(inode).is_none() && !is_status_inode(inode) {
+            reply.error(ENOENT);
+            return;
+        }
+
+        // The list is every name, each followed by a NUL
+        let mut names: Vec<u8> = Vec::new();
+        if let Some(attrs) = self.xattrs.get(&inode) {
+            for name in attrs.keys() {
+                names.extend_from_slice(name.as_bytes());
+                names.push(0);
+            }
+        }
+        TreeFilesystem::reply_xattr(&names, size, reply);
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(super) fn handle_setxattr(&mut self, req: &Request, inode: u64, name: &OsStr, value: &[u8], flags: i32, position: u32, reply: ReplyEmpty) {
+        let _timer = self.metrics.time("setxattr", inode);
+        info!("setxattr(inode={}, name={}, len(value)={}, flags={}, position={})", inode, name.to_string_lossy(), value.len(), flags, position);
+        if self.is_read_only() {
+            reply.error(EROFS);
+            return;
+        }
+        // The status entries are generated, so there's nothing to change
+        if is_status_inode(inode) {
+            reply.error(EPERM);
+            return;
+        }
+        let attrs = match self.get_inode(inode) {
+            Some(a) => *a.attrs(),
+            None => {
+                reply.error(ENOENT);
+                return;
+            },
+        };
+
+        let name = name.to_string_lossy().to_string();
+        if let Err(e) = self.can_change_xattr(&attrs, &name, req) {
+            reply.error(e);
+            return;
+        }
+        if self.options.acl && (name == ACL_ACCESS_XATTR || name == ACL_DEFAULT_XATTR) && Acl::parse(value).is_none() {
+            reply.error(EINVAL);
+            return;
+        }
+
+        let exists = self.xattrs.get(&inode).is_some_and(|attrs| attrs.contains_key(&name));
+        if flags & XATTR_CREATE != 0 && exists {
+            reply.error(EEXIST);
+            return;
+        }
+        if flags & XATTR_REPLACE != 0 && !exists {
+            reply.error(ENODATA);
+            return;
+        }
+
+        self.xattrs_mut().entry(inode).or_default().insert(name, value.to_vec());
+        self.touch_ctime(inode);
+        reply.ok();
+    }
+
+    pub(super) fn handle_removexattr(&mut self, req: &Request, inode: u64, name: &OsStr, reply: ReplyEmpty) {
+        let _timer = self.metrics.time("removexattr", inode);
+        info!("removexattr(inode={}, name={})", inode, name.to_string_lossy());
+        if self.is_read_only() {
+            reply.error(EROFS);
+            return;
+        }
+        // The status entries are generated, so there's nothing to change
+        if is_status_inode(inode) {
+            reply.error(EPERM);
+            return;
+        }
+        let attrs = match self.get_inode(inode) {
+            Some(a) => *a.attrs(),
+            None => {
+                reply.error(ENOENT);
+                return;
+            },
+        };
+        if let Err(e) = self.can_change_xattr(&attrs, &name.to_string_lossy(), req) {
+            reply.error(e);
+            return;
+        }
+
+        match self.xattrs_mut().get_mut(&inode).and_then(|attrs| attrs.remove(name.to_string_lossy().as_ref())) {
+            Some(_) => {
+                self.touch_ctime(inode);
+                reply.ok();
+            },
+            None => reply.error(ENODATA),
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use log::warn;
+use std::collections::BTreeMap;
+use std::fs;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Pids get reused, so a process's groups are only trusted for this long
+const CACHE_TTL: Duration = Duration::from_secs(1);
+
+/// The supplementary groups of requesting processes, read from /proc/<pid>/status. FUSE only
+/// passes along the primary gid of a request
+#[derive(Debug,Default)]
+pub struct GroupCache {
+    groups: Mutex<BTreeMap<u32, (Vec<u32>, Instant)>>,
+}
+
+impl GroupCache {
+    pub fn contains(&self, pid: u32, gid: u32) -> bool {
+        self.supplementary(pid).contains(&gid)
+    }
+
+    pub fn supplementary(&self, pid: u32) -> Vec<u32> {
+        let mut groups = self.groups.lock().unwrap();
+        if let Some((set, read_at)) = groups.get(&pid) {
+            if read_at.elapsed() < CACHE_TTL {
+                return set.clone();
+            }
+        }
+        // A process we can't inspect (already gone, or no /proc) only has its primary group
+        let set = match read_groups(pid) {
+            Some(s) => s,
+            None => {
+                warn!("Could not read the groups of pid {}", pid);
+                Vec::new()
+            },
+        };
+        groups.retain(|_, (_, read_at)| read_at.elapsed() < CACHE_TTL);
+        groups.insert(pid, (set.clone(), Instant::now()));
+        set
+    }
+}
+
+fn read_groups(pid: u32) -> Option<Vec<u32>> {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let line = status.lines().find(|l| l.starts_with("Groups:"))?;
+    line["Groups:".len()..].split_whitespace().map(|g| g.parse().ok()).collect()
+}
@@ -0,0 +1,308 @@
+use crate::backend::StorageBackend;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Keeps each file's contents as an object on a plain HTTP server (--http-url), at `<url>/<inode>`.
+/// Reads ask for just the byte range they need, stores PUT the whole file and deletes DELETE it.
+/// Each request goes over its own HTTP/1.1 connection
+#[derive(Debug)]
+pub struct HttpBackend {
+    // host:port to connect to, which is also what goes in the Host header
+    authority: String,
+    // Path objects are kept under, without a trailing slash
+    base: String,
+}
+
+// What came back for one request
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+impl HttpBackend {
+    pub fn new(url: &str) -> Result<HttpBackend, String> {
+        let rest = match url.strip_prefix("http://") {
+            Some(r) => r,
+            None => return Err(format!("Invalid --http-url {}: only http:// URLs are supported", url)),
+        };
+        let (authority, base) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, ""),
+        };
+        if authority.is_empty() {
+            return Err(format!("Invalid --http-url {}: no host", url));
+        }
+        let authority = if authority.contains(':') { authority.to_string() } else { format!("{}:80", authority) };
+        Ok(HttpBackend{
+            authority,
+            base: base.trim_end_matches('/').to_string(),
+        })
+    }
+
+    fn path(&self, ino: u64) -> String {
+        format!("{}/{}", self.base, ino)
+    }
+
+    fn connect(&self) -> io::Result<TcpStream> {
+        let mut last = io::Error::new(io::ErrorKind::NotFound, format!("{} doesn't resolve to any address", self.authority));
+        for addr in self.authority.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, TIMEOUT) {
+                Ok(s) => return Ok(s),
+                Err(e) => last = e,
+            }
+        }
+        Err(last)
+    }
+
+    // Sends one request and reads the whole reply. The server closes the connection after it
+    fn request(&self, method: &str, path: &str, headers: &[(&str, String)], body: &[u8]) -> io::Result<Response> {
+        let mut stream = self.connect()?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        let mut out = format!("{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n", method, if path.is_empty() { "/" } else { path }, self.authority);
+        for (name, value) in headers {
+            out += &format!("{}: {}\r\n", name, value);
+        }
+        if method == "PUT" {
+            out += &format!("Content-Length: {}\r\n", body.len());
+        }
+        out += "\r\n";
+        let mut out = out.into_bytes();
+        out.extend_from_slice(body);
+        stream.write_all(&out)?;
+
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply)?;
+        parse_response(&reply, method == "HEAD")
+    }
+
+    fn error(method: &str, ino: u64, status: u16) -> io::Error {
+        let msg = format!("HTTP {} of inode {} failed with status {}", method, ino, status);
+        match status {
+            404 => io::Error::new(io::ErrorKind::NotFound, msg),
+            _ => io::Error::other(msg),
+        }
+    }
+}
+
+fn parse_response(reply: &[u8], head: bool) -> io::Result<Response> {
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("Malformed HTTP response: {}", what));
+    let end = match reply.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(e) => e,
+        None => return Err(invalid("no end to the headers")),
+    };
+    let text = String::from_utf8_lossy(&reply[..end]);
+    let mut lines = text.split("\r\n");
+    let status = lines.next().and_then(|l| l.split(' ').nth(1)).and_then(|s| s.parse().ok()).ok_or_else(|| invalid("bad status line"))?;
+    let headers = lines.filter_map(|l| l.split_once(':')).map(|(n, v)| (n.trim().to_string(), v.trim().to_string())).collect();
+    let mut response = Response{ status, headers, body: Vec::new() };
+    if head {
+        return Ok(response);
+    }
+
+    let rest = &reply[end + 4..];
+    response.body = if response.header("Transfer-Encoding").is_some_and(|t| t.eq_ignore_ascii_case("chunked")) {
+        dechunk(rest).ok_or_else(|| invalid("bad chunked body"))?
+    } else if let Some(len) = response.header("Content-Length") {
+        let len: usize = len.parse().map_err(|_| invalid("bad Content-Length"))?;
+        match rest.get(..len) {
+            Some(b) => b.to_vec(),
+            None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "HTTP response ended early")),
+        }
+    } else {
+        rest.to_vec()
+    };
+    Ok(response)
+}
+
+// Joins a chunked body back together, or None if it's cut short or garbled
+fn dechunk(mut rest: &[u8]) -> Option<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = rest.windows(2).position(|w| w == b"\r\n")?;
+        let size = std::str::from_utf8(&rest[..line_end]).ok()?;
+        // Chunk extensions come after a semicolon
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        rest = &rest[line_end + 2..];
+        if size == 0 {
+            return Some(body);
+        }
+        body.extend_from_slice(rest.get(..size)?);
+        rest = rest.get(size + 2..)?;
+    }
+}
+
+impl StorageBackend for HttpBackend {
+    fn load(&self, ino: u64, offset: u64, size: u64) -> io::Result<Vec<u8>> {
+        if size == 0 {
+            return Ok(Vec::new());
+        }
+        let range = format!("bytes={}-{}", offset, offset.saturating_add(size - 1));
+        let response = self.request("GET", &self.path(ino), &[("Range", range)], &[])?;
+        match response.status {
+            206 => Ok(response.body.into_iter().take(size as usize).collect()),
+            // A server that ignores ranges sends the whole object
+            200 => Ok(response.body.into_iter().skip(offset as usize).take(size as usize).collect()),
+            // The range starts past the end of the object
+            416 => Ok(Vec::new()),
+            s => Err(HttpBackend::error("GET", ino, s)),
+        }
+    }
+
+    fn store(&self, ino: u64, data: &[u8]) -> io::Result<()> {
+        let response = self.request("PUT", &self.path(ino), &[], data)?;
+        match response.is_success() {
+            true => Ok(()),
+            false => Err(HttpBackend::error("PUT", ino, response.status)),
+        }
+    }
+
+    fn delete(&self, ino: u64) -> io::Result<()> {
+        let response = self.request("DELETE", &self.path(ino), &[], &[])?;
+        match response.status {
+            // Already gone is as good as deleted
+            404 => Ok(()),
+            _ if response.is_success() => Ok(()),
+            s => Err(HttpBackend::error("DELETE", ino, s)),
+        }
+    }
+
+    fn size(&self, ino: u64) -> io::Result<Option<u64>> {
+        let response = self.request("HEAD", &self.path(ino), &[], &[])?;
+        match response.status {
+            // Never stored, so there's nothing newer than what the tree has
+            404 => Ok(None),
+            _ if response.is_success() => Ok(response.header("Content-Length").and_then(|l| l.parse().ok())),
+            s => Err(HttpBackend::error("HEAD", ino, s)),
+        }
+    }
+
+    fn ping(&self) -> io::Result<()> {
+        // Any answer short of a server error means it's up, whether or not it lists the base path
+        let response = self.request("HEAD", &self.base, &[], &[])?;
+        match response.status {
+            s if s >= 500 => Err(io::Error::other(format!("HTTP server answered with status {}", s))),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HttpBackend;
+    use crate::backend::StorageBackend;
+    use std::collections::BTreeMap;
+    use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    type Objects = Arc<Mutex<BTreeMap<String, Vec<u8>>>>;
+
+    // An object store answering ranged GETs, HEAD, PUT and DELETE, one connection at a time
+    fn serve_objects(objects: Objects) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut headers = BTreeMap::new();
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    if let Some((n, v)) = line.split_once(':') {
+                        headers.insert(n.to_ascii_lowercase(), v.trim().to_string());
+                    }
+                    line.clear();
+                }
+                let mut parts = request_line.split_whitespace();
+                let (method, path) = (parts.next().unwrap(), parts.next().unwrap().to_string());
+
+                let mut objects = objects.lock().unwrap();
+                let reply = match (method, objects.get(&path)) {
+                    ("PUT", _) => {
+                        let mut body = vec![0; headers["content-length"].parse().unwrap()];
+                        reader.read_exact(&mut body).unwrap();
+                        objects.insert(path, body);
+                        "HTTP/1.1 201 Created\r\nContent-Length: 0\r\n\r\n".as_bytes().to_vec()
+                    },
+                    ("DELETE", Some(_)) => {
+                        objects.remove(&path);
+                        "HTTP/1.1 204 No Content\r\n\r\n".as_bytes().to_vec()
+                    },
+                    ("HEAD", Some(o)) => format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", o.len()).into_bytes(),
+                    ("GET", Some(o)) => {
+                        let range = headers["range"].strip_prefix("bytes=").unwrap();
+                        let (start, end) = range.split_once('-').unwrap();
+                        let (start, end): (usize, usize) = (start.parse().unwrap(), end.parse().unwrap());
+                        if start >= o.len() {
+                            "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Length: 0\r\n\r\n".as_bytes().to_vec()
+                        } else {
+                            let part = &o[start..o.len().min(end + 1)];
+                            let mut reply = format!("HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\n\r\n", part.len()).into_bytes();
+                            reply.extend_from_slice(part);
+                            reply
+                        }
+                    },
+                    _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".as_bytes().to_vec(),
+                };
+                stream.write_all(&reply).unwrap();
+            }
+        });
+        format!("http://{}/objects/", addr)
+    }
+
+    #[test]
+    fn contents_round_trip_through_ranged_gets_puts_and_deletes() {
+        let objects = Objects::default();
+        let backend = HttpBackend::new(&serve_objects(objects.clone())).unwrap();
+        backend.ping().unwrap();
+
+        backend.store(7, b"contents kept over HTTP").unwrap();
+        assert_eq!(objects.lock().unwrap()["/objects/7"], b"contents kept over HTTP");
+        assert_eq!(backend.load(7, 9, 4).unwrap(), b"kept");
+        // Ranges running off the end come back short, and ones past it empty
+        assert_eq!(backend.load(7, 19, 100).unwrap(), b"HTTP");
+        assert_eq!(backend.load(7, 23, 10).unwrap(), b"");
+        assert_eq!(backend.size(7).unwrap(), Some(23));
+
+        backend.delete(7).unwrap();
+        assert!(objects.lock().unwrap().is_empty());
+        assert_eq!(backend.load(7, 0, 10).unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(backend.size(7).unwrap(), None);
+        // Deleting what's already gone isn't an error
+        backend.delete(7).unwrap();
+    }
+
+    #[test]
+    fn chunked_bodies_are_joined_back_together() {
+        let response = super::parse_response(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\n\r\n", false).unwrap();
+        assert_eq!(response.body, b"hello world");
+        assert!(super::parse_response(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhel", false).is_err());
+    }
+
+    #[test]
+    fn only_plain_http_urls_with_a_host_are_accepted() {
+        let backend = HttpBackend::new("http://objects.example").unwrap();
+        assert_eq!((backend.authority.as_str(), backend.path(3).as_str()), ("objects.example:80", "/3"));
+        assert!(HttpBackend::new("https://objects.example/files").is_err());
+        assert!(HttpBackend::new("http:///files").is_err());
+    }
+}
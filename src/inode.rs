@@ -1,14 +1,20 @@
+use crate::sparse::SparseData;
 use fuser::FileAttr;
+use std::sync::Arc;
 
 #[derive(Debug,Clone,PartialEq)]
 pub struct FileInode {
     pub inode_num: u64,
     pub attrs: FileAttr,
     pub path: String,
-    pub data: Vec<u8>, //Should be base64 encoded
+    // Shared between clones of the inode; copied on the first write to one of them
+    pub data: Arc<SparseData>, //Should be base64 encoded
+    pub loaded: bool, // False until data has been fetched from the backend
     pub num_links: u32,
     pub name: String,
     pub parent: u64,
+    // Every other (parent, name) the file is hard linked as. `parent`/`name`/`path` are its first
+    pub links: Vec<(u64, String)>,
 }
 
 #[derive(Debug,Clone,PartialEq)]
@@ -45,10 +51,11 @@ pub trait InodeTrait {
     fn inode_num(&self) -> u64;
     fn attrs(&self) -> &FileAttr;
     fn path(&self) -> &String;
-    fn data(&self) -> &Vec<u8>;
+    fn data(&self) -> &SparseData;
     #[allow(dead_code)]
     fn parent(&self) -> u64;
     fn name(&self) -> &String;
+    fn names_in(&self, _: u64) -> Vec<&String>;
     fn contents(&self) -> &Vec<u64>;
     fn target(&self) -> Option<u64>;
     fn get_symlink_data(&self) -> Option<&String>;
@@ -64,6 +71,7 @@ pub trait InodeTrait {
     fn set_link_target(&mut self, _: u64);
     fn set_symlink_data(&mut self, _: String);
     fn write_data(&mut self, _: &[u8], _: usize);
+    fn truncate_data(&mut self, _: usize);
 }
 
 impl InodeTrait for Inode {
@@ -99,7 +107,7 @@ impl InodeTrait for Inode {
         };
     }
 
-    fn data(&self) -> &Vec<u8> {
+    fn data(&self) -> &SparseData {
         match self {
             Inode::FileInode(ref a) => return &a.data,
             Inode::DirectoryInode(_) => todo!(),
@@ -137,6 +145,17 @@ impl InodeTrait for Inode {
         };
     }
 
+    fn names_in(&self, parent: u64) -> Vec<&String> {
+        let mut names = Vec::new();
+        if self.parent() == parent {
+            names.push(self.name());
+        }
+        if let Inode::FileInode(ref a) = self {
+            names.extend(a.links.iter().filter(|(p, _)| *p == parent).map(|(_, n)| n));
+        }
+        names
+    }
+
     fn contents(&self) -> &Vec<u64> {
         match self {
             Inode::FileInode(_) => todo!(),
@@ -204,7 +223,7 @@ impl InodeTrait for Inode {
 
     fn set_data(&mut self, data: Vec<u8>) {
         match self {
-            Inode::FileInode(ref mut a) =>  a.data = data.clone(),
+            Inode::FileInode(ref mut a) =>  a.data = Arc::new(data.into()),
             Inode::DirectoryInode(_) => todo!(),
             Inode::LinkInode(_) => todo!(),
         };
@@ -212,12 +231,22 @@ impl InodeTrait for Inode {
 
     fn write_data(&mut self, data: &[u8], offset: usize) {
         match self {
-            Inode::FileInode(ref mut a) =>  a.data.splice(offset.., data.iter().copied()),
+            // Takes a private copy first if a clone of this inode still shares the buffer
+            Inode::FileInode(ref mut a) => Arc::make_mut(&mut a.data).write(offset as u64, data),
             Inode::DirectoryInode(_) => todo!(),
             Inode::LinkInode(_) => todo!(),
         };
     }
 
+    fn truncate_data(&mut self, size: usize) {
+        match self {
+            // Shrinks the contents, or extends them with a hole
+            Inode::FileInode(ref mut a) => Arc::make_mut(&mut a.data).truncate(size as u64),
+            // Directories and symlinks have no contents to resize
+            Inode::DirectoryInode(_) | Inode::LinkInode(_) => (),
+        };
+    }
+
     fn set_contents(&mut self, data: Vec<u64>) {
         match self {
             Inode::FileInode(_) =>  todo!(),
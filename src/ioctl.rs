@@ -0,0 +1,15 @@
+// Commands understood by TreeFilesystem::ioctl. FUSE only passes through ioctls whose argument
+// size is encoded in the command, which caps a reply at 16383 bytes
+
+const IOC_READ: u32 = 2;
+
+const fn ioc(dir: u32, ty: u8, nr: u8, size: u32) -> u32 {
+    (dir << 30) | (size << 16) | ((ty as u32) << 8) | nr as u32
+}
+
+// _IOR('D', 1, char[16383]): JSON metadata for every inode under the one the ioctl is issued on
+pub const DNSFS_IOC_EXPORT_JSON: u32 = ioc(IOC_READ, b'D', 1, 0x3fff);
+
+// _IOR('D', 2, u64): removes everything beneath the directory it's issued on and returns how
+// many inodes went
+pub const DNSFS_IOC_REMOVE_TREE: u32 = ioc(IOC_READ, b'D', 2, 8);
@@ -0,0 +1,47 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+// How many resolved symlinks are remembered before the least recently used is dropped
+const CAPACITY: usize = 256;
+
+/// Remembers the inode each symlink ultimately resolves to, so following the same link again
+/// doesn't walk the chain one hop at a time. Cleared whenever the tree changes
+#[derive(Debug,Default)]
+pub struct LinkCache {
+    inner: Mutex<Entries>,
+}
+
+#[derive(Debug,Default)]
+struct Entries {
+    // Link inode -> (resolved inode, tick it was last used)
+    targets: BTreeMap<u64, (u64, u64)>,
+    tick: u64,
+}
+
+impl LinkCache {
+    pub fn get(&self, link: u64) -> Option<u64> {
+        let mut entries = self.inner.lock().unwrap();
+        entries.tick += 1;
+        let tick = entries.tick;
+        let entry = entries.targets.get_mut(&link)?;
+        entry.1 = tick;
+        Some(entry.0)
+    }
+
+    pub fn insert(&self, link: u64, target: u64) {
+        let mut entries = self.inner.lock().unwrap();
+        if entries.targets.len() >= CAPACITY && !entries.targets.contains_key(&link) {
+            let oldest = entries.targets.iter().min_by_key(|(_, (_, used))| *used).map(|(ino, _)| *ino);
+            if let Some(ino) = oldest {
+                entries.targets.remove(&ino);
+            }
+        }
+        entries.tick += 1;
+        let tick = entries.tick;
+        entries.targets.insert(link, (target, tick));
+    }
+
+    pub fn clear(&self) {
+        self.inner.lock().unwrap().targets.clear();
+    }
+}
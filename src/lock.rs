@@ -0,0 +1,78 @@
+use libc::{c_int, F_RDLCK, F_UNLCK, F_WRLCK};
+use std::collections::BTreeMap;
+
+/// A POSIX byte-range lock. `end` is inclusive, with u64::MAX meaning "to the end of the file"
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct ByteLock {
+    pub owner: u64,
+    pub start: u64,
+    pub end: u64,
+    pub typ: c_int,
+    pub pid: u32,
+}
+
+impl ByteLock {
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start <= end && start <= self.end
+    }
+}
+
+/// The byte-range locks held on each inode, as set through setlk
+#[derive(Debug,Default)]
+pub struct LockTable {
+    locks: BTreeMap<u64, Vec<ByteLock>>,
+}
+
+impl LockTable {
+    /// The first lock held by someone other than `owner` that stops them taking a `typ` lock on
+    /// `start..=end`. A read lock only conflicts with write locks; a write lock with anything.
+    /// With no owner every lock on the range conflicts
+    pub fn conflict(&self, ino: u64, owner: Option<u64>, start: u64, end: u64, typ: c_int) -> Option<ByteLock> {
+        self.locks.get(&ino)?.iter().find(|l| {
+            Some(l.owner) != owner && l.overlaps(start, end) && (typ == F_WRLCK || l.typ == F_WRLCK)
+        }).copied()
+    }
+
+    /// Sets (or with F_UNLCK clears) `owner`'s lock on `start..=end`, replacing whatever part of
+    /// its existing locks the range covers. Callers check for conflicts first
+    pub fn set(&mut self, ino: u64, owner: u64, start: u64, end: u64, typ: c_int, pid: u32) {
+        let locks = self.locks.entry(ino).or_default();
+        let mut kept = Vec::with_capacity(locks.len() + 1);
+        for l in locks.drain(..) {
+            if l.owner != owner || !l.overlaps(start, end) {
+                kept.push(l);
+                continue;
+            }
+            // Keep whatever sticks out either side of the new range
+            if l.start < start {
+                kept.push(ByteLock{ end: start - 1, ..l });
+            }
+            if l.end > end {
+                kept.push(ByteLock{ start: end + 1, ..l });
+            }
+        }
+        if typ == F_RDLCK || typ == F_WRLCK {
+            kept.push(ByteLock{
+                owner,
+                start,
+                end,
+                typ,
+                pid,
+            });
+        }
+        *locks = kept;
+        if locks.is_empty() {
+            self.locks.remove(&ino);
+        }
+    }
+
+    /// Drops every lock `owner` holds on `ino`, as happens when it closes the file
+    pub fn release_owner(&mut self, ino: u64, owner: u64) {
+        self.set(ino, owner, 0, u64::MAX, F_UNLCK, 0);
+    }
+
+    /// Forgets all locks on an inode that no longer exists
+    pub fn remove(&mut self, ino: u64) {
+        self.locks.remove(&ino);
+    }
+}
@@ -0,0 +1,175 @@
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+// Upper bounds (in seconds) of the latency histogram buckets
+const LATENCY_BUCKETS: [f64; 10] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+#[derive(Debug,Clone,Default)]
+struct OpStats {
+    count: u64,
+    sum: f64,
+    buckets: [u64; LATENCY_BUCKETS.len()],
+}
+
+/// Counters shared between the filesystem and the metrics endpoint
+#[derive(Debug,Default)]
+pub struct Metrics {
+    ops: Mutex<BTreeMap<&'static str, OpStats>>,
+    // Operations slower than this (in milliseconds) get a warning. 0 turns the warning off
+    slow_op_ms: u64,
+    pub inodes: AtomicU64,
+    pub bytes: AtomicU64,
+    pub backend_fetches: AtomicU64,
+    pub backend_stores: AtomicU64,
+    // Fetches and stores (deletes included) the backend failed
+    pub backend_fetch_failures: AtomicU64,
+    pub backend_store_failures: AtomicU64,
+    // The most recent backend failure, for /.dnsfs/backend
+    last_backend_error: Mutex<Option<String>>,
+    // Hops taken following symlink chains, and resolutions answered from the cache instead
+    pub symlink_steps: AtomicU64,
+    pub symlink_cache_hits: AtomicU64,
+}
+
+/// Records how long an operation took when it goes out of scope
+pub struct OpTimer {
+    metrics: Arc<Metrics>,
+    op: &'static str,
+    ino: u64,
+    start: Instant,
+}
+
+impl Drop for OpTimer {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        if self.metrics.slow_op_ms > 0 && elapsed.as_millis() >= self.metrics.slow_op_ms as u128 {
+            warn!("Slow operation: {}(ino={}) took {}ms", self.op, self.ino, elapsed.as_millis());
+        }
+        self.metrics.record(self.op, elapsed.as_secs_f64());
+    }
+}
+
+impl Metrics {
+    pub fn new(slow_op_ms: u64) -> Metrics {
+        Metrics{
+            slow_op_ms,
+            ..Default::default()
+        }
+    }
+
+    /// Starts timing `op` on inode `ino`; the time is recorded when the timer is dropped
+    pub fn time(self: &Arc<Self>, op: &'static str, ino: u64) -> OpTimer {
+        OpTimer{
+            metrics: self.clone(),
+            op,
+            ino,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn backend_error(&self, error: String) {
+        *self.last_backend_error.lock().unwrap() = Some(error);
+    }
+
+    pub fn last_backend_error(&self) -> Option<String> {
+        self.last_backend_error.lock().unwrap().clone()
+    }
+
+    fn record(&self, op: &'static str, seconds: f64) {
+        let mut ops = self.ops.lock().unwrap();
+        let stats = ops.entry(op).or_default();
+        stats.count += 1;
+        stats.sum += seconds;
+        for (idx, bound) in LATENCY_BUCKETS.iter().enumerate() {
+            if seconds <= *bound {
+                stats.buckets[idx] += 1;
+            }
+        }
+    }
+
+    /// Renders everything in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let ops = self.ops.lock().unwrap().clone();
+
+        for (op, stats) in &ops {
+            out += &format!("# TYPE dnsfs_{}_total counter\n", op);
+            out += &format!("dnsfs_{}_total {}\n", op, stats.count);
+        }
+
+        out += "# TYPE dnsfs_op_duration_seconds histogram\n";
+        for (op, stats) in &ops {
+            for (idx, bound) in LATENCY_BUCKETS.iter().enumerate() {
+                out += &format!("dnsfs_op_duration_seconds_bucket{{op=\"{}\",le=\"{}\"}} {}\n", op, bound, stats.buckets[idx]);
+            }
+            out += &format!("dnsfs_op_duration_seconds_bucket{{op=\"{}\",le=\"+Inf\"}} {}\n", op, stats.count);
+            out += &format!("dnsfs_op_duration_seconds_sum{{op=\"{}\"}} {}\n", op, stats.sum);
+            out += &format!("dnsfs_op_duration_seconds_count{{op=\"{}\"}} {}\n", op, stats.count);
+        }
+
+        out += "# TYPE dnsfs_inodes gauge\n";
+        out += &format!("dnsfs_inodes {}\n", self.inodes.load(Ordering::Relaxed));
+        out += "# TYPE dnsfs_bytes gauge\n";
+        out += &format!("dnsfs_bytes {}\n", self.bytes.load(Ordering::Relaxed));
+        out += "# TYPE dnsfs_backend_fetches_total counter\n";
+        out += &format!("dnsfs_backend_fetches_total {}\n", self.backend_fetches.load(Ordering::Relaxed));
+        out += "# TYPE dnsfs_backend_stores_total counter\n";
+        out += &format!("dnsfs_backend_stores_total {}\n", self.backend_stores.load(Ordering::Relaxed));
+        out += "# TYPE dnsfs_backend_fetch_failures_total counter\n";
+        out += &format!("dnsfs_backend_fetch_failures_total {}\n", self.backend_fetch_failures.load(Ordering::Relaxed));
+        out += "# TYPE dnsfs_backend_store_failures_total counter\n";
+        out += &format!("dnsfs_backend_store_failures_total {}\n", self.backend_store_failures.load(Ordering::Relaxed));
+        out += "# TYPE dnsfs_symlink_steps_total counter\n";
+        out += &format!("dnsfs_symlink_steps_total {}\n", self.symlink_steps.load(Ordering::Relaxed));
+        out += "# TYPE dnsfs_symlink_cache_hits_total counter\n";
+        out += &format!("dnsfs_symlink_cache_hits_total {}\n", self.symlink_cache_hits.load(Ordering::Relaxed));
+        out
+    }
+}
+
+/// Serves `GET /metrics` on `addr` from a background thread
+pub fn serve(addr: SocketAddr, metrics: Arc<Metrics>) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!("Serving metrics on http://{}/metrics", addr);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(s) => {
+                    if let Err(e) = handle_connection(s, &metrics) {
+                        warn!("Metrics request failed: {}", e);
+                    }
+                },
+                Err(e) => warn!("Failed to accept metrics connection: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle_connection(stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    // Drain the headers; we don't care about any of them
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()),
+        _ => ("404 Not Found", String::new()),
+    };
+
+    let mut stream = stream;
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body)?;
+    stream.flush()
+}
@@ -0,0 +1,133 @@
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::str::FromStr;
+
+/// What happens to a name that isn't valid UTF-8, since the tree keeps names as strings
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum NameEncoding {
+    // Invalid bytes become U+FFFD, so different names can collapse into one
+    Lossy,
+    // Invalid bytes become %XX escapes, which keeps the original bytes recoverable. A literal %
+    // becomes %25 so it can't be mistaken for one
+    Percent,
+    // Names with invalid bytes can't be created at all
+    Reject,
+}
+
+impl FromStr for NameEncoding {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<NameEncoding, ()> {
+        match s {
+            "lossy" => Ok(NameEncoding::Lossy),
+            "percent" => Ok(NameEncoding::Percent),
+            "reject" => Ok(NameEncoding::Reject),
+            _ => Err(()),
+        }
+    }
+}
+
+impl NameEncoding {
+    /// The name as it's stored and listed, or None if it can't be represented under this policy
+    pub fn encode(&self, name: &OsStr) -> Option<String> {
+        let bytes = name.as_bytes();
+        match (self, std::str::from_utf8(bytes)) {
+            // Even a valid name is escaped, for any % in it
+            (NameEncoding::Percent, _) => Some(percent_encode(bytes)),
+            (_, Ok(s)) => Some(s.to_string()),
+            (NameEncoding::Lossy, Err(_)) => Some(String::from_utf8_lossy(bytes).to_string()),
+            (NameEncoding::Reject, Err(_)) => None,
+        }
+    }
+
+    /// The name as the kernel should see it when the stored `name` is listed: the bytes it was
+    /// created with, where the policy kept them
+    pub fn decode(&self, name: &str) -> OsString {
+        match self {
+            NameEncoding::Percent => OsString::from_vec(percent_decode(name)),
+            _ => OsString::from(name),
+        }
+    }
+}
+
+// Copies the valid UTF-8 runs of `bytes` with only their % escaped, and escapes each invalid byte
+// as %XX
+fn percent_encode(mut bytes: &[u8]) -> String {
+    let mut out = String::new();
+    while !bytes.is_empty() {
+        match std::str::from_utf8(bytes) {
+            Ok(s) => {
+                out.push_str(&s.replace('%', "%25"));
+                break;
+            },
+            Err(e) => {
+                let (valid, rest) = bytes.split_at(e.valid_up_to());
+                out.push_str(&std::str::from_utf8(valid).unwrap().replace('%', "%25"));
+                let bad = e.error_len().unwrap_or(rest.len());
+                for b in &rest[..bad] {
+                    out.push_str(&format!("%{:02X}", b));
+                }
+                bytes = &rest[bad..];
+            },
+        }
+    }
+    out
+}
+
+// Turns each %XX back into the byte it stands for. Anything else, including a % not followed by
+// two hex digits, is copied as it is
+fn percent_decode(name: &str) -> Vec<u8> {
+    let bytes = name.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            if let Some(b) = name.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                out.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NameEncoding;
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    const INVALID: &[u8] = b"caf\xe9 100%";
+
+    #[test]
+    fn lossy_replaces_invalid_bytes() {
+        let name = NameEncoding::Lossy.encode(OsStr::from_bytes(INVALID)).unwrap();
+        assert_eq!(name, "caf\u{FFFD} 100%");
+        assert_eq!(NameEncoding::Lossy.decode(&name), OsStr::new("caf\u{FFFD} 100%"));
+    }
+
+    #[test]
+    fn percent_escapes_invalid_bytes_and_percent_signs() {
+        let name = NameEncoding::Percent.encode(OsStr::from_bytes(INVALID)).unwrap();
+        assert_eq!(name, "caf%E9 100%25");
+        assert_eq!(NameEncoding::Percent.decode(&name), OsStr::from_bytes(INVALID));
+    }
+
+    #[test]
+    fn percent_keeps_names_that_look_escaped_apart_from_escaped_ones() {
+        let literal = NameEncoding::Percent.encode(OsStr::new("caf%E9")).unwrap();
+        let escaped = NameEncoding::Percent.encode(OsStr::from_bytes(b"caf\xe9")).unwrap();
+        assert_ne!(literal, escaped);
+        assert_eq!(NameEncoding::Percent.decode(&literal), OsStr::new("caf%E9"));
+        assert_eq!(NameEncoding::Percent.decode(&escaped), OsStr::from_bytes(b"caf\xe9"));
+    }
+
+    #[test]
+    fn reject_refuses_invalid_bytes_only() {
+        assert_eq!(NameEncoding::Reject.encode(OsStr::from_bytes(INVALID)), None);
+        assert_eq!(NameEncoding::Reject.encode(OsStr::new("100%")), Some("100%".to_string()));
+    }
+}
@@ -0,0 +1,221 @@
+use crate::backend::BackendKind;
+use crate::collation::Collation;
+use crate::dns::RecordType;
+use crate::names::NameEncoding;
+use std::net::SocketAddr;
+
+/// Runtime configuration for a mount, built from the command line
+#[derive(Debug,Clone,PartialEq)]
+pub struct Options {
+    pub mountpoint: String,
+    // Fail fsync/fsyncdir instead of pretending data is durable when nothing can persist it
+    pub strict_fsync: bool,
+    // How long the kernel may cache a failed lookup, in milliseconds. 0 disables negative caching
+    pub negative_ttl_ms: u64,
+    // When rename replaces a file, keep the replaced file's mode and ownership
+    pub rename_preserve_mode: bool,
+    // Append every mutation to this file before applying it, and replay it at mount
+    pub wal: Option<String>,
+    // Where to serve Prometheus metrics, if anywhere
+    pub metrics_addr: Option<SocketAddr>,
+    // Paths (and everything under them) that can't be unlinked, removed or renamed
+    pub protect: Vec<String>,
+    // Largest size, in bytes, any single file may grow to
+    pub max_file_size: Option<u64>,
+    // Most names (hard links) any one file may have
+    pub max_links: u32,
+    // Let POSIX ACLs stored in the system.posix_acl_* xattrs override the mode bits
+    pub acl: bool,
+    // Let processes with CAP_DAC_OVERRIDE/CAP_DAC_READ_SEARCH bypass permission checks
+    pub honor_capabilities: bool,
+    // Refuse to create symlinks or follow existing ones
+    pub deny_symlinks: bool,
+    // Order readdir returns entries in
+    pub collation: Collation,
+    // How names that aren't valid UTF-8 are stored and listed
+    pub name_encoding: NameEncoding,
+    // Where file contents are persisted
+    pub backend: BackendKind,
+    // Host directory used by the dir backend
+    pub backing_dir: Option<String>,
+    // Which record type the dns backend keeps file contents in
+    pub dns_rrtype: RecordType,
+    // Authoritative server the dns backend queries and sends updates to
+    pub dns_server: Option<SocketAddr>,
+    // Zone the dns backend keeps its records in
+    pub dns_zone: Option<String>,
+    // TTL given to records the dns backend writes
+    pub dns_ttl: u32,
+    // Base URL the http backend keeps file contents under
+    pub http_url: Option<String>,
+    // File holding the passphrase file contents are encrypted with before they reach the backend
+    pub encrypt_key_file: Option<String>,
+    // Build the tree from the files published in --dns-zone instead of the built-in seed
+    pub from_dns: bool,
+    // File the tree is loaded from at mount and snapshotted to
+    pub snapshot: Option<String>,
+    // Permissions given to loaded files and directories, which carry no mode of their own
+    pub default_file_mode: u16,
+    pub default_dir_mode: u16,
+    // Permissions of the root directory, if they should differ from --default-dir-mode
+    pub root_mode: Option<u16>,
+    // Owner of the root and of the files loaded at mount
+    pub uid: u32,
+    pub gid: u32,
+    // Seconds between automatic snapshots
+    pub snapshot_interval: Option<u64>,
+    // Older snapshot generations kept beside --snapshot
+    pub keep_snapshots: usize,
+    // Start from this kept generation of --snapshot instead of the latest
+    pub restore_snapshot: Option<String>,
+    // Snapshot to mount read-only instead of the live tree
+    pub mount_snapshot: Option<String>,
+    // Ask the backend for a file's current size on every getattr
+    pub stat_refresh: bool,
+    // Pick up changes made directly to the backing directory
+    pub watch_backing: bool,
+    // Warn about any operation that takes at least this many milliseconds. 0 disables the warning
+    pub slow_op_ms: u64,
+    // Take timestamps from a clock starting at this many seconds past the epoch instead of the
+    // wall clock
+    pub fixed_clock: Option<u64>,
+    // Bypass the kernel page cache, so every read and write reaches the filesystem
+    pub direct_io: bool,
+    // Most handles any one process may have open at once
+    pub max_open_files: Option<usize>,
+    // Mount on a temporary directory, exercise every operation against it and exit
+    pub self_test: bool,
+    // Mount every tree listed in this file instead of a single one
+    pub config: Option<String>,
+}
+
+impl Options {
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+        let mut mountpoint = None;
+        let mut options = Options{
+            mountpoint: String::new(),
+            strict_fsync: false,
+            negative_ttl_ms: 0,
+            rename_preserve_mode: false,
+            wal: None,
+            metrics_addr: None,
+            protect: Vec::new(),
+            max_file_size: None,
+            max_links: 65000,
+            acl: false,
+            honor_capabilities: false,
+            deny_symlinks: false,
+            collation: Collation::Byte,
+            name_encoding: NameEncoding::Lossy,
+            backend: BackendKind::Memory,
+            backing_dir: None,
+            dns_rrtype: RecordType::Txt,
+            dns_server: None,
+            dns_zone: None,
+            dns_ttl: 60,
+            http_url: None,
+            encrypt_key_file: None,
+            from_dns: false,
+            snapshot: None,
+            snapshot_interval: None,
+            default_file_mode: 0o644,
+            default_dir_mode: 0o755,
+            root_mode: None,
+            uid: 1000,
+            gid: 1000,
+            keep_snapshots: 0,
+            restore_snapshot: None,
+            mount_snapshot: None,
+            stat_refresh: false,
+            watch_backing: false,
+            slow_op_ms: 0,
+            fixed_clock: None,
+            direct_io: false,
+            max_open_files: None,
+            self_test: false,
+            config: None,
+        };
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--strict-fsync" => options.strict_fsync = true,
+                "--negative-ttl-ms" => options.negative_ttl_ms = parse_value(&arg, args.next())?,
+                "--rename-preserve-mode" => options.rename_preserve_mode = true,
+                "--wal" => options.wal = Some(parse_value(&arg, args.next())?),
+                "--metrics-addr" => options.metrics_addr = Some(parse_value(&arg, args.next())?),
+                "--protect" => options.protect.push(parse_value(&arg, args.next())?),
+                "--max-file-size" => options.max_file_size = Some(parse_value(&arg, args.next())?),
+                "--max-links" => options.max_links = parse_value(&arg, args.next())?,
+                "--acl" => options.acl = true,
+                "--honor-capabilities" => options.honor_capabilities = true,
+                "--deny-symlinks" => options.deny_symlinks = true,
+                "--collation" => options.collation = parse_value(&arg, args.next())?,
+                "--name-encoding" => options.name_encoding = parse_value(&arg, args.next())?,
+                "--backend" => options.backend = parse_value(&arg, args.next())?,
+                "--backing-dir" => options.backing_dir = Some(parse_value(&arg, args.next())?),
+                "--dns-rrtype" => options.dns_rrtype = parse_value(&arg, args.next())?,
+                "--dns-server" => options.dns_server = Some(parse_value(&arg, args.next())?),
+                "--dns-zone" => options.dns_zone = Some(parse_value(&arg, args.next())?),
+                "--dns-ttl" => options.dns_ttl = parse_value(&arg, args.next())?,
+                "--http-url" => options.http_url = Some(parse_value(&arg, args.next())?),
+                "--encrypt-key-file" => options.encrypt_key_file = Some(parse_value(&arg, args.next())?),
+                "--from-dns" => options.from_dns = true,
+                "--snapshot" => options.snapshot = Some(parse_value(&arg, args.next())?),
+                "--snapshot-interval" => options.snapshot_interval = Some(parse_value(&arg, args.next())?),
+                "--default-file-mode" => options.default_file_mode = parse_mode(&arg, args.next())?,
+                "--default-dir-mode" => options.default_dir_mode = parse_mode(&arg, args.next())?,
+                "--root-mode" => options.root_mode = Some(parse_mode(&arg, args.next())?),
+                "--uid" => options.uid = parse_value(&arg, args.next())?,
+                "--gid" => options.gid = parse_value(&arg, args.next())?,
+                "--keep-snapshots" => options.keep_snapshots = parse_value(&arg, args.next())?,
+                "--restore-snapshot" => options.restore_snapshot = Some(parse_value(&arg, args.next())?),
+                "--mount-snapshot" => options.mount_snapshot = Some(parse_value(&arg, args.next())?),
+                "--stat-refresh" => options.stat_refresh = true,
+                "--watch-backing" => options.watch_backing = true,
+                "--slow-op-ms" => options.slow_op_ms = parse_value(&arg, args.next())?,
+                "--fixed-clock" => options.fixed_clock = Some(parse_value(&arg, args.next())?),
+                "--direct-io" => options.direct_io = true,
+                "--max-open-files" => options.max_open_files = Some(parse_value(&arg, args.next())?),
+                "--self-test" => options.self_test = true,
+                "--config" => options.config = Some(parse_value(&arg, args.next())?),
+                flag if flag.starts_with("--") => return Err(format!("Unknown option {}", flag)),
+                _ => {
+                    if mountpoint.is_some() {
+                        return Err(format!("Unexpected argument {}", arg));
+                    }
+                    mountpoint = Some(arg);
+                },
+            }
+        }
+
+        match mountpoint {
+            Some(m) => options.mountpoint = m,
+            // The self-test picks its own mountpoint
+            None if options.self_test => (),
+            // Each tree in the config names its own
+            None if options.config.is_some() => (),
+            None => return Err("Missing mountpoint".to_string()),
+        }
+        Ok(options)
+    }
+}
+
+// Permission bits written in octal, with or without a leading 0
+fn parse_mode(flag: &str, value: Option<String>) -> Result<u16, String> {
+    let value: String = parse_value(flag, value)?;
+    match u16::from_str_radix(&value, 8) {
+        Ok(m) if m <= 0o7777 => Ok(m),
+        _ => Err(format!("Invalid value for {}: {} (expected an octal mode up to 7777)", flag, value)),
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    let value = match value {
+        Some(v) => v,
+        None => return Err(format!("{} requires a value", flag)),
+    };
+    match value.parse::<T>() {
+        Ok(v) => Ok(v),
+        Err(_) => Err(format!("Invalid value for {}: {}", flag, value)),
+    }
+}
@@ -0,0 +1,116 @@
+use fuser::{Filesystem, MountOption};
+use log::error;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+// A named step of the self-test and the check it runs
+type Step<'a> = (&'static str, Box<dyn Fn() -> Result<(), String> + 'a>);
+
+/// Mounts `fs` on `mountpoint`, runs a scripted battery of operations against it and prints a
+/// pass/fail line for each. Returns whether every step passed
+pub fn run<FS: Filesystem + Send + 'static>(fs: FS, mountpoint: &Path) -> bool {
+    let options = vec![MountOption::FSName("jakefs".to_string())];
+    let session = match fuser::spawn_mount2(fs, mountpoint, &options) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Could not mount {}: {}", mountpoint.display(), e);
+            println!("FAIL mount: {}", e);
+            return false;
+        },
+    };
+    println!("PASS mount");
+
+    let file = mountpoint.join("self-test");
+    let renamed = mountpoint.join("self-test-renamed");
+    let link = mountpoint.join("self-test-link");
+    let dir = mountpoint.join("self-test-dir");
+    let steps: Vec<Step> = vec![
+        ("create", Box::new(|| {
+            File::create(&file).map(|_| ()).map_err(|e| e.to_string())
+        })),
+        ("write", Box::new(|| {
+            let mut f = OpenOptions::new().write(true).open(&file).map_err(|e| e.to_string())?;
+            f.write_all(b"hello world").map_err(|e| e.to_string())
+        })),
+        ("read", Box::new(|| {
+            expect_contents(&file, b"hello world")
+        })),
+        ("symlink", Box::new(|| {
+            std::os::unix::fs::symlink("self-test", &link).map_err(|e| e.to_string())
+        })),
+        ("readlink", Box::new(|| {
+            let target = fs::read_link(&link).map_err(|e| e.to_string())?;
+            match target.file_name() {
+                Some(name) if name == "self-test" => Ok(()),
+                _ => Err(format!("link points at {}", target.display())),
+            }
+        })),
+        ("chmod", Box::new(|| {
+            fs::set_permissions(&file, fs::Permissions::from_mode(0o600)).map_err(|e| e.to_string())?;
+            let mode = fs::metadata(&file).map_err(|e| e.to_string())?.permissions().mode() & 0o777;
+            if mode != 0o600 {
+                return Err(format!("mode is {:o}", mode));
+            }
+            Ok(())
+        })),
+        ("truncate", Box::new(|| {
+            let f = OpenOptions::new().write(true).open(&file).map_err(|e| e.to_string())?;
+            f.set_len(5).map_err(|e| e.to_string())?;
+            expect_contents(&file, b"hello")
+        })),
+        ("rename", Box::new(|| {
+            fs::rename(&file, &renamed).map_err(|e| e.to_string())?;
+            if file.exists() {
+                return Err("source still exists".to_string());
+            }
+            expect_contents(&renamed, b"hello")
+        })),
+        ("unlink", Box::new(|| {
+            fs::remove_file(&link).map_err(|e| e.to_string())?;
+            fs::remove_file(&renamed).map_err(|e| e.to_string())?;
+            if renamed.exists() {
+                return Err("file still exists".to_string());
+            }
+            Ok(())
+        })),
+        ("mkdir", Box::new(|| {
+            fs::create_dir(&dir).map_err(|e| e.to_string())?;
+            if !fs::metadata(&dir).map_err(|e| e.to_string())?.is_dir() {
+                return Err("not a directory".to_string());
+            }
+            Ok(())
+        })),
+        ("rmdir", Box::new(|| {
+            fs::remove_dir(&dir).map_err(|e| e.to_string())?;
+            if dir.exists() {
+                return Err("directory still exists".to_string());
+            }
+            Ok(())
+        })),
+    ];
+
+    let mut passed = true;
+    for (name, step) in &steps {
+        match step() {
+            Ok(()) => println!("PASS {}", name),
+            Err(e) => {
+                println!("FAIL {}: {}", name, e);
+                passed = false;
+            },
+        }
+    }
+
+    session.join();
+    println!("{}", if passed { "Self-test passed" } else { "Self-test failed" });
+    passed
+}
+
+fn expect_contents(path: &Path, expected: &[u8]) -> Result<(), String> {
+    let contents = fs::read(path).map_err(|e| e.to_string())?;
+    if contents != expected {
+        return Err(format!("read {:?}, expected {:?}", String::from_utf8_lossy(&contents), String::from_utf8_lossy(expected)));
+    }
+    Ok(())
+}
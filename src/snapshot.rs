@@ -0,0 +1,429 @@
+use crate::inode::{Inode, DirectoryInode, FileInode, LinkInode, InodeTrait};
+use crate::sparse::SparseData;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use fuser::{FileAttr, FileType};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+/// Extended attributes by inode number, then by name
+pub type Xattrs = BTreeMap<u64, BTreeMap<String, Vec<u8>>>;
+
+/// A point-in-time copy of the whole tree, as written to a snapshot file
+#[derive(Debug,Serialize,Deserialize)]
+pub struct Snapshot {
+    cur_inode: u64,
+    // How many write-ahead log records the snapshot already includes
+    #[serde(default)]
+    wal_records: u64,
+    inodes: Vec<SnapshotInode>,
+    // Values are base64 encoded
+    xattrs: BTreeMap<u64, BTreeMap<String, String>>,
+}
+
+#[derive(Debug,Serialize,Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+enum SnapshotInode {
+    File {
+        #[serde(with = "FileAttrDef")]
+        attrs: FileAttr,
+        path: String,
+        name: String,
+        parent: u64,
+        num_links: u32,
+        // Hard links beyond the first name, as (parent, name)
+        #[serde(default)]
+        links: Vec<(u64, String)>,
+        // Base64 encoded. Only older snapshots use this; newer ones store the extents below
+        #[serde(default)]
+        data: String,
+        // The written ranges of the file, so holes in sparse files aren't stored
+        #[serde(default)]
+        extents: Vec<SnapshotExtent>,
+        // Logical size, holes included
+        #[serde(default)]
+        len: u64,
+        loaded: bool,
+    },
+    Directory {
+        #[serde(with = "FileAttrDef")]
+        attrs: FileAttr,
+        path: String,
+        name: String,
+        parent: u64,
+        num_links: u32,
+        contents: Vec<u64>,
+    },
+    Symlink {
+        #[serde(with = "FileAttrDef")]
+        attrs: FileAttr,
+        path: String,
+        name: String,
+        parent: u64,
+        num_links: u32,
+        target: u64,
+        target_path: String,
+    },
+}
+
+#[derive(Debug,Serialize,Deserialize)]
+struct SnapshotExtent {
+    offset: u64,
+    // Base64 encoded
+    data: String,
+}
+
+// Serializable mirror of fuser's FileAttr
+#[derive(Serialize,Deserialize)]
+#[serde(remote = "FileAttr")]
+struct FileAttrDef {
+    ino: u64,
+    size: u64,
+    blocks: u64,
+    #[serde(with = "unix_time")]
+    atime: SystemTime,
+    #[serde(with = "unix_time")]
+    mtime: SystemTime,
+    #[serde(with = "unix_time")]
+    ctime: SystemTime,
+    #[serde(with = "unix_time")]
+    crtime: SystemTime,
+    #[serde(with = "FileTypeDef")]
+    kind: FileType,
+    perm: u16,
+    nlink: u32,
+    uid: u32,
+    gid: u32,
+    rdev: u32,
+    blksize: u32,
+    flags: u32,
+}
+
+#[derive(Serialize,Deserialize)]
+#[serde(remote = "FileType", rename_all = "lowercase")]
+enum FileTypeDef {
+    NamedPipe,
+    CharDevice,
+    BlockDevice,
+    Directory,
+    RegularFile,
+    Symlink,
+    Socket,
+}
+
+// SystemTimes as seconds and nanoseconds since the unix epoch
+mod unix_time {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::time::{Duration, SystemTime};
+
+    #[derive(Serialize,Deserialize)]
+    struct UnixTime {
+        secs: u64,
+        nanos: u32,
+    }
+
+    pub fn serialize<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+        let since_epoch = time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or(Duration::ZERO);
+        UnixTime{
+            secs: since_epoch.as_secs(),
+            nanos: since_epoch.subsec_nanos(),
+        }.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SystemTime, D::Error> {
+        let t = UnixTime::deserialize(deserializer)?;
+        Ok(SystemTime::UNIX_EPOCH + Duration::new(t.secs, t.nanos))
+    }
+}
+
+impl Snapshot {
+    pub fn capture(tree: &BTreeMap<u64, Inode>, cur_inode: u64, wal_records: u64, xattrs: &Xattrs) -> Snapshot {
+        // A file unlinked while still open has no name left, and a remount couldn't reach it
+        let inodes = tree.values().filter(|inode| match inode {
+            Inode::FileInode(ref a) => a.attrs.nlink > 0,
+            _ => true,
+        }).map(|inode| match inode {
+            Inode::FileInode(ref a) => SnapshotInode::File{
+                attrs: a.attrs,
+                path: a.path.clone(),
+                name: a.name.clone(),
+                parent: a.parent,
+                num_links: a.num_links,
+                links: a.links.clone(),
+                data: String::new(),
+                extents: a.data.extents().map(|(offset, data)| SnapshotExtent{
+                    offset,
+                    data: BASE64.encode(data),
+                }).collect(),
+                len: a.data.len(),
+                loaded: a.loaded,
+            },
+            Inode::DirectoryInode(ref b) => SnapshotInode::Directory{
+                attrs: b.attrs,
+                path: b.path.clone(),
+                name: b.name.clone(),
+                parent: b.parent,
+                num_links: b.num_links,
+                contents: b.contents.clone(),
+            },
+            Inode::LinkInode(ref c) => SnapshotInode::Symlink{
+                attrs: c.attrs,
+                path: c.path.clone(),
+                name: c.name.clone(),
+                parent: c.parent,
+                num_links: c.num_links,
+                target: c.target,
+                target_path: c.target_path.clone(),
+            },
+        }).collect();
+
+        let xattrs = xattrs.iter().map(|(ino, attrs)| {
+            (*ino, attrs.iter().map(|(name, value)| (name.clone(), BASE64.encode(value))).collect())
+        }).collect();
+
+        Snapshot{
+            cur_inode,
+            wal_records,
+            inodes,
+            xattrs,
+        }
+    }
+
+    pub fn cur_inode(&self) -> u64 {
+        self.cur_inode
+    }
+
+    pub fn wal_records(&self) -> u64 {
+        self.wal_records
+    }
+
+    /// Rebuilds the tree and extended attributes the snapshot was taken from
+    pub fn into_tree(self) -> io::Result<(BTreeMap<u64, Inode>, Xattrs)> {
+        let decode = |s: &str| BASE64.decode(s).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+
+        let mut tree = BTreeMap::new();
+        for inode in self.inodes {
+            let inode = match inode {
+                SnapshotInode::File { attrs, path, name, parent, num_links, links, data, extents, len, loaded } => Inode::FileInode(FileInode{
+                    inode_num: attrs.ino,
+                    attrs,
+                    path,
+                    data: {
+                        let mut contents = SparseData::from(decode(&data)?);
+                        for extent in extents {
+                            contents.write(extent.offset, &decode(&extent.data)?);
+                        }
+                        if len > contents.len() {
+                            contents.truncate(len);
+                        }
+                        Arc::new(contents)
+                    },
+                    loaded,
+                    num_links,
+                    name,
+                    parent,
+                    links,
+                }),
+                SnapshotInode::Directory { attrs, path, name, parent, num_links, contents } => Inode::DirectoryInode(DirectoryInode{
+                    inode_num: attrs.ino,
+                    attrs,
+                    path,
+                    contents,
+                    num_links,
+                    parent,
+                    name,
+                }),
+                SnapshotInode::Symlink { attrs, path, name, parent, num_links, target, target_path } => Inode::LinkInode(LinkInode{
+                    inode_num: attrs.ino,
+                    attrs,
+                    path,
+                    target,
+                    name,
+                    parent,
+                    num_links,
+                    target_path,
+                }),
+            };
+            tree.insert(inode.inode_num(), inode);
+        }
+
+        let mut xattrs = BTreeMap::new();
+        for (ino, attrs) in self.xattrs {
+            let mut decoded = BTreeMap::new();
+            for (name, value) in attrs {
+                decoded.insert(name, decode(&value)?);
+            }
+            xattrs.insert(ino, decoded);
+        }
+        Ok((tree, xattrs))
+    }
+
+    pub fn read(path: &str) -> io::Result<Snapshot> {
+        let file = File::open(path)?;
+        serde_json::from_reader(BufReader::new(file)).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Writes the snapshot beside `path` and renames it into place, so a crash mid-write leaves
+    /// the previous snapshot intact
+    pub fn write(&self, path: &str) -> io::Result<()> {
+        let tmp = format!("{}.tmp", path);
+        let file = File::create(&tmp)?;
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer(&mut writer, self).map_err(io::Error::other)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(&tmp, path)
+    }
+
+    /// Writes the snapshot to `path`, keeping `keep` older generations beside it (none if 0)
+    pub fn write_generation(&self, path: &str, keep: usize) -> io::Result<()> {
+        self.write(path)?;
+        if keep > 0 {
+            if let Err(e) = rotate(path, keep) {
+                warn!("Failed to rotate snapshots of {}: {}", path, e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where an older generation of the snapshot at `path` called `name` lives: beside it, unless
+/// `name` is a path of its own
+pub fn generation_path(path: &str, name: &str) -> PathBuf {
+    if name.contains('/') {
+        return PathBuf::from(name);
+    }
+    Path::new(path).with_file_name(name)
+}
+
+// Keeps a copy of the snapshot just written to `path` as `<path>.<unix millis>`, then deletes the
+// oldest copies beyond `keep`. The timestamps are zero padded so the names sort by age
+fn rotate(path: &str, keep: usize) -> io::Result<()> {
+    let millis = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or(Duration::ZERO).as_millis();
+    let generation = format!("{}.{:020}", path, millis);
+    // The live snapshot is always replaced by rename, so a hard link keeps this version intact
+    if fs::hard_link(path, &generation).is_err() {
+        fs::copy(path, &generation)?;
+    }
+
+    let path = Path::new(path);
+    let prefix = format!("{}.", path.file_name().unwrap_or_default().to_string_lossy());
+    let dir = match path.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+    let mut generations: Vec<PathBuf> = fs::read_dir(dir)?.filter_map(|e| e.ok()).filter(|e| {
+        let name = e.file_name().to_string_lossy().to_string();
+        name.strip_prefix(&prefix).is_some_and(|stamp| !stamp.is_empty() && stamp.bytes().all(|b| b.is_ascii_digit()))
+    }).map(|e| e.path()).collect();
+    generations.sort();
+    while generations.len() > keep {
+        let oldest = generations.remove(0);
+        info!("Pruning old snapshot {}", oldest.display());
+        fs::remove_file(&oldest)?;
+    }
+    Ok(())
+}
+
+// How soon the writer tries again when it wakes in the middle of an operation
+const RETRY_DELAY: Duration = Duration::from_millis(10);
+
+// The tree as it stood after the filesystem's last complete operation. Only weak references are
+// kept, so publishing costs nothing: the tree is copied only if the filesystem changes it while
+// the writer holds it for a snapshot. A change moves the tree out from under the weak reference,
+// so it can't be upgraded again until the operation is over and republishes it
+#[derive(Debug,Default)]
+struct Published {
+    tree: Weak<BTreeMap<u64, Inode>>,
+    xattrs: Weak<Xattrs>,
+    cur_inode: u64,
+    wal_records: u64,
+    // Bumped on every change, so an idle filesystem isn't snapshotted over and over
+    generation: u64,
+}
+
+/// Writes a snapshot of the tree every interval on a background thread, so serializing and
+/// writing it never holds up an operation. Nothing is written while the tree is unchanged
+#[derive(Debug)]
+pub struct SnapshotWriter {
+    published: Arc<Mutex<Published>>,
+    stop: Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+impl SnapshotWriter {
+    /// `keep` older generations are kept beside `path` (none if 0)
+    pub fn spawn(path: String, interval: Duration, keep: usize) -> SnapshotWriter {
+        let published = Arc::new(Mutex::new(Published::default()));
+        let shared = published.clone();
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = thread::spawn(move || {
+            let mut written = 0;
+            let mut wait = interval;
+            // Timing out is the timer; anything else means the filesystem is unmounting
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(wait) {
+                wait = interval;
+                let (tree, xattrs, cur_inode, wal_records) = {
+                    let published = shared.lock().unwrap();
+                    if published.generation == written {
+                        continue;
+                    }
+                    match (published.tree.upgrade(), published.xattrs.upgrade()) {
+                        (Some(tree), Some(xattrs)) => {
+                            written = published.generation;
+                            (tree, xattrs, published.cur_inode, published.wal_records)
+                        },
+                        _ => {
+                            wait = RETRY_DELAY;
+                            continue;
+                        },
+                    }
+                };
+                let snapshot = Snapshot::capture(&tree, cur_inode, wal_records, &xattrs);
+                // Let the filesystem change the tree in place again
+                drop((tree, xattrs));
+                match snapshot.write_generation(&path, keep) {
+                    Ok(()) => info!("Wrote snapshot to {}", path),
+                    Err(e) => warn!("Failed to write snapshot to {}: {}", path, e),
+                }
+            }
+        });
+        SnapshotWriter{
+            published,
+            stop,
+            thread,
+        }
+    }
+
+    /// Hands the writer the tree as it stands. Called only between operations, so a snapshot
+    /// never catches one half done
+    pub fn publish(&self, tree: &Arc<BTreeMap<u64, Inode>>, xattrs: &Arc<Xattrs>, cur_inode: u64, wal_records: u64) {
+        let mut published = self.published.lock().unwrap();
+        // Any change in between moved the tree or the attributes to a new allocation
+        if published.tree.as_ptr() == Arc::as_ptr(tree) && published.xattrs.as_ptr() == Arc::as_ptr(xattrs)
+            && published.cur_inode == cur_inode && published.wal_records == wal_records {
+            return;
+        }
+        published.tree = Arc::downgrade(tree);
+        published.xattrs = Arc::downgrade(xattrs);
+        published.cur_inode = cur_inode;
+        published.wal_records = wal_records;
+        published.generation += 1;
+    }
+
+    /// Waits for a snapshot being written to finish, then stops the writer
+    pub fn finish(self) {
+        drop(self.stop);
+        if self.thread.join().is_err() {
+            warn!("Snapshot writer panicked");
+        }
+    }
+}
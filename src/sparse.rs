@@ -0,0 +1,143 @@
+use std::collections::BTreeMap;
+
+/// A file's contents as the ranges that have actually been written. Everything between them, and
+/// past the last one up to `len`, is a hole that reads back as zeros without taking up memory
+#[derive(Debug,Clone,PartialEq,Default)]
+pub struct SparseData {
+    len: u64,
+    // Start offset -> bytes. Extents never overlap
+    extents: BTreeMap<u64, Vec<u8>>,
+}
+
+impl From<Vec<u8>> for SparseData {
+    fn from(data: Vec<u8>) -> SparseData {
+        let mut sparse = SparseData{
+            len: data.len() as u64,
+            extents: BTreeMap::new(),
+        };
+        if !data.is_empty() {
+            sparse.extents.insert(0, data);
+        }
+        sparse
+    }
+}
+
+impl SparseData {
+    /// The logical size, holes included
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// How many bytes are actually stored
+    pub fn allocated(&self) -> u64 {
+        self.extents.values().map(|e| e.len() as u64).sum()
+    }
+
+    pub fn extents(&self) -> impl Iterator<Item = (u64, &Vec<u8>)> {
+        self.extents.iter().map(|(offset, data)| (*offset, data))
+    }
+
+    /// Up to `size` bytes from `offset`, stopping at the end of the file. Holes come back as zeros
+    pub fn read(&self, offset: u64, size: u64) -> Vec<u8> {
+        let end = offset.saturating_add(size).min(self.len);
+        if offset >= end {
+            return Vec::new();
+        }
+        let mut out = vec![0; (end - offset) as usize];
+        for (start, data) in self.extents.range(..end).rev() {
+            let extent_end = start + data.len() as u64;
+            if extent_end <= offset {
+                break;
+            }
+            let from = offset.max(*start);
+            let to = end.min(extent_end);
+            out[(from - offset) as usize..(to - offset) as usize]
+                .copy_from_slice(&data[(from - start) as usize..(to - start) as usize]);
+        }
+        out
+    }
+
+    /// The whole file, holes filled in with zeros
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.read(0, self.len)
+    }
+
+    /// Writes `data` at `offset`, growing the file if it runs past the end. Writing far past the
+    /// end leaves a hole rather than filling the gap
+    pub fn write(&mut self, offset: u64, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let end = offset + data.len() as u64;
+
+        // Extend the extent this write starts in (or right after) in place, so a run of appends
+        // keeps growing one buffer instead of creating an extent per write
+        let start = match self.extents.range(..=offset).next_back() {
+            Some((start, existing)) if start + existing.len() as u64 >= offset => *start,
+            _ => offset,
+        };
+        let mut buf = self.extents.remove(&start).unwrap_or_default();
+        if (buf.len() as u64) < end - start {
+            buf.resize((end - start) as usize, 0);
+        }
+        buf[(offset - start) as usize..(end - start) as usize].copy_from_slice(data);
+
+        // Fold in any later extents the write now touches
+        let covered: Vec<u64> = self.extents.range(start..=end).map(|(s, _)| *s).collect();
+        for s in covered {
+            let next = self.extents.remove(&s).unwrap();
+            let next_end = s + next.len() as u64;
+            let buf_end = start + buf.len() as u64;
+            if next_end > buf_end {
+                buf.extend_from_slice(&next[(buf_end - s) as usize..]);
+            }
+        }
+
+        self.extents.insert(start, buf);
+        self.len = self.len.max(end);
+    }
+
+    /// Cuts the file down to `size`, or extends it with a hole
+    pub fn truncate(&mut self, size: u64) {
+        if size < self.len {
+            self.extents.retain(|start, _| *start < size);
+            if let Some((start, last)) = self.extents.iter_mut().next_back() {
+                if start + last.len() as u64 > size {
+                    last.truncate((size - start) as usize);
+                }
+            }
+        }
+        self.len = size;
+    }
+
+    /// Backs `[offset, offset + length)` with real zeros wherever it's a hole, growing the file if
+    /// the range runs past the end. Data already in the range is kept
+    pub fn allocate(&mut self, offset: u64, length: u64) {
+        let mut buf = self.read(offset, length);
+        buf.resize(length as usize, 0);
+        self.write(offset, &buf);
+    }
+
+    /// Turns `[offset, offset + length)` into a hole, without changing the size
+    pub fn punch_hole(&mut self, offset: u64, length: u64) {
+        let end = offset.saturating_add(length).min(self.len);
+        if offset >= end {
+            return;
+        }
+        let overlapping: Vec<u64> = self.extents.range(..end)
+            .filter(|(start, data)| *start + data.len() as u64 > offset)
+            .map(|(start, _)| *start)
+            .collect();
+        // Keep whatever part of each extent falls either side of the hole
+        for start in overlapping {
+            let data = self.extents.remove(&start).unwrap();
+            let data_end = start + data.len() as u64;
+            if start < offset {
+                self.extents.insert(start, data[..(offset - start) as usize].to_vec());
+            }
+            if data_end > end {
+                self.extents.insert(end, data[(end - start) as usize..].to_vec());
+            }
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use crate::backend::{BackendKind, StorageBackend};
+use crate::metrics::Metrics;
+use std::sync::atomic::Ordering;
+
+// /.dnsfs and /.dnsfs/backend aren't in the tree, so they get inode numbers the tree never
+// reaches and are answered for directly by the handlers
+pub const STATUS_DIR_INO: u64 = u64::MAX - 1;
+pub const STATUS_FILE_INO: u64 = u64::MAX - 2;
+pub const STATUS_DIR_NAME: &str = ".dnsfs";
+pub const STATUS_FILE_NAME: &str = "backend";
+
+pub fn is_status_inode(ino: u64) -> bool {
+    ino == STATUS_DIR_INO || ino == STATUS_FILE_INO
+}
+
+/// The contents of /.dnsfs/backend: whether the backend answers right now, its most recent
+/// failure and how its fetches and stores have gone since mount
+pub fn render(kind: BackendKind, backend: &dyn StorageBackend, metrics: &Metrics) -> String {
+    let fetches = metrics.backend_fetches.load(Ordering::Relaxed);
+    let fetch_failures = metrics.backend_fetch_failures.load(Ordering::Relaxed);
+    let stores = metrics.backend_stores.load(Ordering::Relaxed);
+    let store_failures = metrics.backend_store_failures.load(Ordering::Relaxed);
+
+    let mut out = format!("backend: {:?}\n", kind).to_lowercase();
+    out += &match backend.ping() {
+        Ok(()) => "reachable: yes\n".to_string(),
+        Err(e) => format!("reachable: no ({})\n", e),
+    };
+    out += &format!("last_error: {}\n", metrics.last_backend_error().unwrap_or_else(|| "none".to_string()));
+    out += &format!("fetches: {} ({} failed, {})\n", fetches, fetch_failures, success_rate(fetches, fetch_failures));
+    out += &format!("stores: {} ({} failed, {})\n", stores, store_failures, success_rate(stores, store_failures));
+    out
+}
+
+fn success_rate(total: u64, failed: u64) -> String {
+    if total == 0 {
+        return "no attempts".to_string();
+    }
+    format!("{:.1}% succeeded", (total - failed.min(total)) as f64 * 100.0 / total as f64)
+}
@@ -0,0 +1,228 @@
+use log::warn;
+use serde_json::{json, Value};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::time::{Duration, SystemTime};
+
+/// A single mutation of the tree, appended to the log before it is applied
+#[derive(Debug,Clone,PartialEq)]
+pub enum WalRecord {
+    Create { ino: u64, parent: u64, name: String, mode: u16, uid: u32, gid: u32 },
+    Mkdir { ino: u64, parent: u64, name: String, mode: u16, uid: u32, gid: u32 },
+    Symlink { ino: u64, parent: u64, name: String, target: String, uid: u32, gid: u32 },
+    Write { ino: u64, offset: u64, data: Vec<u8> },
+    Fallocate { ino: u64, offset: u64, length: u64, mode: i32 },
+    Link { ino: u64, new_parent: u64, new_name: String },
+    Unlink { parent: u64, name: String },
+    Rmdir { parent: u64, name: String },
+    RemoveTree { ino: u64 },
+    Rename { parent: u64, name: String, new_parent: u64, new_name: String },
+    Exchange { parent: u64, name: String, new_parent: u64, new_name: String },
+    SetAttr { ino: u64, mode: Option<u16>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, atime: Option<SystemTime>, mtime: Option<SystemTime>, ctime: Option<SystemTime>, crtime: Option<SystemTime> },
+}
+
+#[derive(Debug)]
+pub struct Wal {
+    path: String,
+    file: File,
+    // Records in the log, counting those already there when it was opened
+    records: u64,
+}
+
+impl Wal {
+    /// Opens (or creates) the log at `path` for appending. `records` is how many it already holds
+    pub fn open(path: &str, records: u64) -> io::Result<Wal> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Wal{
+            path: path.to_string(),
+            file,
+            records,
+        })
+    }
+
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    /// Reads back every complete record in the log at `path`. A torn or garbled line (the usual
+    /// result of crashing mid-append) ends the replay rather than failing it
+    pub fn read_records(path: &str) -> io::Result<Vec<WalRecord>> {
+        let file = match File::open(path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut records = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            match serde_json::from_str::<Value>(&line).ok().and_then(|v| WalRecord::from_json(&v)) {
+                Some(record) => records.push(record),
+                None => {
+                    warn!("Stopping WAL replay at unreadable record: {}", line);
+                    break;
+                },
+            }
+        }
+        Ok(records)
+    }
+
+    pub fn append(&mut self, record: &WalRecord) -> io::Result<()> {
+        let mut line = record.to_json().to_string();
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.file.flush()?;
+        self.records += 1;
+        Ok(())
+    }
+
+    /// Makes everything appended so far durable
+    pub fn sync(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    /// Empties the log. Only safe once everything it describes has been persisted elsewhere
+    pub fn truncate(&mut self) -> io::Result<()> {
+        self.file = OpenOptions::new().create(true).write(true).truncate(true).open(&self.path)?;
+        self.file.sync_data()?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.records = 0;
+        Ok(())
+    }
+}
+
+impl WalRecord {
+    fn to_json(&self) -> Value {
+        match self {
+            WalRecord::Create { ino, parent, name, mode, uid, gid } =>
+                json!({"op": "create", "ino": ino, "parent": parent, "name": name, "mode": mode, "uid": uid, "gid": gid}),
+            WalRecord::Mkdir { ino, parent, name, mode, uid, gid } =>
+                json!({"op": "mkdir", "ino": ino, "parent": parent, "name": name, "mode": mode, "uid": uid, "gid": gid}),
+            WalRecord::Symlink { ino, parent, name, target, uid, gid } =>
+                json!({"op": "symlink", "ino": ino, "parent": parent, "name": name, "target": target, "uid": uid, "gid": gid}),
+            WalRecord::Write { ino, offset, data } =>
+                json!({"op": "write", "ino": ino, "offset": offset, "data": to_hex(data)}),
+            WalRecord::Fallocate { ino, offset, length, mode } =>
+                json!({"op": "fallocate", "ino": ino, "offset": offset, "length": length, "mode": mode}),
+            WalRecord::Link { ino, new_parent, new_name } =>
+                json!({"op": "link", "ino": ino, "new_parent": new_parent, "new_name": new_name}),
+            WalRecord::Unlink { parent, name } =>
+                json!({"op": "unlink", "parent": parent, "name": name}),
+            WalRecord::Rmdir { parent, name } =>
+                json!({"op": "rmdir", "parent": parent, "name": name}),
+            WalRecord::RemoveTree { ino } =>
+                json!({"op": "remove_tree", "ino": ino}),
+            WalRecord::Rename { parent, name, new_parent, new_name } =>
+                json!({"op": "rename", "parent": parent, "name": name, "new_parent": new_parent, "new_name": new_name}),
+            WalRecord::Exchange { parent, name, new_parent, new_name } =>
+                json!({"op": "exchange", "parent": parent, "name": name, "new_parent": new_parent, "new_name": new_name}),
+            WalRecord::SetAttr { ino, mode, uid, gid, size, atime, mtime, ctime, crtime } =>
+                json!({"op": "setattr", "ino": ino, "mode": mode, "uid": uid, "gid": gid, "size": size,
+                       "atime": atime.map(time_to_nanos), "mtime": mtime.map(time_to_nanos),
+                       "ctime": ctime.map(time_to_nanos), "crtime": crtime.map(time_to_nanos)}),
+        }
+    }
+
+    fn from_json(v: &Value) -> Option<WalRecord> {
+        let u64_field = |key: &str| v.get(key).and_then(|f| f.as_u64());
+        let str_field = |key: &str| v.get(key).and_then(|f| f.as_str()).map(|f| f.to_string());
+
+        let record = match v.get("op")?.as_str()? {
+            "create" => WalRecord::Create {
+                ino: u64_field("ino")?,
+                parent: u64_field("parent")?,
+                name: str_field("name")?,
+                mode: u64_field("mode")? as u16,
+                uid: u64_field("uid")? as u32,
+                gid: u64_field("gid")? as u32,
+            },
+            "mkdir" => WalRecord::Mkdir {
+                ino: u64_field("ino")?,
+                parent: u64_field("parent")?,
+                name: str_field("name")?,
+                mode: u64_field("mode")? as u16,
+                uid: u64_field("uid")? as u32,
+                gid: u64_field("gid")? as u32,
+            },
+            "symlink" => WalRecord::Symlink {
+                ino: u64_field("ino")?,
+                parent: u64_field("parent")?,
+                name: str_field("name")?,
+                target: str_field("target")?,
+                uid: u64_field("uid")? as u32,
+                gid: u64_field("gid")? as u32,
+            },
+            "write" => WalRecord::Write {
+                ino: u64_field("ino")?,
+                offset: u64_field("offset")?,
+                data: from_hex(&str_field("data")?)?,
+            },
+            "fallocate" => WalRecord::Fallocate {
+                ino: u64_field("ino")?,
+                offset: u64_field("offset")?,
+                length: u64_field("length")?,
+                mode: u64_field("mode")? as i32,
+            },
+            "link" => WalRecord::Link {
+                ino: u64_field("ino")?,
+                new_parent: u64_field("new_parent")?,
+                new_name: str_field("new_name")?,
+            },
+            "unlink" => WalRecord::Unlink {
+                parent: u64_field("parent")?,
+                name: str_field("name")?,
+            },
+            "rmdir" => WalRecord::Rmdir {
+                parent: u64_field("parent")?,
+                name: str_field("name")?,
+            },
+            "remove_tree" => WalRecord::RemoveTree {
+                ino: u64_field("ino")?,
+            },
+            "rename" => WalRecord::Rename {
+                parent: u64_field("parent")?,
+                name: str_field("name")?,
+                new_parent: u64_field("new_parent")?,
+                new_name: str_field("new_name")?,
+            },
+            "exchange" => WalRecord::Exchange {
+                parent: u64_field("parent")?,
+                name: str_field("name")?,
+                new_parent: u64_field("new_parent")?,
+                new_name: str_field("new_name")?,
+            },
+            "setattr" => WalRecord::SetAttr {
+                ino: u64_field("ino")?,
+                mode: u64_field("mode").map(|m| m as u16),
+                uid: u64_field("uid").map(|u| u as u32),
+                gid: u64_field("gid").map(|g| g as u32),
+                size: u64_field("size"),
+                atime: u64_field("atime").map(nanos_to_time),
+                mtime: u64_field("mtime").map(nanos_to_time),
+                ctime: u64_field("ctime").map(nanos_to_time),
+                crtime: u64_field("crtime").map(nanos_to_time),
+            },
+            _ => return None,
+        };
+        Some(record)
+    }
+}
+
+fn time_to_nanos(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or(Duration::ZERO).as_nanos() as u64
+}
+
+fn nanos_to_time(nanos: u64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_nanos(nanos)
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
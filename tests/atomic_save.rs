@@ -0,0 +1,35 @@
+// The save editors make to avoid ever leaving a half-written file: write a temporary file, fsync
+// it, then rename it over the original
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use common::Mount;
+use std::fs;
+use std::io::{Read, Write};
+
+#[test]
+fn write_fsync_rename_replaces_a_file_in_one_step() {
+    let Some(mnt) = Mount::new(&[]) else { return };
+    let original = mnt.join("notes.txt");
+    fs::write(&original, b"first draft\n").unwrap();
+    // Someone still reading the old version while the save happens
+    let mut reader = fs::File::open(&original).unwrap();
+
+    let temp = mnt.join(".notes.txt.swp");
+    let mut file = fs::File::create(&temp).unwrap();
+    file.write_all(b"second draft, a little longer\n").unwrap();
+    file.sync_all().unwrap();
+    drop(file);
+    fs::rename(&temp, &original).unwrap();
+
+    assert_eq!(fs::read(&original).unwrap(), b"second draft, a little longer\n");
+    assert!(!temp.exists());
+    let names: Vec<_> = fs::read_dir(&mnt.path).unwrap().map(|e| e.unwrap().file_name()).collect();
+    assert_eq!(names.iter().filter(|n| n.to_string_lossy().starts_with(".notes")).count(), 0);
+
+    // The replaced file lives on until its last handle closes
+    let mut old = String::new();
+    reader.read_to_string(&mut old).unwrap();
+    assert_eq!(old, "first draft\n");
+}
@@ -0,0 +1,114 @@
+// Helpers for tests that run the dnsfs-rs binary itself against a real FUSE mount. Each test
+// skips itself (and passes vacuously) where nothing can be mounted
+
+#![allow(dead_code)]
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// The binary mounted on a fresh temporary directory, unmounted (with SIGTERM, so destroy()
+/// runs) when dropped
+pub struct Mount {
+    child: Child,
+    pub path: PathBuf,
+}
+
+impl Mount {
+    /// Mounts with `args` ahead of the mountpoint, or returns None if the mount never appears
+    pub fn new(args: &[&str]) -> Option<Mount> {
+        static MOUNTS: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!("dnsfs-it-{}-{}", std::process::id(), MOUNTS.fetch_add(1, Ordering::Relaxed)));
+        fs::create_dir_all(&path).unwrap();
+
+        let mut child = Command::new(env!("CARGO_BIN_EXE_dnsfs-rs"))
+            .args(args)
+            .arg(&path)
+            .stdin(Stdio::null())
+            .spawn()
+            .unwrap();
+        for _ in 0..100 {
+            if is_mounted(&path) {
+                return Some(Mount{ child, path });
+            }
+            if let Ok(Some(status)) = child.try_wait() {
+                eprintln!("Skipping: dnsfs-rs exited with {} before mounting {}", status, path.display());
+                let _ = fs::remove_dir(&path);
+                return None;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        eprintln!("Skipping: {} never got mounted", path.display());
+        let _ = child.kill();
+        let _ = child.wait();
+        let _ = fs::remove_dir(&path);
+        None
+    }
+
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.path.join(name)
+    }
+}
+
+impl Drop for Mount {
+    fn drop(&mut self) {
+        unsafe { libc::kill(self.child.id() as i32, libc::SIGTERM) };
+        let _ = self.child.wait();
+        let _ = fs::remove_dir(&self.path);
+    }
+}
+
+pub fn is_mounted(path: &Path) -> bool {
+    let mounts = fs::read_to_string("/proc/mounts").unwrap_or_default();
+    let path = path.to_string_lossy();
+    mounts.lines().any(|l| l.split_whitespace().nth(1) == Some(&path))
+}
+
+pub fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// The unprivileged user as_user runs as
+pub const NOBODY: u32 = 65534;
+
+/// Runs `op` in a child process as NOBODY and returns the errno it failed with, or 0 if it
+/// succeeded. None (skip the test) when not running as root
+pub fn as_user(op: impl FnOnce() -> io::Result<()>) -> Option<i32> {
+    if !is_root() {
+        eprintln!("Skipping: switching users needs root");
+        return None;
+    }
+    match unsafe { libc::fork() } {
+        0 => {
+            let code = unsafe {
+                if libc::setgroups(0, std::ptr::null()) != 0 || libc::setgid(NOBODY) != 0 || libc::setuid(NOBODY) != 0 {
+                    libc::_exit(255);
+                }
+                match op() {
+                    Ok(()) => 0,
+                    Err(e) => e.raw_os_error().unwrap_or(254),
+                }
+            };
+            unsafe { libc::_exit(code) }
+        },
+        -1 => panic!("fork: {}", io::Error::last_os_error()),
+        pid => {
+            let mut status = 0;
+            unsafe { libc::waitpid(pid, &mut status, 0) };
+            assert!(libc::WIFEXITED(status), "child as uid {} died: status {}", NOBODY, status);
+            Some(libc::WEXITSTATUS(status))
+        },
+    }
+}
+
+/// The errno `result` failed with, or 0 if it succeeded
+pub fn errno<T>(result: io::Result<T>) -> i32 {
+    match result {
+        Ok(_) => 0,
+        Err(e) => e.raw_os_error().unwrap_or(-1),
+    }
+}
@@ -0,0 +1,66 @@
+// `--config` mounts several independent trees from one process and unmounts them together
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use common::{is_mounted, Mount};
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("dnsfs-it-{}-config-{}", std::process::id(), name))
+}
+
+#[test]
+fn each_tree_in_a_config_serves_its_own_files() {
+    // Seed a snapshot for each tree with a file only that tree has
+    let mut lines = Vec::new();
+    let mut trees = Vec::new();
+    for zone in ["a", "b"] {
+        let snapshot = temp_path(&format!("{}.json", zone));
+        let _ = fs::remove_file(&snapshot);
+        let Some(seed) = Mount::new(&["--snapshot", snapshot.to_str().unwrap()]) else { return };
+        fs::write(seed.join(&format!("zone-{}", zone)), zone).unwrap();
+        drop(seed);
+
+        let mountpoint = temp_path(&format!("mnt-{}", zone));
+        fs::create_dir_all(&mountpoint).unwrap();
+        lines.push(format!("--snapshot {} {}", snapshot.display(), mountpoint.display()));
+        trees.push((zone, snapshot, mountpoint));
+    }
+    let config = temp_path("trees.conf");
+    fs::write(&config, format!("# one tree per zone\n{}\n", lines.join("\n"))).unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_dnsfs-rs"))
+        .arg("--config")
+        .arg(&config)
+        .stdin(Stdio::null())
+        .spawn()
+        .unwrap();
+    for _ in 0..100 {
+        if trees.iter().all(|(_, _, m)| is_mounted(m)) {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(trees.iter().all(|(_, _, m)| is_mounted(m)), "not every tree in the config got mounted");
+
+    for (zone, _, mountpoint) in &trees {
+        assert_eq!(fs::read_to_string(mountpoint.join(format!("zone-{}", zone))).unwrap(), *zone);
+        let other = if *zone == "a" { "b" } else { "a" };
+        assert!(!mountpoint.join(format!("zone-{}", other)).exists(), "tree {} has tree {}'s file", zone, other);
+    }
+
+    // One SIGTERM takes every tree down
+    unsafe { libc::kill(child.id() as i32, libc::SIGTERM) };
+    assert!(child.wait().unwrap().success());
+    for (_, snapshot, mountpoint) in &trees {
+        assert!(!is_mounted(mountpoint));
+        let _ = fs::remove_dir(mountpoint);
+        let _ = fs::remove_file(snapshot);
+    }
+    let _ = fs::remove_file(&config);
+}
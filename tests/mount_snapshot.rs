@@ -0,0 +1,35 @@
+// `--mount-snapshot` mounts a snapshot written by `--snapshot` read-only, next to whatever is
+// live
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use common::{errno, Mount};
+use libc::EROFS;
+use std::fs;
+
+#[test]
+fn a_saved_snapshot_mounts_readable_and_read_only() {
+    let snapshot = std::env::temp_dir().join(format!("dnsfs-it-{}-snapshot.json", std::process::id()));
+    let _ = fs::remove_file(&snapshot);
+    let snapshot_arg = snapshot.to_str().unwrap();
+
+    // Unmounting writes the snapshot
+    let Some(live) = Mount::new(&["--snapshot", snapshot_arg]) else { return };
+    fs::create_dir(live.join("dir")).unwrap();
+    fs::write(live.join("dir/saved"), b"saved").unwrap();
+    drop(live);
+    assert!(snapshot.exists());
+
+    let Some(mnt) = Mount::new(&["--mount-snapshot", snapshot_arg]) else { return };
+    assert_eq!(fs::read(mnt.join("dir/saved")).unwrap(), b"saved");
+    assert_eq!(fs::read(mnt.join("foo")).unwrap(), b"bar");
+
+    assert_eq!(errno(fs::write(mnt.join("dir/saved"), b"changed")), EROFS);
+    assert_eq!(errno(fs::write(mnt.join("new"), b"new")), EROFS);
+    assert_eq!(errno(fs::remove_file(mnt.join("dir/saved"))), EROFS);
+    assert_eq!(errno(fs::create_dir(mnt.join("newdir"))), EROFS);
+    assert_eq!(fs::read(mnt.join("dir/saved")).unwrap(), b"saved");
+    drop(mnt);
+    let _ = fs::remove_file(&snapshot);
+}
@@ -0,0 +1,122 @@
+// Every operation fails with the errno POSIX gives for each standard error condition, as seen
+// through a real mount of the binary. The seed tree has /foo and /answer, owned by uid 1000
+
+mod common;
+
+use common::{as_user, errno, Mount};
+use libc::{EACCES, EEXIST, EISDIR, ELOOP, ENAMETOOLONG, ENOENT, ENOTDIR, ENOTEMPTY, EPERM};
+use std::fs::{self, OpenOptions};
+use std::os::unix::fs::{symlink, PermissionsExt};
+
+#[test]
+fn missing_entries_fail_with_enoent() {
+    let Some(mnt) = Mount::new(&[]) else { return };
+    let missing = mnt.join("missing");
+
+    assert_eq!(errno(fs::metadata(&missing)), ENOENT);
+    assert_eq!(errno(fs::read(&missing)), ENOENT);
+    assert_eq!(errno(fs::remove_file(&missing)), ENOENT);
+    assert_eq!(errno(fs::remove_dir(&missing)), ENOENT);
+    assert_eq!(errno(fs::rename(&missing, mnt.join("elsewhere"))), ENOENT);
+    assert_eq!(errno(fs::hard_link(&missing, mnt.join("elsewhere"))), ENOENT);
+    assert_eq!(errno(fs::write(mnt.join("missing/file"), b"x")), ENOENT);
+    assert_eq!(errno(fs::read_link(&missing)), ENOENT);
+}
+
+#[test]
+fn existing_entries_fail_with_eexist() {
+    let Some(mnt) = Mount::new(&[]) else { return };
+    fs::create_dir(mnt.join("dir")).unwrap();
+
+    assert_eq!(errno(fs::create_dir(mnt.join("foo"))), EEXIST);
+    assert_eq!(errno(fs::create_dir(mnt.join("dir"))), EEXIST);
+    assert_eq!(errno(OpenOptions::new().write(true).create_new(true).open(mnt.join("foo"))), EEXIST);
+    assert_eq!(errno(symlink("foo", mnt.join("answer"))), EEXIST);
+    assert_eq!(errno(fs::hard_link(mnt.join("foo"), mnt.join("answer"))), EEXIST);
+}
+
+#[test]
+fn kind_mismatches_fail_with_enotdir_or_eisdir() {
+    let Some(mnt) = Mount::new(&[]) else { return };
+    fs::create_dir(mnt.join("dir")).unwrap();
+    let file = mnt.join("foo");
+    let dir = mnt.join("dir");
+
+    assert_eq!(errno(fs::remove_dir(&file)), ENOTDIR);
+    assert_eq!(errno(fs::create_dir(mnt.join("foo/sub"))), ENOTDIR);
+    assert_eq!(errno(fs::read_dir(&file)), ENOTDIR);
+    assert_eq!(errno(fs::rename(&dir, &file)), ENOTDIR);
+
+    assert_eq!(errno(fs::remove_file(&dir)), EISDIR);
+    assert_eq!(errno(OpenOptions::new().write(true).open(&dir)), EISDIR);
+    assert_eq!(errno(fs::rename(&file, &dir)), EISDIR);
+}
+
+#[test]
+fn permission_failures_fail_with_eacces_or_eperm() {
+    let Some(mnt) = Mount::new(&[]) else { return };
+    let private = mnt.join("private");
+    fs::create_dir(&private).unwrap();
+    fs::write(private.join("secret"), b"x").unwrap();
+    fs::set_permissions(&private, fs::Permissions::from_mode(0o700)).unwrap();
+    fs::set_permissions(mnt.join("answer"), fs::Permissions::from_mode(0o600)).unwrap();
+
+    // Nobody owns nothing here: the root is 755 and owned by uid 1000
+    let Some(e) = as_user(|| fs::write(mnt.join("new"), b"x")) else { return };
+    assert_eq!(e, EACCES);
+    assert_eq!(as_user(|| fs::create_dir(mnt.join("newdir"))), Some(EACCES));
+    assert_eq!(as_user(|| fs::remove_file(mnt.join("foo"))), Some(EACCES));
+    assert_eq!(as_user(|| fs::read(mnt.join("answer")).map(|_| ())), Some(EACCES));
+    // Search permission is checked before the name is even looked for (a name looked up
+    // already would be answered from the kernel's cache)
+    assert_eq!(as_user(|| fs::metadata(private.join("unseen")).map(|_| ())), Some(EACCES));
+    assert_eq!(as_user(|| fs::read_dir(&private).map(|_| ())), Some(EACCES));
+
+    // Only the owner may change a file's mode or owner
+    assert_eq!(as_user(|| fs::set_permissions(mnt.join("foo"), fs::Permissions::from_mode(0o777))), Some(EPERM));
+    assert_eq!(as_user(|| std::os::unix::fs::chown(mnt.join("foo"), Some(common::NOBODY), None)), Some(EPERM));
+}
+
+#[test]
+fn removing_a_non_empty_directory_fails_with_enotempty() {
+    let Some(mnt) = Mount::new(&[]) else { return };
+    fs::create_dir(mnt.join("dir")).unwrap();
+    fs::write(mnt.join("dir/file"), b"x").unwrap();
+    fs::create_dir(mnt.join("other")).unwrap();
+    fs::write(mnt.join("other/file"), b"x").unwrap();
+
+    assert_eq!(errno(fs::remove_dir(mnt.join("dir"))), ENOTEMPTY);
+    assert_eq!(errno(fs::rename(mnt.join("other"), mnt.join("dir"))), ENOTEMPTY);
+    assert!(mnt.join("dir/file").exists());
+}
+
+#[test]
+fn symlink_cycles_fail_with_eloop() {
+    let Some(mnt) = Mount::new(&[]) else { return };
+    symlink("b", mnt.join("a")).unwrap();
+    symlink("a", mnt.join("b")).unwrap();
+    symlink("self", mnt.join("self")).unwrap();
+
+    assert_eq!(errno(fs::read(mnt.join("a"))), ELOOP);
+    assert_eq!(errno(fs::metadata(mnt.join("self"))), ELOOP);
+    assert_eq!(errno(fs::write(mnt.join("a/file"), b"x")), ELOOP);
+    // The links themselves are fine
+    assert_eq!(fs::read_link(mnt.join("a")).unwrap().to_str(), Some("b"));
+}
+
+#[test]
+fn names_longer_than_name_max_fail_with_enametoolong() {
+    let Some(mnt) = Mount::new(&[]) else { return };
+    let long = "x".repeat(256);
+    let longest = "x".repeat(255);
+
+    assert_eq!(errno(fs::write(mnt.join(&long), b"x")), ENAMETOOLONG);
+    assert_eq!(errno(fs::create_dir(mnt.join(&long))), ENAMETOOLONG);
+    assert_eq!(errno(symlink("foo", mnt.join(&long))), ENAMETOOLONG);
+    assert_eq!(errno(fs::hard_link(mnt.join("foo"), mnt.join(&long))), ENAMETOOLONG);
+    assert_eq!(errno(fs::rename(mnt.join("foo"), mnt.join(&long))), ENAMETOOLONG);
+    assert_eq!(errno(fs::metadata(mnt.join(&long))), ENAMETOOLONG);
+
+    fs::write(mnt.join(&longest), b"x").unwrap();
+    assert!(mnt.join("foo").exists());
+}
@@ -0,0 +1,42 @@
+// `dnsfs-rs --self-test` mounts its own tree, runs its battery of operations against it and exits
+// zero when every one passes
+#![cfg(target_os = "linux")]
+
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn self_test_passes_and_exits_zero() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_dnsfs-rs"))
+        .arg("--self-test")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // A step the filesystem never answers would otherwise hang the whole run
+    let mut status = None;
+    for _ in 0..600 {
+        status = child.try_wait().unwrap();
+        if status.is_some() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    let Some(status) = status else {
+        let _ = child.kill();
+        let _ = child.wait();
+        panic!("self-test still running after a minute");
+    };
+    let mut output = String::new();
+    child.stdout.take().unwrap().read_to_string(&mut output).unwrap();
+
+    if output.starts_with("FAIL mount") {
+        eprintln!("Skipping: {}", output.trim());
+        return;
+    }
+    assert!(status.success(), "self-test exited with {}:\n{}", status, output);
+    assert!(output.contains("PASS unlink\n"), "{}", output);
+    assert!(output.ends_with("Self-test passed\n"), "{}", output);
+}